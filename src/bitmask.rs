@@ -4,86 +4,177 @@ use crate::{
     input::IntoID,
     supported_actions::{AXIS_COUNT, BUTTON_COUNT},
 };
-use bincode::{Decode, Encode};
+use anyhow::{Result, bail};
+use bincode::{
+    Decode, Encode,
+    de::{Decoder, read::Reader},
+    enc::{Encoder, write::Writer},
+    error::{DecodeError, EncodeError},
+    impl_borrow_decode,
+};
 use evdev::{AbsoluteAxisCode, KeyCode};
 
-// This struct will contain the public
-// interface for using a bitmask
-#[derive(Debug, Decode, Encode, PartialEq, Eq, Copy, Clone)]
+// A set of bit indices below `size`, stored 8 bits to a byte. A mask can be as wide as it needs to
+// be, and reading past the end of it reads a cleared bit rather than panicking.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Bitmask {
-    raw: u32,
+    blocks: Vec<u8>,
     size: u32,
 }
 
-impl Bitmask {
-    pub const MAX: Self = Self {
-        raw: u32::MAX,
-        size: 32,
-    };
+// Returns the number of bytes needed to hold `size` bits
+fn block_count(size: u32) -> usize {
+    size.div_ceil(8) as usize
+}
 
+impl Bitmask {
     pub fn new(size: u32) -> Self {
-        if size > 32 {
-            panic!("Bitask size cannot exceed 32");
+        Self {
+            blocks: vec![0; block_count(size)],
+            size,
         }
-        Self { raw: 0, size }
-    }
-
-    // Generate from a predefined u32
-    pub fn new_from_u32(size: u32, raw: u32) -> Self {
-        Self { raw, size }
     }
 
-    // Build a bitmask from a raw u32
-    pub fn from_ll(raw: u32, size: u32) -> Self {
-        if size > 32 {
-            panic!("Bitask size cannot exceed 32");
-        }
-        Self { raw, size }
+    // Returns the number of bits in the bitmask
+    pub fn size(&self) -> u32 {
+        self.size
     }
 
-    // Check that the index-th bit of raw is set to 1
+    // Check that the index-th bit is set to 1. Bits past the end of the mask are never set.
     #[inline]
     pub fn read_bit(&self, index: u32) -> bool {
-        self.check_index(index);
-        self.raw & (1 << index) != 0
+        index < self.size && self.blocks[(index / 8) as usize] & (1 << (index % 8)) != 0
     }
 
-    // Set the index-th bit of raw to value
+    // Set the index-th bit to value
     #[inline]
-    pub fn write_bit(&mut self, index: u32, value: bool) {
-        self.check_index(index);
-        let current_state = self.read_bit(index);
-        if current_state == value {
-            return;
-        } else if current_state {
-            self.raw ^= 1 << index;
+    pub fn write_bit(&mut self, index: u32, value: bool) -> Result<()> {
+        if index >= self.size {
+            bail!(
+                "Bit {index} is out of bounds of a bitmask holding {} bits",
+                self.size
+            );
+        }
+        let block = &mut self.blocks[(index / 8) as usize];
+        if value {
+            *block |= 1 << (index % 8);
         } else {
-            self.raw |= 1 << index;
+            *block &= !(1 << (index % 8));
         }
+        Ok(())
     }
 
-    // Checks if the index is valid and panics if not
-    #[inline]
-    fn check_index(&self, index: u32) {
-        if &index >= &self.size {
-            panic!(
-                "index out of bounds: the len is {} but the index is {}",
-                self.size, index
-            );
+    // Generate a bitmask with every bit set
+    pub fn full(size: u32) -> Self {
+        let mut mask = Self {
+            blocks: vec![u8::MAX; block_count(size)],
+            size,
+        };
+        mask.clear_unused_bits();
+        mask
+    }
+
+    // Generate a bitmask with each bit in `ones` set, failing if any of them is out of bounds
+    pub fn from_ones<T>(size: u32, ones: T) -> Result<Self>
+    where
+        T: IntoIterator<Item = u32>,
+    {
+        let mut mask = Self::new(size);
+        for index in ones {
+            mask.write_bit(index, true)?;
+        }
+        Ok(mask)
+    }
+
+    // Returns the indices of the set bits, lowest first
+    pub fn ones(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.size).filter(|index| self.read_bit(*index))
+    }
+
+    // Returns the number of set bits
+    pub fn count_ones(&self) -> u32 {
+        self.blocks.iter().map(|block| block.count_ones()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|block| *block == 0)
+    }
+
+    // Returns the bits set in either mask. The result is as wide as the wider of the two.
+    pub fn union(&self, other: &Bitmask) -> Bitmask {
+        self.combine(other, |a, b| a | b)
+    }
+
+    // Returns the bits set in both masks. The result is as wide as the wider of the two.
+    pub fn intersection(&self, other: &Bitmask) -> Bitmask {
+        self.combine(other, |a, b| a & b)
+    }
+
+    // Returns the bits set in `self` but not in `other`. The result is as wide as `self`.
+    pub fn difference(&self, other: &Bitmask) -> Bitmask {
+        let mut mask = self.clone();
+        for (block, other) in mask.blocks.iter_mut().zip(&other.blocks) {
+            *block &= !other;
+        }
+        mask
+    }
+
+    // Applies `op` to each pair of bytes, treating bytes past the end of the narrower mask as 0
+    fn combine(&self, other: &Bitmask, op: impl Fn(u8, u8) -> u8) -> Bitmask {
+        let size = self.size.max(other.size);
+        let byte = |mask: &Bitmask, i: usize| mask.blocks.get(i).copied().unwrap_or(0);
+        let blocks = (0..block_count(size))
+            .map(|i| op(byte(self, i), byte(other, i)))
+            .collect();
+        Bitmask { blocks, size }
+    }
+
+    // Clears the bits in the last byte that are past `size`, so equal masks hold equal bytes
+    fn clear_unused_bits(&mut self) {
+        let used = self.size % 8;
+        if used != 0
+            && let Some(last) = self.blocks.last_mut()
+        {
+            *last &= (1 << used) - 1;
+        }
+    }
+}
+
+// Encoded as the size followed by the bytes, without a separate length since the size implies it
+impl Encode for Bitmask {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> core::result::Result<(), EncodeError> {
+        self.size.encode(encoder)?;
+        encoder.writer().write(&self.blocks)
+    }
+}
+
+impl<Context> Decode<Context> for Bitmask {
+    fn decode<D: Decoder<Context = Context>>(
+        decoder: &mut D,
+    ) -> core::result::Result<Self, DecodeError> {
+        let size = u32::decode(decoder)?;
+        let len = block_count(size);
+        // Claimed before allocating, so a huge size is refused by the decode limit
+        decoder.claim_bytes_read(len)?;
+        let mut mask = Self::new(size);
+        decoder.reader().read(&mut mask.blocks)?;
+        let decoded = mask.blocks.last().copied();
+        mask.clear_unused_bits();
+        if mask.blocks.last().copied() != decoded {
+            return Err(DecodeError::Other("Bitmask has bits set past its size"));
         }
+        Ok(mask)
     }
 }
 
+impl_borrow_decode!(Bitmask);
+
 impl IntoIterator for Bitmask {
     type Item = bool;
     type IntoIter = BitmaskIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        BitmaskIterator {
-            mask: self.raw,
-            bit: 0,
-            size: self.size,
-        }
+        BitmaskIterator { mask: self, bit: 0 }
     }
 }
 
@@ -92,17 +183,13 @@ impl FromIterator<KeyCode> for Bitmask {
     where
         T: IntoIterator<Item = KeyCode>,
     {
-        let mut raw: u32 = 0;
+        let mut mask = Bitmask::new(BUTTON_COUNT);
         value
             .into_iter()
             .filter_map(|code| code.into_id().ok())
-            .for_each(|code| {
-                raw |= 1 << code;
-            });
-        Bitmask {
-            raw,
-            size: BUTTON_COUNT,
-        }
+            // Safety of using `unwrap()`: every button ID is below BUTTON_COUNT
+            .for_each(|id| mask.write_bit(id, true).unwrap());
+        mask
     }
 }
 
@@ -111,15 +198,13 @@ impl FromIterator<AbsoluteAxisCode> for Bitmask {
     where
         T: IntoIterator<Item = AbsoluteAxisCode>,
     {
-        let mut raw: u32 = 0;
+        let mut mask = Bitmask::new(AXIS_COUNT);
         value
             .into_iter()
             .filter_map(|code| code.into_id().ok())
-            .for_each(|code| raw |= 1 << code);
-        Bitmask {
-            raw,
-            size: AXIS_COUNT,
-        }
+            // Safety of using `unwrap()`: every axis ID is below AXIS_COUNT
+            .for_each(|id| mask.write_bit(id, true).unwrap());
+        mask
     }
 }
 
@@ -127,20 +212,18 @@ impl FromIterator<AbsoluteAxisCode> for Bitmask {
 // that you can iterate through a
 // bitmask's data
 pub struct BitmaskIterator {
-    mask: u32,
+    mask: Bitmask,
     bit: u32,
-    size: u32,
 }
 
 impl Iterator for BitmaskIterator {
     type Item = bool;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.bit += 1;
-        if self.bit - 1 >= self.size {
-            None
-        } else {
-            Some((self.mask & (1 << (self.bit - 1))) != 0)
+        if self.bit >= self.mask.size {
+            return None;
         }
+        self.bit += 1;
+        Some(self.mask.read_bit(self.bit - 1))
     }
 }
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+
+use anyhow::{Result, bail};
+use bincode::{Decode, Encode};
+use evdev::{AbsInfo, AbsoluteAxisCode, AttributeSet, Device, KeyCode};
+
+use crate::datagram::{Message, MessageKind};
+
+// A client tells the server which buttons and axes its device has, and the range of each axis, so
+// that the server can give the controller a virtual joystick that matches it exactly. Games then
+// don't see buttons the controller doesn't have. Controllers the server hasn't heard from yet get
+// the Steam Deck template instead.
+
+// The kernel's limits on key and axis codes (KEY_CNT and ABS_CNT). Codes past these would panic
+// when added to a device.
+pub const KEY_CODES: u16 = 0x300;
+const AXIS_CODES: u16 = 0x40;
+
+// The range of an axis, as the device reports it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Decode, Encode)]
+pub struct AxisRange {
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32, // Changes smaller than this are noise
+    pub flat: i32, // Values within this of the middle are reported as the middle
+    pub resolution: i32,
+}
+
+impl From<AbsInfo> for AxisRange {
+    fn from(info: AbsInfo) -> Self {
+        Self {
+            minimum: info.minimum(),
+            maximum: info.maximum(),
+            fuzz: info.fuzz(),
+            flat: info.flat(),
+            resolution: info.resolution(),
+        }
+    }
+}
+
+impl AxisRange {
+    // An axis rests at the middle of its range, or at its minimum if it only goes one way
+    pub fn rest(&self) -> i32 {
+        0.clamp(self.minimum, self.maximum)
+    }
+
+    // Maps `value`, reported by an axis with the range `from`, onto the same point of this range.
+    // Values outside of `from` are held to it, so the result is always within this range.
+    pub fn rescale(&self, value: i32, from: &AxisRange) -> i32 {
+        if from.minimum == self.minimum && from.maximum == self.maximum {
+            return value.clamp(self.minimum, self.maximum);
+        }
+        if from.minimum >= from.maximum {
+            return self.rest();
+        }
+        // Spans of the full i32 range multiplied together don't fit in an i64
+        let offset = i128::from(value.clamp(from.minimum, from.maximum)) - i128::from(from.minimum);
+        let from_span = i128::from(from.maximum) - i128::from(from.minimum);
+        let to_span = i128::from(self.maximum) - i128::from(self.minimum);
+        // Rounded to the nearest value rather than down, so both ends of the range are reached
+        let scaled = (offset * to_span * 2 + from_span) / (from_span * 2);
+        // Safety of using `unwrap()`: `scaled` is within `to_span`, so the sum is within this range
+        (i128::from(self.minimum) + scaled).try_into().unwrap()
+    }
+
+    // Returns true if `value` is at least `threshold` of the way from the minimum to the maximum,
+    // where `threshold` is between 0 and 1
+    pub fn is_past(&self, value: i32, threshold: f64) -> bool {
+        let travel = f64::from(value) - f64::from(self.minimum);
+        let span = f64::from(self.maximum) - f64::from(self.minimum);
+        travel >= threshold * span
+    }
+
+    pub fn to_abs_info(self) -> AbsInfo {
+        AbsInfo::new(
+            self.rest(),
+            self.minimum,
+            self.maximum,
+            self.fuzz,
+            self.flat,
+            self.resolution,
+        )
+    }
+}
+
+// Every button and axis a device has, by evdev code
+#[derive(Debug, Clone, PartialEq, Eq, Default, Decode, Encode)]
+pub struct DeviceCapabilities {
+    keys: Vec<u16>,
+    axes: Vec<(u16, AxisRange)>,
+}
+
+impl DeviceCapabilities {
+    // Reads the capabilities of `device`
+    pub fn read(device: &Device) -> Result<Self> {
+        let no_keys = AttributeSet::new();
+        let keys = device.supported_keys().unwrap_or(&no_keys).iter();
+        let axes = device
+            .get_absinfo()?
+            .map(|(axis, info)| (axis, info.into()));
+        Ok(Self::new(keys, axes))
+    }
+
+    pub fn new<K, A>(keys: K, axes: A) -> Self
+    where
+        K: IntoIterator<Item = KeyCode>,
+        A: IntoIterator<Item = (AbsoluteAxisCode, AxisRange)>,
+    {
+        Self {
+            keys: keys.into_iter().map(|key| key.0).collect(),
+            axes: axes
+                .into_iter()
+                .map(|(axis, range)| (axis.0, range))
+                .collect(),
+        }
+    }
+
+    // Checks that a virtual joystick can be built from the capabilities. They come from the
+    // network, so nothing about them can be assumed.
+    pub fn validate(&self) -> Result<()> {
+        let mut keys = HashSet::new();
+        for key in &self.keys {
+            if *key >= KEY_CODES {
+                bail!("Key code {key} is past the last key code");
+            }
+            if !keys.insert(*key) {
+                bail!("Key code {key} is listed more than once");
+            }
+        }
+        let mut axes = HashSet::new();
+        for (axis, range) in &self.axes {
+            if *axis >= AXIS_CODES {
+                bail!("Axis code {axis} is past the last axis code");
+            }
+            if !axes.insert(*axis) {
+                bail!("Axis code {axis} is listed more than once");
+            }
+            if range.minimum > range.maximum {
+                bail!(
+                    "Axis code {axis} has a minimum of {} above its maximum of {}",
+                    range.minimum,
+                    range.maximum
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.keys.iter().map(|key| KeyCode(*key))
+    }
+
+    pub fn axes(&self) -> impl Iterator<Item = (AbsoluteAxisCode, AxisRange)> + '_ {
+        self.axes
+            .iter()
+            .map(|(axis, range)| (AbsoluteAxisCode(*axis), *range))
+    }
+}
+
+// Sent by the client on the serial port when it starts sending to a server, and again as often as
+// it announces its presence, so that a server that missed it or restarted still finds out
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct HelloPacket {
+    pub id: u64,
+    pub capabilities: DeviceCapabilities,
+    pub motion: Option<DeviceCapabilities>, // The axes of the device's motion sensors, if any
+    pub desktop: Option<DeviceCapabilities>, // The keys of the keyboards and mice forwarded with it
+}
+
+impl Message for HelloPacket {
+    const KIND: MessageKind = MessageKind::Hello;
+}
@@ -1,21 +1,69 @@
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use crate::datagram::{BroadcastPacket, serialize};
-use crate::evdev_sb::DeviceWrapper;
-use crate::input::StarboardInputPacket;
+use crate::capabilities::HelloPacket;
+use crate::datagram::{
+    BroadcastPacket, DisconnectPacket, Message, MessageKind, deserialize, format_addr, peek_kind,
+    serialize,
+};
+use crate::desktop::DesktopDevices;
+use crate::discovery::{DiscoveredServer, ServerAnnouncement, ServerList};
+use crate::evdev_sb::{DeviceEventStream, DeviceWrapper};
+use crate::force_feedback::{ForceFeedback, ForceFeedbackPacket, RumblePlayer};
+use crate::input::{MotionSample, StarboardDeltaPacket, StarboardInput, StarboardInputPacket};
+use crate::motion::MotionSensors;
+use crate::net::{bind_dual_stack, discovery_addrs, to_dual_stack};
+use crate::pairing::{AuthenticatedPacket, ClientPairing, PairingKey, format_pin};
+use crate::ping::{PingPacket, PongPacket};
 use crate::printdbg;
+use crate::sequence::{SequenceVerdict, Sequencer, SessionWindow};
+use crate::session::{ClientSession, SessionInit};
+use crate::storage::{KeyStore, load_client_id};
 use crate::string::StarboardString;
-use anyhow::Result;
+use crate::trackpad::TouchTracker;
+use anyhow::{Result, bail};
 use bincode::{Decode, Encode};
+use evdev::{Device, EventType, SynchronizationCode};
 use tokio::net::UdpSocket;
-use tokio::time::{Duration, sleep};
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::{Duration, Instant, MissedTickBehavior, interval, timeout};
 
-// If testing both the client and server on the same device, the loopback address must be used
-// instead of the broadcast address.
-#[cfg(feature = "loopback")]
-static BC_ADDR: &'static str = "127.0.0.1";
-#[cfg(not(feature = "loopback"))]
-static BC_ADDR: &'static str = "255.255.255.255";
+// Servers are found by multicasting to the local network, unless the client is told where the
+// server is. Only discovery and pairing are multicast; input is sent to the chosen server alone.
+
+// The file the keys shared with paired servers are kept in, keyed by server ID
+const PAIRED_SERVERS: &str = "paired_servers";
+
+// How long to wait for the server to answer each step of pairing, and how many times to ask
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(2);
+const PAIRING_ATTEMPTS: u32 = 5;
+// How many times the confirmation is sent while the server's operator decides whether to approve
+// the pairing
+const APPROVAL_ATTEMPTS: u32 = 60;
+
+// How often a new encrypted session is negotiated, and how long to wait for the server to accept
+// one before asking again
+const SESSION_LIFETIME: Duration = Duration::from_secs(60);
+const SESSION_RETRY: Duration = Duration::from_secs(2);
+
+// How long to listen for servers before choosing one, and how long a server stays listed after it
+// last announced itself
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(3);
+const SERVER_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How often the client tells servers it's still there
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(5);
+
+// How much force feedback can wait to be replayed before the server's packets are held up
+const FEEDBACK_QUEUE_LEN: usize = 32;
+
+// Where input packets are sent, along with the encrypted session they're sealed with, if any
+struct SerialLink {
+    sock: Arc<UdpSocket>,
+    dest: SocketAddr,
+    session: Option<Arc<Mutex<ClientSession>>>,
+}
 
 // Since all the info needed for the server to see a client is contained
 // in the client struct itself, we can just directly encode and decode
@@ -26,77 +74,824 @@ pub struct StarboardClient {
     name: StarboardString,
     serial_port: u16,
     device_search_port: u16,
+    event_driven: bool,
+    keyframe_interval_ms: u64,
+    key: Option<PairingKey>, // Authenticates every packet once paired
+    encrypt: bool,
+    server_id: Option<u64>, // The server to send input to, if the user has chosen one
+    server_addr: Option<SocketAddr>, // The server's device search address, if it isn't discovered
+    forward: Vec<String>,   // Keyboards and mice to forward along with the controller
+    grab: bool,             // Whether the forwarded devices stop sending input to this device
 }
 
 impl StarboardClient {
     pub fn new(name: &str, serial_port: u16, device_search_port: u16) -> Result<Self> {
-        // TODO: Create a system for randomly generating an ID
         Ok(Self {
-            id: 0,
+            id: load_client_id()?,
             name: StarboardString::try_from(name)?,
             serial_port,
             device_search_port,
+            event_driven: false,
+            keyframe_interval_ms: 1000,
+            key: None,
+            encrypt: false,
+            server_id: None,
+            server_addr: None,
+            forward: Vec::new(),
+            grab: false,
         })
     }
 
+    // Send input to the server with the ID `server_id` and authenticate packets with the key
+    // shared with it. Without an ID, input goes to the only paired server, or to the server the
+    // user picks if the client was never paired, in which case packets go unauthenticated.
+    pub fn paired_with(self, server_id: Option<u64>) -> Result<Self> {
+        let mut client = self;
+        let paired_servers: KeyStore<u64> = KeyStore::load(PAIRED_SERVERS)?;
+        (client.server_id, client.key) = match server_id {
+            Some(id) => match paired_servers.get(&id) {
+                Some(key) => (Some(id), Some(*key)),
+                None => {
+                    bail!("This client hasn't been paired with server {id}; run `starboard pair`")
+                }
+            },
+            None if paired_servers.len() > 1 => {
+                let mut ids: Vec<String> = paired_servers
+                    .iter()
+                    .map(|(id, _)| id.to_string())
+                    .collect();
+                ids.sort();
+                bail!(
+                    "This client has been paired with several servers ({}); choose one with \
+                     --server-id",
+                    ids.join(", ")
+                )
+            }
+            None => match paired_servers.iter().next() {
+                Some((id, key)) => (Some(*id), Some(*key)),
+                None => (None, None),
+            },
+        };
+        Ok(client)
+    }
+
+    // Pair with a server that has its pairing page open. The user is asked for the PIN the server
+    // shows, and the shared key is saved once both sides agree on it.
+    pub async fn pair(&self) -> Result<()> {
+        let sock = bind_dual_stack(0)?;
+        let mut pairing = ClientPairing::new(self.id);
+
+        println!("Searching for a server with its pairing page open...");
+        let request = serialize(&pairing.request(self.name))?;
+        let (commit, server_addr) = exchange(
+            &sock,
+            &request,
+            &self.discovery_addrs()?,
+            None,
+            PAIRING_ATTEMPTS,
+        )
+        .await?;
+        let nonce = serialize(&pairing.handle_commit(commit))?;
+        let (reveal, _) = exchange(
+            &sock,
+            &nonce,
+            &[server_addr],
+            Some(server_addr),
+            PAIRING_ATTEMPTS,
+        )
+        .await?;
+        let pin = pairing.handle_reveal(reveal)?;
+
+        let entered = prompt(format!("Enter the PIN shown on {}: ", commit.server_name)).await?;
+        if entered.trim() != format_pin(pin) {
+            bail!("The PIN doesn't match the one the server generated; pairing aborted");
+        }
+        let confirm = serialize(&pairing.confirm()?)?;
+        println!(
+            "Enter code {} on {} to approve the pairing. Waiting for approval...",
+            format_pin(pairing.code()?),
+            commit.server_name
+        );
+        let (server_confirm, _) = exchange(
+            &sock,
+            &confirm,
+            &[server_addr],
+            Some(server_addr),
+            APPROVAL_ATTEMPTS,
+        )
+        .await?;
+        let (server_id, server_name, key) = pairing.finish(server_confirm)?;
+
+        // Pairing again with a server replaces the key kept for it, which the user asked for by
+        // entering the PIN
+        let mut paired_servers: KeyStore<u64> = KeyStore::load(PAIRED_SERVERS)?;
+        paired_servers.replace(server_id, key)?;
+        println!("Paired with {server_name}, whose server ID is {server_id}.");
+        Ok(())
+    }
+
+    // Send inputs as soon as the device reports them instead of polling every 16ms
+    pub fn event_driven(self, event_driven: bool) -> Self {
+        let mut client = self;
+        client.event_driven = event_driven;
+        client
+    }
+
+    // How often a full keyframe is sent when running event driven
+    pub fn keyframe_interval(self, keyframe_interval_ms: u64) -> Self {
+        let mut client = self;
+        client.keyframe_interval_ms = keyframe_interval_ms;
+        client
+    }
+
+    // Use `id` instead of the ID stored for this user, so that several clients can run as the same
+    // user without colliding
+    pub fn client_id(self, id: Option<u64>) -> Self {
+        let mut client = self;
+        if let Some(id) = id {
+            client.id = id;
+        }
+        client
+    }
+
+    // Look for the server at `server_addr` instead of broadcasting to the local network, so that
+    // servers on other networks can be reached. The address is that of the server's device search
+    // port.
+    pub fn server(self, server_addr: Option<SocketAddr>) -> Self {
+        let mut client = self;
+        client.server_addr = server_addr;
+        client
+    }
+
+    // Where presence and pairing requests are sent while looking for servers
+    fn discovery_addrs(&self) -> Result<Vec<SocketAddr>> {
+        if let Some(server_addr) = self.server_addr {
+            return Ok(vec![to_dual_stack(server_addr)]);
+        }
+        let addrs = discovery_addrs(self.device_search_port)?;
+        if addrs.is_empty() {
+            bail!("There's no network interface to search for servers on; use --server instead");
+        }
+        Ok(addrs)
+    }
+
+    // Encrypt every input packet. Encryption needs the key shared with a paired server.
+    pub fn encrypt(self, encrypt: bool) -> Self {
+        let mut client = self;
+        client.encrypt = encrypt;
+        client
+    }
+
+    // Forward each of `devices`, keyboards or mice given by path or by name, along with the
+    // controller. Grabbing them keeps their input from reaching this device too.
+    pub fn forward(self, devices: Vec<String>, grab: bool) -> Self {
+        let mut client = self;
+        client.forward = devices;
+        client.grab = grab;
+        client
+    }
+
     // Run the client loop
     pub async fn run(&self) -> Result<()> {
         let device = DeviceWrapper::get_steam_deck()?;
-        let dest_addr = format!("{}:{}", BC_ADDR, self.serial_port);
-        let sock = UdpSocket::bind("0.0.0.0:0").await?;
-        let _ = sock.set_broadcast(true)?;
-        let _ = sock.connect(&dest_addr).await?;
-        printdbg!("Serial Socket connected to {}.", dest_addr);
-        tokio::spawn(broadcast_presence(
+        let motion = MotionSensors::find(device.name())?;
+        let desktop = DesktopDevices::open(&self.forward, self.grab)?;
+        let hello = HelloPacket {
+            id: self.id,
+            capabilities: device.capabilities()?,
+            motion: motion.as_ref().map(|motion| motion.capabilities().clone()),
+            desktop: desktop
+                .as_ref()
+                .map(|desktop| desktop.capabilities().clone()),
+        };
+        let device_search_sock = bind_dual_stack(0)?;
+        let server = self.choose_server(&device_search_sock).await?;
+        println!("Sending input to {server}.");
+        let dest = server.serial_addr;
+        let sock = Arc::new(bind_dual_stack(0)?);
+        printdbg!("Serial Socket sending to {}.", dest);
+        let session = match (self.encrypt, self.key) {
+            (false, _) => None,
+            (true, Some(key)) => {
+                let session = Arc::new(Mutex::new(ClientSession::new(self.id, key)));
+                tokio::spawn(maintain_session(
+                    self.id,
+                    key,
+                    session.clone(),
+                    sock.clone(),
+                    dest,
+                ));
+                Some(session)
+            }
+            (true, None) => {
+                bail!("Encryption needs a paired server; run `starboard pair` first")
+            }
+        };
+        let (feedback_tx, feedback_rx) = mpsc::channel(FEEDBACK_QUEUE_LEN);
+        tokio::spawn(listen_to_server(
+            self.id,
+            self.key,
+            session.clone(),
+            sock.clone(),
+            dest,
+            feedback_tx,
+        ));
+        tokio::spawn(say_hello(
+            self.id,
+            self.key,
+            session.clone(),
+            sock.clone(),
+            dest,
+            hello,
+        ));
+        let link = SerialLink {
+            sock,
+            dest,
+            session,
+        };
+        tokio::spawn(announce_presence(
             self.id,
             self.name,
-            self.device_search_port,
+            self.key,
+            device_search_sock,
+            server.device_search_addr,
         ));
+        let input = async {
+            if self.event_driven {
+                self.run_event_driven(device, motion, desktop, &link, feedback_rx)
+                    .await
+            } else {
+                self.run_polling(device, motion, desktop, &link, feedback_rx)
+                    .await
+            }
+        };
+        tokio::select! {
+            res = input => res,
+            _ = tokio::signal::ctrl_c() => {
+                // Lets the server release whatever the controller was holding straight away,
+                // rather than once the controller times out
+                self.send_packet(&DisconnectPacket::new(self.id), &link).await?;
+                println!("Disconnected from {server}.");
+                Ok(())
+            }
+        }
+    }
+
+    // Announces the client's presence until servers answer, then picks the one to send input to:
+    // the server chosen with `--server-id` or paired with, the only server found, or otherwise
+    // the one the user chooses. A server given by address is used as soon as it answers.
+    async fn choose_server(&self, sock: &UdpSocket) -> Result<DiscoveredServer> {
+        let discovery_addrs = self.discovery_addrs()?;
+        let mut presence = BroadcastPacket::new(self.id, self.name)?;
+        let mut servers = ServerList::new(SERVER_TIMEOUT);
+        let mut ticks = interval(Duration::from_secs(1));
+        let started = Instant::now();
+        let mut buf: [u8; 256] = [0; 256];
+        println!("Searching for servers...");
         loop {
-            let packet = self.create_packet(&device)?;
-            let _ = self.send_packet(packet, &sock).await?;
-            tokio::time::sleep(Duration::from_millis(16)).await;
+            tokio::select! {
+                _ = ticks.tick() => {
+                    presence.update();
+                    let raw = encode_packet(self.id, self.key.as_ref(), &presence)?;
+                    for addr in &discovery_addrs {
+                        if let Err(e) = sock.send_to(&raw, addr).await {
+                            err_check_connection_refused(e)?;
+                        }
+                    }
+                    if self.server_addr.is_none() && started.elapsed() < DISCOVERY_WINDOW {
+                        continue;
+                    }
+                    if let Some(id) = self.server_id {
+                        if let Some(server) = servers.find(id) {
+                            return Ok(server);
+                        }
+                        continue;
+                    }
+                    let live = servers.live();
+                    match live.len() {
+                        0 => {}
+                        1 => return Ok(live[0]),
+                        _ => return pick_server(live).await,
+                    }
+                }
+                res = sock.recv_from(&mut buf) => {
+                    let (len, addr) = res?;
+                    match deserialize::<ServerAnnouncement>(&buf[..len]) {
+                        Ok(announcement) => servers.update(addr, announcement),
+                        // Usually a server running a different build, which would otherwise
+                        // never show up without saying why
+                        Err(e) => eprintln!("Rejected a datagram from {}: {e}", format_addr(addr)),
+                    }
+                }
+            }
         }
     }
 
-    // Creates a `StarboardInputPacket` from the state of a device
-    fn create_packet(&self, device: &DeviceWrapper) -> Result<StarboardInputPacket> {
-        let mut packet = StarboardInputPacket::new(self.id);
-        packet.pack_iter(device.get_button_inputs()?)?;
-        packet.pack_iter(device.get_axis_inputs()?)?;
-        Ok(packet)
+    // Sends the full state of the device every 16ms, along with every motion sample and every
+    // change to the forwarded keyboards and mice as it's read, and replays the server's force
+    // feedback
+    async fn run_polling(
+        &self,
+        mut device: DeviceWrapper,
+        mut motion: Option<MotionSensors>,
+        mut desktop: Option<DesktopDevices>,
+        link: &SerialLink,
+        mut feedback: mpsc::Receiver<ForceFeedback>,
+    ) -> Result<()> {
+        let mut sequencer = Sequencer::new();
+        let mut player = RumblePlayer::new();
+        let mut ticks = interval(Duration::from_millis(16));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {
+                    let (session, sequence) = sequencer.next();
+                    let mut packet = StarboardInputPacket::new(self.id, session, sequence);
+                    packet.pack_iter(device.get_button_inputs()?)?;
+                    packet.pack_iter(device.get_axis_inputs()?)?;
+                    packet.pack_iter(device.get_touch_inputs()?)?;
+                    if let Some(desktop) = &desktop {
+                        packet.pack_iter(desktop.key_inputs())?;
+                    }
+                    self.send_packet(&packet, link).await?;
+                }
+                sample = next_motion(&mut motion) => {
+                    let sample = sample?;
+                    self.send_delta(&mut sequencer, [StarboardInput::Motion { sample }], link)
+                        .await?;
+                }
+                inputs = next_desktop(&mut desktop) => {
+                    self.send_delta(&mut sequencer, inputs, link).await?;
+                }
+                Some(feedback) = feedback.recv() => {
+                    play_feedback(&mut player, device.device_mut(), feedback);
+                }
+            }
+        }
     }
 
-    // Broadcast's `packet` to the local network
-    async fn send_packet(&self, packet: StarboardInputPacket, sock: &UdpSocket) -> Result<()> {
-        let raw = serialize(packet)?;
-        let res = sock.send(&raw).await;
-        if let Err(e) = res {
-            err_check_connection_refused(e)?;
+    // Sends a delta for every batch of events the device reports, along with a keyframe every
+    // `keyframe_interval_ms` so that the server recovers from any deltas that were lost. Motion
+    // samples and changes to the forwarded keyboards and mice are sent as they're read, and the
+    // server's force feedback is replayed as it arrives.
+    async fn run_event_driven(
+        &self,
+        device: DeviceWrapper,
+        mut motion: Option<MotionSensors>,
+        mut desktop: Option<DesktopDevices>,
+        link: &SerialLink,
+        mut feedback: mpsc::Receiver<ForceFeedback>,
+    ) -> Result<()> {
+        let mut stream = device.into_event_stream()?;
+        let mut sequencer = Sequencer::new();
+        let mut player = RumblePlayer::new();
+        let mut keyframes = interval(Duration::from_millis(self.keyframe_interval_ms));
+        let mut pending: Vec<StarboardInput> = Vec::new();
+        let mut touches = TouchTracker::new(stream.trackpads().to_vec());
+        let mut dropping = false; // Whether events are being skipped after the device overflowed
+        loop {
+            tokio::select! {
+                _ = keyframes.tick() => {
+                    self.send_keyframe(&mut sequencer, &stream, &desktop, link).await?;
+                }
+                event = stream.next_event() => {
+                    let event = event?;
+                    // The device's buffer overflowed, so events were lost and whatever is pending
+                    // can't be trusted. Everything up to the next SYN_REPORT is skipped, and then
+                    // a keyframe is sent straight away rather than waiting for the next one.
+                    if event.event_type() == EventType::SYNCHRONIZATION
+                        && event.code() == SynchronizationCode::SYN_DROPPED.0
+                    {
+                        pending.clear();
+                        touches = TouchTracker::new(stream.trackpads().to_vec());
+                        dropping = true;
+                        continue;
+                    }
+                    if dropping {
+                        if event.event_type() == EventType::SYNCHRONIZATION {
+                            dropping = false;
+                            self.send_keyframe(&mut sequencer, &stream, &desktop, link).await?;
+                            keyframes.reset();
+                        }
+                        continue;
+                    }
+                    touches.update(&event);
+                    // Devices group simultaneous changes together and end each group with a
+                    // SYN_REPORT, so a delta is sent once a group is complete
+                    if event.event_type() == EventType::SYNCHRONIZATION {
+                        pending.extend(touches.changes());
+                        if pending.is_empty() {
+                            continue;
+                        }
+                        let (session, sequence) = sequencer.next();
+                        let mut packet = StarboardDeltaPacket::new(self.id, session, sequence);
+                        pending.drain(..).for_each(|input| packet.pack(input));
+                        self.send_packet(&packet, link).await?;
+                    } else if let Ok(input) = StarboardInput::try_from(event) {
+                        pending.push(input);
+                    }
+                }
+                sample = next_motion(&mut motion) => {
+                    let sample = sample?;
+                    self.send_delta(&mut sequencer, [StarboardInput::Motion { sample }], link)
+                        .await?;
+                }
+                inputs = next_desktop(&mut desktop) => {
+                    self.send_delta(&mut sequencer, inputs, link).await?;
+                }
+                Some(feedback) = feedback.recv() => {
+                    play_feedback(&mut player, stream.device_mut(), feedback);
+                }
+            }
         }
-        Ok(())
+    }
+
+    // Sends the full state of the device, and of the keys of the forwarded keyboards and mice
+    async fn send_keyframe(
+        &self,
+        sequencer: &mut Sequencer,
+        stream: &DeviceEventStream,
+        desktop: &Option<DesktopDevices>,
+        link: &SerialLink,
+    ) -> Result<()> {
+        let (session, sequence) = sequencer.next();
+        let mut packet = StarboardInputPacket::new(self.id, session, sequence);
+        packet.pack_iter(stream.get_button_inputs()?)?;
+        packet.pack_iter(stream.get_axis_inputs()?)?;
+        packet.pack_iter(stream.get_touch_inputs()?)?;
+        if let Some(desktop) = desktop {
+            packet.pack_iter(desktop.key_inputs())?;
+        }
+        self.send_packet(&packet, link).await
+    }
+
+    // Sends `inputs` in a delta of their own, so that they aren't held up waiting for the
+    // controller's input
+    async fn send_delta<T>(
+        &self,
+        sequencer: &mut Sequencer,
+        inputs: T,
+        link: &SerialLink,
+    ) -> Result<()>
+    where
+        T: IntoIterator<Item = StarboardInput>,
+    {
+        let (session, sequence) = sequencer.next();
+        let mut packet = StarboardDeltaPacket::new(self.id, session, sequence);
+        inputs.into_iter().for_each(|input| packet.pack(input));
+        self.send_packet(&packet, link).await
+    }
+
+    // Sends `packet` to the chosen server, sealed with the current session when encrypting
+    async fn send_packet<T>(&self, packet: &T, link: &SerialLink) -> Result<()>
+    where
+        T: Message,
+    {
+        // Inputs are dropped until the server has accepted a session
+        let session = link.session.as_deref();
+        let Some(raw) = encode_for_server(self.id, self.key.as_ref(), session, packet).await?
+        else {
+            return Ok(());
+        };
+        send_to(&link.sock, &raw, link.dest).await
     }
 }
 
-// Broadcast a client's presence to server's on the local network
-async fn broadcast_presence(id: u64, name: StarboardString, port: u16) -> Result<()> {
-    let dest_addr = format!("{}:{}", BC_ADDR, port);
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let _ = socket.set_broadcast(true);
-    let _ = socket.connect(&dest_addr).await?;
-    printdbg!("Device Search Socket connected to {}.", dest_addr);
+// Serializes `packet` for the serial port: sealed with the current session when encrypting, and
+// otherwise wrapped with a MAC if the client has been paired. Returns `None` if no session has
+// been established yet.
+async fn encode_for_server<T>(
+    id: u64,
+    key: Option<&PairingKey>,
+    session: Option<&Mutex<ClientSession>>,
+    packet: &T,
+) -> Result<Option<Vec<u8>>>
+where
+    T: Message,
+{
+    match session {
+        Some(session) => match session.lock().await.seal(&serialize(packet)?) {
+            Some(sealed) => Ok(Some(serialize(&sealed?)?)),
+            None => Ok(None),
+        },
+        None => Ok(Some(encode_packet(id, key, packet)?)),
+    }
+}
+
+// Serializes `packet`, wrapping it with a MAC if the client has been paired
+fn encode_packet<T>(id: u64, key: Option<&PairingKey>, packet: &T) -> Result<Vec<u8>>
+where
+    T: Message,
+{
+    let raw = serialize(packet)?;
+    match key {
+        Some(key) => serialize(&AuthenticatedPacket::seal(id, raw, key)),
+        None => Ok(raw),
+    }
+}
+
+// Keeps an encrypted session with the server, negotiating a new one every `SESSION_LIFETIME` and
+// asking again until the server accepts it. The server's answers arrive in `listen_to_server`.
+async fn maintain_session(
+    id: u64,
+    key: PairingKey,
+    session: Arc<Mutex<ClientSession>>,
+    sock: Arc<UdpSocket>,
+    dest: SocketAddr,
+) -> Result<()> {
+    let mut rekey = interval(SESSION_LIFETIME);
+    let mut retry = interval(SESSION_RETRY);
+    loop {
+        let init = tokio::select! {
+            _ = rekey.tick() => Some(session.lock().await.init()),
+            _ = retry.tick() => session.lock().await.pending(),
+        };
+        if let Some(init) = init {
+            send_to(&sock, &encode_packet(id, Some(&key), &init)?, dest).await?;
+        }
+    }
+}
+
+// Tells the server what the device has, so that its virtual joystick matches. The hello is
+// repeated every `PRESENCE_INTERVAL`, since it may be lost, sent before the server has detected
+// the client, or sent before a session has been accepted.
+async fn say_hello(
+    id: u64,
+    key: Option<PairingKey>,
+    session: Option<Arc<Mutex<ClientSession>>>,
+    sock: Arc<UdpSocket>,
+    dest: SocketAddr,
+    hello: HelloPacket,
+) -> Result<()> {
+    let mut ticks = interval(PRESENCE_INTERVAL);
+    loop {
+        ticks.tick().await;
+        if let Some(raw) = encode_for_server(id, key.as_ref(), session.as_deref(), &hello).await? {
+            send_to(&sock, &raw, dest).await?;
+        }
+    }
+}
+
+// Handles everything the server sends back to the serial socket: force feedback for the physical
+// controller, pings to be answered and, when encrypting, the answers to session requests. Only
+// datagrams from the server's serial address are acted on, since an unpaired client can't tell
+// the server's datagrams apart from anyone else's by their contents.
+async fn listen_to_server(
+    id: u64,
+    key: Option<PairingKey>,
+    session: Option<Arc<Mutex<ClientSession>>>,
+    sock: Arc<UdpSocket>,
+    dest: SocketAddr,
+    feedback: mpsc::Sender<ForceFeedback>,
+) -> Result<()> {
+    let mut window = SessionWindow::new();
+    let mut buf: [u8; 256] = [0; 256];
+    loop {
+        let (len, addr) = sock.recv_from(&mut buf).await?;
+        if addr != dest {
+            printdbg!(
+                "Ignored a datagram from {}, which isn't the server",
+                (format_addr(addr))
+            );
+            continue;
+        }
+        let res = handle_server_packet(&buf[..len], id, key.as_ref(), &mut window);
+        let reply = match res {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!("Rejected a datagram from {}: {e}", format_addr(addr));
+                continue;
+            }
+        };
+        match (reply, &session) {
+            (ServerReply::Feedback(packet), _) => feedback.send(packet).await?,
+            (ServerReply::Ping(ping), session) => {
+                let pong = PongPacket::answer(&ping);
+                let raw = encode_for_server(id, key.as_ref(), session.as_deref(), &pong).await?;
+                if let Some(raw) = raw {
+                    send_to(&sock, &raw, dest).await?;
+                }
+            }
+            (ServerReply::Session(inner), Some(session)) => {
+                let res = handle_session_reply(&inner, &mut *session.lock().await);
+                match res {
+                    Ok(Some(init)) => {
+                        send_to(&sock, &encode_packet(id, key.as_ref(), &init)?, dest).await?
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        printdbg!("Rejected a session reply: {}", e);
+                    }
+                }
+            }
+            (ServerReply::Session(_), None) => {
+                printdbg!("Received a session reply without having asked for a session");
+            }
+            (ServerReply::Ignored, _) => {}
+        }
+    }
+}
+
+// What the server sent to the serial socket
+enum ServerReply {
+    Feedback(ForceFeedback),
+    Ping(PingPacket),
+    Session(Vec<u8>), // A session reply, still to be decoded
+    Ignored,
+}
+
+// Checks the MAC of a datagram from the server, if the client has been paired, and works out what
+// it holds. Force feedback that arrives out of order is ignored, since replaying it late could
+// restart an effect that has since been stopped.
+fn handle_server_packet(
+    raw: &[u8],
+    id: u64,
+    key: Option<&PairingKey>,
+    window: &mut SessionWindow,
+) -> Result<ServerReply> {
+    let inner = match key {
+        Some(key) => {
+            let packet: AuthenticatedPacket = deserialize(raw)?;
+            if *packet.id() != id {
+                bail!("Received a packet meant for controller {}", packet.id());
+            }
+            packet.open(key)?
+        }
+        None => raw.to_vec(),
+    };
+    match peek_kind(&inner)? {
+        MessageKind::ForceFeedback => {
+            let packet: ForceFeedbackPacket = deserialize(&inner)?;
+            if packet.id != id {
+                bail!("Received force feedback meant for controller {}", packet.id);
+            }
+            match window.check(packet.session, packet.sequence) {
+                SequenceVerdict::Accepted { .. } => Ok(ServerReply::Feedback(packet.feedback)),
+                _ => Ok(ServerReply::Ignored),
+            }
+        }
+        MessageKind::Ping => {
+            let ping: PingPacket = deserialize(&inner)?;
+            if ping.id != id {
+                bail!("Received a ping meant for controller {}", ping.id);
+            }
+            Ok(ServerReply::Ping(ping))
+        }
+        MessageKind::SessionAccept | MessageKind::SessionExpired => Ok(ServerReply::Session(inner)),
+        kind => bail!("Unexpected {kind:?} on the serial socket"),
+    }
+}
+
+// Handles the server's answer to a session request, which has already been authenticated. Returns
+// a new request to send if the server no longer knows the current session.
+fn handle_session_reply(inner: &[u8], session: &mut ClientSession) -> Result<Option<SessionInit>> {
+    match peek_kind(inner)? {
+        MessageKind::SessionAccept => {
+            session.accept(deserialize(inner)?)?;
+            printdbg!("Encrypted session established.");
+            Ok(None)
+        }
+        MessageKind::SessionExpired => {
+            let expired = deserialize(inner)?;
+            // The server answers every packet sealed with an expired session, so a new session is
+            // only requested if one isn't already on its way
+            if session.is_current(&expired) && session.pending().is_none() {
+                Ok(Some(session.init()))
+            } else {
+                Ok(None)
+            }
+        }
+        kind => bail!("Expected a session reply, got {kind:?}"),
+    }
+}
+
+// Waits for the next motion sample, or forever if the controller has no motion sensors
+async fn next_motion(motion: &mut Option<MotionSensors>) -> Result<MotionSample> {
+    match motion {
+        Some(motion) => motion.next_sample().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Waits for the next changes to the forwarded keyboards and mice, or forever if there are none or
+// they've all gone away
+async fn next_desktop(desktop: &mut Option<DesktopDevices>) -> Vec<StarboardInput> {
+    if let Some(devices) = desktop
+        && let Some(inputs) = devices.next_inputs().await
+    {
+        return inputs;
+    }
+    std::future::pending().await
+}
+
+// Sends `raw` to `dest`, ignoring connection refused errors
+async fn send_to(sock: &UdpSocket, raw: &[u8], dest: SocketAddr) -> Result<()> {
+    if let Err(e) = sock.send_to(raw, dest).await {
+        err_check_connection_refused(e)?;
+    }
+    Ok(())
+}
+
+// Replays force feedback on the physical controller. A controller that can't rumble isn't fatal.
+fn play_feedback(player: &mut RumblePlayer, device: &mut Device, feedback: ForceFeedback) {
+    if let Err(e) = player.apply(device, feedback) {
+        printdbg!("Couldn't play force feedback: {}", e);
+    }
+}
+
+// Sends `request` to every address in `dests` until a `T` comes back, from `expected` if given,
+// giving up after `attempts` tries.
+// Returns the reply along with the address it came from.
+async fn exchange<T>(
+    sock: &UdpSocket,
+    request: &[u8],
+    dests: &[SocketAddr],
+    expected: Option<SocketAddr>,
+    attempts: u32,
+) -> Result<(T, SocketAddr)>
+where
+    T: Message,
+{
+    let mut buf: [u8; 256] = [0; 256];
+    for _ in 0..attempts {
+        for dest in dests {
+            sock.send_to(request, dest).await?;
+        }
+        let reply = timeout(PAIRING_TIMEOUT, async {
+            loop {
+                let (len, addr) = sock.recv_from(&mut buf).await?;
+                if expected.is_some_and(|expected| expected != addr) {
+                    continue;
+                }
+                if let Ok(packet) = deserialize::<T>(&buf[..len]) {
+                    return anyhow::Ok((packet, addr));
+                }
+            }
+        })
+        .await;
+        if let Ok(reply) = reply {
+            return reply;
+        }
+    }
+    bail!("The server stopped responding; make sure its pairing page is open")
+}
+
+// Prints `message` and waits for the user to enter a line
+async fn prompt(message: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        print!("{message}");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        Ok(line)
+    })
+    .await?
+}
+
+// Lists `servers` and asks the user which one to send input to
+async fn pick_server(servers: Vec<DiscoveredServer>) -> Result<DiscoveredServer> {
+    println!("Found several servers:");
+    for (i, server) in servers.iter().enumerate() {
+        println!("  {}. {server}", i + 1);
+    }
+    loop {
+        let entered = prompt(format!("Choose a server [1-{}]: ", servers.len())).await?;
+        match entered.trim().parse::<usize>() {
+            Ok(choice) if (1..=servers.len()).contains(&choice) => return Ok(servers[choice - 1]),
+            _ => println!("'{}' isn't one of the servers listed", entered.trim()),
+        }
+    }
+}
+
+// Tells the chosen server the client is still there. Its announcements keep coming back, but the
+// server has already been chosen, so they're discarded.
+async fn announce_presence(
+    id: u64,
+    name: StarboardString,
+    key: Option<PairingKey>,
+    sock: UdpSocket,
+    dest: SocketAddr,
+) -> Result<()> {
+    printdbg!("Device Search Socket sending to {}.", dest);
     let mut packet = BroadcastPacket::new(id, name)?;
+    let mut ticks = interval(PRESENCE_INTERVAL);
+    let mut buf: [u8; 256] = [0; 256];
     loop {
-        // The packet only needs to be created once, but it needs to be updated and serialized on
-        // every loop to keep the timestamp up-to-date
-        packet.update();
-        let packet_raw = serialize(packet)?;
-        let res = socket.send(&packet_raw).await;
-        if let Err(e) = res {
-            err_check_connection_refused(e)?;
-        }
-        printdbg!("Device Search Packet Sent.");
-        let _ = sleep(Duration::from_secs(5)).await;
+        tokio::select! {
+            _ = ticks.tick() => {
+                // The packet only needs to be created once, but it needs to be updated and
+                // serialized on every loop to keep the timestamp up-to-date
+                packet.update();
+                let packet_raw = encode_packet(id, key.as_ref(), &packet)?;
+                if let Err(e) = sock.send_to(&packet_raw, dest).await {
+                    err_check_connection_refused(e)?;
+                }
+                printdbg!("Device Search Packet Sent.");
+            }
+            res = sock.recv_from(&mut buf) => {
+                res?;
+            }
+        }
     }
 }
 
@@ -1,30 +1,240 @@
-use anyhow::Result;
-use bincode::{Decode, Encode, config::Configuration, decode_from_slice, encode_to_vec};
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Result, bail};
+use bincode::{
+    Decode, Encode,
+    config::{Configuration, Limit, LittleEndian, Varint},
+    decode_from_slice, encode_to_vec,
+};
 
 use crate::string::StarboardString;
-use chrono::{DateTime, Local};
 
 static BINCODE_CONFIG: Configuration = bincode::config::standard();
 
-// Return a formatted address (i.e. 255.255.255.255:8080) or a specified default
-pub fn format_addr(ip: [u8; 4], port: u16) -> String {
-    format!("{}.{}.{}.{}:{}", ip[0], ip[1], ip[2], ip[3], port)
+// No payload can be longer than its length field allows. Decoding is held to that, since otherwise
+// a forged length prefix on a `Vec` would have bincode allocate however much memory it claims.
+const DECODE_LIMIT: usize = u16::MAX as usize;
+static DECODE_CONFIG: Configuration<LittleEndian, Varint, Limit<DECODE_LIMIT>> =
+    bincode::config::standard().with_limit::<DECODE_LIMIT>();
+
+// Every Starboard datagram begins with these bytes so that foreign traffic can be told apart from
+// our own before any decoding is attempted
+pub const MAGIC: [u8; 4] = *b"STBD";
+
+// Must be bumped whenever the wire format of any message changes, so that mismatched builds reject
+// each other's traffic instead of misinterpreting it
+pub const PROTOCOL_VERSION: u16 = 13;
+
+// magic (4) + version (2) + kind (1) + payload length (2) + checksum (4)
+pub const HEADER_LEN: usize = 13;
+
+// Identifies which message a datagram's payload holds
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    Input = 1,
+    Broadcast = 2,
+    Delta = 3,
+    Authenticated = 4,
+    PairRequest = 5,
+    PairCommit = 6,
+    PairNonce = 7,
+    PairReveal = 8,
+    PairConfirm = 9,
+    SessionInit = 10,
+    SessionAccept = 11,
+    SessionExpired = 12,
+    Sealed = 13,
+    Announcement = 14,
+    ForceFeedback = 15,
+    Disconnect = 16,
+    Ping = 17,
+    Pong = 18,
+    Hello = 19,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => Self::Input,
+            2 => Self::Broadcast,
+            3 => Self::Delta,
+            4 => Self::Authenticated,
+            5 => Self::PairRequest,
+            6 => Self::PairCommit,
+            7 => Self::PairNonce,
+            8 => Self::PairReveal,
+            9 => Self::PairConfirm,
+            10 => Self::SessionInit,
+            11 => Self::SessionAccept,
+            12 => Self::SessionExpired,
+            13 => Self::Sealed,
+            14 => Self::Announcement,
+            15 => Self::ForceFeedback,
+            16 => Self::Disconnect,
+            17 => Self::Ping,
+            18 => Self::Pong,
+            19 => Self::Hello,
+            _ => bail!("Unknown message kind '{value}'"),
+        })
+    }
+}
+
+// Implemented by every struct that can be sent over the wire, tying it to its `MessageKind`
+pub trait Message: Encode + Decode<()> {
+    const KIND: MessageKind;
+}
+
+// The envelope that precedes the payload of every datagram
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u16,
+    pub kind: MessageKind,
+    pub length: u16,
+    pub checksum: u32,
+}
+
+impl FrameHeader {
+    // Parses and validates the header at the start of `raw`. Anything that isn't a well-formed
+    // frame from a build speaking the same protocol version is rejected.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        if raw.len() < HEADER_LEN {
+            bail!(
+                "Datagram of {} bytes is too short to hold a Starboard header",
+                raw.len()
+            );
+        }
+        if raw[0..4] != MAGIC {
+            bail!(
+                "Datagram does not start with the Starboard magic bytes; ignoring foreign traffic"
+            );
+        }
+        let version = u16::from_le_bytes([raw[4], raw[5]]);
+        if version != PROTOCOL_VERSION {
+            bail!(
+                "Datagram uses protocol version {version}, but this build speaks version \
+                 {PROTOCOL_VERSION}; make sure the client and server are running the same release"
+            );
+        }
+        let kind = MessageKind::try_from(raw[6])?;
+        let length = u16::from_le_bytes([raw[7], raw[8]]);
+        let checksum = u32::from_le_bytes([raw[9], raw[10], raw[11], raw[12]]);
+        if raw.len() - HEADER_LEN != length as usize {
+            bail!(
+                "Datagram declares a {length} byte payload but carries {} bytes",
+                raw.len() - HEADER_LEN
+            );
+        }
+        if checksum != frame_checksum(&raw[4..9], &raw[HEADER_LEN..]) {
+            bail!("Datagram checksum mismatch; the payload is corrupt");
+        }
+        Ok(Self {
+            version,
+            kind,
+            length,
+            checksum,
+        })
+    }
+}
+
+// CRC32 over everything in the frame but the magic bytes and the checksum itself
+fn frame_checksum(header_fields: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header_fields);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+// Return a formatted address (i.e. 192.168.1.10:8080 or [fe80::1%2]:8080). IPv4-mapped addresses
+// are shown as plain IPv4.
+pub fn format_addr(addr: SocketAddr) -> String {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.into(), addr.port()).to_string(),
+        IpAddr::V6(_) => addr.to_string(),
+    }
 }
 
-// Deserialize binary data into a StarboardInputPacket
-pub fn deserialize<T>(raw: Vec<u8>) -> Result<T>
+// Returns the kind of message held in `raw` without decoding its payload
+pub fn peek_kind(raw: &[u8]) -> Result<MessageKind> {
+    Ok(FrameHeader::parse(raw)?.kind)
+}
+
+// Validate a framed datagram and deserialize its payload into a `T`
+pub fn deserialize<T>(raw: &[u8]) -> Result<T>
 where
-    T: Decode<()>,
+    T: Message,
 {
-    Ok(decode_from_slice::<T, Configuration>(raw.as_slice(), BINCODE_CONFIG).map(|tup| tup.0)?)
+    let header = FrameHeader::parse(raw)?;
+    if header.kind != T::KIND {
+        bail!(
+            "Expected a {:?} message but received a {:?} message",
+            T::KIND,
+            header.kind
+        );
+    }
+    let payload = &raw[HEADER_LEN..];
+    let (packet, read) = decode_from_slice(payload, DECODE_CONFIG)?;
+    if read != payload.len() {
+        bail!(
+            "{:?} message has {} trailing bytes after its payload",
+            T::KIND,
+            payload.len() - read
+        );
+    }
+    Ok(packet)
 }
 
-// Serialize a packet into binary data
-pub fn serialize<T>(packet: T) -> Result<Vec<u8>>
+// Serialize a packet into a framed datagram
+pub fn serialize<T>(packet: &T) -> Result<Vec<u8>>
 where
-    T: Encode,
+    T: Message,
 {
-    Ok(encode_to_vec::<T, Configuration>(packet, BINCODE_CONFIG)?)
+    let payload = encode_to_vec::<&T, Configuration>(packet, BINCODE_CONFIG)?;
+    frame(T::KIND, &payload)
+}
+
+// Wraps an encoded payload in a frame of kind `kind`
+pub fn frame(kind: MessageKind, payload: &[u8]) -> Result<Vec<u8>> {
+    let length: u16 = match payload.len().try_into() {
+        Ok(length) => length,
+        Err(_) => bail!(
+            "{:?} message payload of {} bytes does not fit in a datagram",
+            kind,
+            payload.len()
+        ),
+    };
+    let mut raw = Vec::with_capacity(HEADER_LEN + payload.len());
+    raw.extend_from_slice(&MAGIC);
+    raw.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    raw.push(kind as u8);
+    raw.extend_from_slice(&length.to_le_bytes());
+    let checksum = frame_checksum(&raw[4..9], payload);
+    raw.extend_from_slice(&checksum.to_le_bytes());
+    raw.extend_from_slice(payload);
+    Ok(raw)
+}
+
+// Sent by a client on the serial port when it shuts down, so that the server can release whatever
+// the controller was holding without waiting for it to time out
+#[derive(Copy, Clone, Decode, Encode)]
+pub struct DisconnectPacket {
+    id: u64,
+}
+
+impl Message for DisconnectPacket {
+    const KIND: MessageKind = MessageKind::Disconnect;
+}
+
+impl DisconnectPacket {
+    pub fn new(id: u64) -> Self {
+        Self { id }
+    }
+
+    pub fn id(&self) -> &u64 {
+        &self.id
+    }
 }
 
 // Packet for a client to broadcast its presence on the network
@@ -35,6 +245,10 @@ pub struct BroadcastPacket {
     sent_at: i64,
 }
 
+impl Message for BroadcastPacket {
+    const KIND: MessageKind = MessageKind::Broadcast;
+}
+
 impl BroadcastPacket {
     pub fn new<T>(id: u64, name: T) -> Result<Self>
     where
@@ -63,13 +277,4 @@ impl BroadcastPacket {
     pub fn sent_at(&self) -> &i64 {
         &self.sent_at
     }
-
-    // Returns the number of milliseconds it's been since the packet was sent
-    pub fn latency(&self) -> i64 {
-        // Safety: `self.sent_at()` is initialized via. `Local::now().timestamp()`, so it's
-        // guaranteed valid
-        let sent_at = DateTime::from_timestamp_millis(self.sent_at).unwrap();
-        let delta = Local::now().signed_duration_since(sent_at);
-        delta.num_milliseconds()
-    }
 }
@@ -2,15 +2,25 @@
 // will throttle performance to check if a certain `debug` flag is enabled every time you want to
 // print debug information. This macro will check at compile time, and only at a `println!` call if
 // the `debug` feature is enabled.
+// When the feature is disabled the arguments are still referenced (but never evaluated) so that
+// values which only exist to be printed don't trigger unused variable warnings.
 #[macro_export]
 macro_rules! printdbg {
     ($base:expr, $($args:tt), *) => {
         #[cfg(feature = "debug")]
         println!($base, $($args), *);
+        #[cfg(not(feature = "debug"))]
+        if false {
+            $(let _ = &$args;)*
+        }
     };
     ($base:expr, $($args:expr), *) => {
         #[cfg(feature = "debug")]
         println!($base, $($args), *);
+        #[cfg(not(feature = "debug"))]
+        if false {
+            $(let _ = &$args;)*
+        }
     };
     ($base:expr) => {
         #[cfg(feature = "debug")]
@@ -0,0 +1,319 @@
+use anyhow::{Result, bail};
+use evdev::{
+    AttributeSet, Device, EventType, InputEvent, KeyCode, RelativeAxisCode, enumerate,
+    uinput::VirtualDevice,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    bitmask::Bitmask,
+    capabilities::{DeviceCapabilities, KEY_CODES},
+    input::StarboardInput,
+    motion::phys,
+    printdbg,
+};
+
+// Keyboards and mice plugged into the controller, like a Bluetooth keyboard paired with a Steam
+// Deck, can be forwarded along with it. Their keys travel like buttons, in deltas and keyframes,
+// while pointer motion and scrolling only travel in deltas. The server replays them on a virtual
+// keyboard and mouse that sit alongside the controller's virtual joystick.
+
+// How many events from the forwarded devices can wait to be sent before the devices are held up
+const EVENT_QUEUE_LEN: usize = 256;
+
+// Returns true if `key` is a mouse button rather than a keyboard key
+fn is_mouse_button(key: KeyCode) -> bool {
+    (KeyCode::BTN_LEFT.0..=KeyCode::BTN_TASK.0).contains(&key.0)
+}
+
+// Returns true if `key` belongs on a keyboard. Joystick and gamepad buttons sit between the mouse
+// buttons and the rest of the keys, and are left to the controller.
+fn is_keyboard_key(key: KeyCode) -> bool {
+    key.0 < KeyCode::BTN_0.0 || key.0 >= KeyCode::KEY_OK.0
+}
+
+// Opens the device at `path`, or otherwise the device named `device`
+fn open_device(device: &str) -> Result<Device> {
+    if device.starts_with('/') {
+        return Ok(Device::open(device)?);
+    }
+    match enumerate().find(|(_, found)| found.name() == Some(device)) {
+        Some((_, found)) => Ok(found),
+        None => bail!("Couldn't find an input device named `{device}`"),
+    }
+}
+
+// Follows the keys held down on the forwarded devices, and how far they've moved the pointer and
+// scrolled, through the events they report
+pub struct DesktopTracker {
+    held: Bitmask,
+    changes: Vec<StarboardInput>, // Keys pressed or released since the last SYN_REPORT
+    motion: (i32, i32),
+    wheel: (i32, i32),
+}
+
+impl DesktopTracker {
+    pub fn new() -> Self {
+        Self {
+            held: Bitmask::new(KEY_CODES.into()),
+            changes: Vec::new(),
+            motion: (0, 0),
+            wheel: (0, 0),
+        }
+    }
+
+    // Returns true if `key` is held down
+    pub fn is_held(&self, key: KeyCode) -> bool {
+        self.held.read_bit(key.0.into())
+    }
+
+    // Records `event`, returning what changed once the device has reported a whole batch of events
+    pub fn update(&mut self, event: &InputEvent) -> Option<Vec<StarboardInput>> {
+        match event.event_type() {
+            // Key repeats are reported with a value of 2, but the key was already held
+            EventType::KEY if event.code() < KEY_CODES => {
+                let pressed = event.value() != 0;
+                if self.is_held(KeyCode(event.code())) != pressed {
+                    // Safety of using `unwrap()`: the code was just checked to be a key code
+                    self.held.write_bit(event.code().into(), pressed).unwrap();
+                    self.changes.push(StarboardInput::Key {
+                        code: event.code(),
+                        value: pressed,
+                    });
+                }
+            }
+            EventType::RELATIVE => match RelativeAxisCode(event.code()) {
+                RelativeAxisCode::REL_X => self.motion.0 += event.value(),
+                RelativeAxisCode::REL_Y => self.motion.1 += event.value(),
+                RelativeAxisCode::REL_WHEEL => self.wheel.0 += event.value(),
+                RelativeAxisCode::REL_HWHEEL => self.wheel.1 += event.value(),
+                _ => {}
+            },
+            EventType::SYNCHRONIZATION => {
+                let mut inputs = std::mem::take(&mut self.changes);
+                let (x, y) = std::mem::take(&mut self.motion);
+                if (x, y) != (0, 0) {
+                    inputs.push(StarboardInput::Rel { x, y });
+                }
+                let (vertical, horizontal) = std::mem::take(&mut self.wheel);
+                if (vertical, horizontal) != (0, 0) {
+                    inputs.push(StarboardInput::Wheel {
+                        vertical,
+                        horizontal,
+                    });
+                }
+                return (!inputs.is_empty()).then_some(inputs);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // Lets go of every key held down, returning the releases
+    pub fn release_all(&mut self) -> Vec<StarboardInput> {
+        let released = self
+            .held
+            .ones()
+            .map(|code| StarboardInput::Key {
+                code: code as u16,
+                value: false,
+            })
+            .collect();
+        self.held = Bitmask::new(KEY_CODES.into());
+        released
+    }
+}
+
+// The keyboards and mice the client forwards
+pub struct DesktopDevices {
+    capabilities: DeviceCapabilities, // The keys of every device put together
+    events: mpsc::Receiver<InputEvent>,
+    tracker: DesktopTracker,
+    closed: bool, // Whether every device has gone away
+}
+
+impl DesktopDevices {
+    // Starts reading from each of `devices`, given by path or by name. Grabbed devices only send
+    // their input to the server, rather than to this device as well.
+    pub fn open(devices: &[String], grab: bool) -> Result<Option<Self>> {
+        if devices.is_empty() {
+            return Ok(None);
+        }
+        let (tx, events) = mpsc::channel(EVENT_QUEUE_LEN);
+        let mut keys = AttributeSet::<KeyCode>::new();
+        for device in devices {
+            let mut device = open_device(device)?;
+            printdbg!(
+                "Forwarding input from: `{}`",
+                (device.name().unwrap_or_default())
+            );
+            if let Some(supported) = device.supported_keys() {
+                supported.iter().for_each(|key| keys.insert(key));
+            }
+            if grab {
+                device.grab()?;
+            }
+            let mut stream = device.into_event_stream()?;
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while let Ok(event) = stream.next_event().await {
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Some(Self {
+            capabilities: DeviceCapabilities::new(keys.iter(), []),
+            events,
+            tracker: DesktopTracker::new(),
+            closed: false,
+        }))
+    }
+
+    // Returns the keys of the forwarded devices, which the server builds its keyboard and mouse
+    // from
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
+
+    // Returns whether each key of the forwarded devices is held down, for keyframes
+    pub fn key_inputs(&self) -> Vec<StarboardInput> {
+        self.capabilities
+            .keys()
+            .map(|key| StarboardInput::Key {
+                code: key.0,
+                value: self.tracker.is_held(key),
+            })
+            .collect()
+    }
+
+    // Waits for the forwarded devices to report a batch of changes. Once every device has gone
+    // away, whatever was held is let go, and `None` is returned from then on.
+    pub async fn next_inputs(&mut self) -> Option<Vec<StarboardInput>> {
+        if self.closed {
+            return None;
+        }
+        loop {
+            let Some(event) = self.events.recv().await else {
+                self.closed = true;
+                return Some(self.tracker.release_all());
+            };
+            if let Some(inputs) = self.tracker.update(&event) {
+                return Some(inputs);
+            }
+        }
+    }
+}
+
+fn key_event(key: KeyCode, pressed: bool) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, key.0, pressed.into())
+}
+
+fn rel_event(axis: RelativeAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE.0, axis.0, value)
+}
+
+// The keyboard and mouse that go with a controller's virtual joystick
+pub struct VirtualDesktop {
+    keyboard: Option<VirtualDevice>, // Only there if the client forwards keyboard keys
+    mouse: Option<VirtualDevice>,    // Only there if the client forwards mouse buttons
+    held: Bitmask,
+}
+
+impl VirtualDesktop {
+    // Builds a keyboard with each keyboard key in `capabilities`, and a mouse with each mouse
+    // button, named after the joystick they go with
+    pub fn new(name: &str, capabilities: &DeviceCapabilities) -> Result<Self> {
+        let phys = phys(name)?;
+        let keyboard_keys: AttributeSet<KeyCode> = capabilities
+            .keys()
+            .filter(|key| is_keyboard_key(*key))
+            .collect();
+        let mouse_buttons: AttributeSet<KeyCode> = capabilities
+            .keys()
+            .filter(|key| is_mouse_button(*key))
+            .collect();
+        let keyboard = match keyboard_keys.iter().next() {
+            Some(_) => Some(
+                VirtualDevice::builder()?
+                    .name(&format!("{name} Keyboard"))
+                    .with_phys(&phys)?
+                    .with_keys(&keyboard_keys)?
+                    .build()?,
+            ),
+            None => None,
+        };
+        let axes: AttributeSet<RelativeAxisCode> = [
+            RelativeAxisCode::REL_X,
+            RelativeAxisCode::REL_Y,
+            RelativeAxisCode::REL_WHEEL,
+            RelativeAxisCode::REL_HWHEEL,
+        ]
+        .into_iter()
+        .collect();
+        let mouse = match mouse_buttons.iter().next() {
+            Some(_) => Some(
+                VirtualDevice::builder()?
+                    .name(&format!("{name} Mouse"))
+                    .with_phys(&phys)?
+                    .with_keys(&mouse_buttons)?
+                    .with_relative_axes(&axes)?
+                    .build()?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            keyboard,
+            mouse,
+            held: Bitmask::new(KEY_CODES.into()),
+        })
+    }
+
+    // Presses or releases `key` on whichever device has it
+    pub fn key(&mut self, key: KeyCode, pressed: bool) -> Result<()> {
+        // Keyframes repeat every key, so only changes are passed on
+        if key.0 >= KEY_CODES || self.held.read_bit(key.0.into()) == pressed {
+            return Ok(());
+        }
+        let device = match is_mouse_button(key) {
+            true => &mut self.mouse,
+            false => &mut self.keyboard,
+        };
+        if let Some(device) = device {
+            device.emit(&[key_event(key, pressed)])?;
+        }
+        self.held.write_bit(key.0.into(), pressed)
+    }
+
+    // Moves the pointer `x` counts right and `y` counts down
+    pub fn move_by(&mut self, x: i32, y: i32) -> Result<()> {
+        if let Some(mouse) = &mut self.mouse {
+            mouse.emit(&[
+                rel_event(RelativeAxisCode::REL_X, x),
+                rel_event(RelativeAxisCode::REL_Y, y),
+            ])?;
+        }
+        Ok(())
+    }
+
+    // Scrolls `vertical` detents up and `horizontal` detents right
+    pub fn scroll(&mut self, vertical: i32, horizontal: i32) -> Result<()> {
+        if let Some(mouse) = &mut self.mouse {
+            mouse.emit(&[
+                rel_event(RelativeAxisCode::REL_WHEEL, vertical),
+                rel_event(RelativeAxisCode::REL_HWHEEL, horizontal),
+            ])?;
+        }
+        Ok(())
+    }
+
+    // Lets go of every key and button held down
+    pub fn neutralize(&mut self) -> Result<()> {
+        let held: Vec<u32> = self.held.ones().collect();
+        for code in held {
+            self.key(KeyCode(code as u16), false)?;
+        }
+        Ok(())
+    }
+}
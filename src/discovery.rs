@@ -0,0 +1,106 @@
+use core::fmt::{self, Display, Formatter};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+
+use crate::{
+    datagram::{Message, MessageKind, format_addr},
+    string::StarboardString,
+};
+
+// Servers answer every presence broadcast with a `ServerAnnouncement`, so clients learn which
+// servers are on the network and can send their input to just one of them. A server's host is
+// the address its announcements come from.
+
+// Sent by a server to tell a client where to send its input
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct ServerAnnouncement {
+    pub id: u64, // Tells servers apart, since several servers can have the same name
+    pub name: StarboardString,
+    pub serial_port: u16,
+    pub device_search_port: u16,
+}
+
+impl Message for ServerAnnouncement {
+    const KIND: MessageKind = MessageKind::Announcement;
+}
+
+// A server a client has heard from
+#[derive(Debug, Copy, Clone)]
+pub struct DiscoveredServer {
+    pub id: u64,
+    pub name: StarboardString,
+    pub serial_addr: SocketAddr,
+    pub device_search_addr: SocketAddr,
+    last_seen: Instant,
+}
+
+impl Display for DiscoveredServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, ID {})",
+            self.name,
+            format_addr(self.serial_addr),
+            self.id
+        )
+    }
+}
+
+// The servers a client has heard from recently, keyed by the address they announced themselves from
+pub struct ServerList {
+    servers: HashMap<SocketAddr, DiscoveredServer>,
+    timeout: Duration, // How long a server is listed after its last announcement
+}
+
+impl ServerList {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            servers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    // Records an announcement received from `addr` and forgets servers that have gone quiet
+    pub fn update(&mut self, addr: SocketAddr, announcement: ServerAnnouncement) {
+        // The ports are swapped into the address the announcement came from, rather than building
+        // a new address from its IP, so that link-local addresses keep their zone
+        let mut serial_addr = addr;
+        serial_addr.set_port(announcement.serial_port);
+        let mut device_search_addr = addr;
+        device_search_addr.set_port(announcement.device_search_port);
+        let server = DiscoveredServer {
+            id: announcement.id,
+            name: announcement.name,
+            serial_addr,
+            device_search_addr,
+            last_seen: Instant::now(),
+        };
+        self.servers.insert(addr, server);
+        let timeout = self.timeout;
+        self.servers
+            .retain(|_, server| server.last_seen.elapsed() <= timeout);
+    }
+
+    // Returns every server that has announced itself within the timeout, sorted by name so that
+    // they're always listed in the same order
+    pub fn live(&self) -> Vec<DiscoveredServer> {
+        let mut servers: Vec<DiscoveredServer> = self
+            .servers
+            .values()
+            .filter(|server| server.last_seen.elapsed() <= self.timeout)
+            .copied()
+            .collect();
+        servers.sort_by_key(|server| (server.name.to_string(), server.serial_addr));
+        servers
+    }
+
+    // Returns the live server with the ID `id`, if there is one
+    pub fn find(&self, id: u64) -> Option<DiscoveredServer> {
+        self.live().into_iter().find(|server| server.id == id)
+    }
+}
@@ -1,5 +1,6 @@
 use crate::bitmask::Bitmask;
-use crate::evdev_sb::{SUPPORTED_AXES, SUPPORTED_BUTTONS, VirtualJoystick, VirtualJoystickBuilder};
+use crate::evdev_sb::{VirtualJoystick, VirtualJoystickBuilder};
+use crate::supported_actions::{AXIS_COUNT, BUTTON_COUNT};
 use anyhow::Result;
 use std::time::Duration;
 
@@ -11,8 +12,8 @@ pub struct DummySteamDeck {
 
 impl DummySteamDeck {
     pub fn new() -> Result<Self> {
-        let buttons: Bitmask = SUPPORTED_BUTTONS.iter().map(|button| *button).collect();
-        let axes: Bitmask = SUPPORTED_AXES.iter().map(|axis| *axis).collect();
+        let buttons = Bitmask::full(BUTTON_COUNT);
+        let axes = Bitmask::full(AXIS_COUNT);
         Ok(Self {
             inner: VirtualJoystickBuilder::new()?
                 .enable_buttons_bitmask(buttons)?
@@ -35,8 +36,14 @@ impl DummySteamDeck {
             let angle: f64 = counter.into();
             let x = (i16::MAX as f64 * f64::cos(angle * SPEED)) as i16;
             let y = (i16::MAX as f64 * f64::sin(angle * SPEED)) as i16;
-            let x_input = StarboardInput::Axis { id: 0, value: x };
-            let y_input = StarboardInput::Axis { id: 1, value: y };
+            let x_input = StarboardInput::Axis {
+                id: 0,
+                value: x.into(),
+            };
+            let y_input = StarboardInput::Axis {
+                id: 1,
+                value: y.into(),
+            };
             self.inner.send_input(x_input);
             self.inner.send_input(y_input);
             self.inner.sync();
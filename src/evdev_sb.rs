@@ -1,100 +1,595 @@
 use core::{convert::TryInto, iter::IntoIterator};
+use std::{collections::HashMap, io::ErrorKind, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use evdev::{
-    AbsoluteAxisCode, AttributeSet, Device, InputEvent, KeyCode, enumerate,
-    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AbsoluteAxisCode, AttributeSet, Device, EventStream, EventSummary, EventType, FFEffectCode,
+    InputEvent, KeyCode, UInputCode, UinputAbsSetup, enumerate,
+    uinput::{VirtualDevice, VirtualDeviceBuilder, VirtualEventStream},
 };
 use heapless::index_map::FnvIndexMap;
 
 use crate::{
     bitmask::Bitmask,
-    input::{FromByte, FromID, IntoID, StarboardInput},
+    capabilities::{AxisRange, DeviceCapabilities},
+    desktop::VirtualDesktop,
+    force_feedback::{FF_EFFECTS_MAX, ForceFeedback, Rumble},
+    gyro::{GyroAim, GyroMode, GyroSettings, STICK_AXES, deflect},
+    input::{FromID, IntoID, StarboardInput},
+    motion::{VirtualMotion, phys},
     printdbg,
+    remap::{Profile, Remapper},
     string::StarboardString,
-    supported_actions::{SUPPORTED_AXES, SUPPORTED_BUTTONS},
+    supported_actions::{
+        AXIS_COUNT, AxisDescriptor, BUTTON_COUNT, SUPPORTED_BUTTONS, TrackpadDescriptor,
+        axis_by_code, axis_by_id, trackpad_by_axis, trackpad_by_click,
+    },
+    trackpad::{TrackpadMode, VirtualMouse, VirtualTrackpads, touch_at, trackpads_on},
 };
 
+// The errno a virtual joystick answers with when a game uploads an effect it can't relay
+const EINVAL: i32 = 22;
+
 // Wrapper for Virtual Joysticks using uinput instead of SDL3
 pub struct VirtualJoystick {
-    raw: VirtualDevice,
+    // The device is kept as a stream because that puts it in non-blocking mode, which lets force
+    // feedback be polled for without waiting on it
+    raw: VirtualEventStream,
+    effects: HashMap<i16, Rumble>, // Rumble effects uploaded by games, by effect ID
+    keys: Vec<KeyCode>,
+    axes: Vec<(AbsoluteAxisCode, AxisRange)>, // Every axis, along with the range it declares
+    // The supported buttons and axes among `keys` and `axes`, by Starboard ID
+    button_mask: Bitmask,
+    axis_mask: Bitmask,
+    // The range each of the controller's axes reports in, when the controller has said. Values
+    // are rescaled from it into the range the joystick declares.
+    sources: HashMap<AbsoluteAxisCode, AxisRange>,
+    // How far a trigger has to be pulled to press its button, if triggers press their buttons
+    trigger_threshold: Option<f64>,
+    // Whether each trigger axis is pulled past the threshold. Several axes can press the same
+    // button, which is held while any of them is pulled.
+    trigger_pulls: HashMap<AbsoluteAxisCode, bool>,
+    name: String,
+    trackpad_mode: TrackpadMode,
+    // The devices the trackpads drive instead of the joystick's hats, unless they drive the hats
+    trackpads: Option<VirtualTrackpads>,
+    motion: Option<VirtualMotion>, // Where motion goes, if the controller has motion sensors
+    gyro_mode: GyroMode,
+    gyro: GyroAim,
+    gyro_mouse: Option<VirtualMouse>, // The pointer the gyro moves, when it aims with the mouse
+    gyro_stick: (f64, f64),           // How far the gyro pushes the right stick, from -1 to 1
+    right_stick: [Option<i32>; 2], // Where the controller holds the right stick, once it has said
+    desktop: Option<VirtualDesktop>, // Where forwarded keyboards and mice go, if there are any
+    remapper: Option<Remapper>, // How the controller's buttons and axes are remapped, if they are
+}
+
+// Returns the range the controller's axis reports in, going by `sources`, or the range Starboard
+// expects if the controller hasn't said
+fn source_range(
+    sources: &HashMap<AbsoluteAxisCode, AxisRange>,
+    axis: AbsoluteAxisCode,
+) -> AxisRange {
+    match sources.get(&axis) {
+        Some(source) => *source,
+        // Safety of using `unwrap()`: profiles only map supported axes
+        None => axis_by_code(axis).unwrap().range,
+    }
 }
 
 impl VirtualJoystick {
-    pub fn steam_deck_template(name: StarboardString) -> Result<Self> {
-        let buttons = SUPPORTED_BUTTONS.keys().map(|button| *button).collect();
-        let axes = SUPPORTED_AXES.keys().map(|axis| *axis).collect();
+    pub fn steam_deck_template(
+        name: StarboardString,
+        trigger_threshold: Option<f64>,
+    ) -> Result<Self> {
+        let buttons = Bitmask::full(BUTTON_COUNT);
+        let axes = Bitmask::full(AXIS_COUNT);
         let name: &str = &(<StarboardString as Into<String>>::into(name));
         Ok(VirtualJoystickBuilder::new()?
             .enable_buttons_bitmask(buttons)?
             .enable_axes_bitmask(axes)?
+            .enable_force_feedback()?
+            .digital_triggers(trigger_threshold)
             .build(name)?)
     }
 
+    // Builds a joystick with exactly the buttons and axes of the controller's device
+    pub fn from_capabilities(
+        name: StarboardString,
+        capabilities: &DeviceCapabilities,
+        trigger_threshold: Option<f64>,
+    ) -> Result<Self> {
+        let name: &str = &(<StarboardString as Into<String>>::into(name));
+        let mut joystick = VirtualJoystickBuilder::new()?
+            .enable_keys(capabilities.keys())?
+            .enable_axes(capabilities.axes())?
+            .enable_force_feedback()?
+            .digital_triggers(trigger_threshold)
+            .build(name)?;
+        joystick.set_source_ranges(capabilities);
+        Ok(joystick)
+    }
+
+    // Returns the buttons the joystick has, by Starboard ID
+    pub fn button_mask(&self) -> &Bitmask {
+        &self.button_mask
+    }
+
+    // Returns the axes the joystick has, by Starboard ID
+    pub fn axis_mask(&self) -> &Bitmask {
+        &self.axis_mask
+    }
+
+    // Records the range each of the controller's axes reports in. A joystick built from the
+    // controller's capabilities declares the same ranges, so its values go through untouched.
+    pub fn set_source_ranges(&mut self, capabilities: &DeviceCapabilities) {
+        self.sources = capabilities.axes().collect();
+    }
+
+    // Gives the joystick a motion device with the axes of the controller's motion sensors, or
+    // takes it away if the controller has none
+    pub fn set_motion(&mut self, capabilities: Option<&DeviceCapabilities>) -> Result<()> {
+        self.gyro.calibrate(capabilities);
+        self.motion = match capabilities {
+            Some(capabilities) => Some(VirtualMotion::new(&self.name, capabilities)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    // Gives the joystick a keyboard and mouse with the keys of the keyboards and mice forwarded
+    // with the controller, or takes them away if none are. Whatever was held on the old ones is
+    // let go first.
+    pub fn set_desktop(&mut self, capabilities: Option<&DeviceCapabilities>) -> Result<()> {
+        if let Some(desktop) = &mut self.desktop {
+            desktop.neutralize()?;
+        }
+        self.desktop = match capabilities {
+            Some(capabilities) => Some(VirtualDesktop::new(&self.name, capabilities)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    // Moves the controller's trackpads onto the devices for `mode`. Whatever they held on the
+    // devices they leave is let go first.
+    pub fn set_trackpad_mode(&mut self, mode: TrackpadMode) -> Result<()> {
+        if mode == self.trackpad_mode {
+            return Ok(());
+        }
+        self.neutralize()?;
+        self.trackpads = VirtualTrackpads::new(mode, &self.name, &self.sources)?;
+        self.trackpad_mode = mode;
+        Ok(())
+    }
+
+    // Aims with the controller's gyro in `mode`, as `settings` say. Whatever the gyro was pushing
+    // the right stick by is let go first.
+    pub fn set_gyro(&mut self, mode: GyroMode, settings: GyroSettings) -> Result<()> {
+        self.release_gyro_stick()?;
+        self.gyro.set_settings(settings);
+        if mode != self.gyro_mode {
+            self.gyro_mouse = match mode {
+                GyroMode::Mouse => Some(VirtualMouse::new(&format!("{} Gyro Mouse", self.name))?),
+                _ => None,
+            };
+            self.gyro_mode = mode;
+        }
+        Ok(())
+    }
+
+    // Remaps the controller's buttons and axes through `profile` from now on, or stops remapping
+    // them. Whatever the old mappings held is let go first.
+    pub fn set_profile(&mut self, profile: Option<Arc<Profile>>) -> Result<()> {
+        self.neutralize()?;
+        self.remapper = profile.map(Remapper::new);
+        Ok(())
+    }
+
+    // Turns the controller's inputs into the inputs its profile maps them to, before they're sent
+    pub fn remap(&mut self, inputs: Vec<StarboardInput>) -> Vec<StarboardInput> {
+        let sources = &self.sources;
+        match &mut self.remapper {
+            Some(remapper) => remapper.remap(inputs, |axis| source_range(sources, axis)),
+            None => inputs,
+        }
+    }
+
     // Sends `input` to your system's input handling
     pub fn send_input(&mut self, input: StarboardInput) -> Result<()> {
-        let event: InputEvent = input.try_into()?;
-        self.raw.emit(&[event])?;
+        let mut events = Vec::with_capacity(2);
+        match input {
+            StarboardInput::Axis { id, value } => {
+                let Some(axis) = axis_by_id(id) else {
+                    bail!("Couldn't convert given Starboard ID '{id}' into `AbsoluteAxisCode`.");
+                };
+                // Trackpads that drive their own devices leave the joystick's hats alone
+                if self.trackpads.is_some() && trackpad_by_axis(axis.code).is_some() {
+                    return Ok(());
+                }
+                let value = self.rescale(axis.code, value);
+                match STICK_AXES.iter().position(|code| *code == axis.code) {
+                    // The gyro pushes the right stick on from wherever the controller holds it
+                    Some(index) if self.gyro_mode == GyroMode::Stick => {
+                        self.right_stick[index] = Some(value);
+                        events.extend(self.gyro_stick_events());
+                    }
+                    Some(index) => {
+                        self.right_stick[index] = Some(value);
+                        events.push(InputEvent::new(EventType::ABSOLUTE.0, axis.code.0, value));
+                    }
+                    None => events.push(InputEvent::new(EventType::ABSOLUTE.0, axis.code.0, value)),
+                }
+                self.pull_trigger(axis, value);
+            }
+            StarboardInput::Button { id, value } => {
+                let key: KeyCode = id.from_id()?;
+                // The ratchet button still reaches the game, as well as starting and stopping the
+                // gyro
+                if self.gyro.press(key, value) && !value {
+                    self.release_gyro_stick()?;
+                }
+                if let Some(trackpads) = &mut self.trackpads
+                    && let Some(pad) = trackpad_by_click(key)
+                {
+                    return trackpads.click(pad.id, value);
+                }
+                // The buttons of triggers follow how far the triggers are pulled instead
+                if self.trigger_threshold.is_some() && self.is_trigger_button(key) {
+                    return Ok(());
+                }
+                events.push(input.try_into()?);
+            }
+            StarboardInput::Touch { id, state } => {
+                if let Some(trackpads) = &mut self.trackpads {
+                    trackpads.touch(id, state)?;
+                }
+                return Ok(());
+            }
+            StarboardInput::Motion { sample } => {
+                if let Some(motion) = &mut self.motion {
+                    motion.send(&sample)?;
+                }
+                match self.gyro_mode {
+                    GyroMode::Off => {}
+                    GyroMode::Mouse => {
+                        if let Some(mouse) = &mut self.gyro_mouse {
+                            let (dx, dy) = self.gyro.mouse_motion(&sample);
+                            mouse.move_by(dx, dy)?;
+                        }
+                    }
+                    GyroMode::Stick => {
+                        self.gyro_stick = self.gyro.stick_deflection(&sample);
+                        events.extend(self.gyro_stick_events());
+                    }
+                }
+                if events.is_empty() {
+                    return Ok(());
+                }
+            }
+            StarboardInput::Key { code, value } => {
+                if let Some(desktop) = &mut self.desktop {
+                    desktop.key(KeyCode(code), value)?;
+                }
+                return Ok(());
+            }
+            StarboardInput::Rel { x, y } => {
+                if let Some(desktop) = &mut self.desktop {
+                    desktop.move_by(x, y)?;
+                }
+                return Ok(());
+            }
+            StarboardInput::Wheel {
+                vertical,
+                horizontal,
+            } => {
+                if let Some(desktop) = &mut self.desktop {
+                    desktop.scroll(vertical, horizontal)?;
+                }
+                return Ok(());
+            }
+        }
+        self.raw.device_mut().emit(&events)?;
         Ok(())
     }
 
-    // Emits a `sync` input to the virtual device
+    // Returns the events that put each axis of the right stick where the controller holds it,
+    // pushed on by the gyro
+    fn gyro_stick_events(&self) -> Vec<InputEvent> {
+        let deflections = [self.gyro_stick.0, self.gyro_stick.1];
+        STICK_AXES
+            .iter()
+            .zip(self.right_stick)
+            .zip(deflections)
+            .filter_map(|((axis, position), deflection)| {
+                let (_, declared) = self.axes.iter().find(|(code, _)| code == axis)?;
+                let position = position.unwrap_or(declared.rest());
+                let value = deflect(declared, position, deflection);
+                Some(InputEvent::new(EventType::ABSOLUTE.0, axis.0, value))
+            })
+            .collect()
+    }
+
+    // Stops the gyro pushing the right stick, leaving it where the controller holds it
+    fn release_gyro_stick(&mut self) -> Result<()> {
+        if self.gyro_stick == (0.0, 0.0) {
+            return Ok(());
+        }
+        self.gyro_stick = (0.0, 0.0);
+        let events = self.gyro_stick_events();
+        self.raw.device_mut().emit(&events)?;
+        Ok(())
+    }
+
+    // Records whether `axis` is pulled past the threshold, if it's a trigger and triggers press
+    // their buttons. `value` is in the range the joystick declares.
+    fn pull_trigger(&mut self, axis: &AxisDescriptor, value: i32) {
+        let (Some(threshold), Some(_)) = (self.trigger_threshold, axis.button) else {
+            return;
+        };
+        if let Some((_, declared)) = self.axes.iter().find(|(code, _)| *code == axis.code) {
+            let pulled = declared.is_past(value, threshold);
+            self.trigger_pulls.insert(axis.code, pulled);
+        }
+    }
+
+    // Returns the events that press or release the button of each trigger. They're worked out once
+    // per report, so that one axis letting go doesn't release a button another axis still holds.
+    fn trigger_events(&self) -> Vec<InputEvent> {
+        if self.trigger_threshold.is_none() {
+            return Vec::new();
+        }
+        let mut buttons: Vec<KeyCode> = self
+            .axes
+            .iter()
+            .filter_map(|(code, _)| axis_by_code(*code)?.button)
+            .collect();
+        buttons.sort_by_key(|button| button.0);
+        buttons.dedup();
+        buttons
+            .into_iter()
+            .map(|button| {
+                let pressed = self.trigger_pulls.iter().any(|(code, pulled)| {
+                    *pulled && axis_by_code(*code).and_then(|axis| axis.button) == Some(button)
+                });
+                InputEvent::new(EventType::KEY.0, button.0, pressed.into())
+            })
+            .collect()
+    }
+
+    // Returns true if `key` is pressed by one of the joystick's triggers
+    fn is_trigger_button(&self, key: KeyCode) -> bool {
+        self.axes
+            .iter()
+            .filter_map(|(code, _)| axis_by_code(*code)?.button)
+            .any(|button| button == key)
+    }
+
+    // Maps a value from the range the controller's axis reports in to the range the joystick
+    // declares. Values are passed through as they are until the controller says what its range is.
+    fn rescale(&self, axis: AbsoluteAxisCode, value: i32) -> i32 {
+        let declared = self.axes.iter().find(|(code, _)| *code == axis);
+        match (self.sources.get(&axis), declared) {
+            (Some(source), Some((_, declared))) => declared.rescale(value, source),
+            _ => value,
+        }
+    }
+
+    // Emits the report's trigger buttons followed by a `sync` input to the virtual device. Buttons
+    // that haven't changed are dropped by the kernel.
     pub fn sync(&mut self) -> Result<()> {
         const EVENT_TYPE: u16 = evdev::EventType::SYNCHRONIZATION.0;
         const EVENT_CODE: u16 = evdev::SynchronizationCode::SYN_REPORT.0;
-        let event = InputEvent::new(EVENT_TYPE, EVENT_CODE, 0);
-        self.raw.emit(&[event])?;
+        let mut events = self.trigger_events();
+        events.push(InputEvent::new(EVENT_TYPE, EVENT_CODE, 0));
+        self.raw.device_mut().emit(&events)?;
         Ok(())
     }
+
+    // Releases every key and returns every axis to rest, so that nothing stays held once the
+    // controller stops sending input. Events that don't change anything are dropped by the kernel,
+    // so only the inputs that were actually held reach the game.
+    pub fn neutralize(&mut self) -> Result<()> {
+        let keys = self
+            .keys
+            .iter()
+            .map(|key| InputEvent::new(EventType::KEY.0, key.0, 0));
+        let axes = self
+            .axes
+            .iter()
+            .map(|(axis, range)| InputEvent::new(EventType::ABSOLUTE.0, axis.0, range.rest()));
+        let events: Vec<InputEvent> = keys.chain(axes).collect();
+        self.raw.device_mut().emit(&events)?;
+        self.trigger_pulls.clear();
+        if let Some(trackpads) = &mut self.trackpads {
+            trackpads.neutralize()?;
+        }
+        if let Some(desktop) = &mut self.desktop {
+            desktop.neutralize()?;
+        }
+        self.gyro.reset();
+        self.gyro_stick = (0.0, 0.0);
+        self.right_stick = [None; 2];
+        self.sync()
+    }
+
+    // Handles the force feedback games have sent to the device since the last call, returning
+    // what should be relayed to the client. Uploads and erases have to be answered here, since the
+    // game is blocked until they are.
+    pub fn poll_force_feedback(&mut self) -> Result<Vec<ForceFeedback>> {
+        let events: Vec<InputEvent> = match self.raw.device_mut().fetch_events() {
+            Ok(events) => events.collect(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut feedback = Vec::new();
+        for event in events {
+            match event.destructure() {
+                EventSummary::UInput(event, UInputCode::UI_FF_UPLOAD, _) => {
+                    let mut upload = self.raw.device_mut().process_ff_upload(event)?;
+                    match Rumble::from_effect(upload.effect()) {
+                        Some(rumble) => {
+                            let effect = upload.effect_id();
+                            self.effects.insert(effect, rumble);
+                            feedback.push(ForceFeedback::Upload { effect, rumble });
+                        }
+                        None => upload.set_retval(-EINVAL),
+                    }
+                }
+                EventSummary::UInput(event, UInputCode::UI_FF_ERASE, _) => {
+                    let erase = self.raw.device_mut().process_ff_erase(event)?;
+                    let effect = erase.effect_id() as i16;
+                    if self.effects.remove(&effect).is_some() {
+                        feedback.push(ForceFeedback::Erase { effect });
+                    }
+                }
+                EventSummary::ForceFeedback(_, FFEffectCode::FF_GAIN, value) => {
+                    feedback.push(ForceFeedback::Gain(value as u16));
+                }
+                EventSummary::ForceFeedback(_, code, count) => {
+                    let effect = code.0 as i16;
+                    if let Some(rumble) = self.effects.get(&effect) {
+                        feedback.push(ForceFeedback::Play {
+                            effect,
+                            rumble: *rumble,
+                            count,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(feedback)
+    }
 }
 
 // Builder struct for VirtualJoystick
 pub struct VirtualJoystickBuilder<'a> {
     raw: VirtualDeviceBuilder<'a>,
+    keys: Vec<KeyCode>,
+    axes: Vec<(AbsoluteAxisCode, AxisRange)>,
+    trigger_threshold: Option<f64>,
 }
 
 impl VirtualJoystickBuilder<'_> {
     pub fn new() -> Result<Self> {
         Ok(Self {
             raw: VirtualDevice::builder()?,
+            keys: Vec::new(),
+            axes: Vec::new(),
+            trigger_threshold: None,
         })
     }
 
     // Build a VirtualJoystick
     // A name is required to build a joystick, so it's passed in here to ensure that it's set
     pub fn build(self, name: &str) -> Result<VirtualJoystick> {
-        let raw = self.raw.name(name);
+        let mut builder = self;
+        // Triggers press their buttons even if the controller doesn't have them
+        if builder.trigger_threshold.is_some() {
+            let missing: AttributeSet<KeyCode> = builder
+                .axes
+                .iter()
+                .filter_map(|(code, _)| axis_by_code(*code)?.button)
+                .filter(|button| !builder.keys.contains(button))
+                .collect();
+            builder = builder.enable_keys(missing.iter())?;
+        }
+        // Shared with the joystick's motion device, so that games know they go together
+        let phys = phys(name)?;
+        let raw = builder.raw.name(name).with_phys(&phys)?;
         Ok(VirtualJoystick {
-            raw: { raw.build()? },
+            raw: raw.build()?.into_event_stream()?,
+            effects: HashMap::new(),
+            button_mask: builder.keys.iter().copied().collect(),
+            axis_mask: builder.axes.iter().map(|(axis, _)| *axis).collect(),
+            keys: builder.keys,
+            axes: builder.axes,
+            sources: HashMap::new(),
+            trigger_threshold: builder.trigger_threshold,
+            trigger_pulls: HashMap::new(),
+            name: name.to_string(),
+            trackpad_mode: TrackpadMode::Joystick,
+            trackpads: None,
+            motion: None,
+            gyro_mode: GyroMode::Off,
+            gyro: GyroAim::new(GyroSettings::default()),
+            gyro_mouse: None,
+            gyro_stick: (0.0, 0.0),
+            right_stick: [None; 2],
+            desktop: None,
+            remapper: None,
         })
     }
 
+    // Press the button of each trigger once it's pulled `threshold` of the way, between 0 and 1.
+    // The controller's own presses of those buttons are then ignored, so that they don't fight
+    // over them.
+    pub fn digital_triggers(self, threshold: Option<f64>) -> Self {
+        let mut builder = self;
+        builder.trigger_threshold = threshold;
+        builder
+    }
+
+    // Let games play rumble effects on the joystick, and change their strength
+    pub fn enable_force_feedback(self) -> Result<Self> {
+        let mut attribute_set: AttributeSet<FFEffectCode> = AttributeSet::new();
+        attribute_set.insert(FFEffectCode::FF_RUMBLE);
+        attribute_set.insert(FFEffectCode::FF_GAIN);
+        let mut builder = self;
+        builder.raw = builder
+            .raw
+            .with_ff(&attribute_set)?
+            .with_ff_effects_max(FF_EFFECTS_MAX);
+        Ok(builder)
+    }
+
     // Enable all valid buttons in `buttons`
     pub fn enable_buttons_bitmask(self, buttons: Bitmask) -> Result<Self> {
-        let mut attribute_set: AttributeSet<KeyCode> = AttributeSet::new();
-        for (bit, state) in buttons.into_iter().enumerate() {
-            if !state {
-                continue;
-            }
-            let id: u32 = bit.try_into()?;
-            if let Ok(key_code) = FromID::<KeyCode>::from_id(id) {
-                attribute_set.insert(key_code);
-            }
+        let attribute_set: AttributeSet<KeyCode> = buttons
+            .ones()
+            .filter_map(|id| FromID::<KeyCode>::from_id(id).ok())
+            .collect();
+        let mut builder = self;
+        builder.raw = builder.raw.with_keys(&attribute_set)?;
+        builder.keys.extend(attribute_set.iter());
+        Ok(builder)
+    }
+
+    // Enable every key in `keys`
+    pub fn enable_keys<T>(self, keys: T) -> Result<Self>
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let attribute_set: AttributeSet<KeyCode> = keys.into_iter().collect();
+        let mut builder = self;
+        if attribute_set.iter().next().is_some() {
+            builder.raw = builder.raw.with_keys(&attribute_set)?;
         }
-        let raw = self.raw.with_keys(&attribute_set)?;
-        Ok(Self { raw })
+        builder.keys.extend(attribute_set.iter());
+        Ok(builder)
     }
 
-    // Enable all valid axes in `axes`
-    pub fn enable_axes_bitmask(self, axes: Bitmask) -> Result<Self> {
-        let mut raw = self.raw;
-        for (bit, state) in axes.into_iter().enumerate() {
-            if !state {
-                continue;
-            }
-            raw = raw.with_absolute_axis(&(1 << bit).from_byte()?)?;
+    // Enable every axis in `axes`, each with the range it's paired with
+    pub fn enable_axes<T>(self, axes: T) -> Result<Self>
+    where
+        T: IntoIterator<Item = (AbsoluteAxisCode, AxisRange)>,
+    {
+        let mut builder = self;
+        for (axis, range) in axes {
+            let setup = UinputAbsSetup::new(axis, range.to_abs_info());
+            builder.raw = builder.raw.with_absolute_axis(&setup)?;
+            builder.axes.push((axis, range));
         }
-        Ok(Self { raw })
+        Ok(builder)
+    }
+
+    // Enable all valid axes in `axes`, each with its default range
+    pub fn enable_axes_bitmask(self, axes: Bitmask) -> Result<Self> {
+        let enabled = axes
+            .ones()
+            .filter_map(axis_by_id)
+            .map(|axis| (axis.code, axis.range));
+        self.enable_axes(enabled)
     }
 }
 
@@ -116,7 +611,7 @@ fn get_device_supported_attributes_score(device: &Device) -> u8 {
         .supported_absolute_axes()
         .unwrap_or(&AttributeSet::new())
         .iter()
-        .filter(|code| SUPPORTED_AXES.contains_key(code))
+        .filter(|code| axis_by_code(*code).is_some())
         .for_each(|_| score += 1);
     score
 }
@@ -136,6 +631,7 @@ pub struct DeviceWrapper {
     device: Device,
     supported_buttons: Vec<KeyCode>,
     supported_axes: Vec<AbsoluteAxisCode>,
+    trackpads: Vec<&'static TrackpadDescriptor>,
 }
 
 impl DeviceWrapper {
@@ -154,14 +650,45 @@ impl DeviceWrapper {
             .unwrap_or(&AttributeSet::new())
             .iter()
             .collect();
+        let forwarded: Vec<&str> = supported_axes
+            .iter()
+            .filter_map(|axis| Some(axis_by_code(*axis)?.name))
+            .collect();
+        printdbg!("Forwarding axes: {}", (forwarded.join(", ")));
+        let trackpads = trackpads_on(&supported_axes);
 
         Ok(Self {
             device,
             supported_buttons,
             supported_axes,
+            trackpads,
         })
     }
 
+    // Converts the device into a stream that yields events as soon as the device produces them
+    pub fn into_event_stream(self) -> Result<DeviceEventStream> {
+        Ok(DeviceEventStream {
+            stream: self.device.into_event_stream()?,
+            supported_buttons: self.supported_buttons,
+            supported_axes: self.supported_axes,
+            trackpads: self.trackpads,
+        })
+    }
+
+    pub fn device_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+
+    // Returns every button and axis the device has, which the server builds its virtual joystick
+    // from
+    pub fn capabilities(&self) -> Result<DeviceCapabilities> {
+        DeviceCapabilities::read(&self.device)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.device.name()
+    }
+
     // Returns the state of each supported button on the device
     pub fn get_button_states(&self) -> Result<Vec<(KeyCode, bool)>> {
         let attr_set = self.device.get_key_state()?;
@@ -175,18 +702,7 @@ impl DeviceWrapper {
 
     // Returns a vector of StarboardInputs representing the state of every supported button
     pub fn get_button_inputs(&self) -> Result<Vec<StarboardInput>> {
-        let attr_set = self.device.get_key_state()?;
-        let inputs = self
-            .supported_buttons
-            .iter()
-            .map(|button| {
-                anyhow::Ok(StarboardInput::Button {
-                    id: button.into_id()?,
-                    value: attr_set.contains(*button),
-                })
-            })
-            .collect();
-        inputs
+        read_button_inputs(&self.device, &self.supported_buttons)
     }
 
     // Returns the state of each supported axis on the device
@@ -202,17 +718,97 @@ impl DeviceWrapper {
 
     // Returns a vector of StarboardInputs representing the state of every supported axis
     pub fn get_axis_inputs(&self) -> Result<Vec<StarboardInput>> {
-        let states = self.device.get_abs_state()?;
-        let inputs = self
-            .supported_axes
-            .iter()
-            .map(|axis| {
-                Ok(StarboardInput::Axis {
-                    id: axis.into_id()?,
-                    value: states[axis.0 as usize].value as i16,
-                })
-            })
-            .collect();
-        inputs
+        read_axis_inputs(&self.device, &self.supported_axes)
     }
+
+    // Returns a vector of StarboardInputs representing the contact on every trackpad
+    pub fn get_touch_inputs(&self) -> Result<Vec<StarboardInput>> {
+        read_touch_inputs(&self.device, &self.trackpads)
+    }
+}
+
+// Wrapper for evdev::EventStream. The full state of the device can still be read at any time,
+// which is needed to send keyframes.
+pub struct DeviceEventStream {
+    stream: EventStream,
+    supported_buttons: Vec<KeyCode>,
+    supported_axes: Vec<AbsoluteAxisCode>,
+    trackpads: Vec<&'static TrackpadDescriptor>,
+}
+
+impl DeviceEventStream {
+    // Waits for the device to produce its next event
+    pub async fn next_event(&mut self) -> Result<InputEvent> {
+        Ok(self.stream.next_event().await?)
+    }
+
+    pub fn device_mut(&mut self) -> &mut Device {
+        self.stream.device_mut()
+    }
+
+    // Returns a vector of StarboardInputs representing the state of every supported button
+    pub fn get_button_inputs(&self) -> Result<Vec<StarboardInput>> {
+        read_button_inputs(self.stream.device(), &self.supported_buttons)
+    }
+
+    // Returns a vector of StarboardInputs representing the state of every supported axis
+    pub fn get_axis_inputs(&self) -> Result<Vec<StarboardInput>> {
+        read_axis_inputs(self.stream.device(), &self.supported_axes)
+    }
+
+    // Returns a vector of StarboardInputs representing the contact on every trackpad
+    pub fn get_touch_inputs(&self) -> Result<Vec<StarboardInput>> {
+        read_touch_inputs(self.stream.device(), &self.trackpads)
+    }
+
+    // Returns the trackpads the device has
+    pub fn trackpads(&self) -> &[&'static TrackpadDescriptor] {
+        &self.trackpads
+    }
+}
+
+// Reads the state of every button in `buttons` that Starboard supports from `device`
+fn read_button_inputs(device: &Device, buttons: &[KeyCode]) -> Result<Vec<StarboardInput>> {
+    let attr_set = device.get_key_state()?;
+    Ok(buttons
+        .iter()
+        .filter_map(|button| {
+            Some(StarboardInput::Button {
+                id: button.into_id().ok()?,
+                value: attr_set.contains(*button),
+            })
+        })
+        .collect())
+}
+
+// Reads the state of every axis in `axes` that Starboard supports from `device`
+fn read_axis_inputs(device: &Device, axes: &[AbsoluteAxisCode]) -> Result<Vec<StarboardInput>> {
+    let states = device.get_abs_state()?;
+    Ok(axes
+        .iter()
+        .filter_map(|axis| {
+            Some(StarboardInput::Axis {
+                id: axis.into_id().ok()?,
+                value: states[axis.0 as usize].value,
+            })
+        })
+        .collect())
+}
+
+// Reads the contact on every trackpad in `trackpads` from `device`
+fn read_touch_inputs(
+    device: &Device,
+    trackpads: &[&'static TrackpadDescriptor],
+) -> Result<Vec<StarboardInput>> {
+    let states = device.get_abs_state()?;
+    Ok(trackpads
+        .iter()
+        .map(|pad| StarboardInput::Touch {
+            id: pad.id,
+            state: touch_at(
+                states[pad.x.0 as usize].value,
+                states[pad.y.0 as usize].value,
+            ),
+        })
+        .collect())
 }
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use evdev::{
+    Device, FFEffect, FFEffectCode, FFEffectData, FFEffectKind, FFEvent, FFReplay, FFTrigger,
+};
+
+use crate::datagram::{Message, MessageKind};
+
+// Games send force feedback effects to the virtual joystick on the server. The server relays them
+// to the client, which replays them on the physical controller. Only rumble effects are relayed,
+// since that's all a Steam Deck can play.
+
+// The most effects a game can upload to a virtual joystick at once
+pub const FF_EFFECTS_MAX: u32 = 16;
+
+// A rumble effect, as uploaded by a game
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Decode, Encode)]
+pub struct Rumble {
+    pub strong_magnitude: u16,
+    pub weak_magnitude: u16,
+    pub length_ms: u16, // How long the effect plays for. 0 plays it until it's stopped.
+    pub delay_ms: u16,
+}
+
+impl Rumble {
+    // Returns the rumble described by `data`, or `None` if it's some other kind of effect
+    pub fn from_effect(data: FFEffectData) -> Option<Self> {
+        match data.kind {
+            FFEffectKind::Rumble {
+                strong_magnitude,
+                weak_magnitude,
+            } => Some(Self {
+                strong_magnitude,
+                weak_magnitude,
+                length_ms: data.replay.length,
+                delay_ms: data.replay.delay,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_effect(self) -> FFEffectData {
+        FFEffectData {
+            direction: 0,
+            trigger: FFTrigger::default(),
+            replay: FFReplay {
+                length: self.length_ms,
+                delay: self.delay_ms,
+            },
+            kind: FFEffectKind::Rumble {
+                strong_magnitude: self.strong_magnitude,
+                weak_magnitude: self.weak_magnitude,
+            },
+        }
+    }
+}
+
+// Something a game did to the force feedback of a virtual joystick. Effects are identified by the
+// ID the virtual joystick gave them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Decode, Encode)]
+pub enum ForceFeedback {
+    Upload {
+        effect: i16,
+        rumble: Rumble,
+    },
+    Erase {
+        effect: i16,
+    },
+    // `Play` carries the effect too, so that a lost `Upload` doesn't stop it from playing. A
+    // `count` of 0 stops the effect.
+    Play {
+        effect: i16,
+        rumble: Rumble,
+        count: i32,
+    },
+    Gain(u16),
+    // Sent when the controller's virtual joystick goes away, since nothing will stop its effects
+    StopAll,
+}
+
+// Sent by the server to the client whose virtual joystick received `feedback`
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct ForceFeedbackPacket {
+    pub id: u64,
+    pub session: u64,
+    pub sequence: u64,
+    pub feedback: ForceFeedback,
+}
+
+impl Message for ForceFeedbackPacket {
+    const KIND: MessageKind = MessageKind::ForceFeedback;
+}
+
+// Replays the force feedback relayed by the server on the client's physical controller
+#[derive(Default)]
+pub struct RumblePlayer {
+    // Effects uploaded to the physical controller, by the ID the server's virtual joystick gave
+    // them. Dropping an `FFEffect` erases it, which also stops it.
+    effects: HashMap<i16, (Rumble, FFEffect)>,
+}
+
+impl RumblePlayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&mut self, device: &mut Device, feedback: ForceFeedback) -> Result<()> {
+        match feedback {
+            ForceFeedback::Upload { effect, rumble } => {
+                self.upload(device, effect, rumble)?;
+            }
+            ForceFeedback::Erase { effect } => {
+                self.effects.remove(&effect);
+            }
+            ForceFeedback::Play {
+                effect,
+                rumble,
+                count,
+            } => {
+                let handle = self.upload(device, effect, rumble)?;
+                if count > 0 {
+                    handle.play(count)?;
+                } else {
+                    handle.stop()?;
+                }
+            }
+            ForceFeedback::Gain(gain) => {
+                device.send_events(&[*FFEvent::new(FFEffectCode::FF_GAIN, gain.into())])?;
+            }
+            ForceFeedback::StopAll => self.stop_all(),
+        }
+        Ok(())
+    }
+
+    // Stops and erases every effect
+    pub fn stop_all(&mut self) {
+        self.effects.clear();
+    }
+
+    // Makes sure `effect` is on the physical controller and up to date with `rumble`
+    fn upload(
+        &mut self,
+        device: &mut Device,
+        effect: i16,
+        rumble: Rumble,
+    ) -> Result<&mut FFEffect> {
+        if let Some((current, handle)) = self.effects.get_mut(&effect) {
+            if *current != rumble {
+                handle.update(rumble.to_effect())?;
+                *current = rumble;
+            }
+        } else {
+            let handle = device.upload_ff_effect(rumble.to_effect())?;
+            self.effects.insert(effect, (rumble, handle));
+        }
+        // Safety of using `unwrap()`: the effect was either found or inserted above
+        Ok(&mut self.effects.get_mut(&effect).unwrap().1)
+    }
+}
@@ -0,0 +1,243 @@
+use core::fmt::{self, Display};
+
+use anyhow::{Result, bail};
+use evdev::{AbsoluteAxisCode, KeyCode};
+
+use crate::{
+    capabilities::{AxisRange, DeviceCapabilities},
+    input::MotionSample,
+    supported_actions::{SUPPORTED_BUTTONS, motion_axis_by_code},
+};
+
+// Gyro aiming turns the rotation of the controller into pointer motion or right stick deflection,
+// for games that don't read motion sensors themselves. It's worked out on the server from the
+// motion samples the controller already forwards, so the controller doesn't know or care.
+
+// The Steam Deck reports rotation about the axis pointing up through the top of its screen as
+// yaw, and rotation about the axis pointing right as pitch. Turning left and tilting the top of the
+// Deck towards the player are positive.
+const YAW: AbsoluteAxisCode = AbsoluteAxisCode::ABS_RY;
+const PITCH: AbsoluteAxisCode = AbsoluteAxisCode::ABS_RX;
+
+// The joystick axes the gyro deflects when it aims with the right stick
+pub const STICK_AXES: [AbsoluteAxisCode; 2] = [AbsoluteAxisCode::ABS_RX, AbsoluteAxisCode::ABS_RY];
+
+// How far the pointer moves, in pixels, for each degree the controller turns at a sensitivity of 1
+const PIXELS_PER_DEGREE: f64 = 10.0;
+
+// How fast the controller has to turn, in degrees per second, to push the right stick all the way
+// at a sensitivity of 1
+const FULL_DEFLECTION_SPEED: f64 = 180.0;
+
+// Samples further apart than this, in seconds, are taken to follow a gap in the readings rather
+// than the controller turning all that time
+const MAX_SAMPLE_GAP: f64 = 0.1;
+
+// What the gyro of a controller aims with on the server
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum GyroMode {
+    #[default]
+    Off, // Motion is only passed through to the controller's motion device
+    Mouse, // Turning the controller moves the pointer
+    Stick, // Turning the controller pushes the right stick
+}
+
+impl GyroMode {
+    // Returns the mode after this one, for cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Mouse,
+            Self::Mouse => Self::Stick,
+            Self::Stick => Self::Off,
+        }
+    }
+}
+
+impl Display for GyroMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::Mouse => write!(f, "mouse"),
+            Self::Stick => write!(f, "stick"),
+        }
+    }
+}
+
+// How the gyro aims, shared by every controller on the server
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GyroSettings {
+    pub sensitivity: f64, // Multiplies how far the pointer or stick moves for each turn
+    pub smoothing: f64,   // How much of the last speed carries over into the next, from 0 up to 1
+    pub deadzone: f64,    // Turns slower than this, in degrees per second, are ignored as drift
+    pub ratchet: Option<KeyCode>, // The gyro only aims while this button is held, if there is one
+}
+
+impl Default for GyroSettings {
+    fn default() -> Self {
+        Self {
+            sensitivity: 1.0,
+            smoothing: 0.0,
+            deadzone: 0.0,
+            ratchet: None,
+        }
+    }
+}
+
+impl GyroSettings {
+    // Checks that every setting is one the gyro can aim with
+    pub fn validate(&self) -> Result<()> {
+        if !(self.sensitivity > 0.0 && self.sensitivity.is_finite()) {
+            bail!(
+                "The gyro sensitivity must be above 0, not {}",
+                self.sensitivity
+            );
+        }
+        if !(0.0..1.0).contains(&self.smoothing) {
+            bail!(
+                "The gyro smoothing must be at least 0 and below 1, not {}",
+                self.smoothing
+            );
+        }
+        if !(self.deadzone >= 0.0 && self.deadzone.is_finite()) {
+            bail!("The gyro deadzone can't be negative, not {}", self.deadzone);
+        }
+        if let Some(ratchet) = self.ratchet
+            && !SUPPORTED_BUTTONS.contains_key(&ratchet)
+        {
+            bail!("The gyro ratchet must be a button controllers send, not {ratchet:?}");
+        }
+        Ok(())
+    }
+}
+
+// Returns the range the gyroscope reports rotation about `axis` in
+fn gyro_range(capabilities: Option<&DeviceCapabilities>, axis: AbsoluteAxisCode) -> AxisRange {
+    let reported = capabilities.and_then(|capabilities| {
+        capabilities
+            .axes()
+            .find(|(code, _)| *code == axis)
+            .map(|(_, range)| range)
+    });
+    // Safety of using `unwrap()`: both gyro axes are in MOTION_AXES
+    reported.unwrap_or(motion_axis_by_code(axis).unwrap().range)
+}
+
+// Returns where an axis in `range` is when the gyro pushes it `deflection` of the way to either
+// end from `position`, between -1 and 1
+pub fn deflect(range: &AxisRange, position: i32, deflection: f64) -> i32 {
+    let half_span = (f64::from(range.maximum) - f64::from(range.minimum)) / 2.0;
+    let value = (f64::from(position) + deflection * half_span).round();
+    (value as i32).clamp(range.minimum, range.maximum)
+}
+
+// Turns the motion samples of one controller into aiming
+pub struct GyroAim {
+    settings: GyroSettings,
+    // How many units the gyroscope reports for each degree per second, about the yaw and pitch axes
+    resolution: (f64, f64),
+    held: bool,                  // Whether the ratchet button is held
+    speed: (f64, f64),           // The smoothed speed the aim moves at, in degrees per second
+    last_timestamp: Option<i32>, // When the last sample was taken, in microseconds
+}
+
+impl GyroAim {
+    pub fn new(settings: GyroSettings) -> Self {
+        let mut aim = Self {
+            settings,
+            resolution: (0.0, 0.0),
+            held: false,
+            speed: (0.0, 0.0),
+            last_timestamp: None,
+        };
+        aim.calibrate(None);
+        aim
+    }
+
+    // Aims as `settings` say from now on, starting from rest
+    pub fn set_settings(&mut self, settings: GyroSettings) {
+        self.settings = settings;
+        self.held = false;
+        self.reset();
+    }
+
+    // Reads how the controller's gyroscope counts rotation from the capabilities of its motion
+    // sensors. Controllers that haven't said are taken to count the way the Steam Deck does.
+    pub fn calibrate(&mut self, capabilities: Option<&DeviceCapabilities>) {
+        let resolution = |axis| match gyro_range(capabilities, axis).resolution {
+            // Safety of using `unwrap()`: both gyro axes are in MOTION_AXES
+            0 => f64::from(motion_axis_by_code(axis).unwrap().range.resolution),
+            resolution => f64::from(resolution),
+        };
+        self.resolution = (resolution(YAW), resolution(PITCH));
+    }
+
+    // Returns true if the gyro should be aiming
+    pub fn is_active(&self) -> bool {
+        self.settings.ratchet.is_none() || self.held
+    }
+
+    // Records a press or release of `key`, returning true if it's the ratchet button
+    pub fn press(&mut self, key: KeyCode, pressed: bool) -> bool {
+        if self.settings.ratchet != Some(key) {
+            return false;
+        }
+        self.held = pressed;
+        if !pressed {
+            self.reset();
+        }
+        true
+    }
+
+    // Forgets how the controller was turning, so that the next turn starts from rest
+    pub fn reset(&mut self) {
+        self.speed = (0.0, 0.0);
+        self.last_timestamp = None;
+    }
+
+    // Returns how far the pointer moves, in pixels, since the last sample
+    pub fn mouse_motion(&mut self, sample: &MotionSample) -> (f64, f64) {
+        let elapsed = self.elapsed(sample);
+        let (x, y) = self.speed(sample);
+        let Some(elapsed) = elapsed else {
+            return (0.0, 0.0);
+        };
+        let scale = elapsed * PIXELS_PER_DEGREE * self.settings.sensitivity;
+        (x * scale, y * scale)
+    }
+
+    // Returns how far the right stick is pushed, between -1 and 1 on each axis
+    pub fn stick_deflection(&mut self, sample: &MotionSample) -> (f64, f64) {
+        let (x, y) = self.speed(sample);
+        let scale = self.settings.sensitivity / FULL_DEFLECTION_SPEED;
+        ((x * scale).clamp(-1.0, 1.0), (y * scale).clamp(-1.0, 1.0))
+    }
+
+    // Returns how long ago the last sample was taken, in seconds, if it followed on from it
+    fn elapsed(&mut self, sample: &MotionSample) -> Option<f64> {
+        let last = self.last_timestamp.replace(sample.timestamp)?;
+        // Timestamps wrap around, like the kernel's
+        let elapsed = f64::from(sample.timestamp.wrapping_sub(last) as u32) / 1_000_000.0;
+        (elapsed > 0.0 && elapsed <= MAX_SAMPLE_GAP).then_some(elapsed)
+    }
+
+    // Returns how fast the aim moves, in degrees per second, with right and down as positive
+    fn speed(&mut self, sample: &MotionSample) -> (f64, f64) {
+        if !self.is_active() {
+            return (0.0, 0.0);
+        }
+        // Safety of using `unwrap()`: both gyro axes are in MOTION_AXES
+        let value = |axis| sample.values[motion_axis_by_code(axis).unwrap().id as usize];
+        // Turning left moves the aim left, and tilting the top of the Deck back moves it up
+        let mut x = -f64::from(value(YAW)) / self.resolution.0;
+        let mut y = -f64::from(value(PITCH)) / self.resolution.1;
+        if x.hypot(y) < self.settings.deadzone {
+            (x, y) = (0.0, 0.0);
+        }
+        let carried = self.settings.smoothing;
+        self.speed = (
+            self.speed.0 * carried + x * (1.0 - carried),
+            self.speed.1 * carried + y * (1.0 - carried),
+        );
+        self.speed
+    }
+}
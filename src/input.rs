@@ -2,13 +2,18 @@ use core::{convert::TryInto, iter::IntoIterator};
 
 use crate::{
     bitmask::Bitmask,
-    supported_actions::{AXIS_COUNT, BUTTON_COUNT, SUPPORTED_AXES, SUPPORTED_BUTTONS},
+    capabilities::KEY_CODES,
+    datagram::{Message, MessageKind, deserialize, peek_kind},
+    supported_actions::{
+        AXIS_COUNT, BUTTON_COUNT, MOTION_AXIS_COUNT, SUPPORTED_BUTTONS, TRACKPAD_COUNT,
+        axis_by_code, axis_by_id,
+    },
 };
 use anyhow::{Result, bail};
 use bincode::{Decode, Encode};
-use evdev::{AbsInfo, AbsoluteAxisCode, EventType, InputEvent, KeyCode, UinputAbsSetup};
+use evdev::{AbsoluteAxisCode, EventType, InputEvent, KeyCode};
 
-// There are 25 different buttons in SDL3, requiring at least a u32 to cover them all.
+// One bit per supported button, however many there are
 #[derive(PartialEq, Eq, Debug, Decode, Encode)]
 pub struct StarboardButtonStates {
     pub raw: Bitmask,
@@ -29,28 +34,22 @@ impl StarboardButtonStates {
     }
 
     // runs get_state for each positive bit in `mask`
-    pub fn get_state_with_mask(&self, mask: Bitmask) -> Vec<StarboardInput> {
-        let mut inputs: Vec<StarboardInput> = Vec::new();
-        mask.into_iter()
-            .enumerate()
-            .filter(|(_, value)| *value)
-            .for_each(|(id, _)| inputs.push(self.get_state(id as u32)));
-        inputs
+    pub fn get_state_with_mask(&self, mask: &Bitmask) -> Vec<StarboardInput> {
+        mask.ones().map(|id| self.get_state(id)).collect()
     }
 
-    // Registers `button` as pressed and packs it ino the `self`
-    fn pack_button(&mut self, id: u32) -> Result<()> {
+    // Registers whether `button` is pressed and packs it ino the `self`
+    fn pack_button(&mut self, id: u32, value: bool) -> Result<()> {
         if id >= BUTTON_COUNT {
             bail!("Could not pack button with id {}; id is out of bounds", id);
         }
-        self.raw.write_bit(id, true);
-        Ok(())
+        self.raw.write_bit(id, value)
     }
 }
 
 #[derive(PartialEq, Eq, Debug, Decode, Encode)]
 pub struct StarboardAxisStates {
-    pub axes: [i16; AXIS_COUNT as usize],
+    pub axes: [i32; AXIS_COUNT as usize], // Raw values, in the range the client's device reports
 }
 
 impl StarboardAxisStates {
@@ -67,17 +66,15 @@ impl StarboardAxisStates {
     }
 
     // runs get_state for each positive bit in `mask`
-    pub fn get_state_with_mask(&self, mask: Bitmask) -> Vec<StarboardInput> {
-        let mut inputs: Vec<StarboardInput> = Vec::new();
-        mask.into_iter()
-            .enumerate()
-            .filter(|(id, value)| *value && *id < self.axes.len())
-            .for_each(|(id, _)| inputs.push(self.get_state(id as u32)));
-        inputs
+    pub fn get_state_with_mask(&self, mask: &Bitmask) -> Vec<StarboardInput> {
+        mask.ones()
+            .filter(|id| (*id as usize) < self.axes.len())
+            .map(|id| self.get_state(id))
+            .collect()
     }
 
     // Registers `axis` as holding value `value`
-    fn pack_axis(&mut self, id: usize, value: i16) -> Result<()> {
+    fn pack_axis(&mut self, id: usize, value: i32) -> Result<()> {
         if id >= self.axes.len() {
             bail!("Could not pack axis with id {}; id is out of bounds", id);
         }
@@ -86,37 +83,152 @@ impl StarboardAxisStates {
     }
 }
 
+// Where a trackpad is being touched, if it is
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default, Decode, Encode)]
+pub struct TouchState {
+    pub touching: bool,
+    pub x: i32, // Raw position, in the range the client's device reports
+    pub y: i32,
+}
+
+// One reading of every motion sensor axis, as the client's motion device reported it
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default, Decode, Encode)]
+pub struct MotionSample {
+    pub values: [i32; MOTION_AXIS_COUNT as usize], // Raw values, by motion axis ID
+    pub timestamp: i32, // When the sample was taken, in microseconds, as the device counts them
+}
+
+#[derive(PartialEq, Eq, Debug, Decode, Encode)]
+pub struct StarboardTouchStates {
+    pub pads: [TouchState; TRACKPAD_COUNT as usize],
+}
+
+impl StarboardTouchStates {
+    pub fn new() -> Self {
+        Self {
+            pads: [TouchState::default(); TRACKPAD_COUNT as usize],
+        }
+    }
+
+    // Returns a StarboardInput for every trackpad. Trackpads aren't masked, since the server
+    // decides per controller where they go.
+    pub fn get_states(&self) -> Vec<StarboardInput> {
+        (0..TRACKPAD_COUNT)
+            .zip(self.pads)
+            .map(|(id, state)| StarboardInput::Touch { id, state })
+            .collect()
+    }
+
+    // Registers `state` as the contact on the id-th trackpad
+    fn pack_touch(&mut self, id: usize, state: TouchState) -> Result<()> {
+        if id >= self.pads.len() {
+            bail!(
+                "Could not pack trackpad with id {}; id is out of bounds",
+                id
+            );
+        }
+        self.pads[id] = state;
+        Ok(())
+    }
+}
+
+// One bit per key code, for the keyboards and mice forwarded along with the controller. Clients
+// that don't forward any leave it empty, so that their keyframes don't carry a bit per key.
+#[derive(PartialEq, Eq, Debug, Decode, Encode)]
+pub struct StarboardKeyStates {
+    pub raw: Bitmask,
+}
+
+impl StarboardKeyStates {
+    pub fn new() -> Self {
+        Self {
+            raw: Bitmask::new(0),
+        }
+    }
+
+    // Returns a StarboardInput for every key, so that keys released in a lost delta are released
+    pub fn get_states(&self) -> Vec<StarboardInput> {
+        (0..self.raw.size())
+            .map(|code| StarboardInput::Key {
+                code: code as u16,
+                value: self.raw.read_bit(code),
+            })
+            .collect()
+    }
+
+    // Registers whether the key with code `code` is held down
+    fn pack_key(&mut self, code: u16, value: bool) -> Result<()> {
+        if code >= KEY_CODES {
+            bail!(
+                "Could not pack key with code {}; code is out of bounds",
+                code
+            );
+        }
+        if self.raw.size() == 0 {
+            self.raw = Bitmask::new(KEY_CODES.into());
+        }
+        self.raw.write_bit(code.into(), value)
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Decode, Encode)]
 pub struct StarboardInputPacket {
     pub buttons: StarboardButtonStates,
     pub axes: StarboardAxisStates,
+    pub touches: StarboardTouchStates,
+    pub keys: StarboardKeyStates,
     pub id: u64,
+    // Newer each time the client starts, so the server knows when the sequence numbers start over
+    // and can refuse packets from earlier sessions
+    pub session: u64,
+    // Increases by one with every packet the client sends during a session
+    pub sequence: u64,
+}
+
+impl Message for StarboardInputPacket {
+    const KIND: MessageKind = MessageKind::Input;
 }
 
 impl StarboardInputPacket {
-    pub fn new(id: u64) -> Self {
+    pub fn new(id: u64, session: u64, sequence: u64) -> Self {
         Self {
             buttons: StarboardButtonStates::new(),
             axes: StarboardAxisStates::new(),
+            touches: StarboardTouchStates::new(),
+            keys: StarboardKeyStates::new(),
             id,
+            session,
+            sequence,
         }
     }
 
     // Unpack all inputs in the packet into a vector of StarboardInputs
-    pub fn unpack(self, button_mask: Bitmask, axis_mask: Bitmask) -> Vec<StarboardInput> {
+    pub fn unpack(self, button_mask: &Bitmask, axis_mask: &Bitmask) -> Vec<StarboardInput> {
         let button_states = self.buttons.get_state_with_mask(button_mask);
         let axis_states = self.axes.get_state_with_mask(axis_mask);
 
         let mut inputs = button_states;
         inputs.extend(axis_states);
+        inputs.extend(self.touches.get_states());
+        inputs.extend(self.keys.get_states());
         inputs
     }
 
     // Pack `input` into the packet
     pub fn pack(&mut self, input: StarboardInput) -> Result<()> {
         Ok(match input {
-            StarboardInput::Button { id, .. } => self.buttons.pack_button(id)?,
+            StarboardInput::Button { id, value } => self.buttons.pack_button(id, value)?,
             StarboardInput::Axis { id, value } => self.axes.pack_axis(id.try_into()?, value)?,
+            StarboardInput::Touch { id, state } => {
+                self.touches.pack_touch(id.try_into()?, state)?
+            }
+            // A sample is out of date by the next one, so there's no state to recover
+            StarboardInput::Motion { .. } => bail!("Motion is only sent in deltas"),
+            StarboardInput::Key { code, value } => self.keys.pack_key(code, value)?,
+            // Movement has already been made by the time the next packet is sent
+            StarboardInput::Rel { .. } | StarboardInput::Wheel { .. } => {
+                bail!("Pointer motion and scrolling are only sent in deltas")
+            }
         })
     }
 
@@ -135,12 +247,192 @@ impl StarboardInputPacket {
     pub fn client_id(&self) -> &u64 {
         &self.id
     }
+
+    // Returns the session the packet was sent in
+    pub fn session(&self) -> &u64 {
+        &self.session
+    }
+
+    // Returns the packet's position in its session
+    pub fn sequence(&self) -> &u64 {
+        &self.sequence
+    }
+}
+
+// Packet holding only the inputs that changed since the previous packet. Deltas are sent as soon
+// as the device reports a change, while full `StarboardInputPacket`s are still sent periodically as
+// keyframes so that the server can recover from lost deltas.
+#[derive(PartialEq, Eq, Debug, Decode, Encode)]
+pub struct StarboardDeltaPacket {
+    pub inputs: Vec<StarboardInput>,
+    pub id: u64,
+    pub session: u64,
+    pub sequence: u64,
+}
+
+impl Message for StarboardDeltaPacket {
+    const KIND: MessageKind = MessageKind::Delta;
+}
+
+impl StarboardDeltaPacket {
+    pub fn new(id: u64, session: u64, sequence: u64) -> Self {
+        Self {
+            inputs: Vec::new(),
+            id,
+            session,
+            sequence,
+        }
+    }
+
+    // Unpack every input in the packet that is enabled in the masks
+    pub fn unpack(self, button_mask: &Bitmask, axis_mask: &Bitmask) -> Vec<StarboardInput> {
+        self.inputs
+            .into_iter()
+            .filter(|input| match input {
+                StarboardInput::Button { id, .. } => button_mask.read_bit(*id),
+                StarboardInput::Axis { id, .. } => axis_mask.read_bit(*id),
+                StarboardInput::Touch { id, .. } => *id < TRACKPAD_COUNT,
+                StarboardInput::Motion { .. } => true,
+                StarboardInput::Key { code, .. } => *code < KEY_CODES,
+                StarboardInput::Rel { .. } | StarboardInput::Wheel { .. } => true,
+            })
+            .collect()
+    }
+
+    // Pack `input` into the packet, replacing any earlier change to the same input
+    pub fn pack(&mut self, input: StarboardInput) {
+        match self
+            .inputs
+            .iter_mut()
+            .find(|packed| packed.same_input(&input))
+        {
+            Some(packed) => *packed = input,
+            None => self.inputs.push(input),
+        }
+    }
+
+    // Returns the ID of the client that sent the packet
+    pub fn client_id(&self) -> &u64 {
+        &self.id
+    }
+
+    // Returns the session the packet was sent in
+    pub fn session(&self) -> &u64 {
+        &self.session
+    }
+
+    // Returns the packet's position in its session
+    pub fn sequence(&self) -> &u64 {
+        &self.sequence
+    }
 }
 
+// Either of the packets a client sends to drive a virtual joystick
 #[derive(PartialEq, Eq, Debug)]
+pub enum InputFrame {
+    Keyframe(StarboardInputPacket),
+    Delta(StarboardDeltaPacket),
+}
+
+impl InputFrame {
+    // Deserialize a datagram holding either a keyframe or a delta
+    pub fn deserialize(raw: &[u8]) -> Result<Self> {
+        Ok(match peek_kind(raw)? {
+            MessageKind::Input => {
+                let packet: StarboardInputPacket = deserialize(raw)?;
+                // A build with different buttons would give the bits different meanings
+                let buttons = packet.buttons.raw.size();
+                if buttons != BUTTON_COUNT {
+                    bail!("Keyframe holds {buttons} buttons, but this build has {BUTTON_COUNT}");
+                }
+                let keys = packet.keys.raw.size();
+                if keys != 0 && keys != u32::from(KEY_CODES) {
+                    bail!("Keyframe holds {keys} keys, but there are {KEY_CODES} key codes");
+                }
+                Self::Keyframe(packet)
+            }
+            MessageKind::Delta => Self::Delta(deserialize(raw)?),
+            kind => bail!("Expected an input message but received a {kind:?} message"),
+        })
+    }
+
+    pub fn client_id(&self) -> &u64 {
+        match self {
+            Self::Keyframe(packet) => packet.client_id(),
+            Self::Delta(packet) => packet.client_id(),
+        }
+    }
+
+    pub fn session(&self) -> &u64 {
+        match self {
+            Self::Keyframe(packet) => packet.session(),
+            Self::Delta(packet) => packet.session(),
+        }
+    }
+
+    pub fn sequence(&self) -> &u64 {
+        match self {
+            Self::Keyframe(packet) => packet.sequence(),
+            Self::Delta(packet) => packet.sequence(),
+        }
+    }
+
+    // Unpack all inputs in the frame that are enabled in the masks
+    pub fn unpack(self, button_mask: &Bitmask, axis_mask: &Bitmask) -> Vec<StarboardInput> {
+        match self {
+            Self::Keyframe(packet) => packet.unpack(button_mask, axis_mask),
+            Self::Delta(packet) => packet.unpack(button_mask, axis_mask),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Decode, Encode)]
 pub enum StarboardInput {
-    Axis { id: u32, value: i16 },
+    Axis { id: u32, value: i32 },
     Button { id: u32, value: bool },
+    Touch { id: u32, state: TouchState },
+    Motion { sample: MotionSample },
+    Key { code: u16, value: bool }, // A keyboard key or mouse button, by its evdev code
+    Rel { x: i32, y: i32 },         // Pointer motion, in counts, with right and down as positive
+    Wheel { vertical: i32, horizontal: i32 }, // Scrolling, in detents, up and right positive
+}
+
+impl StarboardInput {
+    // Returns true if `self` and `other` describe the same button or axis. Pointer motion and
+    // scrolling add up rather than replacing each other, so they're never the same input.
+    pub fn same_input(&self, other: &StarboardInput) -> bool {
+        match (self, other) {
+            (Self::Axis { id, .. }, Self::Axis { id: other, .. }) => id == other,
+            (Self::Button { id, .. }, Self::Button { id: other, .. }) => id == other,
+            (Self::Touch { id, .. }, Self::Touch { id: other, .. }) => id == other,
+            (Self::Motion { .. }, Self::Motion { .. }) => true,
+            (Self::Key { code, .. }, Self::Key { code: other, .. }) => code == other,
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<InputEvent> for StarboardInput {
+    type Error = anyhow::Error;
+
+    // Converts an event read from an evdev device. Fails for events that aren't supported buttons
+    // or axes.
+    fn try_from(event: InputEvent) -> Result<Self> {
+        Ok(match event.event_type() {
+            EventType::ABSOLUTE => StarboardInput::Axis {
+                id: AbsoluteAxisCode(event.code()).into_id()?,
+                value: event.value(),
+            },
+            // Key repeats are reported with a value of 2, which still means pressed
+            EventType::KEY => StarboardInput::Button {
+                id: KeyCode(event.code()).into_id()?,
+                value: event.value() != 0,
+            },
+            event_type => {
+                bail!("Couldn't convert event of type '{event_type:?}' into a `StarboardInput`")
+            }
+        })
+    }
 }
 
 impl TryInto<InputEvent> for StarboardInput {
@@ -151,29 +443,32 @@ impl TryInto<InputEvent> for StarboardInput {
             StarboardInput::Axis { id, value } => {
                 const EVENT_TYPE: u16 = EventType::ABSOLUTE.0;
                 let event_code = FromID::<AbsoluteAxisCode>::from_id(id)?.0;
-                InputEvent::new(EVENT_TYPE, event_code, value.into())
+                InputEvent::new(EVENT_TYPE, event_code, value)
             }
             StarboardInput::Button { id, value } => {
                 const EVENT_TYPE: u16 = EventType::KEY.0;
                 let event_code = FromID::<KeyCode>::from_id(id)?.0;
                 InputEvent::new(EVENT_TYPE, event_code, value.into())
             }
+            // A contact takes several events, and where they go depends on the trackpad mode
+            StarboardInput::Touch { .. } => {
+                bail!("Couldn't convert a touch into a single `InputEvent`")
+            }
+            // Motion goes to the motion device rather than the joystick
+            StarboardInput::Motion { .. } => {
+                bail!("Couldn't convert a motion sample into a single `InputEvent`")
+            }
+            StarboardInput::Key { code, value } => {
+                InputEvent::new(EventType::KEY.0, code, value.into())
+            }
+            // Both take an event per axis
+            StarboardInput::Rel { .. } | StarboardInput::Wheel { .. } => {
+                bail!("Couldn't convert pointer motion or scrolling into a single `InputEvent`")
+            }
         })
     }
 }
 
-// Trait to convert a foreign library's input type into a relevant bitmask
-pub trait IntoByte {
-    fn into_byte(self) -> Result<u32>;
-}
-
-// Trait to convert a bitmask into a foreign library's input type
-pub trait FromByte<T> {
-    fn from_byte(self) -> Result<T>
-    where
-        T: Sized;
-}
-
 // Trait to convert a struct into the ID of a Starboard Input
 pub trait IntoID {
     fn into_id(self) -> Result<u32>;
@@ -217,86 +512,6 @@ impl FromByte<KeyCode> for u32 {
 }
 */
 
-impl IntoByte for KeyCode {
-    fn into_byte(self) -> Result<u32> {
-        Ok((2 as u32).pow(self.into_id()?))
-    }
-}
-
-impl FromByte<AbsoluteAxisCode> for u32 {
-    fn from_byte(self) -> Result<AbsoluteAxisCode> {
-        Ok(match self {
-            1 => AbsoluteAxisCode::ABS_X,
-            2 => AbsoluteAxisCode::ABS_Y,
-            4 => AbsoluteAxisCode::ABS_Z,
-            8 => AbsoluteAxisCode::ABS_RX,
-            16 => AbsoluteAxisCode::ABS_RY,
-            32 => AbsoluteAxisCode::ABS_RZ,
-            64 => AbsoluteAxisCode::ABS_HAT0X,
-            128 => AbsoluteAxisCode::ABS_HAT0Y,
-            _ => bail!("Couldn't convert given u32 '{self:?}' into `AbsoluteAxisCode`"),
-        })
-    }
-}
-
-impl IntoByte for AbsoluteAxisCode {
-    fn into_byte(self) -> Result<u32> {
-        Ok(match self {
-            AbsoluteAxisCode::ABS_X => 1,
-            AbsoluteAxisCode::ABS_Y => 2,
-            AbsoluteAxisCode::ABS_Z => 4,
-            AbsoluteAxisCode::ABS_RX => 8,
-            AbsoluteAxisCode::ABS_RY => 16,
-            AbsoluteAxisCode::ABS_RZ => 32,
-            AbsoluteAxisCode::ABS_HAT0X => 64,
-            AbsoluteAxisCode::ABS_HAT0Y => 128,
-            _ => bail!("Couldn't convert given AbsoluteAxisCode '{self:?}' into `u32`"),
-        })
-    }
-}
-
-// Some of the traits of axes (i.e. deadzones) should be customizable
-// at runtime rather than at compile time, but evdev requires certain
-// values for these traits. We implement this trait for AbsInfo
-// instead of just AbsoluteAxisCode so we can bake it default neutral values.
-impl FromByte<AbsInfo> for u32 {
-    fn from_byte(self) -> Result<AbsInfo>
-    where
-        AbsInfo: Sized,
-    {
-        Ok(match self {
-            1 => AbsInfo::new(0, -32768, 32767, 16, 128, 0),
-            2 => AbsInfo::new(0, -32768, 32767, 16, 128, 0),
-            4 => AbsInfo::new(0, 0, 255, 0, 0, 0),
-            8 => AbsInfo::new(0, -32768, 32767, 16, 128, 0),
-            16 => AbsInfo::new(0, -32768, 32767, 16, 128, 0),
-            32 => AbsInfo::new(0, 0, 255, 0, 0, 0),
-            64 => AbsInfo::new(0, -1, 1, 0, 0, 2),
-            128 => AbsInfo::new(0, -1, 1, 0, 0, 2),
-            _ => bail!("Couldn't convert given u32 '{self}' into `AbsInfo`"),
-        })
-    }
-}
-
-impl FromByte<UinputAbsSetup> for u32 {
-    fn from_byte(self) -> Result<UinputAbsSetup>
-    where
-        UinputAbsSetup: Sized,
-    {
-        Ok(match self {
-            1 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            2 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            4 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            8 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            16 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            32 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            64 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            128 => UinputAbsSetup::new(self.from_byte()?, self.from_byte()?),
-            _ => bail!("Couldn't convert given u32 '{self}' into `UinputAbsSetup`"),
-        })
-    }
-}
-
 impl IntoID for KeyCode {
     fn into_id(self) -> Result<u32> {
         match SUPPORTED_BUTTONS.get_index_of(&self) {
@@ -321,8 +536,8 @@ impl FromID<KeyCode> for u32 {
 
 impl IntoID for AbsoluteAxisCode {
     fn into_id(self) -> Result<u32> {
-        match SUPPORTED_AXES.get_index_of(&self) {
-            Some(v) => Ok(v as u32),
+        match axis_by_code(self) {
+            Some(axis) => Ok(axis.id),
             None => {
                 bail!("Couldn't convert given AbsoluteAxisCode '{self:?}' into a Starboard ID.",)
             }
@@ -332,9 +547,8 @@ impl IntoID for AbsoluteAxisCode {
 
 impl FromID<AbsoluteAxisCode> for u32 {
     fn from_id(self) -> Result<AbsoluteAxisCode> {
-        let index = self as usize;
-        Ok(match SUPPORTED_AXES.get_index(index) {
-            Some(v) => *v.0,
+        Ok(match axis_by_id(self) {
+            Some(axis) => axis.code,
             _ => bail!("Couldn't convert given Starboard ID '{self}' into `AbsoluteAxisCode`."),
         })
     }
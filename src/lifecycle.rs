@@ -0,0 +1,93 @@
+use core::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+// A detected controller is Online while it keeps in touch, Degraded while its connection is poor,
+// NotResponding once it has been quiet for too long and Disconnected once it says it has shut
+// down. A controller that starts talking again goes back to being Online, and one that stays
+// quiet for long enough is forgotten.
+
+// Records the current state of a detected controller
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ControllerState {
+    Online,
+    Degraded,
+    NotResponding,
+    Disconnected,
+}
+
+impl Display for ControllerState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let string = match self {
+            Self::Online => "Online",
+            Self::Degraded => "Degraded",
+            Self::NotResponding => "Not Responding",
+            Self::Disconnected => "Disconnected",
+        };
+        write!(f, "{string}")
+    }
+}
+
+impl ControllerState {
+    // Returns true if no input is coming from the controller, so its virtual joystick should be
+    // left at rest
+    pub fn is_silent(&self) -> bool {
+        matches!(self, Self::NotResponding | Self::Disconnected)
+    }
+}
+
+// How a controller's connection has been doing since it was last checked
+#[derive(Debug, Copy, Clone)]
+pub struct ConnectionHealth {
+    pub silent_for: Duration, // Time since the controller last announced its presence
+    pub latency_ms: i64,
+    pub loss_rate: f64, // Percentage of input packets lost since the last check
+}
+
+// The limits that move a controller from one state to another
+#[derive(Debug, Copy, Clone)]
+pub struct LifecycleThresholds {
+    pub degraded_latency_ms: i64,
+    pub degraded_loss_rate: f64, // Percentage
+    pub not_responding_after: Duration,
+    pub evict_after: Duration,
+}
+
+impl Default for LifecycleThresholds {
+    fn default() -> Self {
+        Self {
+            degraded_latency_ms: 100,
+            degraded_loss_rate: 5.0,
+            not_responding_after: Duration::from_secs(15),
+            evict_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl LifecycleThresholds {
+    // Returns the state a controller in `current` should be in, given how its connection is
+    // doing. A disconnected controller stays disconnected until it announces itself again.
+    pub fn next_state(
+        &self,
+        current: ControllerState,
+        health: &ConnectionHealth,
+    ) -> ControllerState {
+        if current == ControllerState::Disconnected {
+            ControllerState::Disconnected
+        } else if health.silent_for >= self.not_responding_after {
+            ControllerState::NotResponding
+        } else if health.latency_ms > self.degraded_latency_ms
+            || health.loss_rate > self.degraded_loss_rate
+        {
+            ControllerState::Degraded
+        } else {
+            ControllerState::Online
+        }
+    }
+
+    // Returns true if a controller has been quiet for long enough to be forgotten
+    pub fn should_evict(&self, health: &ConnectionHealth) -> bool {
+        health.silent_for >= self.evict_after
+    }
+}
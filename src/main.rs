@@ -1,14 +1,30 @@
 mod bitmask;
+mod capabilities;
 mod client;
 mod datagram;
 mod debug;
+mod desktop;
+mod discovery;
 mod evdev_sb;
 mod fixed_queue;
+mod force_feedback;
+mod gyro;
 mod input;
+mod lifecycle;
+mod motion;
+mod net;
+mod pairing;
+mod ping;
+mod rejects;
+mod remap;
+mod sequence;
 mod server;
 mod server_ui;
+mod session;
+mod storage;
 mod string;
 mod supported_actions;
+mod trackpad;
 
 #[cfg(feature = "dummy-steam-deck")]
 mod dummy_steam_deck;
@@ -16,12 +32,23 @@ mod dummy_steam_deck;
 #[cfg(test)]
 mod test;
 
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
 use anyhow::Result;
 
 use clap::{Arg, ArgMatches, Command};
+use evdev::KeyCode;
 
 use crate::{
     client::StarboardClient,
+    gyro::GyroSettings,
+    lifecycle::LifecycleThresholds,
+    net::resolve_addr,
+    remap::default_profiles_dir,
     server::StarboardServerBuilder,
     supported_actions::{SUPPORTED_AXES, SUPPORTED_BUTTONS},
 };
@@ -40,6 +67,44 @@ fn client_args() -> Vec<Arg> {
             .default_value("61000")
             .long("device-search-port")
             .help("The port on which the server will broadcast its presence to servers"),
+        Arg::new("event-driven")
+            .action(clap::ArgAction::SetTrue)
+            .long("event-driven")
+            .help(
+                "Send inputs as soon as they change instead of sending the full state every 16ms",
+            ),
+        Arg::new("keyframe-interval")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("1000")
+            .long("keyframe-interval")
+            .help("How often, in milliseconds, the full state is sent when running event driven"),
+        Arg::new("server-id")
+            .value_parser(clap::value_parser!(u64))
+            .long("server-id")
+            .help(
+                "The ID of the server to send input to; otherwise the paired server is used, or \
+                 the user is asked",
+            ),
+        Arg::new("encrypt")
+            .action(clap::ArgAction::SetTrue)
+            .long("encrypt")
+            .help("Encrypt every input packet; the client must have been paired with the server"),
+        Arg::new("forward")
+            .action(clap::ArgAction::Append)
+            .long("forward")
+            .value_name("DEVICE")
+            .help(
+                "Forward this keyboard or mouse along with the controller, given by path \
+                 (i.e. /dev/input/event7) or by name; can be given more than once",
+            ),
+        Arg::new("grab")
+            .action(clap::ArgAction::SetTrue)
+            .long("grab")
+            .requires("forward")
+            .help("Keep forwarded devices from also sending their input to this device"),
+        server_arg(),
+        loopback_arg(),
+        client_id_arg(),
     ]
 }
 
@@ -48,6 +113,77 @@ fn client_cmd() -> Command {
     Command::new("client").args(client_args())
 }
 
+// Defines all the arguments that 'pair' can take in
+fn pair_args() -> Vec<Arg> {
+    vec![
+        Arg::new("device-search-port")
+            .value_parser(clap::value_parser!(u16))
+            .default_value("61000")
+            .long("device-search-port")
+            .help("The port on which servers listen for pairing requests"),
+        server_arg(),
+        loopback_arg(),
+        client_id_arg(),
+    ]
+}
+
+// Defines the argument that overrides the client ID generated on first run
+fn client_id_arg() -> Arg {
+    Arg::new("client-id")
+        .value_parser(clap::value_parser!(u64))
+        .long("client-id")
+        .help(
+            "The ID this controller goes by, instead of the one generated for this user on first \
+             run",
+        )
+}
+
+// Defines the argument that points the client at a server instead of searching for one
+fn server_arg() -> Arg {
+    Arg::new("server")
+        .long("server")
+        .value_name("HOST:PORT")
+        .help(
+            "Contact the server at this address, where PORT is its device search port, instead of \
+             searching the local network. IPv6 addresses go in brackets (i.e. \
+             [fe80::1%wlan0]:61000)",
+        )
+}
+
+// Defines the argument that makes the client search for a server on the same device
+fn loopback_arg() -> Arg {
+    Arg::new("loopback")
+        .action(clap::ArgAction::SetTrue)
+        .long("loopback")
+        .conflicts_with("server")
+        .help("Search for a server running on this device")
+}
+
+// Returns where the client should contact the server, or `None` if it should search for one
+async fn server_addr(subcommand_matches: &ArgMatches) -> Result<Option<SocketAddr>> {
+    // Safety of using `unwrap()`: `device_search_port` will default if unset
+    let device_search_port = *(subcommand_matches
+        .get_one::<u16>("device-search-port")
+        .unwrap());
+    if subcommand_matches.get_flag("loopback") {
+        return Ok(Some(SocketAddr::new(
+            Ipv6Addr::LOCALHOST.into(),
+            device_search_port,
+        )));
+    }
+    match subcommand_matches.get_one::<String>("server") {
+        Some(addr) => Ok(Some(resolve_addr(addr).await?)),
+        None => Ok(None),
+    }
+}
+
+// Defines a command: 'pair'
+fn pair_cmd() -> Command {
+    Command::new("pair")
+        .about("Pair with a server that has its pairing page open")
+        .args(pair_args())
+}
+
 // TODO: Implement the args
 // Defines all the arguments that 'server' can take in
 fn server_args() -> Vec<Arg> {
@@ -66,6 +202,78 @@ fn server_args() -> Vec<Arg> {
             .default_value("Starboard Virtual Gamepad")
             .long("name")
             .short('n'),
+        Arg::new("require-pairing")
+            .action(clap::ArgAction::SetTrue)
+            .long("require-pairing")
+            .help("Only accept input from controllers that have been paired with this server"),
+        Arg::new("require-encryption")
+            .action(clap::ArgAction::SetTrue)
+            .long("require-encryption")
+            .help("Only accept encrypted input, which implies --require-pairing"),
+        Arg::new("degraded-latency")
+            .value_parser(clap::value_parser!(i64).range(0..))
+            .default_value("100")
+            .long("degraded-latency")
+            .help("The latency, in milliseconds, above which a controller is shown as degraded"),
+        Arg::new("degraded-loss")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("5")
+            .long("degraded-loss")
+            .help("The percentage of lost packets above which a controller is shown as degraded"),
+        Arg::new("timeout")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("15")
+            .long("timeout")
+            .help("How long, in seconds, a controller can go quiet before it stops responding"),
+        Arg::new("forget-after")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("300")
+            .long("forget-after")
+            .help("How long, in seconds, a controller can go quiet before it's forgotten"),
+        Arg::new("trigger-threshold")
+            .value_parser(clap::value_parser!(f64))
+            .long("trigger-threshold")
+            .value_name("PERCENT")
+            .help(
+                "Press L2/R2 once an analog trigger is pulled this far, for games that only read \
+                 the buttons",
+            ),
+        Arg::new("gyro-sensitivity")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("1")
+            .long("gyro-sensitivity")
+            .help(
+                "How far the gyro moves the pointer or right stick for each turn, once it's turned \
+                 on for a controller",
+            ),
+        Arg::new("gyro-smoothing")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("0")
+            .long("gyro-smoothing")
+            .help("How much the gyro's aim is smoothed out, from 0 for not at all up to 1"),
+        Arg::new("gyro-deadzone")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("0")
+            .long("gyro-deadzone")
+            .value_name("DEGREES_PER_SECOND")
+            .help("Turns slower than this are ignored by the gyro, to hide drift"),
+        Arg::new("gyro-ratchet")
+            .value_parser(|button: &str| {
+                button
+                    .parse::<KeyCode>()
+                    .map_err(|_| format!("`{button}` isn't a button (i.e. BTN_TL)"))
+            })
+            .long("gyro-ratchet")
+            .value_name("BUTTON")
+            .help("Only aim with the gyro while this button is held"),
+        Arg::new("profiles")
+            .value_parser(clap::value_parser!(PathBuf))
+            .long("profiles")
+            .value_name("DIR")
+            .help(
+                "Where to load button and axis remapping profiles from (defaults to \
+                 ~/.local/share/starboard/profiles)",
+            ),
         #[cfg(feature = "debug")]
         Arg::new("no-ui")
             .action(clap::ArgAction::SetTrue)
@@ -80,7 +288,7 @@ fn server_cmd() -> Command {
 
 // Defines all the commands
 fn starboard_commands() -> Vec<Command> {
-    vec![client_cmd(), server_cmd()]
+    vec![client_cmd(), pair_cmd(), server_cmd()]
 }
 
 async fn server(subcommand_matches: &ArgMatches) -> Result<()> {
@@ -93,31 +301,106 @@ async fn server(subcommand_matches: &ArgMatches) -> Result<()> {
         .get_one::<String>("name")
         .unwrap()
         .to_owned();
+    let require_pairing = subcommand_matches.get_flag("require-pairing");
+    let require_encryption = subcommand_matches.get_flag("require-encryption");
+    // Safety of using `unwrap()`: every threshold will default if unset
+    let thresholds = LifecycleThresholds {
+        degraded_latency_ms: *subcommand_matches
+            .get_one::<i64>("degraded-latency")
+            .unwrap(),
+        degraded_loss_rate: *subcommand_matches.get_one::<f64>("degraded-loss").unwrap(),
+        not_responding_after: Duration::from_secs(
+            *subcommand_matches.get_one::<u64>("timeout").unwrap(),
+        ),
+        evict_after: Duration::from_secs(
+            *subcommand_matches.get_one::<u64>("forget-after").unwrap(),
+        ),
+    };
+    let trigger_threshold = subcommand_matches
+        .get_one::<f64>("trigger-threshold")
+        .copied();
+    // Safety of using `unwrap()`: every gyro setting but the ratchet will default if unset
+    let gyro = GyroSettings {
+        sensitivity: *subcommand_matches
+            .get_one::<f64>("gyro-sensitivity")
+            .unwrap(),
+        smoothing: *subcommand_matches.get_one::<f64>("gyro-smoothing").unwrap(),
+        deadzone: *subcommand_matches.get_one::<f64>("gyro-deadzone").unwrap(),
+        ratchet: subcommand_matches
+            .get_one::<KeyCode>("gyro-ratchet")
+            .copied(),
+    };
+    let profiles = match subcommand_matches.get_one::<PathBuf>("profiles") {
+        Some(dir) => dir.clone(),
+        None => default_profiles_dir()?,
+    };
     let no_ui = false;
     #[cfg(feature = "debug")]
     let no_ui = subcommand_matches.get_flag("no-ui");
     let supported_buttons = SUPPORTED_BUTTONS.keys().map(|code| *code);
-    let supported_axes = SUPPORTED_AXES.keys().map(|code| *code);
+    let supported_axes = SUPPORTED_AXES.iter().map(|axis| axis.code);
     StarboardServerBuilder::new(serial_port, device_search_port)
         .enable_buttons(supported_buttons)?
         .enable_axes(supported_axes)?
         .disable_ui(no_ui)
-        .build(name)
+        .require_pairing(require_pairing)
+        .require_encryption(require_encryption)
+        .lifecycle(thresholds)
+        .digital_triggers(trigger_threshold)?
+        .gyro(gyro)?
+        .profiles(&profiles)?
+        .build(name)?
         .run()
         .await
 }
 
 async fn client(subcommand_matches: &ArgMatches) -> Result<()> {
-    // Safety of using `unwrap()`: `serial_port` and `device_search_port` both will default if unset
+    // Safety of using `unwrap()`: `serial_port`, `device_search_port` and `keyframe_interval` will
+    // all default if unset
     let serial_port = *(subcommand_matches.get_one::<u16>("serial-port").unwrap());
     let device_search_port = *(subcommand_matches
         .get_one::<u16>("device-search-port")
         .unwrap());
+    let event_driven = subcommand_matches.get_flag("event-driven");
+    let keyframe_interval = *(subcommand_matches
+        .get_one::<u64>("keyframe-interval")
+        .unwrap());
+    let server_id = subcommand_matches.get_one::<u64>("server-id").copied();
+    let encrypt = subcommand_matches.get_flag("encrypt");
+    let server_addr = server_addr(subcommand_matches).await?;
+    let client_id = subcommand_matches.get_one::<u64>("client-id").copied();
+    let forward = subcommand_matches
+        .get_many::<String>("forward")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let grab = subcommand_matches.get_flag("grab");
     StarboardClient::new("Starboard Gamepad", serial_port, device_search_port)?
+        .client_id(client_id)
+        .server(server_addr)
+        .event_driven(event_driven)
+        .keyframe_interval(keyframe_interval)
+        .paired_with(server_id)?
+        .encrypt(encrypt)
+        .forward(forward, grab)
         .run()
         .await
 }
 
+async fn pair(subcommand_matches: &ArgMatches) -> Result<()> {
+    // Safety of using `unwrap()`: `device_search_port` will default if unset
+    let device_search_port = *(subcommand_matches
+        .get_one::<u16>("device-search-port")
+        .unwrap());
+    let server_addr = server_addr(subcommand_matches).await?;
+    let client_id = subcommand_matches.get_one::<u64>("client-id").copied();
+    StarboardClient::new("Starboard Gamepad", 0, device_search_port)?
+        .client_id(client_id)
+        .server(server_addr)
+        .pair()
+        .await
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     #[cfg(feature = "dummy-steam-deck")]
@@ -138,6 +421,7 @@ async fn main() -> Result<()> {
     match subcommand_name {
         "server" => server(subcommand_matches).await?,
         "client" => client(subcommand_matches).await?,
+        "pair" => pair(subcommand_matches).await?,
         &_ => {}
     }
     Ok(())
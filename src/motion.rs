@@ -0,0 +1,163 @@
+use std::ffi::CString;
+
+use anyhow::Result;
+use evdev::{
+    AbsoluteAxisCode, AttributeSet, EventStream, EventType, InputEvent, MiscCode, PropType,
+    UinputAbsSetup, enumerate, uinput::VirtualDevice,
+};
+
+use crate::{
+    capabilities::DeviceCapabilities,
+    input::MotionSample,
+    printdbg,
+    supported_actions::{MOTION_AXES, motion_axis_by_code},
+};
+
+// Controllers with motion sensors report them on a device of their own, separately from their
+// buttons and axes. Each reading the device reports is sent to the server as soon as it's
+// complete, and replayed there on a motion device that sits alongside the controller's virtual
+// joystick, the way the controller's own devices do.
+
+// Returns the path games use to tell which devices belong to the same controller
+pub fn phys(name: &str) -> Result<CString> {
+    Ok(CString::new(format!("starboard/{name}"))?)
+}
+
+// The motion sensors of the controller the client forwards
+pub struct MotionSensors {
+    stream: EventStream,
+    capabilities: DeviceCapabilities,
+    tracker: MotionTracker,
+}
+
+impl MotionSensors {
+    // Looks for the motion sensors of the controller named `gamepad`. Controllers name their
+    // motion sensors after themselves, like "Steam Deck Motion Sensors". Other accelerometers,
+    // like a laptop's, are left alone.
+    pub fn find(gamepad: Option<&str>) -> Result<Option<Self>> {
+        let Some(gamepad) = gamepad else {
+            return Ok(None);
+        };
+        let device = enumerate().map(|(_, device)| device).find(|device| {
+            device.properties().contains(PropType::ACCELEROMETER)
+                && device.name().is_some_and(|name| name.starts_with(gamepad))
+        });
+        let Some(device) = device else {
+            return Ok(None);
+        };
+        printdbg!(
+            "Forwarding motion from: `{}`",
+            (device.name().unwrap_or_default())
+        );
+        let capabilities = DeviceCapabilities::new([], DeviceCapabilities::read(&device)?.axes());
+        Ok(Some(Self {
+            stream: device.into_event_stream()?,
+            capabilities,
+            tracker: MotionTracker::new(),
+        }))
+    }
+
+    // Returns the axes of the motion sensors, which the server builds its motion device from
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
+
+    // Waits for the motion sensors to finish their next reading
+    pub async fn next_sample(&mut self) -> Result<MotionSample> {
+        loop {
+            let event = self.stream.next_event().await?;
+            if let Some(sample) = self.tracker.update(&event) {
+                return Ok(sample);
+            }
+        }
+    }
+}
+
+// Follows the readings of a motion device through the events it reports, since each reading is
+// spread across an event for every axis
+pub struct MotionTracker {
+    sample: MotionSample,
+    changed: bool,
+}
+
+impl MotionTracker {
+    pub fn new() -> Self {
+        Self {
+            sample: MotionSample::default(),
+            changed: false,
+        }
+    }
+
+    // Records `event`, returning the reading it completes, if it completes one
+    pub fn update(&mut self, event: &InputEvent) -> Option<MotionSample> {
+        match event.event_type() {
+            EventType::ABSOLUTE => {
+                let axis = motion_axis_by_code(AbsoluteAxisCode(event.code()))?;
+                self.sample.values[axis.id as usize] = event.value();
+                self.changed = true;
+            }
+            EventType::MISC if event.code() == MiscCode::MSC_TIMESTAMP.0 => {
+                self.sample.timestamp = event.value();
+            }
+            EventType::SYNCHRONIZATION if std::mem::take(&mut self.changed) => {
+                return Some(self.sample);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+// The motion device that goes with a controller's virtual joystick
+pub struct VirtualMotion {
+    raw: VirtualDevice,
+    axes: Vec<AbsoluteAxisCode>, // The motion axes the controller has
+}
+
+impl VirtualMotion {
+    // Builds a motion device with each of the motion axes in `capabilities`, in the range the
+    // controller reports them in, so that samples go through untouched
+    pub fn new(name: &str, capabilities: &DeviceCapabilities) -> Result<Self> {
+        let properties: AttributeSet<PropType> = [PropType::ACCELEROMETER].into_iter().collect();
+        let misc: AttributeSet<MiscCode> = [MiscCode::MSC_TIMESTAMP].into_iter().collect();
+        let motion_name = format!("{name} Motion Sensors");
+        let phys = phys(name)?;
+        let mut builder = VirtualDevice::builder()?
+            .name(&motion_name)
+            .with_phys(&phys)?
+            .with_properties(&properties)?
+            .with_msc(&misc)?;
+        let mut axes = Vec::new();
+        for (axis, range) in capabilities.axes() {
+            if motion_axis_by_code(axis).is_none() {
+                continue;
+            }
+            builder =
+                builder.with_absolute_axis(&UinputAbsSetup::new(axis, range.to_abs_info()))?;
+            axes.push(axis);
+        }
+        Ok(Self {
+            raw: builder.build()?,
+            axes,
+        })
+    }
+
+    // Replays `sample` on the device
+    pub fn send(&mut self, sample: &MotionSample) -> Result<()> {
+        let mut events: Vec<InputEvent> = MOTION_AXES
+            .iter()
+            .filter(|axis| self.axes.contains(&axis.code))
+            .map(|axis| {
+                let value = sample.values[axis.id as usize];
+                InputEvent::new(EventType::ABSOLUTE.0, axis.code.0, value)
+            })
+            .collect();
+        events.push(InputEvent::new(
+            EventType::MISC.0,
+            MiscCode::MSC_TIMESTAMP.0,
+            sample.timestamp,
+        ));
+        self.raw.emit(&events)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    sync::OnceLock,
+};
+
+use anyhow::{Result, anyhow};
+use if_addrs::{IfAddr, get_if_addrs};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{UdpSocket, lookup_host};
+
+use crate::printdbg;
+
+// Every Starboard socket is a dual-stack IPv6 socket, so IPv4 and IPv6 peers are handled the same
+// way. IPv4 peers show up as IPv4-mapped IPv6 addresses (i.e. ::ffff:192.168.1.10). Servers are
+// discovered with link-local multicast rather than IPv4 broadcast, which works on IPv6-only
+// networks and on IPv4 networks alike, since every interface has an IPv6 link-local address.
+// Hosts that have IPv6 turned off altogether (i.e. booted with ipv6.disable=1) fall back to IPv4
+// sockets, and to discovering servers with IPv4 broadcast.
+
+// The link-local multicast group servers listen on for presence broadcasts and pairing requests
+pub const DISCOVERY_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5354, 0x4244);
+
+// Returns true if IPv6 sockets can be created. This is only checked once, and the first check
+// says why Starboard falls back to IPv4 if it has to.
+fn ipv6_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(
+        || match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!(
+                    "IPv6 isn't available ({e}); falling back to IPv4, where only IPv4 peers can \
+                     be reached and servers are discovered with broadcast"
+                );
+                false
+            }
+        },
+    )
+}
+
+// Binds a UDP socket to `port` on every address, accepting both IPv6 and IPv4 traffic. Without
+// IPv6, the socket only takes IPv4 traffic.
+pub fn bind_dual_stack(port: u16) -> Result<UdpSocket> {
+    if !ipv6_available() {
+        return bind_ipv4(port);
+    }
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// Binds an IPv4 UDP socket to `port` on every address, for hosts without IPv6. It can broadcast,
+// since that's how servers are discovered without IPv6.
+pub fn bind_ipv4(port: u16) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// Returns the indices of the interfaces link-local multicast can be sent on, which are those with
+// an IPv6 link-local address
+pub fn multicast_interfaces() -> Result<Vec<u32>> {
+    let mut indices: Vec<u32> = get_if_addrs()?
+        .into_iter()
+        .filter(|interface| {
+            matches!(interface.addr, IfAddr::V6(_))
+                && interface.is_link_local()
+                && !interface.is_loopback()
+        })
+        .filter_map(|interface| interface.index)
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    Ok(indices)
+}
+
+// Joins the discovery group on every interface that can carry it. An interface that can't join
+// isn't fatal, since controllers can still be pointed at the server by address. Without IPv6
+// there's no group to join, since broadcasts reach every IPv4 socket.
+pub fn join_discovery_group(sock: &UdpSocket) -> Result<()> {
+    if !ipv6_available() {
+        return Ok(());
+    }
+    for index in multicast_interfaces()? {
+        if let Err(e) = sock.join_multicast_v6(&DISCOVERY_GROUP, index) {
+            printdbg!(
+                "Couldn't join the discovery group on interface {}: {}",
+                index,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+// Returns where to send a message that every server on the local network should receive: the
+// discovery group on every interface, or the broadcast address of every interface without IPv6
+pub fn discovery_addrs(port: u16) -> Result<Vec<SocketAddr>> {
+    if !ipv6_available() {
+        return broadcast_addrs(port);
+    }
+    Ok(multicast_interfaces()?
+        .into_iter()
+        .map(|index| SocketAddrV6::new(DISCOVERY_GROUP, port, 0, index).into())
+        .collect())
+}
+
+// Returns the IPv4 broadcast address of every interface that has one
+pub fn broadcast_addrs(port: u16) -> Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = get_if_addrs()?
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter_map(|interface| match interface.addr {
+            IfAddr::V4(v4) => v4.broadcast,
+            IfAddr::V6(_) => None,
+        })
+        .map(|broadcast| SocketAddr::new(broadcast.into(), port))
+        .collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+// Converts an IPv4 address into the IPv4-mapped IPv6 address a dual-stack socket sends to. Without
+// IPv6, sockets only take IPv4 addresses, so IPv4-mapped addresses are turned back into IPv4 ones
+// instead.
+pub fn to_dual_stack(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if ipv6_available() => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        SocketAddr::V6(v6) if !ipv6_available() => {
+            SocketAddr::new(v6.ip().to_canonical(), v6.port())
+        }
+        _ => addr,
+    }
+}
+
+// Resolves a `host:port` given on the command line. The host may be a name, an IPv4 address or
+// a bracketed IPv6 address, including link-local addresses with a zone (i.e.
+// [fe80::1%wlan0]:61000).
+pub async fn resolve_addr(addr: &str) -> Result<SocketAddr> {
+    if let Some(scoped) = parse_scoped(addr)? {
+        return Ok(scoped);
+    }
+    let resolved = lookup_host(addr)
+        .await
+        .map_err(|e| anyhow!("Couldn't resolve '{addr}': {e}"))?
+        .next()
+        .ok_or_else(|| anyhow!("'{addr}' didn't resolve to any address"))?;
+    Ok(to_dual_stack(resolved))
+}
+
+// Parses an IPv6 address with a zone, which may be an interface name or index. Returns `None` if
+// `addr` doesn't have a zone.
+fn parse_scoped(addr: &str) -> Result<Option<SocketAddr>> {
+    let Some((host, port)) = addr
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+    else {
+        return Ok(None);
+    };
+    let Some((ip, zone)) = host.split_once('%') else {
+        return Ok(None);
+    };
+    let ip: Ipv6Addr = ip
+        .parse()
+        .map_err(|_| anyhow!("'{ip}' isn't an IPv6 address"))?;
+    let port: u16 = port.parse().map_err(|_| anyhow!("'{port}' isn't a port"))?;
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => get_if_addrs()?
+            .into_iter()
+            .find(|interface| interface.name == zone)
+            .and_then(|interface| interface.index)
+            .ok_or_else(|| anyhow!("There's no network interface called '{zone}'"))?,
+    };
+    Ok(Some(SocketAddrV6::new(ip, port, 0, scope_id).into()))
+}
@@ -0,0 +1,670 @@
+use core::{
+    fmt::{self, Debug, Display, Formatter},
+    time::Duration,
+};
+use std::{net::SocketAddr, time::Instant};
+
+use anyhow::{Result, bail};
+use bincode::{Decode, Encode};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    datagram::{Message, MessageKind},
+    string::StarboardString,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TAG_LEN: usize = 32;
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 32;
+
+// The number of digits in the PIN shown during pairing
+pub const PIN_DIGITS: u32 = 6;
+
+// How long a handshake the server is in the middle of holds off other pairing requests, in case the
+// client gave up on it
+const PENDING_TIMEOUT: Duration = Duration::from_secs(180);
+
+// Secret shared by a client and a server once they've been paired. Every packet a paired client
+// sends is authenticated with it.
+#[derive(Copy, Clone, PartialEq, Eq, Decode, Encode)]
+pub struct PairingKey([u8; KEY_LEN]);
+
+impl PairingKey {
+    // Computes the MAC for a datagram sent by the client with the ID `id`
+    pub fn sign(&self, id: u64, raw: &[u8]) -> [u8; TAG_LEN] {
+        self.mac(id, raw).finalize().into_bytes().into()
+    }
+
+    // Checks `tag` against the MAC for a datagram sent by the client with the ID `id`
+    pub fn verify(&self, id: u64, raw: &[u8], tag: &[u8; TAG_LEN]) -> bool {
+        self.mac(id, raw).verify_slice(tag).is_ok()
+    }
+
+    fn mac(&self, id: u64, raw: &[u8]) -> HmacSha256 {
+        // Safety of using `unwrap()`: HMAC accepts keys of any length
+        HmacSha256::new_from_slice(&self.0)
+            .unwrap()
+            .chain_update(id.to_le_bytes())
+            .chain_update(raw)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+// Keys should never end up in logs
+impl Debug for PairingKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PairingKey(..)")
+    }
+}
+
+// A framed datagram wrapped together with the ID of the client that sent it and a MAC over both
+#[derive(Debug, Decode, Encode)]
+pub struct AuthenticatedPacket {
+    id: u64,
+    inner: Vec<u8>,
+    tag: [u8; TAG_LEN],
+}
+
+impl Message for AuthenticatedPacket {
+    const KIND: MessageKind = MessageKind::Authenticated;
+}
+
+impl AuthenticatedPacket {
+    pub fn seal(id: u64, inner: Vec<u8>, key: &PairingKey) -> Self {
+        let tag = key.sign(id, &inner);
+        Self { id, inner, tag }
+    }
+
+    // Returns the ID of the client that claims to have sent the packet
+    pub fn id(&self) -> &u64 {
+        &self.id
+    }
+
+    // Returns the wrapped datagram if the MAC is valid for `key`
+    pub fn open(self, key: &PairingKey) -> Result<Vec<u8>> {
+        if !key.verify(self.id, &self.inner, &self.tag) {
+            bail!(
+                "Rejected a packet from controller {} with an invalid MAC",
+                self.id
+            );
+        }
+        Ok(self.inner)
+    }
+}
+
+// Pairing is a numeric comparison handshake. The client and server exchange X25519 public keys,
+// then the server commits to a random nonce before either nonce is revealed. Both sides derive the
+// key, a short PIN and a short code from the keys and nonces. The server shows its PIN and the user
+// types it into the client, which only finishes pairing if it matches its own. The client then
+// shows its code and the operator types it into the server, which only stores the key if it
+// matches its own, so that a host on the network can't be approved in place of the controller the
+// operator is holding. A man in the middle ends up with a different PIN and code on each side and,
+// because of the commitment, can't search for keys that would make them agree.
+
+// Sent by the client to start pairing
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PairRequest {
+    pub id: u64,
+    pub name: StarboardString,
+    pub public_key: [u8; 32],
+}
+
+impl Message for PairRequest {
+    const KIND: MessageKind = MessageKind::PairRequest;
+}
+
+// The server's reply to a `PairRequest`, committing to its nonce
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PairCommit {
+    pub server_id: u64,
+    pub server_name: StarboardString,
+    pub public_key: [u8; 32],
+    pub commitment: [u8; 32],
+}
+
+impl Message for PairCommit {
+    const KIND: MessageKind = MessageKind::PairCommit;
+}
+
+// The client's nonce, sent once it has the server's commitment
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PairNonce {
+    pub id: u64,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl Message for PairNonce {
+    const KIND: MessageKind = MessageKind::PairNonce;
+}
+
+// The server's nonce, which the client checks against the commitment
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PairReveal {
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl Message for PairReveal {
+    const KIND: MessageKind = MessageKind::PairReveal;
+}
+
+// Proves to the other side that the sender derived the same key. The client only sends it once the
+// user has confirmed the PIN, and the server answers with its own.
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PairConfirm {
+    pub id: u64,
+    pub tag: [u8; TAG_LEN],
+}
+
+impl Message for PairConfirm {
+    const KIND: MessageKind = MessageKind::PairConfirm;
+}
+
+// Which side of the handshake a confirmation comes from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+// Everything both sides know once the nonces have been exchanged
+struct Handshake {
+    id: u64,
+    server_id: u64,
+    client_public_key: [u8; 32],
+    server_public_key: [u8; 32],
+    client_nonce: [u8; NONCE_LEN],
+    server_nonce: [u8; NONCE_LEN],
+    shared_secret: [u8; 32],
+}
+
+impl Handshake {
+    fn transcript(&self) -> Vec<u8> {
+        let mut transcript = Vec::with_capacity(8 * 2 + 32 * 2 + NONCE_LEN * 2);
+        transcript.extend_from_slice(&self.id.to_le_bytes());
+        transcript.extend_from_slice(&self.server_id.to_le_bytes());
+        transcript.extend_from_slice(&self.client_public_key);
+        transcript.extend_from_slice(&self.server_public_key);
+        transcript.extend_from_slice(&self.client_nonce);
+        transcript.extend_from_slice(&self.server_nonce);
+        transcript
+    }
+
+    fn key(&self) -> PairingKey {
+        let hkdf = Hkdf::<Sha256>::new(Some(&self.transcript()), &self.shared_secret);
+        let mut key = [0; KEY_LEN];
+        // Safety of using `unwrap()`: 32 bytes is well under HKDF's maximum output length
+        hkdf.expand(b"starboard pairing key", &mut key).unwrap();
+        PairingKey(key)
+    }
+
+    // The PIN the server shows, which the user enters on the client
+    fn pin(&self) -> u32 {
+        self.digits(b"starboard pairing pin")
+    }
+
+    // The code the client shows, which the operator enters on the server. Only the two ends of the
+    // handshake can work it out, so a controller the operator isn't holding can't be approved.
+    fn code(&self) -> u32 {
+        self.digits(b"starboard pairing code")
+    }
+
+    fn digits(&self, label: &[u8]) -> u32 {
+        let digest = Sha256::new()
+            .chain_update(label)
+            .chain_update(self.transcript())
+            .finalize();
+        let value = u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        value % 10u32.pow(PIN_DIGITS)
+    }
+
+    fn confirmation(&self, role: Role) -> [u8; TAG_LEN] {
+        let label: &[u8] = match role {
+            Role::Client => b"starboard client confirm",
+            Role::Server => b"starboard server confirm",
+        };
+        let key = self.key();
+        // Safety of using `unwrap()`: HMAC accepts keys of any length
+        HmacSha256::new_from_slice(key.as_bytes())
+            .unwrap()
+            .chain_update(label)
+            .chain_update(self.transcript())
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+}
+
+fn commitment(nonce: &[u8; NONCE_LEN], public_key: &[u8; 32], peer: &[u8; 32]) -> [u8; 32] {
+    // Safety of using `unwrap()`: HMAC accepts keys of any length
+    HmacSha256::new_from_slice(nonce)
+        .unwrap()
+        .chain_update(public_key)
+        .chain_update(peer)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// Formats a PIN with its leading zeroes
+pub fn format_pin(pin: u32) -> String {
+    format!("{:0width$}", pin, width = PIN_DIGITS as usize)
+}
+
+// The client's side of the pairing handshake
+pub struct ClientPairing {
+    id: u64,
+    secret: Option<EphemeralSecret>,
+    public_key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    commit: Option<PairCommit>,
+    handshake: Option<Handshake>,
+}
+
+impl ClientPairing {
+    pub fn new(id: u64) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret).to_bytes();
+        Self {
+            id,
+            secret: Some(secret),
+            public_key,
+            nonce: random_nonce(),
+            commit: None,
+            handshake: None,
+        }
+    }
+
+    pub fn request(&self, name: StarboardString) -> PairRequest {
+        PairRequest {
+            id: self.id,
+            name,
+            public_key: self.public_key,
+        }
+    }
+
+    // Records the server's commitment and returns the client's nonce
+    pub fn handle_commit(&mut self, commit: PairCommit) -> PairNonce {
+        self.commit = Some(commit);
+        PairNonce {
+            id: self.id,
+            nonce: self.nonce,
+        }
+    }
+
+    // Checks the server's nonce against its commitment and returns the PIN the server should be
+    // showing
+    pub fn handle_reveal(&mut self, reveal: PairReveal) -> Result<u32> {
+        let (Some(commit), Some(secret)) = (self.commit, self.secret.take()) else {
+            bail!("Received the server's nonce before its commitment");
+        };
+        if commitment(&reveal.nonce, &commit.public_key, &self.public_key) != commit.commitment {
+            bail!(
+                "The server's nonce doesn't match its commitment; pairing has been tampered with"
+            );
+        }
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(commit.public_key));
+        let handshake = Handshake {
+            id: self.id,
+            server_id: commit.server_id,
+            client_public_key: self.public_key,
+            server_public_key: commit.public_key,
+            client_nonce: self.nonce,
+            server_nonce: reveal.nonce,
+            shared_secret: shared_secret.to_bytes(),
+        };
+        let pin = handshake.pin();
+        self.handshake = Some(handshake);
+        Ok(pin)
+    }
+
+    // Returns the code the operator has to enter on the server to approve the pairing
+    pub fn code(&self) -> Result<u32> {
+        let Some(handshake) = &self.handshake else {
+            bail!("Can't show the pairing code before the nonces have been exchanged");
+        };
+        Ok(handshake.code())
+    }
+
+    // Returns the confirmation to send once the user has entered a matching PIN
+    pub fn confirm(&self) -> Result<PairConfirm> {
+        let Some(handshake) = &self.handshake else {
+            bail!("Can't confirm pairing before the nonces have been exchanged");
+        };
+        Ok(PairConfirm {
+            id: self.id,
+            tag: handshake.confirmation(Role::Client),
+        })
+    }
+
+    // Checks the server's confirmation and returns the server's ID and name and the shared key
+    pub fn finish(&self, confirm: PairConfirm) -> Result<(u64, StarboardString, PairingKey)> {
+        let (Some(commit), Some(handshake)) = (&self.commit, &self.handshake) else {
+            bail!("Can't finish pairing before the nonces have been exchanged");
+        };
+        if confirm.tag != handshake.confirmation(Role::Server) {
+            bail!("The server derived a different key; pairing failed");
+        }
+        Ok((commit.server_id, commit.server_name, handshake.key()))
+    }
+}
+
+// What the server's pairing page shows
+#[derive(Debug, Clone)]
+pub enum PairingStatus {
+    Closed,
+    Waiting,
+    // `entered` is as much of the controller's code as the operator has typed, and `mismatch` is
+    // set if the last code typed was wrong. `repairing` is set if the controller has been paired
+    // before, and pairing replaces its key.
+    AwaitingPin {
+        name: StarboardString,
+        pin: u32,
+        entered: String,
+        mismatch: bool,
+        repairing: bool,
+    },
+    Approved {
+        name: StarboardString,
+    },
+    Paired {
+        name: StarboardString,
+    },
+    Failed(String),
+}
+
+impl Display for PairingStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "Pairing is closed"),
+            Self::Waiting => write!(
+                f,
+                "Waiting for a controller. Run `starboard pair` on the device you want to pair."
+            ),
+            Self::AwaitingPin {
+                name,
+                pin,
+                entered,
+                mismatch,
+                repairing,
+            } => {
+                if *mismatch {
+                    write!(f, "That code doesn't match the one {name} shows. ")?;
+                }
+                write!(
+                    f,
+                    "Enter PIN {} on {}, then type the code it shows to approve pairing, or press \
+                     N to refuse it.",
+                    format_pin(*pin),
+                    name
+                )?;
+                if *repairing {
+                    write!(f, " {name} is already paired; approving replaces its key.")?;
+                }
+                write!(f, " Code: {entered:_<width$}", width = PIN_DIGITS as usize)
+            }
+            Self::Approved { name } => write!(f, "Approved; waiting for {name} to confirm the PIN"),
+            Self::Paired { name } => write!(f, "Paired with {name}"),
+            Self::Failed(reason) => write!(f, "Pairing failed: {reason}"),
+        }
+    }
+}
+
+// A handshake the server is in the middle of
+struct PendingPairing {
+    addr: SocketAddr,
+    id: u64,
+    name: StarboardString,
+    client_public_key: [u8; 32],
+    server_public_key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    shared_secret: [u8; 32],
+    commit: PairCommit, // Sent again if the request is
+    handshake: Option<Handshake>,
+    repairing: bool, // Whether the client already has a key, which pairing replaces
+    approved: bool,  // Whether the operator has approved the pairing
+    started: Instant,
+}
+
+// A client the server has finished pairing with
+#[derive(Debug)]
+pub struct Paired {
+    pub id: u64,
+    pub key: PairingKey,
+    pub confirm: PairConfirm, // The server's confirmation, to send back to the client
+    pub repairing: bool,      // Whether the key replaces one the client already had
+}
+
+// The server's side of the pairing handshake. Requests are only answered while pairing is open,
+// which is while the pairing page is shown in the UI, and only one handshake is answered at a
+// time.
+pub struct ServerPairing {
+    server_id: u64,
+    server_name: StarboardString,
+    status: PairingStatus,
+    pending: Option<PendingPairing>,
+}
+
+impl ServerPairing {
+    pub fn new(server_id: u64, server_name: StarboardString) -> Self {
+        Self {
+            server_id,
+            server_name,
+            status: PairingStatus::Closed,
+            pending: None,
+        }
+    }
+
+    pub fn status(&self) -> &PairingStatus {
+        &self.status
+    }
+
+    pub fn open(&mut self) {
+        self.status = PairingStatus::Waiting;
+        self.pending = None;
+    }
+
+    pub fn close(&mut self) {
+        self.status = PairingStatus::Closed;
+        self.pending = None;
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(self.status, PairingStatus::Closed)
+    }
+
+    // Starts a handshake with the client at `addr` and returns the server's commitment. `paired` is
+    // whether the client already has a key. A request that arrives while another client is
+    // pairing is refused, unless that handshake has been abandoned.
+    pub fn handle_request(
+        &mut self,
+        addr: SocketAddr,
+        request: PairRequest,
+        paired: bool,
+    ) -> Result<PairCommit> {
+        if !self.is_open() {
+            bail!(
+                "Ignoring a pairing request from {}; pairing isn't open",
+                request.name
+            );
+        }
+        if let Some(pending) = &self.pending {
+            // The client sends its request again if the commitment got lost
+            if pending.addr == addr
+                && pending.id == request.id
+                && pending.client_public_key == request.public_key
+                && pending.handshake.is_none()
+            {
+                return Ok(pending.commit);
+            }
+            if pending.started.elapsed() < PENDING_TIMEOUT {
+                bail!(
+                    "Ignoring a pairing request from {}; already pairing with {}",
+                    request.name,
+                    pending.name
+                );
+            }
+        }
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_public_key = PublicKey::from(&secret).to_bytes();
+        let nonce = random_nonce();
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(request.public_key));
+        let commit = PairCommit {
+            server_id: self.server_id,
+            server_name: self.server_name,
+            public_key: server_public_key,
+            commitment: commitment(&nonce, &server_public_key, &request.public_key),
+        };
+        self.pending = Some(PendingPairing {
+            addr,
+            id: request.id,
+            name: request.name,
+            client_public_key: request.public_key,
+            server_public_key,
+            nonce,
+            shared_secret: shared_secret.to_bytes(),
+            commit,
+            handshake: None,
+            repairing: paired,
+            approved: false,
+            started: Instant::now(),
+        });
+        self.status = PairingStatus::Waiting;
+        Ok(commit)
+    }
+
+    // Records the client's nonce, shows the PIN and returns the server's nonce
+    pub fn handle_nonce(&mut self, addr: SocketAddr, nonce: PairNonce) -> Result<PairReveal> {
+        let server_id = self.server_id;
+        let pending = self.pending_from(addr, nonce.id)?;
+        let reveal = PairReveal {
+            nonce: pending.nonce,
+        };
+        // The client sends its nonce again if the reveal got lost, but it can't pick another one
+        // once it has seen the server's
+        if let Some(handshake) = &pending.handshake {
+            if handshake.client_nonce != nonce.nonce {
+                bail!("{} changed its nonce during pairing", pending.name);
+            }
+            return Ok(reveal);
+        }
+        let handshake = Handshake {
+            id: pending.id,
+            server_id,
+            client_public_key: pending.client_public_key,
+            server_public_key: pending.server_public_key,
+            client_nonce: nonce.nonce,
+            server_nonce: pending.nonce,
+            shared_secret: pending.shared_secret,
+        };
+        let name = pending.name;
+        let pin = handshake.pin();
+        let repairing = pending.repairing;
+        pending.handshake = Some(handshake);
+        self.status = PairingStatus::AwaitingPin {
+            name,
+            pin,
+            entered: String::new(),
+            mismatch: false,
+            repairing,
+        };
+        Ok(reveal)
+    }
+
+    // Takes the next digit of the code the controller being paired shows. Once the whole code has
+    // been typed, the pairing is approved if it matches, so that it finishes once the client
+    // confirms. Nothing can be approved before the PIN is shown.
+    pub fn enter_digit(&mut self, digit: char) {
+        let (
+            Some(pending),
+            PairingStatus::AwaitingPin {
+                entered, mismatch, ..
+            },
+        ) = (&mut self.pending, &mut self.status)
+        else {
+            return;
+        };
+        let Some(handshake) = &pending.handshake else {
+            return;
+        };
+        if !digit.is_ascii_digit() {
+            return;
+        }
+        entered.push(digit);
+        *mismatch = false;
+        if entered.len() < PIN_DIGITS as usize {
+            return;
+        }
+        if *entered == format_pin(handshake.code()) {
+            pending.approved = true;
+            self.status = PairingStatus::Approved { name: pending.name };
+        } else {
+            entered.clear();
+            *mismatch = true;
+        }
+    }
+
+    // Refuses the pairing in progress, which lets other controllers pair
+    pub fn refuse(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.status = PairingStatus::Failed(format!("Refused to pair with {}", pending.name));
+        }
+    }
+
+    // Checks the client's confirmation. Returns the newly paired client once the operator has
+    // approved the pairing, and `None` until then, leaving the client to send its confirmation
+    // again.
+    pub fn handle_confirm(
+        &mut self,
+        addr: SocketAddr,
+        confirm: PairConfirm,
+    ) -> Result<Option<Paired>> {
+        let pending = self.pending_from(addr, confirm.id)?;
+        let Some(handshake) = &pending.handshake else {
+            bail!("Received a pairing confirmation before the nonces were exchanged");
+        };
+        let name = pending.name;
+        if confirm.tag != handshake.confirmation(Role::Client) {
+            self.pending = None;
+            self.status = PairingStatus::Failed(format!("{name} derived a different key"));
+            bail!("Pairing with {name} failed; the confirmation didn't match");
+        }
+        if !pending.approved {
+            return Ok(None);
+        }
+        let paired = Paired {
+            id: handshake.id,
+            key: handshake.key(),
+            confirm: PairConfirm {
+                id: handshake.id,
+                tag: handshake.confirmation(Role::Server),
+            },
+            repairing: pending.repairing,
+        };
+        self.pending = None;
+        self.status = PairingStatus::Paired { name };
+        Ok(Some(paired))
+    }
+
+    // Returns the pending handshake if it belongs to the client with ID `id` at `addr`
+    fn pending_from(&mut self, addr: SocketAddr, id: u64) -> Result<&mut PendingPairing> {
+        match &mut self.pending {
+            Some(pending) if pending.addr == addr && pending.id == id => Ok(pending),
+            _ => bail!("Received a pairing message from {addr} without a pairing request"),
+        }
+    }
+}
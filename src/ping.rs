@@ -0,0 +1,64 @@
+use bincode::{Decode, Encode};
+
+use crate::datagram::{Message, MessageKind};
+
+// The server measures the latency to each controller by pinging it and timing the answer, so the
+// round trip only depends on the server's clock. The client stamps its answer with its own clock,
+// which gives an estimate of how far apart the two clocks are, assuming the trip takes as long each
+// way. Every timestamp is in milliseconds.
+
+// Sent by the server to a controller's serial address
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PingPacket {
+    pub id: u64,
+    pub nonce: u64, // Tells the pings apart, so that only an answer to one that was sent counts
+    pub sent_at: i64, // The server's clock when the ping was sent
+}
+
+impl Message for PingPacket {
+    const KIND: MessageKind = MessageKind::Ping;
+}
+
+// Sent by the client as soon as it receives a `PingPacket`
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct PongPacket {
+    pub id: u64,
+    pub nonce: u64,        // Echoed from the ping
+    pub ping_sent_at: i64, // Echoed from the ping
+    pub client_time: i64,  // The client's clock when it answered
+}
+
+impl Message for PongPacket {
+    const KIND: MessageKind = MessageKind::Pong;
+}
+
+impl PongPacket {
+    pub fn answer(ping: &PingPacket) -> Self {
+        Self {
+            id: ping.id,
+            nonce: ping.nonce,
+            ping_sent_at: ping.sent_at,
+            client_time: chrono::Local::now().timestamp_millis(),
+        }
+    }
+}
+
+// A latency measurement taken from a ping and its pong
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RoundTrip {
+    pub rtt_ms: i64,
+    pub clock_offset_ms: i64, // How far the client's clock is ahead of the server's
+}
+
+impl RoundTrip {
+    // Measures the round trip of `pong`, which the server received at `received_at`
+    pub fn measure(pong: &PongPacket, received_at: i64) -> Self {
+        let rtt_ms = (received_at - pong.ping_sent_at).max(0);
+        // The client answered halfway through the round trip, as far as the server can tell
+        let clock_offset_ms = pong.client_time - (pong.ping_sent_at + rtt_ms / 2);
+        Self {
+            rtt_ms,
+            clock_offset_ms,
+        }
+    }
+}
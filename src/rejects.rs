@@ -0,0 +1,106 @@
+use core::fmt::{self, Display, Formatter};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+};
+
+use chrono::{DateTime, Local};
+
+use crate::datagram::format_addr;
+
+// Datagrams the server can't make sense of, or won't accept, are dropped rather than allowed to
+// stop the loop that received them. Each one is counted against the host that sent it, and the
+// most recent are kept so the UI can show what's going wrong.
+
+// How many rejected datagrams are kept for the UI
+pub const RECENT_REJECTS: usize = 8;
+
+// How many hosts rejects are counted for. Past that, the host with the fewest rejects is forgotten
+// to make room, so that a flood of spoofed sources can't use up the server's memory.
+const MAX_SOURCES: usize = 1024;
+
+// The port a datagram was received on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Port {
+    Serial,
+    DeviceSearch,
+}
+
+impl Display for Port {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let string = match self {
+            Self::Serial => "serial",
+            Self::DeviceSearch => "device search",
+        };
+        write!(f, "{string}")
+    }
+}
+
+// A datagram the server rejected
+#[derive(Debug, Clone)]
+pub struct Reject {
+    pub at: DateTime<Local>,
+    pub port: Port,
+    pub source: SocketAddr,
+    pub reason: String,
+}
+
+impl Display for Reject {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.at.format("%H:%M:%S"),
+            self.port,
+            format_addr(self.source),
+            self.reason
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct RejectLog {
+    counts: HashMap<IpAddr, u64>, // Rejects by host, since the port changes when a client restarts
+    recent: VecDeque<Reject>,
+}
+
+impl RejectLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Records that a datagram from `source` on `port` was rejected for `reason`
+    pub fn record(&mut self, port: Port, source: SocketAddr, reason: String) {
+        let host = source.ip().to_canonical();
+        if !self.counts.contains_key(&host)
+            && self.counts.len() >= MAX_SOURCES
+            && let Some(quietest) = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(host, _)| *host)
+        {
+            self.counts.remove(&quietest);
+        }
+        *self.counts.entry(host).or_insert(0) += 1;
+        if self.recent.len() == RECENT_REJECTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Reject {
+            at: Local::now(),
+            port,
+            source,
+            reason,
+        });
+    }
+
+    // Returns how many datagrams from `host` have been rejected
+    pub fn count(&self, host: IpAddr) -> u64 {
+        self.counts.get(&host.to_canonical()).copied().unwrap_or(0)
+    }
+
+    // Returns the most recent rejects, newest first
+    pub fn recent(&self) -> impl Iterator<Item = &Reject> {
+        self.recent.iter().rev()
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Result, anyhow, bail};
+use evdev::{AbsoluteAxisCode, KeyCode};
+
+use crate::{
+    capabilities::{AxisRange, DeviceCapabilities},
+    input::{FromID, IntoID, StarboardInput},
+    storage::data_dir,
+    supported_actions::{SUPPORTED_BUTTONS, axis_by_code},
+};
+
+// A profile changes which buttons and axes the controller's inputs come out as on the server.
+// Profiles are written by hand, one file each, in the profiles directory. Each line maps one of the
+// controller's inputs to one of the joystick's:
+//
+//   # Swap A and B, and invert the left stick
+//   BTN_SOUTH -> BTN_EAST
+//   BTN_EAST -> BTN_SOUTH
+//   ABS_Y -> ABS_Y invert
+//   BTN_DPAD_LEFT -> ABS_X min
+//   ABS_HAT2Y -> BTN_TL 25%
+//
+// A button mapped to an axis pushes it to its maximum, or its minimum, while held. An axis mapped
+// to a button presses it once the axis is pushed past the threshold from rest, towards its minimum
+// for negative thresholds. Inputs that aren't mapped come out as themselves, unless something is
+// mapped to them, in which case only the mappings decide what they are. A button several inputs
+// are mapped to is pressed while any of them is, and an axis several inputs are mapped to is pushed
+// as far as the furthest of them pushes it.
+
+// The extension profile files are recognized by
+const PROFILE_EXTENSION: &str = "profile";
+
+// How far an axis mapped to a button has to be pushed when the profile doesn't say
+const DEFAULT_THRESHOLD: f64 = 0.5;
+
+// Returns the directory profiles are loaded from when none is given (i.e.
+// ~/.local/share/starboard/profiles)
+pub fn default_profiles_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("profiles"))
+}
+
+// Loads every profile in `dir`, in order of name, along with why each profile that couldn't be
+// loaded was skipped. A directory that doesn't exist holds no profiles.
+pub fn load_profiles(dir: &Path) -> Result<(Vec<Arc<Profile>>, Vec<String>)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), Vec::new())),
+        Err(e) => return Err(e.into()),
+    };
+    let mut profiles = Vec::new();
+    let mut skipped = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != PROFILE_EXTENSION)
+        {
+            continue;
+        }
+        match Profile::load(&path) {
+            Ok(profile) => profiles.push(Arc::new(profile)),
+            Err(e) => skipped.push(format!("Skipped profile {e}")),
+        }
+    }
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    skipped.sort();
+    Ok((profiles, skipped))
+}
+
+// Returns the profile after `current` in `profiles`, for cycling through them. No profile comes
+// before the first and after the last.
+pub fn next_profile(
+    profiles: &[Arc<Profile>],
+    current: Option<&Arc<Profile>>,
+) -> Option<Arc<Profile>> {
+    let next = match current {
+        Some(current) => profiles
+            .iter()
+            .position(|profile| profile.name == current.name)
+            .map_or(0, |index| index + 1),
+        None => 0,
+    };
+    profiles.get(next).cloned()
+}
+
+// Returns true if an axis in `range` at `value` is pushed past `threshold` of the way from rest,
+// towards its maximum, or towards its minimum if `threshold` is negative
+fn is_pushed(range: &AxisRange, value: i32, threshold: f64) -> bool {
+    let rest = f64::from(range.rest());
+    let end = match threshold < 0.0 {
+        true => f64::from(range.minimum),
+        false => f64::from(range.maximum),
+    };
+    // An axis that rests at the end it's pushed towards can't be pushed
+    if end == rest {
+        return false;
+    }
+    (f64::from(value) - rest) / (end - rest) >= threshold.abs()
+}
+
+// Where one of the controller's inputs goes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mapping {
+    Button {
+        from: KeyCode,
+        to: KeyCode,
+    },
+    Axis {
+        from: AbsoluteAxisCode,
+        to: AbsoluteAxisCode,
+        invert: bool,
+    },
+    ButtonToAxis {
+        from: KeyCode,
+        to: AbsoluteAxisCode,
+        toward_maximum: bool, // Whether holding the button pushes the axis to its maximum
+    },
+    AxisToButton {
+        from: AbsoluteAxisCode,
+        to: KeyCode,
+        threshold: f64, // How far the axis is pushed to press the button, from -1 to 1
+    },
+}
+
+// Either kind of input a mapping goes from or to
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Code {
+    Button(KeyCode),
+    Axis(AbsoluteAxisCode),
+}
+
+impl Code {
+    // Parses the name of a supported button or axis (i.e. BTN_SOUTH or ABS_X)
+    fn parse(name: &str) -> Result<Self> {
+        if let Ok(key) = name.parse::<KeyCode>()
+            && SUPPORTED_BUTTONS.contains_key(&key)
+        {
+            return Ok(Self::Button(key));
+        }
+        if let Ok(axis) = name.parse::<AbsoluteAxisCode>()
+            && axis_by_code(axis).is_some()
+        {
+            return Ok(Self::Axis(axis));
+        }
+        bail!("`{name}` isn't a supported button or axis")
+    }
+
+    // Returns the button or axis `input` sets, if it sets one
+    fn of(input: StarboardInput) -> Option<Self> {
+        match input {
+            StarboardInput::Button { id, .. } => {
+                FromID::<KeyCode>::from_id(id).ok().map(Self::Button)
+            }
+            StarboardInput::Axis { id, .. } => {
+                FromID::<AbsoluteAxisCode>::from_id(id).ok().map(Self::Axis)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Mapping {
+    // Parses a line of a profile, like `ABS_Y -> ABS_Y invert`
+    fn parse(line: &str) -> Result<Self> {
+        let Some((from, rest)) = line.split_once("->") else {
+            bail!("Expected a mapping like `BTN_SOUTH -> BTN_EAST`");
+        };
+        let mut words = rest.split_whitespace();
+        let Some(to) = words.next() else {
+            bail!("Expected a button or axis after `->`");
+        };
+        let option = words.next();
+        if let Some(extra) = words.next() {
+            bail!("Unexpected `{extra}` at the end of the mapping");
+        }
+        Ok(
+            match (Code::parse(from.trim())?, Code::parse(to)?, option) {
+                (Code::Button(from), Code::Button(to), None) => Self::Button { from, to },
+                (Code::Axis(from), Code::Axis(to), None | Some("invert")) => Self::Axis {
+                    from,
+                    to,
+                    invert: option.is_some(),
+                },
+                (Code::Button(from), Code::Axis(to), None | Some("max") | Some("min")) => {
+                    Self::ButtonToAxis {
+                        from,
+                        to,
+                        toward_maximum: option != Some("min"),
+                    }
+                }
+                (Code::Axis(from), Code::Button(to), option) => Self::AxisToButton {
+                    from,
+                    to,
+                    threshold: match option {
+                        Some(percent) => parse_threshold(percent)?,
+                        None => DEFAULT_THRESHOLD,
+                    },
+                },
+                (_, _, Some(option)) => bail!("`{option}` doesn't apply to this mapping"),
+            },
+        )
+    }
+
+    // Returns what the mapping sends its target when its source is set by `input`, with buttons
+    // sent as 0 or 1, or `None` if `input` isn't its source. `range` gives the range the controller
+    // reports each axis in.
+    fn apply<F>(&self, input: StarboardInput, range: &F) -> Option<i32>
+    where
+        F: Fn(AbsoluteAxisCode) -> AxisRange,
+    {
+        if Code::of(input) != Some(self.source()) {
+            return None;
+        }
+        Some(match (*self, input) {
+            (Self::Button { .. }, StarboardInput::Button { value, .. }) => value.into(),
+            (Self::Axis { from, to, invert }, StarboardInput::Axis { value, .. }) => {
+                let target = range(to);
+                let value = target.rescale(value, &range(from));
+                match invert {
+                    true => target.maximum - (value - target.minimum),
+                    false => value,
+                }
+            }
+            (
+                Self::ButtonToAxis {
+                    to, toward_maximum, ..
+                },
+                StarboardInput::Button { value, .. },
+            ) => {
+                let target = range(to);
+                match (value, toward_maximum) {
+                    (false, _) => target.rest(),
+                    (true, true) => target.maximum,
+                    (true, false) => target.minimum,
+                }
+            }
+            (
+                Self::AxisToButton {
+                    from, threshold, ..
+                },
+                StarboardInput::Axis { value, .. },
+            ) => is_pushed(&range(from), value, threshold).into(),
+            _ => return None,
+        })
+    }
+
+    // Returns the button or axis the mapping goes from
+    fn source(&self) -> Code {
+        match *self {
+            Self::Button { from, .. } | Self::ButtonToAxis { from, .. } => Code::Button(from),
+            Self::Axis { from, .. } | Self::AxisToButton { from, .. } => Code::Axis(from),
+        }
+    }
+
+    // Returns the button or axis the mapping goes to
+    fn target(&self) -> Code {
+        match *self {
+            Self::Button { to, .. } | Self::AxisToButton { to, .. } => Code::Button(to),
+            Self::Axis { to, .. } | Self::ButtonToAxis { to, .. } => Code::Axis(to),
+        }
+    }
+}
+
+// Parses a threshold given as a percentage, like `25%` or `-50%`
+fn parse_threshold(percent: &str) -> Result<f64> {
+    let threshold = percent
+        .strip_suffix('%')
+        .and_then(|percent| percent.parse::<f64>().ok())
+        .ok_or_else(|| anyhow!("Expected a threshold like `50%`, not `{percent}`"))?;
+    if !(threshold != 0.0 && (-100.0..=100.0).contains(&threshold)) {
+        bail!("The threshold must be between -100% and 100%, and not 0%, not {percent}");
+    }
+    Ok(threshold / 100.0)
+}
+
+// A named set of mappings
+#[derive(Debug, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub mappings: Vec<Mapping>,
+}
+
+impl Profile {
+    // Loads the profile at `path`, which is named after the file
+    pub fn load(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .ok_or_else(|| anyhow!("'{}' isn't a profile", path.display()))?
+            .to_string_lossy();
+        let text = fs::read_to_string(path).map_err(|e| anyhow!("'{}': {e}", path.display()))?;
+        Self::parse(&name, &text).map_err(|e| anyhow!("'{}': {e}", path.display()))
+    }
+
+    // Parses the lines of a profile. Blank lines are skipped, and `#` starts a comment.
+    pub fn parse(name: &str, text: &str) -> Result<Self> {
+        let mut mappings = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mapping = Mapping::parse(line).map_err(|e| anyhow!("line {}: {e}", number + 1))?;
+            mappings.push(mapping);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            mappings,
+        })
+    }
+
+    // Returns `capabilities` along with every button and axis the profile maps to, so that a
+    // joystick built from them has somewhere for each mapping to go. Axes the controller doesn't
+    // have are given the range Starboard expects.
+    pub fn extend(&self, capabilities: &DeviceCapabilities) -> DeviceCapabilities {
+        let mut keys: Vec<KeyCode> = capabilities.keys().collect();
+        let mut axes: Vec<(AbsoluteAxisCode, AxisRange)> = capabilities.axes().collect();
+        for mapping in &self.mappings {
+            match mapping.target() {
+                Code::Button(key) if !keys.contains(&key) => keys.push(key),
+                Code::Axis(axis) if !axes.iter().any(|(code, _)| *code == axis) => {
+                    // Safety of using `unwrap()`: profiles only map to supported axes
+                    axes.push((axis, axis_by_code(axis).unwrap().range));
+                }
+                _ => {}
+            }
+        }
+        DeviceCapabilities::new(keys, axes)
+    }
+}
+
+// Remaps a controller's inputs through a profile. Remembers what each mapping last sent, so that
+// the mappings that share a target can be combined even when a frame only carries some of their
+// sources.
+pub struct Remapper {
+    profile: Arc<Profile>,
+    sent: Vec<Option<i32>>, // What each mapping last sent its target, once its source has been set
+}
+
+impl Remapper {
+    pub fn new(profile: Arc<Profile>) -> Self {
+        let sent = vec![None; profile.mappings.len()];
+        Self { profile, sent }
+    }
+
+    // Turns `inputs` into the inputs they're mapped to. Every button and axis that's mapped to
+    // comes out once, after the inputs that aren't mapped. `range` gives the range the controller
+    // reports each axis in, which values mapped to an axis are put in.
+    pub fn remap<F>(&mut self, inputs: Vec<StarboardInput>, range: F) -> Vec<StarboardInput>
+    where
+        F: Fn(AbsoluteAxisCode) -> AxisRange,
+    {
+        let mappings = &self.profile.mappings;
+        let mut remapped = Vec::with_capacity(inputs.len());
+        let mut changed = Vec::new();
+        for input in inputs {
+            let code = Code::of(input);
+            let mut mapped = false;
+            for (mapping, sent) in mappings.iter().zip(&mut self.sent) {
+                let Some(value) = mapping.apply(input, &range) else {
+                    continue;
+                };
+                *sent = Some(value);
+                mapped = true;
+                if !changed.contains(&mapping.target()) {
+                    changed.push(mapping.target());
+                }
+            }
+            // Only the mappings to a button or axis decide what it is
+            let is_target = mappings
+                .iter()
+                .any(|mapping| Some(mapping.target()) == code);
+            if !mapped && !is_target {
+                remapped.push(input);
+            }
+        }
+        remapped.extend(
+            changed
+                .into_iter()
+                .filter_map(|target| self.combine(target, &range)),
+        );
+        remapped
+    }
+
+    // Returns the input that sets `target` to what every mapping to it sends, combined
+    fn combine<F>(&self, target: Code, range: &F) -> Option<StarboardInput>
+    where
+        F: Fn(AbsoluteAxisCode) -> AxisRange,
+    {
+        let mut sent = self
+            .profile
+            .mappings
+            .iter()
+            .zip(&self.sent)
+            .filter(|(mapping, _)| mapping.target() == target)
+            .filter_map(|(_, sent)| *sent);
+        match target {
+            Code::Button(key) => button(key, sent.any(|value| value != 0)),
+            Code::Axis(code) => {
+                let rest = range(code).rest();
+                let furthest = sent.max_by_key(|value| (i64::from(*value) - i64::from(rest)).abs());
+                axis(code, furthest.unwrap_or(rest))
+            }
+        }
+    }
+}
+
+// Returns the input that sets `key`, if it's a supported button
+fn button(key: KeyCode, value: bool) -> Option<StarboardInput> {
+    let id = key.into_id().ok()?;
+    Some(StarboardInput::Button { id, value })
+}
+
+// Returns the input that sets `axis`, if it's a supported axis
+fn axis(axis: AbsoluteAxisCode, value: i32) -> Option<StarboardInput> {
+    let id = axis.into_id().ok()?;
+    Some(StarboardInput::Axis { id, value })
+}
@@ -0,0 +1,143 @@
+use crate::fixed_queue::FixedQueue;
+
+// UDP makes no promises about ordering or delivery, so every input packet carries a sequence
+// number. `SequenceWindow` remembers the highest sequence number seen along with which of the
+// `WINDOW_SIZE` numbers before it have arrived, which lets late packets be told apart from
+// duplicates.
+
+pub const WINDOW_SIZE: u64 = 64;
+
+// How many of a sender's earlier sessions are remembered, so that packets from them are refused
+pub const RETIRED_SESSIONS: usize = 16;
+
+// The outcome of checking a sequence number against a `SequenceWindow`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SequenceVerdict {
+    // The packet is the newest seen so far. `skipped` is how many sequence numbers were jumped
+    // over to reach it, which are presumed lost until they turn up late.
+    Accepted { skipped: u64 },
+    // The packet is older than the newest one but hasn't been seen before
+    Late,
+    // The packet has already been seen
+    Duplicate,
+    // The packet is too old for the window to tell whether it has been seen
+    Stale,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SequenceWindow {
+    highest: Option<u64>,
+    // Bit `n` is set if sequence number `highest - n` has been seen
+    seen: u64,
+}
+
+impl SequenceWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Forget every sequence number seen so far
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    // Records `sequence` as seen and reports how it relates to the packets before it
+    pub fn check(&mut self, sequence: u64) -> SequenceVerdict {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.seen = 1;
+            return SequenceVerdict::Accepted { skipped: 0 };
+        };
+
+        if sequence > highest {
+            let advance = sequence - highest;
+            self.seen = if advance >= WINDOW_SIZE {
+                0
+            } else {
+                self.seen << advance
+            };
+            self.seen |= 1;
+            self.highest = Some(sequence);
+            return SequenceVerdict::Accepted {
+                skipped: advance - 1,
+            };
+        }
+
+        let age = highest - sequence;
+        if age >= WINDOW_SIZE {
+            return SequenceVerdict::Stale;
+        }
+        let bit = 1 << age;
+        if self.seen & bit != 0 {
+            SequenceVerdict::Duplicate
+        } else {
+            self.seen |= bit;
+            SequenceVerdict::Late
+        }
+    }
+}
+
+// A `SequenceWindow` for packets stamped with a session as well as a sequence number. A session the
+// sender hasn't used before means it has restarted and its sequence numbers have started over.
+// Packets from the sender's recent sessions are refused, so that traffic captured before a restart
+// can't be replayed.
+#[derive(Debug, Copy, Clone)]
+pub struct SessionWindow {
+    session: Option<u64>,
+    retired: FixedQueue<u64, RETIRED_SESSIONS>, // The sessions before the current one, oldest first
+    window: SequenceWindow,
+}
+
+impl SessionWindow {
+    pub fn new() -> Self {
+        Self {
+            session: None,
+            retired: FixedQueue::new(),
+            window: SequenceWindow::new(),
+        }
+    }
+
+    // Records `sequence` as seen in `session` and reports how it relates to the packets before it
+    pub fn check(&mut self, session: u64, sequence: u64) -> SequenceVerdict {
+        if self.session != Some(session) {
+            if self
+                .retired
+                .into_iter()
+                .any(|retired| retired == Some(session))
+            {
+                return SequenceVerdict::Stale;
+            }
+            if let Some(current) = self.session {
+                self.retired.push_back(Some(current));
+            }
+            self.session = Some(session);
+            self.window.reset();
+        }
+        self.window.check(sequence)
+    }
+}
+
+// Hands out the session and sequence numbers stamped onto every packet a sender sends. Each sender
+// gets a random session, so that the receiver can tell when the sender has restarted without
+// relying on the sender's clock.
+#[derive(Debug, Copy, Clone)]
+pub struct Sequencer {
+    session: u64,
+    next: u64,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Self {
+            session: rand::random(),
+            next: 0,
+        }
+    }
+
+    // Returns the session and the sequence number for the next packet
+    pub fn next(&mut self) -> (u64, u64) {
+        let sequence = self.next;
+        self.next += 1;
+        (self.session, sequence)
+    }
+}
@@ -1,8 +1,12 @@
 use core::{
-    fmt::{self, Display, Formatter},
+    fmt::{self, Display},
     time::Duration,
 };
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 use tokio::net::UdpSocket;
 
 use evdev::{AbsoluteAxisCode, KeyCode};
@@ -11,7 +15,7 @@ use tokio::{
     select,
     sync::{RwLock, RwLockWriteGuard},
     task::JoinSet,
-    time::{Interval, interval},
+    time::{Interval, MissedTickBehavior, interval},
 };
 use tokio_util::sync::CancellationToken;
 
@@ -26,63 +30,305 @@ use crossterm::event;
 
 use crate::{
     bitmask::Bitmask,
-    datagram::{BroadcastPacket, deserialize},
+    capabilities::{DeviceCapabilities, HelloPacket},
+    datagram::{
+        BroadcastPacket, DisconnectPacket, Message, MessageKind, deserialize, format_addr,
+        peek_kind, serialize,
+    },
+    discovery::ServerAnnouncement,
     evdev_sb::VirtualJoystick,
     fixed_queue::FixedQueue,
-    input::{IntoID, StarboardInputPacket},
+    force_feedback::{ForceFeedback, ForceFeedbackPacket},
+    gyro::{GyroMode, GyroSettings},
+    input::{InputFrame, IntoID},
+    lifecycle::{ConnectionHealth, ControllerState, LifecycleThresholds},
+    net::{bind_dual_stack, join_discovery_group},
+    pairing::{AuthenticatedPacket, PairRequest, ServerPairing},
+    ping::{PingPacket, PongPacket, RoundTrip},
     printdbg,
-    server_ui::StarboardServerUI,
+    rejects::{Port, RejectLog},
+    remap::{Profile, load_profiles},
+    sequence::{SequenceVerdict, Sequencer, SessionWindow},
+    server_ui::{JoystickSettings, StarboardServerUI},
+    session::{SealedPacket, ServerSessions, SessionExpired, SessionInit},
+    storage::{KeyStore, data_dir, id_at},
     string::StarboardString,
     supported_actions::{AXIS_COUNT, BUTTON_COUNT},
+    trackpad::TrackpadMode,
 };
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 
 pub type DiagnosticMap = HashMap<u64, ControllerDiagnostic>;
 pub type ControllerMap = HashMap<u64, VirtualJoystick>;
 
-// Records the current state of a detected controller
-#[derive(Debug, Copy, Clone)]
-pub enum ControllerState {
-    Online,
-    NotResponding,
+// How often the virtual joysticks are checked for force feedback from games
+const FF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How often controllers are pinged to measure their latency, and how many unanswered pings are
+// kept, so that an answer arriving several intervals late is still measured
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+pub const OUTSTANDING_PINGS: usize = 8;
+
+// The largest datagram the serial port takes. A hello describing a device with many buttons and
+// axes is much larger than an input packet.
+const SERIAL_BUFFER_LEN: usize = 2048;
+
+// The files in Starboard's data directory the keys of paired controllers and the server's ID are
+// kept in
+const PAIRED_CLIENTS: &str = "paired_clients";
+const SERVER_ID: &str = "server_id";
+
+// Counts how the input packets sent by a controller have fared on their way to the server
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PacketStats {
+    pub received: u64,
+    pub duplicates: u64,
+    pub stale: u64, // Packets that arrived after a newer one had already been applied
+    pub lost: u64,
 }
 
-impl Display for ControllerState {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let string = match self {
-            Self::Online => "Online",
-            Self::NotResponding => "Not Responding",
-        };
-        write!(f, "{string}")
+impl PacketStats {
+    // Returns what has happened to the controller's packets since `earlier` was recorded
+    pub fn since(&self, earlier: &PacketStats) -> PacketStats {
+        PacketStats {
+            received: self.received.saturating_sub(earlier.received),
+            duplicates: self.duplicates.saturating_sub(earlier.duplicates),
+            stale: self.stale.saturating_sub(earlier.stale),
+            lost: self.lost.saturating_sub(earlier.lost),
+        }
+    }
+
+    // Returns the percentage of packets that never arrived
+    pub fn loss_rate(&self) -> f64 {
+        let expected = self.received - self.duplicates + self.lost;
+        if expected == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / expected as f64 * 100.0
+    }
+
+    // Returns the percentage of packets that arrived out of order
+    pub fn stale_rate(&self) -> f64 {
+        if self.received == 0 {
+            return 0.0;
+        }
+        self.stale as f64 / self.received as f64 * 100.0
     }
 }
 
 // Records various information about a controller
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct ControllerDiagnostic {
     id: u64,
     name: StarboardString,
     status: ControllerState,
     pub last_ping: i64,
-    pub latency: FixedQueue<i64, 10>,
+    pub latency: FixedQueue<i64, 10>, // Round trip times, in milliseconds
+    pub clock_offset: Option<i64>,    // How far the controller's clock is ahead, in milliseconds
+    pending_pings: Vec<(u64, i64)>, // The nonce and send time of each unanswered ping, oldest first
+    pub packets: PacketStats,
+    sequence: SessionWindow,
+    serial_addr: Option<SocketAddr>, // Where input comes from and force feedback goes
+    feedback: Sequencer,             // Numbers the force feedback sent to the controller
+    checkpoint: PacketStats,         // `packets` as of the last status update
+    presence_addr: Option<SocketAddr>, // Where the controller's presence broadcasts come from
+    conflict: Option<SocketAddr>,    // The last address refused for using the controller's ID
+    handshake_addr: Option<SocketAddr>, // Where the controller last authenticated itself from
+    pub refused: u64,                // Packets refused for coming from the wrong address
+    capabilities: Option<DeviceCapabilities>, // What the controller has, once it says hello
+    pub trackpad_mode: TrackpadMode, // Where the controller's trackpads go once it's active
+    motion: Option<DeviceCapabilities>, // The axes of the controller's motion sensors, if any
+    pub gyro_mode: GyroMode,         // What the controller's gyro aims with once it's active
+    desktop: Option<DeviceCapabilities>, // The keys of the keyboards and mice forwarded with it
+    pub profile: Option<Arc<Profile>>, // How the controller's buttons and axes are remapped
 }
 
 impl ControllerDiagnostic {
-    pub fn new(
-        id: u64,
-        name: StarboardString,
-        status: ControllerState,
-        initial_latency: i64,
-    ) -> Self {
-        let mut latency = FixedQueue::new();
-        latency.push_back(Some(initial_latency));
+    pub fn new(id: u64, name: StarboardString, status: ControllerState) -> Self {
         Self {
             id,
             name,
             status,
             last_ping: Local::now().timestamp(),
-            latency,
+            latency: FixedQueue::new(),
+            clock_offset: None,
+            pending_pings: Vec::new(),
+            packets: PacketStats::default(),
+            sequence: SessionWindow::new(),
+            serial_addr: None,
+            feedback: Sequencer::new(),
+            checkpoint: PacketStats::default(),
+            presence_addr: None,
+            conflict: None,
+            handshake_addr: None,
+            refused: 0,
+            capabilities: None,
+            trackpad_mode: TrackpadMode::default(),
+            motion: None,
+            gyro_mode: GyroMode::default(),
+            desktop: None,
+            profile: None,
+        }
+    }
+
+    // Checks that presence broadcasts from `addr` come from the controller rather than another
+    // one using the same ID. The address a controller broadcasts from is only given up once it has
+    // gone quiet, since a controller that restarts comes back from a new port. Returns false, and
+    // records the refusal, if the broadcast is refused.
+    pub fn claim_presence_addr(&mut self, addr: SocketAddr) -> bool {
+        match self.presence_addr {
+            Some(current) if current == addr => true,
+            Some(_) if !self.status.is_silent() => self.refuse(addr),
+            previous => {
+                self.presence_addr = Some(addr);
+                self.conflict = None;
+                // A controller that comes back from somewhere else has restarted, so its input
+                // will come from somewhere else too
+                if previous.is_some() {
+                    self.serial_addr = None;
+                }
+                true
+            }
+        }
+    }
+
+    // Checks that packets on the serial port from `addr` come from the controller rather than
+    // another one using the same ID, or someone who has learnt it. The first input is bound to the
+    // controller if it comes from the host the controller broadcasts from, and every packet after
+    // that must come from the same address until the controller handshakes again. Returns false,
+    // and records the refusal, if the packet is refused.
+    pub fn claim_serial_addr(&mut self, addr: SocketAddr) -> bool {
+        match self.serial_addr {
+            Some(current) if current == addr => true,
+            _ if self.handshake_addr == Some(addr) => {
+                self.serial_addr = Some(addr);
+                self.handshake_addr = None;
+                self.conflict = None;
+                true
+            }
+            Some(_) => self.refuse(addr),
+            None if self
+                .presence_addr
+                .is_some_and(|presence| presence.ip() != addr.ip()) =>
+            {
+                self.refuse(addr)
+            }
+            None => {
+                self.serial_addr = Some(addr);
+                true
+            }
+        }
+    }
+
+    // Records that the controller has authenticated itself from `addr`, which it may then send
+    // input from
+    pub fn record_handshake(&mut self, addr: SocketAddr) {
+        self.handshake_addr = Some(addr);
+    }
+
+    fn refuse(&mut self, addr: SocketAddr) -> bool {
+        self.conflict = Some(addr);
+        self.refused += 1;
+        false
+    }
+
+    pub fn conflict(&self) -> Option<SocketAddr> {
+        self.conflict
+    }
+
+    // Works out how the controller's connection has been doing since the last call, at `now`, a
+    // timestamp in seconds
+    pub fn check_health(&mut self, now: i64) -> ConnectionHealth {
+        let recent = self.packets.since(&self.checkpoint);
+        self.checkpoint = self.packets;
+        let latencies: Vec<i64> = self.latency.into_iter().flatten().collect();
+        let latency_ms = match latencies.len() {
+            0 => 0,
+            count => latencies.iter().sum::<i64>() / count as i64,
+        };
+        ConnectionHealth {
+            silent_for: Duration::from_secs((now - self.last_ping).max(0) as u64),
+            latency_ms,
+            loss_rate: recent.loss_rate(),
+        }
+    }
+
+    // Moves the controller to the state its connection calls for, returning the state it was in
+    pub fn update_status(
+        &mut self,
+        thresholds: &LifecycleThresholds,
+        health: &ConnectionHealth,
+    ) -> ControllerState {
+        let previous = self.status;
+        self.status = thresholds.next_state(previous, health);
+        previous
+    }
+
+    // Notes that a ping is being sent at `sent_at` and returns its nonce. Only the last
+    // `OUTSTANDING_PINGS` pings are waited on, so an answer to an older one is ignored.
+    pub fn send_ping(&mut self, sent_at: i64) -> u64 {
+        let nonce = rand::random();
+        self.pending_pings.push((nonce, sent_at));
+        if self.pending_pings.len() > OUTSTANDING_PINGS {
+            self.pending_pings.remove(0);
+        }
+        nonce
+    }
+
+    // Records the round trip of a pong received at `received_at`, however late it is. Returns
+    // false if it doesn't answer a ping that's still being waited on, which is also the case for
+    // a pong that's replayed.
+    pub fn record_pong(&mut self, pong: &PongPacket, received_at: i64) -> bool {
+        let Some(index) = self
+            .pending_pings
+            .iter()
+            .position(|pending| *pending == (pong.nonce, pong.ping_sent_at))
+        else {
+            return false;
+        };
+        self.pending_pings.remove(index);
+        let round_trip = RoundTrip::measure(pong, received_at);
+        self.latency.push_back(Some(round_trip.rtt_ms));
+        self.clock_offset = Some(round_trip.clock_offset_ms);
+        true
+    }
+
+    // Records that the controller has announced itself, which brings it back if it had gone quiet
+    pub fn record_ping(&mut self) {
+        self.last_ping = Local::now().timestamp();
+        if self.status.is_silent() {
+            self.status = ControllerState::Online;
+        }
+    }
+
+    pub fn status(&self) -> &ControllerState {
+        &self.status
+    }
+
+    // Checks an input packet's place in the controller's sequence and records the outcome in
+    // `self.packets`. Returns true if the packet is newer than every packet applied so far.
+    pub fn accept_sequence(&mut self, session: u64, sequence: u64) -> bool {
+        self.packets.received += 1;
+        match self.sequence.check(session, sequence) {
+            SequenceVerdict::Accepted { skipped } => {
+                self.packets.lost += skipped;
+                true
+            }
+            SequenceVerdict::Late => {
+                // The packet was counted as lost when a newer one skipped past it
+                self.packets.lost = self.packets.lost.saturating_sub(1);
+                self.packets.stale += 1;
+                false
+            }
+            SequenceVerdict::Stale => {
+                self.packets.stale += 1;
+                false
+            }
+            SequenceVerdict::Duplicate => {
+                self.packets.duplicates += 1;
+                false
+            }
         }
     }
 
@@ -93,24 +339,70 @@ impl ControllerDiagnostic {
     pub fn name(&self) -> &StarboardString {
         &self.name
     }
+
+    // Returns the buttons and axes of the controller's device, if it has said hello
+    pub fn capabilities(&self) -> Option<&DeviceCapabilities> {
+        self.capabilities.as_ref()
+    }
+
+    // Returns the axes of the controller's motion sensors, if it has said hello and has any
+    pub fn motion(&self) -> Option<&DeviceCapabilities> {
+        self.motion.as_ref()
+    }
+
+    // Returns the keys of the keyboards and mice forwarded with the controller, if it has said
+    // hello and forwards any
+    pub fn desktop(&self) -> Option<&DeviceCapabilities> {
+        self.desktop.as_ref()
+    }
 }
 
 impl Display for ControllerDiagnostic {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // A received packet should always have a value for latency.last()
-        let latency = self.latency.last().unwrap();
-        write!(f, "{}: {} ({}ms)", self.name, self.status, latency)
+        // The latency isn't known until the controller has answered a ping
+        let latency = match self.latency.last() {
+            Some(latency) => format!("{latency}ms"),
+            None => "?ms".to_string(),
+        };
+        let clock_offset = match self.clock_offset {
+            Some(offset) => format!(", clock {offset:+}ms"),
+            None => String::new(),
+        };
+        write!(
+            f,
+            "{}: {} ({}{}, {:.1}% loss, {:.1}% stale, {} dup)",
+            self.name,
+            self.status,
+            latency,
+            clock_offset,
+            self.packets.loss_rate(),
+            self.packets.stale_rate(),
+            self.packets.duplicates
+        )?;
+        if self.trackpad_mode != TrackpadMode::Joystick {
+            write!(f, ", trackpads as {}", self.trackpad_mode)?;
+        }
+        if self.gyro_mode != GyroMode::Off {
+            write!(f, ", gyro as {}", self.gyro_mode)?;
+        }
+        if let Some(profile) = &self.profile {
+            write!(f, ", profile {}", profile.name)?;
+        }
+        if let Some(addr) = self.conflict {
+            write!(
+                f,
+                " ID conflict with {}, {} refused",
+                format_addr(addr),
+                self.refused
+            )?;
+        }
+        Ok(())
     }
 }
 
 impl From<BroadcastPacket> for ControllerDiagnostic {
     fn from(value: BroadcastPacket) -> Self {
-        Self::new(
-            *value.id(),
-            *value.name(),
-            ControllerState::Online,
-            value.latency(),
-        )
+        Self::new(*value.id(), *value.name(), ControllerState::Online)
     }
 }
 
@@ -121,6 +413,14 @@ pub struct StarboardServerBuilder {
     enabled_buttons: Bitmask,
     enabled_axes: Bitmask,
     no_ui: bool,
+    require_pairing: bool,
+    require_encryption: bool,
+    thresholds: LifecycleThresholds,
+    trigger_threshold: Option<f64>,
+    gyro: GyroSettings,
+    profiles: Vec<Arc<Profile>>,
+    skipped_profiles: Vec<String>,
+    data_dir: Option<PathBuf>,
 }
 
 impl StarboardServerBuilder {
@@ -131,11 +431,19 @@ impl StarboardServerBuilder {
             enabled_buttons: Bitmask::new(BUTTON_COUNT),
             enabled_axes: Bitmask::new(AXIS_COUNT),
             no_ui: false,
+            require_pairing: false,
+            require_encryption: false,
+            thresholds: LifecycleThresholds::default(),
+            trigger_threshold: None,
+            gyro: GyroSettings::default(),
+            profiles: Vec::new(),
+            skipped_profiles: Vec::new(),
+            data_dir: None,
         }
     }
 
     // Build the server
-    pub fn build(self, name: String) -> Arc<StarboardServer> {
+    pub fn build(self, name: String) -> Result<Arc<StarboardServer>> {
         let serial_port = self.serial_port;
         let device_search_port = self.device_search_port;
         let enabled_buttons = self.enabled_buttons;
@@ -144,8 +452,32 @@ impl StarboardServerBuilder {
             Arc::new(RwLock::new(HashMap::new()));
         let active_controllers: Arc<RwLock<ControllerMap>> = Arc::new(RwLock::new(HashMap::new()));
         let no_ui = self.no_ui;
+        let require_pairing = self.require_pairing;
+        let require_encryption = self.require_encryption;
+        let thresholds = self.thresholds;
+        let trigger_threshold = self.trigger_threshold;
+        let gyro = self.gyro;
+        let profiles = self.profiles;
+        let skipped_profiles = self.skipped_profiles;
+        let data_dir = match self.data_dir {
+            Some(dir) => dir,
+            None => data_dir()?,
+        };
+        let paired_clients = RwLock::new(KeyStore::open(data_dir.join(PAIRED_CLIENTS))?);
+        // Clients tell servers apart by ID, since several servers can share a name
+        let announcement = ServerAnnouncement {
+            id: id_at(&data_dir.join(SERVER_ID))?,
+            name: StarboardString::try_from(name.as_str())?,
+            serial_port,
+            device_search_port,
+        };
+        // Pairing is only ever opened from the UI, where the operator approves each controller
+        let pairing = Arc::new(RwLock::new(ServerPairing::new(
+            announcement.id,
+            announcement.name,
+        )));
 
-        Arc::new(StarboardServer {
+        Ok(Arc::new(StarboardServer {
             serial_port,
             device_search_port,
             enabled_buttons,
@@ -153,49 +485,51 @@ impl StarboardServerBuilder {
             detected_controllers,
             active_controllers,
             name,
+            announcement,
             no_ui,
+            require_pairing,
+            require_encryption,
+            thresholds,
+            trigger_threshold,
+            gyro,
+            profiles,
+            skipped_profiles,
+            paired_clients,
+            pairing,
+            sessions: RwLock::new(ServerSessions::new()),
+            rejects: Arc::new(RwLock::new(RejectLog::new())),
             mutated: AtomicBool::new(true), // Initialized to true to render the UI
             cancellation_token: CancellationToken::new(),
-        })
+        }))
     }
 
-    // Enable `button` on the server
-    pub fn enable_button(self, button: KeyCode) -> Result<Self> {
-        let mut builder = self;
-        let button_code: u32 = button.into_id()?;
-        builder.enabled_buttons.write_bit(button_code, true);
-        Ok(builder)
-    }
-
-    // Enable each button in `buttons` on the server
+    // Enable each button in `buttons` on the server, on top of those already enabled
     pub fn enable_buttons<T>(self, buttons: T) -> Result<Self>
     where
         T: IntoIterator<Item = KeyCode>,
     {
         let mut builder = self;
-        for button in buttons {
-            builder = builder.enable_button(button)?;
-        }
-        Ok(builder)
-    }
-
-    // Enable `axis` on the server
-    pub fn enable_axis(self, axis: AbsoluteAxisCode) -> Result<Self> {
-        let mut builder = self;
-        let axis_code: u32 = axis.into_id()?;
-        builder.enabled_axes.write_bit(axis_code, true);
+        let ids = buttons
+            .into_iter()
+            .map(IntoID::into_id)
+            .collect::<Result<Vec<u32>>>()?;
+        let buttons = Bitmask::from_ones(BUTTON_COUNT, ids)?;
+        builder.enabled_buttons = builder.enabled_buttons.union(&buttons);
         Ok(builder)
     }
 
-    // Enable each axes in `axes` on the server
+    // Enable each axes in `axes` on the server, on top of those already enabled
     pub fn enable_axes<T>(self, axes: T) -> Result<Self>
     where
         T: IntoIterator<Item = AbsoluteAxisCode>,
     {
         let mut builder = self;
-        for axis in axes {
-            builder = builder.enable_axis(axis)?;
-        }
+        let ids = axes
+            .into_iter()
+            .map(IntoID::into_id)
+            .collect::<Result<Vec<u32>>>()?;
+        let axes = Bitmask::from_ones(AXIS_COUNT, ids)?;
+        builder.enabled_axes = builder.enabled_axes.union(&axes);
         Ok(builder)
     }
 
@@ -205,6 +539,66 @@ impl StarboardServerBuilder {
         builder.no_ui = no_ui;
         builder
     }
+
+    // Reject every packet that isn't authenticated by a paired controller
+    pub fn require_pairing(self, require_pairing: bool) -> Self {
+        let mut builder = self;
+        builder.require_pairing = require_pairing;
+        builder
+    }
+
+    // Reject every input packet that isn't encrypted. Encryption needs a paired controller, so
+    // this implies `require_pairing`.
+    pub fn require_encryption(self, require_encryption: bool) -> Self {
+        let mut builder = self;
+        builder.require_encryption = require_encryption;
+        builder
+    }
+
+    // Set when controllers count as degraded, stop responding and are forgotten
+    pub fn lifecycle(self, thresholds: LifecycleThresholds) -> Self {
+        let mut builder = self;
+        builder.thresholds = thresholds;
+        builder
+    }
+
+    // Have analog triggers press their buttons once they're pulled `percent` of the way, for games
+    // that only read the buttons
+    pub fn digital_triggers(self, percent: Option<f64>) -> Result<Self> {
+        let mut builder = self;
+        if let Some(percent) = percent
+            && !(percent > 0.0 && percent <= 100.0)
+        {
+            bail!("The trigger threshold must be above 0% and at most 100%, not {percent}%");
+        }
+        builder.trigger_threshold = percent.map(|percent| percent / 100.0);
+        Ok(builder)
+    }
+
+    // Set how the gyro of each controller aims, in whichever mode it's put in
+    pub fn gyro(self, settings: GyroSettings) -> Result<Self> {
+        let mut builder = self;
+        settings.validate()?;
+        builder.gyro = settings;
+        Ok(builder)
+    }
+
+    // Load the remapping profiles controllers can be given from `dir`. Profiles that can't be
+    // loaded are skipped, and reported once the server runs.
+    pub fn profiles(self, dir: &Path) -> Result<Self> {
+        let mut builder = self;
+        (builder.profiles, builder.skipped_profiles) = load_profiles(dir)?;
+        Ok(builder)
+    }
+
+    // Keep the keys of paired controllers and the server's ID in `dir` rather than in Starboard's
+    // data directory, so that tests don't read or write the state of whoever runs them
+    #[cfg(test)]
+    pub fn data_dir(self, dir: PathBuf) -> Self {
+        let mut builder = self;
+        builder.data_dir = Some(dir);
+        builder
+    }
 }
 
 pub struct StarboardServer {
@@ -217,7 +611,19 @@ pub struct StarboardServer {
     detected_controllers: Arc<RwLock<DiagnosticMap>>,
     active_controllers: Arc<RwLock<ControllerMap>>,
     name: String,
+    announcement: ServerAnnouncement, // Sent to every controller looking for servers
     no_ui: bool,
+    require_pairing: bool,
+    require_encryption: bool,
+    thresholds: LifecycleThresholds,
+    trigger_threshold: Option<f64>, // How far triggers press their buttons, from 0 to 1
+    gyro: GyroSettings,             // How the gyro of each controller aims
+    profiles: Vec<Arc<Profile>>,    // The remapping profiles controllers can be given, by name
+    skipped_profiles: Vec<String>,  // Why each profile that couldn't be loaded was skipped
+    paired_clients: RwLock<KeyStore<u64>>, // Keys shared with paired controllers, by client ID
+    pairing: Arc<RwLock<ServerPairing>>,
+    sessions: RwLock<ServerSessions>, // Encrypted sessions, by client ID
+    rejects: Arc<RwLock<RejectLog>>,  // Datagrams that were dropped, by sender
     mutated: AtomicBool,
     cancellation_token: CancellationToken,
 }
@@ -225,13 +631,37 @@ pub struct StarboardServer {
 impl StarboardServer {
     // Public facing function to run the server
     pub async fn run(self: Arc<Self>) -> Result<()> {
+        let sock = Arc::new(bind_dual_stack(self.serial_port)?);
         let mut join_set = JoinSet::new();
-        join_set.spawn(self.clone().run_serial_loop());
+        join_set.spawn(self.clone().run_serial_loop(sock.clone()));
+        join_set.spawn(self.clone().run_force_feedback_loop(sock.clone()));
+        join_set.spawn(self.clone().run_ping_loop(sock.clone()));
         join_set.spawn(self.clone().run_device_search_loop());
         if !self.no_ui {
-            join_set.spawn_blocking(|| self.run_ui());
+            let server = self.clone();
+            join_set.spawn_blocking(|| server.run_ui());
+        } else {
+            println!("Pairing stays closed without the UI, since there's no one to approve it");
+            for skipped in &self.skipped_profiles {
+                eprintln!("{skipped}");
+            }
         }
         join_set.join_next().await;
+        // Nothing will release the inputs controllers were holding, or stop the effects games have
+        // started, once the server is gone
+        let active: Vec<u64> = self
+            .active_controllers
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect();
+        self.neutralize_controllers(&active).await;
+        for id in active {
+            let _ = self
+                .send_force_feedback(&sock, id, ForceFeedback::StopAll)
+                .await;
+        }
         Ok(())
     }
 
@@ -239,6 +669,14 @@ impl StarboardServer {
         let mut ui = StarboardServerUI::new(
             self.detected_controllers.clone(),
             self.active_controllers.clone(),
+            self.pairing.clone(),
+            self.rejects().clone(),
+            JoystickSettings {
+                trigger_threshold: self.trigger_threshold,
+                gyro: self.gyro,
+                profiles: self.profiles.clone(),
+            },
+            self.skipped_profiles.clone(),
             self.cancellation_token.clone(),
         )?;
         while !(&self).cancellation_token.is_cancelled() {
@@ -250,7 +688,7 @@ impl StarboardServer {
 
     fn poll_events(self: &Arc<Self>, ui: &mut StarboardServerUI) -> Result<()> {
         while event::poll(Duration::default())? {
-            ui.handle_event(event::read()?)?;
+            ui.handle_event(event::read()?);
             self.mutated.store(true, Ordering::Relaxed);
         }
         Ok(())
@@ -271,45 +709,476 @@ impl StarboardServer {
 
     // This is the main loop for the server that receives packets and sends them to the input
     // handling
-    async fn run_serial_loop(self: Arc<Self>) -> Result<()> {
-        let mut buf: [u8; 256] = [0; 256];
-        let addr = format!("0.0.0.0:{}", self.serial_port);
-        let sock = UdpSocket::bind(addr).await?;
+    async fn run_serial_loop(self: Arc<Self>, sock: Arc<UdpSocket>) -> Result<()> {
+        let mut buf = [0; SERIAL_BUFFER_LEN];
         loop {
-            let _ = self.get_packet(&mut buf, &sock).await;
-            let raw = Vec::from(&mut buf);
-            let packet: StarboardInputPacket = deserialize(raw)?;
-            printdbg!("{:?}", packet);
-            let mut active_controllers = self.active_controllers.write().await;
-            if let Some(mut virt_joystick) = active_controllers.get_mut(packet.client_id()) {
-                self.handle_packet(&mut virt_joystick, packet)?;
-            }
+            let Ok((len, addr)) = self.get_packet(&mut buf, &sock).await else {
+                continue;
+            };
+            self.receive_serial(&buf[..len], addr, &sock).await;
+        }
+    }
+
+    // Handles a datagram received on the serial port. Foreign traffic, malformed packets and
+    // packets from mismatched builds are dropped and recorded here rather than being applied to a
+    // virtual joystick, so that nothing a sender does can stop the serial loop.
+    pub async fn receive_serial(self: &Arc<Self>, raw: &[u8], addr: SocketAddr, sock: &UdpSocket) {
+        if let Err(e) = self.handle_serial_packet(raw, addr, sock).await {
+            printdbg!(
+                "Rejected datagram on the serial port from {}: {}",
+                format_addr(addr),
+                e
+            );
+            self.record_reject(Port::Serial, addr, e).await;
         }
     }
 
-    // Waits for a packet to be received and writes the data into `buf`
-    async fn get_packet(&self, buf: &mut [u8; 256], sock: &UdpSocket) -> Result<()> {
-        while sock.recv(buf).await? <= 0 {}
+    async fn record_reject(self: &Arc<Self>, port: Port, addr: SocketAddr, e: anyhow::Error) {
+        self.rejects.write().await.record(port, addr, e.to_string());
+        self.mutated.store(true, Ordering::Relaxed);
+    }
+
+    // The log of datagrams the server has dropped
+    pub fn rejects(&self) -> &Arc<RwLock<RejectLog>> {
+        &self.rejects
+    }
+
+    // The serial port carries input packets, which may be authenticated or encrypted, along with
+    // the requests that start encrypted sessions
+    async fn handle_serial_packet(
+        &self,
+        raw: &[u8],
+        addr: SocketAddr,
+        sock: &UdpSocket,
+    ) -> Result<()> {
+        let (inner, authenticated_as, encrypted) = if peek_kind(raw)? == MessageKind::Sealed {
+            let (inner, id) = self.open_sealed(raw, addr, sock).await?;
+            (inner, Some(id), true)
+        } else {
+            let (inner, authenticated_as) = self.authenticate(raw).await?;
+            (inner, authenticated_as, false)
+        };
+        if peek_kind(&inner)? == MessageKind::SessionInit {
+            let Some(id) = authenticated_as else {
+                bail!("Rejected an unauthenticated session request");
+            };
+            return self
+                .start_session(id, deserialize(&inner)?, addr, sock)
+                .await;
+        }
+        if self.require_encryption && !encrypted {
+            bail!("Rejected an unencrypted input packet");
+        }
+        if peek_kind(&inner)? == MessageKind::Disconnect {
+            let packet: DisconnectPacket = deserialize(&inner)?;
+            self.check_sender(*packet.id(), authenticated_as).await?;
+            self.claim_serial_addr(*packet.id(), addr, encrypted)
+                .await?;
+            printdbg!("Controller {} disconnected", packet.id());
+            if let Some(diagnostic) = self.detected_controllers.write().await.get_mut(packet.id()) {
+                diagnostic.status = ControllerState::Disconnected;
+                self.mutated.store(true, Ordering::Relaxed);
+            }
+            self.neutralize_controllers(&[*packet.id()]).await;
+            return Ok(());
+        }
+        if peek_kind(&inner)? == MessageKind::Hello {
+            let hello: HelloPacket = deserialize(&inner)?;
+            self.check_sender(hello.id, authenticated_as).await?;
+            self.claim_serial_addr(hello.id, addr, encrypted).await?;
+            return self.record_hello(hello).await;
+        }
+        if peek_kind(&inner)? == MessageKind::Pong {
+            let pong: PongPacket = deserialize(&inner)?;
+            self.check_sender(pong.id, authenticated_as).await?;
+            self.claim_serial_addr(pong.id, addr, encrypted).await?;
+            return self.record_pong(pong).await;
+        }
+        let packet = InputFrame::deserialize(&inner)?;
+        self.check_sender(*packet.client_id(), authenticated_as)
+            .await?;
+        printdbg!("{:?}", packet);
+        if authenticated_as.is_some() {
+            // Authenticated input that isn't a replay can only have come from the controller, so it
+            // may move to wherever it came from
+            if !self.accept_sequence(&packet).await {
+                return Ok(());
+            }
+            self.claim_serial_addr(*packet.client_id(), addr, true)
+                .await?;
+        } else {
+            self.claim_serial_addr(*packet.client_id(), addr, false)
+                .await?;
+            if !self.accept_sequence(&packet).await {
+                return Ok(());
+            }
+        }
+        let mut active_controllers = self.active_controllers.write().await;
+        if let Some(mut virt_joystick) = active_controllers.get_mut(packet.client_id()) {
+            self.handle_packet(&mut virt_joystick, packet)?;
+        }
         Ok(())
     }
 
-    // Unpacks a StarboardInputPacket and sends the inputs to your device's input handling
-    fn handle_packet(
+    // Decrypts a sealed datagram. Returns the datagram inside along with the ID of the controller
+    // whose session key it was sealed with. A controller using a session the server doesn't know,
+    // usually because the server restarted, is told to start a new one.
+    async fn open_sealed(
         &self,
-        virt_joystick: &mut VirtualJoystick,
-        packet: StarboardInputPacket,
+        raw: &[u8],
+        addr: SocketAddr,
+        sock: &UdpSocket,
+    ) -> Result<(Vec<u8>, u64)> {
+        let packet: SealedPacket = deserialize(raw)?;
+        let id = *packet.id();
+        let mut sessions = self.sessions.write().await;
+        if !sessions.knows(&packet) {
+            let expired = SessionExpired {
+                id,
+                session: *packet.session(),
+            };
+            if let Some(key) = self.paired_clients.read().await.get(&id) {
+                let expired = AuthenticatedPacket::seal(id, serialize(&expired)?, key);
+                reply(sock, &expired, addr).await?;
+            }
+            bail!("Rejected a sealed packet from controller {id} for an unknown session");
+        }
+        Ok((sessions.open(packet)?, id))
+    }
+
+    // Accepts a controller's request for a new encrypted session, which has already been
+    // authenticated as coming from controller `id`
+    async fn start_session(
+        &self,
+        id: u64,
+        init: SessionInit,
+        addr: SocketAddr,
+        sock: &UdpSocket,
     ) -> Result<()> {
-        let inputs = packet.unpack(self.enabled_buttons, self.enabled_axes);
-        for input in inputs {
+        if init.id != id {
+            bail!(
+                "Controller {id} requested a session for controller {}",
+                init.id
+            );
+        }
+        // Only the controller can authenticate a session request, so it may send input from
+        // wherever the request came from
+        if let Some(diagnostic) = self.detected_controllers.write().await.get_mut(&id) {
+            diagnostic.record_handshake(addr);
+        }
+        let paired_clients = self.paired_clients.read().await;
+        // Safety of using `unwrap()`: the request was authenticated with this controller's key
+        let key = paired_clients.get(&id).unwrap();
+        let accept = self.sessions.write().await.accept(key, init);
+        printdbg!(
+            "Offered encrypted session {} with controller {}",
+            accept.session,
+            id
+        );
+        let accept = AuthenticatedPacket::seal(id, serialize(&accept)?, key);
+        reply(sock, &accept, addr).await
+    }
+
+    // Strips the MAC from an authenticated datagram. Returns the datagram inside along with the ID
+    // of the controller it was authenticated as, or the datagram itself and `None` if it carries no
+    // MAC.
+    async fn authenticate(&self, raw: &[u8]) -> Result<(Vec<u8>, Option<u64>)> {
+        if peek_kind(raw)? != MessageKind::Authenticated {
+            return Ok((raw.to_vec(), None));
+        }
+        let packet: AuthenticatedPacket = deserialize(raw)?;
+        let id = *packet.id();
+        let paired_clients = self.paired_clients.read().await;
+        let Some(key) = paired_clients.get(&id) else {
+            bail!("Rejected an authenticated packet from controller {id}, which isn't paired");
+        };
+        Ok((packet.open(key)?, Some(id)))
+    }
+
+    // Rejects packets claiming to be from controller `id` unless they were authenticated as it.
+    // Unauthenticated packets are only accepted from controllers that have never been paired, and
+    // only if the server doesn't require pairing.
+    async fn check_sender(&self, id: u64, authenticated_as: Option<u64>) -> Result<()> {
+        match authenticated_as {
+            Some(authenticated) if authenticated == id => Ok(()),
+            Some(authenticated) => {
+                bail!(
+                    "Controller {authenticated} sent a packet claiming to be from controller {id}"
+                )
+            }
+            None if self.paired_clients.read().await.contains(&id) => {
+                bail!("Rejected an unauthenticated packet for paired controller {id}")
+            }
+            None if self.require_pairing || self.require_encryption => {
+                bail!("Rejected a packet from controller {id}, which isn't paired")
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Refuses packets on the serial port from an address other than the one controller `id` has
+    // been sending from, since they come from another controller using the same ID. The address
+    // is also where the controller's force feedback and pings go. A packet that is `verified` as
+    // coming from the controller, and isn't a replay, moves it to the packet's address, since a
+    // controller that restarts comes back from a new port.
+    async fn claim_serial_addr(&self, id: u64, addr: SocketAddr, verified: bool) -> Result<()> {
+        let mut detected_controllers = self.detected_controllers.write().await;
+        let Some(diagnostic) = detected_controllers.get_mut(&id) else {
+            return Ok(());
+        };
+        if verified {
+            diagnostic.record_handshake(addr);
+        }
+        if !diagnostic.claim_serial_addr(addr) {
+            self.mutated.store(true, Ordering::Relaxed);
+            bail!(
+                "Refused {}, which is using the ID of controller {id}",
+                format_addr(addr)
+            );
+        }
+        Ok(())
+    }
+
+    // Drops duplicate and out-of-order packets, and packets from controllers that haven't been
+    // detected yet
+    async fn accept_sequence(&self, packet: &InputFrame) -> bool {
+        let mut detected_controllers = self.detected_controllers.write().await;
+        let Some(diagnostic) = detected_controllers.get_mut(packet.client_id()) else {
+            return false;
+        };
+        let before = diagnostic.packets;
+        let accepted = diagnostic.accept_sequence(*packet.session(), *packet.sequence());
+        let after = diagnostic.packets;
+        // Only redraw the UI when something worth showing has changed
+        if (before.duplicates, before.stale, before.lost)
+            != (after.duplicates, after.stale, after.lost)
+        {
+            self.mutated.store(true, Ordering::Relaxed);
+        }
+        accepted
+    }
+
+    // Waits for a packet to be received, writes the data into `buf` and returns its length along
+    // with the address it came from
+    async fn get_packet(
+        &self,
+        buf: &mut [u8; SERIAL_BUFFER_LEN],
+        sock: &UdpSocket,
+    ) -> Result<(usize, SocketAddr)> {
+        loop {
+            let (len, addr) = sock.recv_from(buf).await?;
+            if len > 0 {
+                return Ok((len, addr));
+            }
+        }
+    }
+
+    // Unpacks a keyframe or delta and sends the inputs to your device's input handling. A keyframe
+    // sets every enabled input the joystick has, while a delta only touches the inputs that
+    // changed.
+    fn handle_packet(&self, virt_joystick: &mut VirtualJoystick, packet: InputFrame) -> Result<()> {
+        let buttons = self
+            .enabled_buttons
+            .intersection(virt_joystick.button_mask());
+        let axes = self.enabled_axes.intersection(virt_joystick.axis_mask());
+        let inputs = packet.unpack(&buttons, &axes);
+        // Remapped before they're sent, so the joystick only sees what the profile maps them to
+        for input in virt_joystick.remap(inputs) {
             virt_joystick.send_input(input)?;
-            virt_joystick.sync()?;
+        }
+        virt_joystick.sync()?;
+        Ok(())
+    }
+
+    // Relays the force feedback games send to the virtual joysticks to the controllers they belong
+    // to
+    async fn run_force_feedback_loop(self: Arc<Self>, sock: Arc<UdpSocket>) -> Result<()> {
+        let mut ticks = interval(FF_POLL_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut relaying = HashSet::new();
+        while !self.cancellation_token.is_cancelled() {
+            ticks.tick().await;
+            for (id, feedback) in self.poll_force_feedback(&mut relaying).await {
+                if let Err(e) = self.send_force_feedback(&sock, id, feedback).await {
+                    printdbg!("Couldn't send force feedback to controller {}: {}", id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Collects the force feedback every virtual joystick has received since the last poll.
+    // `relaying` holds the controllers that had a virtual joystick at the last poll; those that no
+    // longer have one are told to stop every effect, since their virtual joystick can't anymore.
+    async fn poll_force_feedback(&self, relaying: &mut HashSet<u64>) -> Vec<(u64, ForceFeedback)> {
+        let mut polled = Vec::new();
+        let mut active_controllers = self.active_controllers.write().await;
+        for (id, virt_joystick) in active_controllers.iter_mut() {
+            match virt_joystick.poll_force_feedback() {
+                Ok(feedback) => polled.extend(feedback.into_iter().map(|feedback| (*id, feedback))),
+                Err(e) => {
+                    printdbg!("Couldn't read force feedback for controller {}: {}", id, e);
+                }
+            }
+        }
+        for id in relaying.iter() {
+            if !active_controllers.contains_key(id) {
+                polled.push((*id, ForceFeedback::StopAll));
+            }
+        }
+        *relaying = active_controllers.keys().copied().collect();
+        polled
+    }
+
+    // Sends force feedback to the address controller `id` sends its input from, authenticated if
+    // the controller is paired
+    async fn send_force_feedback(
+        &self,
+        sock: &UdpSocket,
+        id: u64,
+        feedback: ForceFeedback,
+    ) -> Result<()> {
+        let (addr, (session, sequence)) = {
+            let mut detected_controllers = self.detected_controllers.write().await;
+            let Some(diagnostic) = detected_controllers.get_mut(&id) else {
+                bail!("Controller {id} hasn't been detected");
+            };
+            let Some(addr) = diagnostic.serial_addr else {
+                bail!("Controller {id} hasn't sent any input yet");
+            };
+            (addr, diagnostic.feedback.next())
+        };
+        let packet = ForceFeedbackPacket {
+            id,
+            session,
+            sequence,
+            feedback,
+        };
+        self.send_to_controller(sock, id, &packet, addr).await
+    }
+
+    // Sends `packet` to controller `id` at `addr`, authenticated if the controller is paired
+    async fn send_to_controller<T>(
+        &self,
+        sock: &UdpSocket,
+        id: u64,
+        packet: &T,
+        addr: SocketAddr,
+    ) -> Result<()>
+    where
+        T: Message,
+    {
+        match self.paired_clients.read().await.get(&id) {
+            Some(key) => {
+                let packet = AuthenticatedPacket::seal(id, serialize(packet)?, key);
+                reply(sock, &packet, addr).await
+            }
+            None => reply(sock, packet, addr).await,
+        }
+    }
+
+    // Pings every controller that has sent input, so that their latency can be measured
+    async fn run_ping_loop(self: Arc<Self>, sock: Arc<UdpSocket>) -> Result<()> {
+        let mut ticks = interval(PING_INTERVAL);
+        while !self.cancellation_token.is_cancelled() {
+            ticks.tick().await;
+            let sent_at = Local::now().timestamp_millis();
+            let pings: Vec<(u64, u64, SocketAddr)> = self
+                .detected_controllers
+                .write()
+                .await
+                .values_mut()
+                .filter(|diagnostic| !diagnostic.status.is_silent())
+                .filter_map(|diagnostic| {
+                    let addr = diagnostic.serial_addr?;
+                    let nonce = diagnostic.send_ping(sent_at);
+                    Some((diagnostic.id, nonce, addr))
+                })
+                .collect();
+            for (id, nonce, addr) in pings {
+                let ping = PingPacket { id, nonce, sent_at };
+                if let Err(e) = self.send_to_controller(&sock, id, &ping, addr).await {
+                    printdbg!("Couldn't ping controller {}: {}", id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Measures the round trip to the controller that answered a ping
+    async fn record_pong(&self, pong: PongPacket) -> Result<()> {
+        let received_at = Local::now().timestamp_millis();
+        let mut detected_controllers = self.detected_controllers.write().await;
+        let Some(diagnostic) = detected_controllers.get_mut(&pong.id) else {
+            bail!(
+                "Received a pong from controller {}, which hasn't been detected",
+                pong.id
+            );
+        };
+        if !diagnostic.record_pong(&pong, received_at) {
+            bail!(
+                "Received a pong from controller {} that doesn't answer an outstanding ping",
+                pong.id
+            );
+        }
+        self.mutated.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Records what the controller that said hello has, which its virtual joystick is built from
+    // the next time it's activated. A virtual joystick that's already active keeps its buttons and
+    // axes, but rescales the controller's axes from the ranges it reports.
+    async fn record_hello(&self, hello: HelloPacket) -> Result<()> {
+        hello.capabilities.validate()?;
+        if let Some(motion) = &hello.motion {
+            motion.validate()?;
+        }
+        if let Some(desktop) = &hello.desktop {
+            desktop.validate()?;
+        }
+        let mut detected_controllers = self.detected_controllers.write().await;
+        let Some(diagnostic) = detected_controllers.get_mut(&hello.id) else {
+            bail!(
+                "Received a hello from controller {}, which hasn't been detected",
+                hello.id
+            );
+        };
+        if diagnostic.capabilities.as_ref() != Some(&hello.capabilities)
+            || diagnostic.motion != hello.motion
+            || diagnostic.desktop != hello.desktop
+        {
+            printdbg!("Controller {} said hello", hello.id);
+            // The enabled inputs a controller doesn't have are never sent to its joystick
+            let buttons: Bitmask = hello.capabilities.keys().collect();
+            let axes: Bitmask = hello.capabilities.axes().map(|(axis, _)| axis).collect();
+            let missing_buttons = self.enabled_buttons.difference(&buttons);
+            let missing_axes = self.enabled_axes.difference(&axes);
+            if !missing_buttons.is_empty() || !missing_axes.is_empty() {
+                printdbg!(
+                    "Controller {} lacks {} of the enabled buttons and {} of the enabled axes",
+                    (hello.id),
+                    (missing_buttons.count_ones()),
+                    (missing_axes.count_ones())
+                );
+            }
+            if let Some(virt_joystick) = self.active_controllers.write().await.get_mut(&hello.id) {
+                virt_joystick.set_source_ranges(&hello.capabilities);
+                virt_joystick.set_motion(hello.motion.as_ref())?;
+                if diagnostic.desktop != hello.desktop {
+                    virt_joystick.set_desktop(hello.desktop.as_ref())?;
+                }
+            }
+            diagnostic.capabilities = Some(hello.capabilities);
+            diagnostic.motion = hello.motion;
+            diagnostic.desktop = hello.desktop;
+            self.mutated.store(true, Ordering::Relaxed);
         }
         Ok(())
     }
 
     async fn run_device_search_loop(self: Arc<Self>) -> Result<()> {
-        let addr = format!("0.0.0.0:{}", self.device_search_port);
-        let sock = UdpSocket::bind(addr).await?;
+        let sock = bind_dual_stack(self.device_search_port)?;
+        join_discovery_group(&sock)?;
         let mut interval = interval(Duration::from_secs(3));
         let mut buf: [u8; 256] = [0; 256];
         while !(&self).cancellation_token.is_cancelled() {
@@ -327,48 +1196,187 @@ impl StarboardServer {
         buf: &mut [u8; 256],
     ) -> Result<()> {
         tokio::select! {
-            _ = interval.tick() => {}
-            _ = sock.recv(buf) => self.handle_device_search_packet(buf).await?
+            _ = interval.tick() => self.update_lifecycles().await,
+            res = sock.recv_from(buf) => {
+                let (len, addr) = res?;
+                self.handle_device_search_packet(&buf[..len], addr, sock).await?
+            }
+        }
+        Ok(())
+    }
 
+    // Handles a datagram received on the device search port, recording it if it's dropped
+    pub async fn handle_device_search_packet(
+        self: &Arc<Self>,
+        raw: &[u8],
+        addr: SocketAddr,
+        sock: &UdpSocket,
+    ) -> Result<()> {
+        if let Err(e) = self.dispatch_device_search_packet(raw, addr, sock).await {
+            printdbg!(
+                "Rejected datagram on the device search port from {}: {}",
+                format_addr(addr),
+                e
+            );
+            self.record_reject(Port::DeviceSearch, addr, e).await;
         }
-        self.update_timeout_statuses().await;
         Ok(())
     }
 
-    async fn handle_device_search_packet(self: &Arc<Self>, buf: &mut [u8; 256]) -> Result<()> {
-        let packet: BroadcastPacket = deserialize(Vec::from(&mut *buf))?;
-        self.update_device_info_with_packet(packet).await;
+    // The device search port carries both presence broadcasts and pairing handshakes. Every
+    // presence broadcast is answered with the server's announcement.
+    async fn dispatch_device_search_packet(
+        self: &Arc<Self>,
+        raw: &[u8],
+        addr: SocketAddr,
+        sock: &UdpSocket,
+    ) -> Result<()> {
+        match peek_kind(raw)? {
+            MessageKind::PairRequest => {
+                let request: PairRequest = deserialize(raw)?;
+                let paired = self.paired_clients.read().await.contains(&request.id);
+                let commit = self
+                    .pairing
+                    .write()
+                    .await
+                    .handle_request(addr, request, paired)?;
+                self.mutated.store(true, Ordering::Relaxed);
+                reply(sock, &commit, addr).await?;
+            }
+            MessageKind::PairNonce => {
+                let mut pairing = self.pairing.write().await;
+                let reveal = pairing.handle_nonce(addr, deserialize(raw)?)?;
+                printdbg!("{}", (pairing.status()));
+                self.mutated.store(true, Ordering::Relaxed);
+                reply(sock, &reveal, addr).await?;
+            }
+            MessageKind::PairConfirm => {
+                let res = self
+                    .pairing
+                    .write()
+                    .await
+                    .handle_confirm(addr, deserialize(raw)?);
+                self.mutated.store(true, Ordering::Relaxed);
+                // The client keeps confirming until the operator has approved the pairing
+                let Some(paired) = res? else {
+                    return Ok(());
+                };
+                let mut paired_clients = self.paired_clients.write().await;
+                match paired.repairing {
+                    true => paired_clients.replace(paired.id, paired.key)?,
+                    false => paired_clients.insert(paired.id, paired.key)?,
+                }
+                printdbg!("Paired with controller {}", (paired.id));
+                reply(sock, &paired.confirm, addr).await?;
+            }
+            MessageKind::Broadcast | MessageKind::Authenticated => {
+                // Controllers are told about the server before their input is checked, so that
+                // every controller looking for servers can list it
+                reply(sock, &self.announcement, addr).await?;
+                let (inner, authenticated_as) = self.authenticate(raw).await?;
+                let packet: BroadcastPacket = deserialize(&inner)?;
+                self.check_sender(*packet.id(), authenticated_as).await?;
+                self.update_device_info_with_packet(packet, addr).await?;
+            }
+            kind => bail!("Unexpected {kind:?} on the device search port"),
+        }
         Ok(())
     }
 
-    async fn update_device_info_with_packet(self: &Arc<Self>, packet: BroadcastPacket) {
+    // Records a presence broadcast from `addr`. A broadcast using the ID of a controller that's
+    // still broadcasting from somewhere else is refused.
+    async fn update_device_info_with_packet(
+        self: &Arc<Self>,
+        packet: BroadcastPacket,
+        addr: SocketAddr,
+    ) -> Result<()> {
         let mut detected_controllers = self.detected_controllers.write().await;
-        if !detected_controllers.contains_key(packet.id()) {
-            let diagnostic: ControllerDiagnostic = packet.into();
-            detected_controllers.insert(*diagnostic.id(), diagnostic);
-        } else if let Some(diagnostic) = detected_controllers.get_mut(packet.id()) {
-            diagnostic.latency.push_back(Some(packet.latency()));
-            diagnostic.last_ping = Local::now().timestamp();
-        }
         self.mutated.store(true, Ordering::Relaxed);
+        let diagnostic = detected_controllers
+            .entry(*packet.id())
+            .or_insert_with(|| packet.into());
+        if !diagnostic.claim_presence_addr(addr) {
+            bail!(
+                "Refused {}, which is using the ID of controller {}",
+                format_addr(addr),
+                packet.id()
+            );
+        }
+        diagnostic.record_ping();
+        Ok(())
     }
 
-    async fn update_timeout_statuses(self: &Arc<Self>) {
-        let mut detected_controllers = self.detected_controllers.write().await;
-        for diagnostic in detected_controllers.values_mut() {
-            self.update_timeout_status(diagnostic);
+    // Moves every detected controller to the state its connection calls for. Controllers that go
+    // quiet have their virtual joysticks released, and those that have been quiet for long enough
+    // are forgotten along with their virtual joysticks.
+    async fn update_lifecycles(self: &Arc<Self>) {
+        let now = Local::now().timestamp();
+        let mut silenced = Vec::new();
+        let mut evicted = Vec::new();
+        {
+            let mut detected_controllers = self.detected_controllers.write().await;
+            for diagnostic in detected_controllers.values_mut() {
+                let health = diagnostic.check_health(now);
+                let previous = diagnostic.update_status(&self.thresholds, &health);
+                let current = *diagnostic.status();
+                if previous != current {
+                    printdbg!(
+                        "Controller {} went from {} to {}",
+                        diagnostic.id(),
+                        previous,
+                        current
+                    );
+                    self.mutated.store(true, Ordering::Relaxed);
+                }
+                // Whatever the last packet held would otherwise stay held until the controller
+                // came back
+                if current.is_silent() && !previous.is_silent() {
+                    silenced.push(*diagnostic.id());
+                }
+                if self.thresholds.should_evict(&health) {
+                    evicted.push(*diagnostic.id());
+                }
+            }
+            if !evicted.is_empty() {
+                detected_controllers.retain(|id, _| !evicted.contains(id));
+                self.mutated.store(true, Ordering::Relaxed);
+            }
         }
+        self.neutralize_controllers(&silenced).await;
+        self.remove_controllers(&evicted).await;
     }
 
-    fn update_timeout_status(self: &Arc<Self>, diagnostic: &mut ControllerDiagnostic) {
-        if Self::poll_device_timed_out(diagnostic) {
-            diagnostic.status = ControllerState::NotResponding;
-            self.mutated.store(true, Ordering::Relaxed);
+    // Destroys the virtual joysticks of `ids`, releasing their inputs first
+    async fn remove_controllers(&self, ids: &[u64]) {
+        let mut active_controllers = self.active_controllers.write().await;
+        for id in ids {
+            if let Some(mut virt_joystick) = active_controllers.remove(id) {
+                printdbg!("Forgetting controller {}", id);
+                if let Err(e) = virt_joystick.neutralize() {
+                    printdbg!("Couldn't release the inputs of controller {}: {}", id, e);
+                }
+            }
         }
     }
 
-    #[inline]
-    fn poll_device_timed_out(diagnostic: &ControllerDiagnostic) -> bool {
-        Local::now().timestamp() - diagnostic.last_ping >= 15
+    // Releases every input held on the virtual joysticks of `ids`
+    async fn neutralize_controllers(&self, ids: &[u64]) {
+        let mut active_controllers = self.active_controllers.write().await;
+        for id in ids {
+            if let Some(virt_joystick) = active_controllers.get_mut(id)
+                && let Err(e) = virt_joystick.neutralize()
+            {
+                printdbg!("Couldn't release the inputs of controller {}: {}", id, e);
+            }
+        }
     }
 }
+
+// Sends `packet` to `addr`, which is usually the address a request came from
+async fn reply<T>(sock: &UdpSocket, packet: &T, addr: SocketAddr) -> Result<()>
+where
+    T: Message,
+{
+    sock.send_to(&serialize(packet)?, addr).await?;
+    Ok(())
+}
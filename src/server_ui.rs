@@ -12,24 +12,51 @@ use tokio::sync::{Mutex, RwLock};
 use tokio_util::sync::CancellationToken;
 
 use crate::evdev_sb::VirtualJoystick;
+use crate::gyro::GyroSettings;
+use crate::lifecycle::ControllerState;
+use crate::pairing::ServerPairing;
+use crate::rejects::{RECENT_REJECTS, RejectLog};
+use crate::remap::{Profile, next_profile};
 use crate::server::{ControllerMap, DiagnosticMap};
 use crate::string::StarboardString;
 
 const LAVENDER: Color = Color::Rgb(150, 100, 175);
 
+// Returns how a detected controller in `status` is drawn, so that problems stand out
+fn status_style(status: ControllerState) -> Style {
+    match status {
+        ControllerState::Online => Style::default(),
+        ControllerState::Degraded => Style::default().fg(Color::Yellow),
+        ControllerState::NotResponding => Style::default().fg(Color::Red),
+        ControllerState::Disconnected => Style::default().fg(Color::DarkGray),
+    }
+}
+
 // Keeps track of what page the UI is currently on
 #[derive(PartialEq, Copy, Clone)]
 enum UIPage {
     Home,
     Controllers,
+    Pairing,
     Settings,
 }
 
+// What the server builds every virtual joystick with
+pub struct JoystickSettings {
+    pub trigger_threshold: Option<f64>,
+    pub gyro: GyroSettings,
+    pub profiles: Vec<Arc<Profile>>, // The remapping profiles controllers are cycled through
+}
+
 pub struct UIState {
     page: UIPage,
     selection_state: ListState,
     detected_controllers: Arc<RwLock<DiagnosticMap>>,
     active_controllers: Arc<RwLock<ControllerMap>>,
+    pairing: Arc<RwLock<ServerPairing>>,
+    rejects: Arc<RwLock<RejectLog>>,
+    joysticks: JoystickSettings,
+    errors: Vec<String>, // What went wrong recently on the server's side, newest first
 }
 
 // This is an optimized version of the UI that can be run in the main thread and does not need to
@@ -44,6 +71,10 @@ impl StarboardServerUI {
     pub fn new(
         detected_controllers: Arc<RwLock<DiagnosticMap>>,
         active_controllers: Arc<RwLock<ControllerMap>>,
+        pairing: Arc<RwLock<ServerPairing>>,
+        rejects: Arc<RwLock<RejectLog>>,
+        joysticks: JoystickSettings,
+        errors: Vec<String>,
         cancellation_token: CancellationToken,
     ) -> Result<Self> {
         let terminal = ratatui::init();
@@ -52,6 +83,10 @@ impl StarboardServerUI {
             selection_state: ListState::default().with_selected(Some(0)),
             detected_controllers,
             active_controllers,
+            pairing,
+            rejects,
+            joysticks,
+            errors,
         };
         Ok(Self {
             terminal,
@@ -70,6 +105,7 @@ impl StarboardServerUI {
         match ui_state.page {
             UIPage::Home => Self::render_home(frame, ui_state),
             UIPage::Controllers => Self::render_controllers(frame, ui_state),
+            UIPage::Pairing => Self::render_pairing(frame, ui_state),
             _ => {}
         }
     }
@@ -84,7 +120,7 @@ impl StarboardServerUI {
             .flex(Flex::Center);
         let [rect] = major_layout.areas(frame.area());
         let [rect] = minor_layout.areas(rect);
-        let list = List::new(["Controllers", "Settings", "Exit"])
+        let list = List::new(["Controllers", "Pair Controller", "Settings", "Exit"])
             .block(Block::bordered().title("Starboard"))
             .style(LAVENDER)
             .highlight_style(Style::default().bg(LAVENDER).fg(Color::Black));
@@ -94,9 +130,13 @@ impl StarboardServerUI {
     fn render_controllers(frame: &mut Frame, ui_state: &mut UIState) {
         let detected_controllers = ui_state.detected_controllers.blocking_read();
         let active_controllers = ui_state.active_controllers.blocking_write();
-        let detected_controller_names = detected_controllers
-            .values()
-            .map(|diagnostic| diagnostic.to_text());
+        let detected_controller_names = detected_controllers.values().map(|diagnostic| {
+            let style = match diagnostic.conflict() {
+                Some(_) => Style::default().fg(Color::Magenta),
+                None => status_style(*diagnostic.status()),
+            };
+            ListItem::new(diagnostic.to_text()).style(style)
+        });
 
         // The `None` values
         // being filtered out are ID's that
@@ -108,6 +148,24 @@ impl StarboardServerUI {
             .filter_map(|id| detected_controllers.get(id))
             .map(|diagnostic| diagnostic.name().to_text());
 
+        // Each recent reject is shown with how many datagrams its sender has had rejected so far,
+        // after whatever went wrong on the server's side
+        let rejects = ui_state.rejects.blocking_read();
+        let error_lines = ui_state
+            .errors
+            .iter()
+            .map(|error| ListItem::new(error.as_str()).style(Color::Red));
+        let reject_lines = rejects.recent().map(|reject| {
+            let count = rejects.count(reject.source.ip());
+            ListItem::new(format!("{reject} ({count} rejected)")).style(Color::Red)
+        });
+
+        let [lists_rect, rejects_rect, hints_rect] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(RECENT_REJECTS as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
         let layout = Layout::horizontal([Constraint::Ratio(1, 2); 2]).horizontal_margin(5);
         let active_list = List::new(active_controller_names)
             .block(Block::bordered().title("Active Controllers"))
@@ -117,20 +175,65 @@ impl StarboardServerUI {
             .style(LAVENDER)
             .highlight_style(Style::default().bg(LAVENDER).fg(Color::Black));
 
-        let [active_rect, detected_rect] = layout.areas(frame.area());
+        let rejects_list = List::new(error_lines.chain(reject_lines))
+            .block(Block::bordered().title("Recent Errors"))
+            .style(LAVENDER);
+
+        let [active_rect, detected_rect] = layout.areas(lists_rect);
+        let rejects_rect = Layout::horizontal([Constraint::Min(0)])
+            .horizontal_margin(5)
+            .split(rejects_rect)[0];
         frame.render_widget(active_list, active_rect);
         frame.render_stateful_widget(detected_list, detected_rect, &mut ui_state.selection_state);
+        frame.render_widget(rejects_list, rejects_rect);
+        frame.render_widget(
+            Self::hints(
+                "Enter: enable/disable, t: trackpads, g: gyro, p: profile, Backspace: back",
+            ),
+            hints_rect,
+        );
     }
 
-    pub fn handle_event(&mut self, event: Event) -> Result<()> {
-        match event {
-            Event::Key(key) => self.handle_key_press(key)?,
-            _ => {}
+    // Returns the line that lists the keys a page takes
+    fn hints(hints: &str) -> Paragraph<'_> {
+        Paragraph::new(hints).style(Color::DarkGray).centered()
+    }
+
+    // Render the pairing page, which shows the PIN to enter on the controller being paired and asks
+    // the operator for the code it shows in return
+    fn render_pairing(frame: &mut Frame, ui_state: &mut UIState) {
+        let pairing = ui_state.pairing.blocking_read();
+        let layout = Layout::vertical([Constraint::Max(5), Constraint::Length(1)])
+            .horizontal_margin(5)
+            .flex(Flex::Center);
+        let [rect, hints_rect] = layout.areas(frame.area());
+        let paragraph = Paragraph::new(pairing.status().to_string())
+            .block(Block::bordered().title("Pair Controller"))
+            .style(LAVENDER)
+            .centered()
+            .wrap(Wrap { trim: true });
+        frame.render_widget(paragraph, rect);
+        frame.render_widget(
+            Self::hints("0-9: enter the controller's code, n: refuse, Backspace: back"),
+            hints_rect,
+        );
+    }
+
+    pub fn handle_event(&mut self, event: Event) {
+        if let Event::Key(key) = event {
+            self.handle_key_press(key);
         }
-        Ok(())
     }
 
-    fn handle_key_press(&mut self, key: KeyEvent) -> Result<()> {
+    // Shows what went wrong if `result` is an error, rather than letting it stop the server
+    fn report(&mut self, result: Result<()>) {
+        if let Err(e) = result {
+            self.ui_state.errors.insert(0, e.to_string());
+            self.ui_state.errors.truncate(RECENT_REJECTS);
+        }
+    }
+
+    fn handle_key_press(&mut self, key: KeyEvent) {
         match key.code {
             KeyCode::Char('c') => {
                 // TODO: Clean up this nested logic. Yes, I was lazy when I wrote it.
@@ -140,34 +243,64 @@ impl StarboardServerUI {
             }
             KeyCode::Up => self.ui_state.selection_state.scroll_up_by(1),
             KeyCode::Down => self.ui_state.selection_state.scroll_down_by(1),
-            KeyCode::Enter => self.on_enter()?,
+            KeyCode::Enter => self.on_enter(),
+            KeyCode::Char('t') if self.ui_state.page == UIPage::Controllers => {
+                let result = self.cycle_trackpad_mode();
+                self.report(result);
+            }
+            KeyCode::Char('g') if self.ui_state.page == UIPage::Controllers => {
+                let result = self.cycle_gyro_mode();
+                self.report(result);
+            }
+            KeyCode::Char('p') if self.ui_state.page == UIPage::Controllers => {
+                let result = self.cycle_profile();
+                self.report(result);
+            }
+            KeyCode::Char(digit)
+                if self.ui_state.page == UIPage::Pairing && digit.is_ascii_digit() =>
+            {
+                self.ui_state.pairing.blocking_write().enter_digit(digit)
+            }
+            KeyCode::Char('n') if self.ui_state.page == UIPage::Pairing => {
+                self.ui_state.pairing.blocking_write().refuse()
+            }
             KeyCode::Backspace => self.on_backspace(),
             _ => {}
         }
-        Ok(())
     }
 
     // Execute behavior based on the currently selected button
-    fn on_enter(&mut self) -> Result<()> {
+    fn on_enter(&mut self) {
         let selected = self.ui_state.selection_state.selected();
         match (self.ui_state.page, selected) {
             (UIPage::Home, Some(0)) => self.switch_page(UIPage::Controllers),
-            (UIPage::Home, Some(1)) => self.switch_page(UIPage::Settings),
-            (UIPage::Home, Some(2)) => self.cancellation_token.cancel(),
-            (UIPage::Controllers, Some(v)) => self.toggle_controller(v)?,
+            (UIPage::Home, Some(1)) => self.switch_page(UIPage::Pairing),
+            (UIPage::Home, Some(2)) => self.switch_page(UIPage::Settings),
+            (UIPage::Home, Some(3)) => self.cancellation_token.cancel(),
+            (UIPage::Controllers, Some(v)) => {
+                let result = self.toggle_controller(v);
+                self.report(result);
+            }
             _ => {}
         }
-        Ok(())
     }
 
     fn switch_page(&mut self, page: UIPage) {
+        // Pairing requests are only answered while the pairing page is open
+        if page == UIPage::Pairing {
+            self.ui_state.pairing.blocking_write().open();
+        } else if self.ui_state.page == UIPage::Pairing {
+            self.ui_state.pairing.blocking_write().close();
+        }
         self.ui_state.page = page;
         self.ui_state.selection_state.select(Some(0));
     }
 
     fn on_backspace(&mut self) {
         match self.ui_state.page {
-            UIPage::Controllers | UIPage::Settings => self.switch_page(UIPage::Home),
+            UIPage::Controllers | UIPage::Pairing | UIPage::Settings => {
+                self.switch_page(UIPage::Home)
+            }
             _ => {}
         }
     }
@@ -176,13 +309,93 @@ impl StarboardServerUI {
     fn toggle_controller(&self, selected: usize) -> Result<()> {
         let detected_controllers = self.ui_state.detected_controllers.blocking_read();
         let mut active_controllers = self.ui_state.active_controllers.blocking_write();
-        let controller = detected_controllers.values().nth(selected).unwrap();
+        // The selection may be past the end of the list if a controller has just been forgotten
+        let Some(controller) = detected_controllers.values().nth(selected) else {
+            return Ok(());
+        };
         let id = controller.id();
         let name = controller.name();
-        if active_controllers.contains_key(id) {
-            active_controllers.remove(id);
+        if let Some(mut virt_joystick) = active_controllers.remove(id) {
+            // The virtual joystick is about to go away, but nothing it holds should outlive it
+            virt_joystick.neutralize()?;
         } else {
-            active_controllers.insert(*id, VirtualJoystick::steam_deck_template(*name)?);
+            // Controllers that haven't said what they have yet look like a Steam Deck. Otherwise
+            // the joystick also gets whatever the controller's profile maps to.
+            let threshold = self.ui_state.joysticks.trigger_threshold;
+            let mut virt_joystick = match (controller.capabilities(), &controller.profile) {
+                (Some(capabilities), Some(profile)) => {
+                    let capabilities = profile.extend(capabilities);
+                    VirtualJoystick::from_capabilities(*name, &capabilities, threshold)?
+                }
+                (Some(capabilities), None) => {
+                    VirtualJoystick::from_capabilities(*name, capabilities, threshold)?
+                }
+                (None, _) => VirtualJoystick::steam_deck_template(*name, threshold)?,
+            };
+            virt_joystick.set_trackpad_mode(controller.trackpad_mode)?;
+            virt_joystick.set_motion(controller.motion())?;
+            virt_joystick.set_desktop(controller.desktop())?;
+            virt_joystick.set_gyro(controller.gyro_mode, self.ui_state.joysticks.gyro)?;
+            virt_joystick.set_profile(controller.profile.clone())?;
+            active_controllers.insert(*id, virt_joystick);
+        }
+        Ok(())
+    }
+
+    // Moves the selected controller's trackpads on to the next mode, straight away if the
+    // controller is active
+    fn cycle_trackpad_mode(&self) -> Result<()> {
+        let Some(selected) = self.ui_state.selection_state.selected() else {
+            return Ok(());
+        };
+        let mut detected_controllers = self.ui_state.detected_controllers.blocking_write();
+        let mut active_controllers = self.ui_state.active_controllers.blocking_write();
+        let Some(controller) = detected_controllers.values_mut().nth(selected) else {
+            return Ok(());
+        };
+        controller.trackpad_mode = controller.trackpad_mode.next();
+        if let Some(virt_joystick) = active_controllers.get_mut(controller.id()) {
+            virt_joystick.set_trackpad_mode(controller.trackpad_mode)?;
+        }
+        Ok(())
+    }
+
+    // Moves the selected controller's gyro on to aiming the next way, straight away if the
+    // controller is active
+    fn cycle_gyro_mode(&self) -> Result<()> {
+        let Some(selected) = self.ui_state.selection_state.selected() else {
+            return Ok(());
+        };
+        let mut detected_controllers = self.ui_state.detected_controllers.blocking_write();
+        let mut active_controllers = self.ui_state.active_controllers.blocking_write();
+        let Some(controller) = detected_controllers.values_mut().nth(selected) else {
+            return Ok(());
+        };
+        controller.gyro_mode = controller.gyro_mode.next();
+        if let Some(virt_joystick) = active_controllers.get_mut(controller.id()) {
+            virt_joystick.set_gyro(controller.gyro_mode, self.ui_state.joysticks.gyro)?;
+        }
+        Ok(())
+    }
+
+    // Moves the selected controller on to the next remapping profile, straight away if the
+    // controller is active. Buttons and axes the profile maps to that an active joystick doesn't
+    // have only show up once the controller is next activated.
+    fn cycle_profile(&self) -> Result<()> {
+        let Some(selected) = self.ui_state.selection_state.selected() else {
+            return Ok(());
+        };
+        let mut detected_controllers = self.ui_state.detected_controllers.blocking_write();
+        let mut active_controllers = self.ui_state.active_controllers.blocking_write();
+        let Some(controller) = detected_controllers.values_mut().nth(selected) else {
+            return Ok(());
+        };
+        controller.profile = next_profile(
+            &self.ui_state.joysticks.profiles,
+            controller.profile.as_ref(),
+        );
+        if let Some(virt_joystick) = active_controllers.get_mut(controller.id()) {
+            virt_joystick.set_profile(controller.profile.clone())?;
         }
         Ok(())
     }
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use bincode::{Decode, Encode};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use rand::{RngCore, rngs::OsRng};
+use sha2::Sha256;
+
+use crate::{
+    datagram::{Message, MessageKind},
+    pairing::{NONCE_LEN, PairingKey},
+    sequence::{SequenceVerdict, SequenceWindow},
+};
+
+// Encrypted sessions are keyed off the pairing key. The client sends a `SessionInit` with a fresh
+// nonce, authenticated with the pairing key, and the server answers with a `SessionAccept` holding
+// a nonce of its own. Both nonces go into the session key, so every session is encrypted with a
+// different key. The server keeps using the client's current session until a packet arrives in the
+// new one, which only the client can seal, so a captured request can't be replayed to cut the
+// client off. Every sealed packet carries a counter, which doubles as the AEAD nonce and is checked
+// against a `SequenceWindow` to reject replays.
+
+// How many sessions a client can be offered before it sends a packet in one of them. Offers past
+// that push out the oldest, so replayed requests can't pile up.
+const OFFERED_SESSIONS: usize = 4;
+
+// Sent by the client, wrapped in an `AuthenticatedPacket`, to start a new session
+#[derive(Debug, Copy, Clone, PartialEq, Decode, Encode)]
+pub struct SessionInit {
+    pub id: u64,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl Message for SessionInit {
+    const KIND: MessageKind = MessageKind::SessionInit;
+}
+
+// The server's answer to a `SessionInit`, wrapped in an `AuthenticatedPacket`
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct SessionAccept {
+    pub id: u64,
+    pub session: u32,
+    pub client_nonce: [u8; NONCE_LEN],
+    pub server_nonce: [u8; NONCE_LEN],
+}
+
+impl Message for SessionAccept {
+    const KIND: MessageKind = MessageKind::SessionAccept;
+}
+
+// Sent by the server, wrapped in an `AuthenticatedPacket`, when it receives a packet for a session
+// it doesn't know, which usually means the server restarted
+#[derive(Debug, Copy, Clone, Decode, Encode)]
+pub struct SessionExpired {
+    pub id: u64,
+    pub session: u32,
+}
+
+impl Message for SessionExpired {
+    const KIND: MessageKind = MessageKind::SessionExpired;
+}
+
+// A framed datagram encrypted with a session key
+#[derive(Debug, Decode, Encode)]
+pub struct SealedPacket {
+    id: u64,
+    session: u32,
+    counter: u64,
+    ciphertext: Vec<u8>,
+}
+
+impl Message for SealedPacket {
+    const KIND: MessageKind = MessageKind::Sealed;
+}
+
+impl SealedPacket {
+    // Returns the ID of the client that claims to have sent the packet
+    pub fn id(&self) -> &u64 {
+        &self.id
+    }
+
+    pub fn session(&self) -> &u32 {
+        &self.session
+    }
+
+    // The header fields are authenticated along with the ciphertext, so they can't be swapped
+    // between packets
+    fn associated_data(id: u64, session: u32, counter: u64) -> [u8; 20] {
+        let mut aad = [0; 20];
+        aad[0..8].copy_from_slice(&id.to_le_bytes());
+        aad[8..12].copy_from_slice(&session.to_le_bytes());
+        aad[12..20].copy_from_slice(&counter.to_le_bytes());
+        aad
+    }
+}
+
+// The cipher for the packets a client sends during a session
+pub struct SessionCipher {
+    session: u32,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    window: SequenceWindow,
+}
+
+impl SessionCipher {
+    fn derive(pairing_key: &PairingKey, id: u64, accept: &SessionAccept) -> Self {
+        let mut salt = [0; NONCE_LEN * 2];
+        salt[..NONCE_LEN].copy_from_slice(&accept.client_nonce);
+        salt[NONCE_LEN..].copy_from_slice(&accept.server_nonce);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), pairing_key.as_bytes());
+        let mut info = b"starboard session client to server".to_vec();
+        info.extend_from_slice(&id.to_le_bytes());
+        info.extend_from_slice(&accept.session.to_le_bytes());
+        let mut key = [0; 32];
+        // Safety of using `unwrap()`: 32 bytes is well under HKDF's maximum output length
+        hkdf.expand(&info, &mut key).unwrap();
+        Self {
+            session: accept.session,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            counter: 0,
+            window: SequenceWindow::new(),
+        }
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    // Encrypts a framed datagram. Every packet gets a fresh counter, so no nonce is ever reused.
+    pub fn seal(&mut self, id: u64, inner: &[u8]) -> Result<SealedPacket> {
+        let counter = self.counter;
+        self.counter += 1;
+        let aad = SealedPacket::associated_data(id, self.session, counter);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&Self::nonce(counter)),
+                Payload {
+                    msg: inner,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Couldn't encrypt a packet"))?;
+        Ok(SealedPacket {
+            id,
+            session: self.session,
+            counter,
+            ciphertext,
+        })
+    }
+
+    // Decrypts a sealed packet, rejecting it if it has been tampered with or replayed
+    pub fn open(&mut self, packet: SealedPacket) -> Result<Vec<u8>> {
+        let aad = SealedPacket::associated_data(packet.id, packet.session, packet.counter);
+        let inner = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&Self::nonce(packet.counter)),
+                Payload {
+                    msg: &packet.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                anyhow!(
+                    "Rejected a sealed packet from controller {} that failed to decrypt",
+                    packet.id
+                )
+            })?;
+        // The window is only updated once the packet is known to be genuine, so forged counters
+        // can't push real packets out of it
+        match self.window.check(packet.counter) {
+            SequenceVerdict::Accepted { .. } | SequenceVerdict::Late => Ok(inner),
+            SequenceVerdict::Duplicate | SequenceVerdict::Stale => {
+                bail!("Rejected a replayed packet from controller {}", packet.id)
+            }
+        }
+    }
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// The client's side of an encrypted session
+pub struct ClientSession {
+    id: u64,
+    key: PairingKey,
+    pending: Option<SessionInit>,
+    cipher: Option<SessionCipher>,
+}
+
+impl ClientSession {
+    pub fn new(id: u64, key: PairingKey) -> Self {
+        Self {
+            id,
+            key,
+            pending: None,
+            cipher: None,
+        }
+    }
+
+    // Starts negotiating a new session. The current session, if any, stays usable until the
+    // server accepts the new one.
+    pub fn init(&mut self) -> SessionInit {
+        let init = SessionInit {
+            id: self.id,
+            nonce: random_nonce(),
+        };
+        self.pending = Some(init);
+        init
+    }
+
+    // Returns the `SessionInit` the server hasn't answered yet, if any
+    pub fn pending(&self) -> Option<SessionInit> {
+        self.pending
+    }
+
+    // Derives the session keys once the server accepts the pending `SessionInit`
+    pub fn accept(&mut self, accept: SessionAccept) -> Result<()> {
+        match self.pending {
+            Some(init) if init.nonce == accept.client_nonce && init.id == accept.id => {}
+            _ => bail!("Received a session acceptance that doesn't answer the pending request"),
+        }
+        self.pending = None;
+        self.cipher = Some(SessionCipher::derive(&self.key, self.id, &accept));
+        Ok(())
+    }
+
+    // Returns true if `expired` names the session currently in use, which must then be renegotiated
+    pub fn is_current(&self, expired: &SessionExpired) -> bool {
+        self.cipher
+            .as_ref()
+            .is_some_and(|cipher| cipher.session == expired.session)
+    }
+
+    // Encrypts a framed datagram, or returns `None` if no session has been established yet
+    pub fn seal(&mut self, inner: &[u8]) -> Option<Result<SealedPacket>> {
+        let id = self.id;
+        self.cipher.as_mut().map(|cipher| cipher.seal(id, inner))
+    }
+}
+
+// Every encrypted session the server has accepted. The previous session of each client is kept
+// around so that packets sent while renegotiating aren't lost.
+#[derive(Default)]
+pub struct ServerSessions {
+    current: HashMap<u64, SessionCipher>,
+    previous: HashMap<u64, SessionCipher>,
+    // The sessions each client has been offered but hasn't sent a packet in yet, oldest first
+    offered: HashMap<u64, Vec<Offer>>,
+}
+
+// A session offered in answer to a `SessionInit`
+struct Offer {
+    init: SessionInit,
+    accept: SessionAccept,
+    cipher: SessionCipher,
+}
+
+impl ServerSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Offers a new session to the client that sent `init`, which has already been authenticated
+    // with `key`. The session only replaces the client's current one once the client sends a packet
+    // in it. A request the client resent because the answer was lost gets the same answer again.
+    pub fn accept(&mut self, key: &PairingKey, init: SessionInit) -> SessionAccept {
+        let offered = self.offered.entry(init.id).or_default();
+        if let Some(offer) = offered.iter().find(|offer| offer.init == init) {
+            return offer.accept;
+        }
+        let accept = SessionAccept {
+            id: init.id,
+            session: rand::random(),
+            client_nonce: init.nonce,
+            server_nonce: random_nonce(),
+        };
+        let cipher = SessionCipher::derive(key, init.id, &accept);
+        if offered.len() == OFFERED_SESSIONS {
+            offered.remove(0);
+        }
+        offered.push(Offer {
+            init,
+            accept,
+            cipher,
+        });
+        accept
+    }
+
+    // Returns true if `packet` belongs to a session the server knows
+    pub fn knows(&self, packet: &SealedPacket) -> bool {
+        let offered = self.offered.get(packet.id()).into_iter().flatten();
+        [&self.current, &self.previous]
+            .iter()
+            .filter_map(|sessions| sessions.get(packet.id()))
+            .chain(offered.map(|offer| &offer.cipher))
+            .any(|cipher| cipher.session == *packet.session())
+    }
+
+    // Decrypts a packet sent by a client. The first packet in a session the client was offered
+    // makes it the client's current session.
+    pub fn open(&mut self, packet: SealedPacket) -> Result<Vec<u8>> {
+        let id = *packet.id();
+        for sessions in [&mut self.current, &mut self.previous] {
+            if let Some(cipher) = sessions.get_mut(&id)
+                && cipher.session == *packet.session()
+            {
+                return cipher.open(packet);
+            }
+        }
+        let offered = self.offered.entry(id).or_default();
+        if let Some(index) = offered
+            .iter()
+            .position(|offer| offer.cipher.session == *packet.session())
+        {
+            let inner = offered[index].cipher.open(packet)?;
+            // The requests that weren't taken up are of no use to the client anymore
+            let offer = offered.swap_remove(index);
+            offered.clear();
+            if let Some(current) = self.current.insert(id, offer.cipher) {
+                self.previous.insert(id, current);
+            }
+            return Ok(inner);
+        }
+        bail!(
+            "Received a sealed packet from controller {} for an unknown session",
+            packet.id()
+        )
+    }
+}
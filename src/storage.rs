@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    hash::Hash,
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow, bail};
+use bincode::{Decode, Encode, config::Configuration, decode_from_slice, encode_to_vec};
+
+use crate::pairing::PairingKey;
+
+static BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+// Returns the directory Starboard keeps its persistent state in (i.e. ~/.local/share/starboard),
+// creating it if it doesn't exist yet
+pub fn data_dir() -> Result<PathBuf> {
+    let dir = dirs::data_local_dir()
+        .ok_or_else(|| anyhow!("Couldn't find a directory to store Starboard's data in"))?
+        .join("starboard");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// Reads and decodes the contents of `path`, or returns `None` if the file doesn't exist
+pub fn read_file<T>(path: &Path) -> Result<Option<T>>
+where
+    T: Decode<()>,
+{
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (value, _) = decode_from_slice::<T, Configuration>(&raw, BINCODE_CONFIG)
+        .map_err(|e| anyhow!("'{}' is corrupt: {e}", path.display()))?;
+    Ok(Some(value))
+}
+
+// Encodes `value` into `path`. The file is only readable by the current user, since some of what
+// Starboard stores are secret keys.
+pub fn write_file<T>(path: &Path, value: &T) -> Result<()>
+where
+    T: Encode,
+{
+    let raw = encode_to_vec::<&T, Configuration>(value, BINCODE_CONFIG)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&raw)?;
+    Ok(())
+}
+
+// Returns this user's client ID, generating a random one on first run. Every controller needs an ID
+// of its own, since the server tells controllers apart by their IDs.
+pub fn load_client_id() -> Result<u64> {
+    id_at(&data_dir()?.join("client_id"))
+}
+
+// Returns the ID stored at `path`, generating and storing a random one if there isn't one yet
+pub fn id_at(path: &Path) -> Result<u64> {
+    if let Some(id) = read_file(path)? {
+        return Ok(id);
+    }
+    let id: u64 = rand::random();
+    write_file(path, &id)?;
+    Ok(id)
+}
+
+// Keys shared with paired peers, saved to disk whenever a new peer is paired. The server keys its
+// store by client ID and the client keys its store by server ID.
+pub struct KeyStore<K> {
+    path: PathBuf,
+    keys: HashMap<K, PairingKey>,
+}
+
+impl<K> KeyStore<K>
+where
+    K: Encode + Decode<()> + Hash + Eq,
+{
+    // Loads the store named `file_name` from Starboard's data directory
+    pub fn load(file_name: &str) -> Result<Self> {
+        Self::open(data_dir()?.join(file_name))
+    }
+
+    // Loads the store at `path`, starting an empty one if it doesn't exist yet
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let keys = read_file(&path)?.unwrap_or_default();
+        Ok(Self { path, keys })
+    }
+
+    pub fn get(&self, peer: &K) -> Option<&PairingKey> {
+        self.keys.get(peer)
+    }
+
+    pub fn contains(&self, peer: &K) -> bool {
+        self.keys.contains_key(peer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &PairingKey)> {
+        self.keys.iter()
+    }
+
+    // Stores the key for a newly paired `peer` and saves the store to disk. A peer that's already
+    // paired keeps its key, since re-pairing has to be asked for with `replace`.
+    pub fn insert(&mut self, peer: K, key: PairingKey) -> Result<()> {
+        if self.keys.contains_key(&peer) {
+            bail!("Refused to replace the key of a peer that's already paired");
+        }
+        self.replace(peer, key)
+    }
+
+    // Stores the key for `peer`, replacing any previous pairing, and saves the store to disk
+    pub fn replace(&mut self, peer: K, key: PairingKey) -> Result<()> {
+        self.keys.insert(peer, key);
+        write_file(&self.path, &self.keys)
+    }
+}
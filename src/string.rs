@@ -34,7 +34,7 @@ impl TryFrom<String> for StarboardString {
 
 impl Display for StarboardString {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let string = String::from_iter(self.inner);
+        let string = String::from_iter(self.inner.into_iter().take(self.count));
         write!(f, "{string}")
     }
 }
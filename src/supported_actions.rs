@@ -4,6 +4,8 @@ use evdev::{AbsoluteAxisCode, KeyCode};
 use heapless::index_map::FnvIndexMap;
 use std::sync::LazyLock;
 
+use crate::capabilities::AxisRange;
+
 #[auto_const_array]
 const SUPPORTED_BUTTONS_BLUEPRINT: [KeyCode; _] = [
     KeyCode::BTN_THUMB,
@@ -34,23 +36,208 @@ const SUPPORTED_BUTTONS_BLUEPRINT: [KeyCode; _] = [
 
 pub const BUTTON_COUNT: u32 = SUPPORTED_BUTTONS_BLUEPRINT.len() as u32;
 
-#[auto_const_array]
-const SUPPORTED_AXES_BLUEPRINT: [AbsoluteAxisCode; _] = [
-    AbsoluteAxisCode::ABS_X,
-    AbsoluteAxisCode::ABS_Y,
-    AbsoluteAxisCode::ABS_RX,
-    AbsoluteAxisCode::ABS_RY,
-    AbsoluteAxisCode::ABS_HAT0X,
-    AbsoluteAxisCode::ABS_HAT0Y,
-    AbsoluteAxisCode::ABS_HAT1X,
-    AbsoluteAxisCode::ABS_HAT1Y,
-    AbsoluteAxisCode::ABS_HAT2X,
-    AbsoluteAxisCode::ABS_HAT2Y,
+// Everything Starboard knows about an axis it supports. Every conversion between evdev codes and
+// Starboard IDs, every axis bitmask and every virtual joystick's axes come from `SUPPORTED_AXES`.
+#[derive(Debug, Copy, Clone)]
+pub struct AxisDescriptor {
+    pub code: AbsoluteAxisCode,
+    pub id: u32, // The axis's Starboard ID, which is also its place in input packets and bitmasks
+    pub range: AxisRange, // What a virtual joystick declares until the controller says otherwise
+    pub name: &'static str,
+    pub button: Option<KeyCode>, // The button pressed along with a trigger, if the axis is one
+}
+
+impl AxisDescriptor {
+    const fn new(code: AbsoluteAxisCode, id: u32, range: AxisRange, name: &'static str) -> Self {
+        Self {
+            code,
+            id,
+            range,
+            name,
+            button: None,
+        }
+    }
+
+    // Marks the axis as a trigger, which presses `button` once it's pulled far enough
+    const fn pressing(self, button: KeyCode) -> Self {
+        Self {
+            button: Some(button),
+            ..self
+        }
+    }
+}
+
+const STICK: AxisRange = AxisRange {
+    minimum: -32768,
+    maximum: 32767,
+    fuzz: 16,
+    flat: 128,
+    resolution: 0,
+};
+
+// The Steam Deck reports its trackpads on the HAT0 and HAT1 axes, and how far its triggers are
+// pulled on the HAT2 axes, rather than using them as d-pads
+const DECK_TRACKPAD: AxisRange = AxisRange {
+    minimum: -32767,
+    maximum: 32767,
+    fuzz: 0,
+    flat: 0,
+    resolution: 0,
+};
+
+const DECK_TRIGGER: AxisRange = AxisRange {
+    minimum: 0,
+    maximum: 32767,
+    fuzz: 0,
+    flat: 0,
+    resolution: 0,
+};
+
+// Analog triggers on most gamepads
+const TRIGGER: AxisRange = AxisRange {
+    minimum: 0,
+    maximum: 255,
+    fuzz: 0,
+    flat: 0,
+    resolution: 0,
+};
+
+pub const SUPPORTED_AXES: [AxisDescriptor; 12] = [
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_X, 0, STICK, "Left Stick X"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_Y, 1, STICK, "Left Stick Y"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_RX, 2, STICK, "Right Stick X"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_RY, 3, STICK, "Right Stick Y"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_HAT0X, 4, DECK_TRACKPAD, "Hat 0 X"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_HAT0Y, 5, DECK_TRACKPAD, "Hat 0 Y"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_HAT1X, 6, DECK_TRACKPAD, "Hat 1 X"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_HAT1Y, 7, DECK_TRACKPAD, "Hat 1 Y"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_HAT2X, 8, DECK_TRIGGER, "Hat 2 X")
+        .pressing(KeyCode::BTN_TR2),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_HAT2Y, 9, DECK_TRIGGER, "Hat 2 Y")
+        .pressing(KeyCode::BTN_TL2),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_Z, 10, TRIGGER, "Left Trigger")
+        .pressing(KeyCode::BTN_TL2),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_RZ, 11, TRIGGER, "Right Trigger")
+        .pressing(KeyCode::BTN_TR2),
 ];
 
-pub const AXIS_COUNT: u32 = SUPPORTED_AXES_BLUEPRINT.len() as u32;
+pub const AXIS_COUNT: u32 = SUPPORTED_AXES.len() as u32;
+
+// Returns the supported axis with evdev code `code`
+pub fn axis_by_code(code: AbsoluteAxisCode) -> Option<&'static AxisDescriptor> {
+    SUPPORTED_AXES.iter().find(|axis| axis.code == code)
+}
+
+// Returns the supported axis with Starboard ID `id`
+pub fn axis_by_id(id: u32) -> Option<&'static AxisDescriptor> {
+    SUPPORTED_AXES.iter().find(|axis| axis.id == id)
+}
+
+// The Steam Deck's motion sensors report acceleration on ABS_X, ABS_Y and ABS_Z, at 16384 units
+// per g, and rotation on ABS_RX, ABS_RY and ABS_RZ, at 16 units per degree per second
+const DECK_ACCELEROMETER: AxisRange = AxisRange {
+    minimum: -32768,
+    maximum: 32767,
+    fuzz: 0,
+    flat: 0,
+    resolution: 16384,
+};
+
+const DECK_GYROSCOPE: AxisRange = AxisRange {
+    minimum: -32768,
+    maximum: 32767,
+    fuzz: 0,
+    flat: 0,
+    resolution: 16,
+};
 
-fn gen_support_map<T, const N: usize>(source: [T; N]) -> FnvIndexMap<T, usize, 32>
+// The axes of a motion sensor device. Their IDs are their places in a motion sample, separately
+// from the joystick's axes.
+pub const MOTION_AXES: [AxisDescriptor; 6] = [
+    AxisDescriptor::new(
+        AbsoluteAxisCode::ABS_X,
+        0,
+        DECK_ACCELEROMETER,
+        "Accelerometer X",
+    ),
+    AxisDescriptor::new(
+        AbsoluteAxisCode::ABS_Y,
+        1,
+        DECK_ACCELEROMETER,
+        "Accelerometer Y",
+    ),
+    AxisDescriptor::new(
+        AbsoluteAxisCode::ABS_Z,
+        2,
+        DECK_ACCELEROMETER,
+        "Accelerometer Z",
+    ),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_RX, 3, DECK_GYROSCOPE, "Gyroscope X"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_RY, 4, DECK_GYROSCOPE, "Gyroscope Y"),
+    AxisDescriptor::new(AbsoluteAxisCode::ABS_RZ, 5, DECK_GYROSCOPE, "Gyroscope Z"),
+];
+
+pub const MOTION_AXIS_COUNT: u32 = MOTION_AXES.len() as u32;
+
+// Returns the motion axis with evdev code `code`
+pub fn motion_axis_by_code(code: AbsoluteAxisCode) -> Option<&'static AxisDescriptor> {
+    MOTION_AXES.iter().find(|axis| axis.code == code)
+}
+
+// A trackpad, made up of the two axes its contact is reported on and the button pressed by
+// clicking it. The Steam Deck reports no position while a trackpad isn't touched.
+#[derive(Debug, Copy, Clone)]
+pub struct TrackpadDescriptor {
+    pub id: u32, // The trackpad's Starboard ID, which is also its place in input packets
+    pub x: AbsoluteAxisCode,
+    pub y: AbsoluteAxisCode,
+    pub click: KeyCode,
+    pub mouse_button: KeyCode, // What clicking the trackpad presses when it's used as a mouse
+    pub name: &'static str,
+}
+
+pub const SUPPORTED_TRACKPADS: [TrackpadDescriptor; 2] = [
+    TrackpadDescriptor {
+        id: 0,
+        x: AbsoluteAxisCode::ABS_HAT0X,
+        y: AbsoluteAxisCode::ABS_HAT0Y,
+        click: KeyCode::BTN_THUMB,
+        mouse_button: KeyCode::BTN_RIGHT,
+        name: "Left Trackpad",
+    },
+    TrackpadDescriptor {
+        id: 1,
+        x: AbsoluteAxisCode::ABS_HAT1X,
+        y: AbsoluteAxisCode::ABS_HAT1Y,
+        click: KeyCode::BTN_THUMB2,
+        mouse_button: KeyCode::BTN_LEFT,
+        name: "Right Trackpad",
+    },
+];
+
+pub const TRACKPAD_COUNT: u32 = SUPPORTED_TRACKPADS.len() as u32;
+
+// Returns the supported trackpad with Starboard ID `id`
+pub fn trackpad_by_id(id: u32) -> Option<&'static TrackpadDescriptor> {
+    SUPPORTED_TRACKPADS.iter().find(|pad| pad.id == id)
+}
+
+// Returns the supported trackpad that reports its contact on `axis`
+pub fn trackpad_by_axis(axis: AbsoluteAxisCode) -> Option<&'static TrackpadDescriptor> {
+    SUPPORTED_TRACKPADS
+        .iter()
+        .find(|pad| pad.x == axis || pad.y == axis)
+}
+
+// Returns the supported trackpad that presses `key` when it's clicked
+pub fn trackpad_by_click(key: KeyCode) -> Option<&'static TrackpadDescriptor> {
+    SUPPORTED_TRACKPADS.iter().find(|pad| pad.click == key)
+}
+
+// heapless maps need a power of two capacity, so this is the smallest one that fits every button
+const BUTTON_CAPACITY: usize = SUPPORTED_BUTTONS_BLUEPRINT.len().next_power_of_two();
+
+fn gen_support_map<T, const N: usize, const M: usize>(source: [T; N]) -> FnvIndexMap<T, usize, M>
 where
     T: Eq + Copy + Hash + Debug,
 {
@@ -61,8 +248,5 @@ where
     map
 }
 
-pub static SUPPORTED_BUTTONS: LazyLock<FnvIndexMap<KeyCode, usize, 32>> =
+pub static SUPPORTED_BUTTONS: LazyLock<FnvIndexMap<KeyCode, usize, BUTTON_CAPACITY>> =
     LazyLock::new(|| gen_support_map(SUPPORTED_BUTTONS_BLUEPRINT));
-
-pub static SUPPORTED_AXES: LazyLock<FnvIndexMap<AbsoluteAxisCode, usize, 32>> =
-    LazyLock::new(|| gen_support_map(SUPPORTED_AXES_BLUEPRINT));
@@ -4,23 +4,105 @@ use crate::bitmask::Bitmask;
 fn test_bitmask_read_write() {
     let mut bitmask = Bitmask::new(5);
     assert!(!bitmask.read_bit(0));
-    bitmask.write_bit(0, true);
+    bitmask.write_bit(0, true).unwrap();
     assert!(bitmask.read_bit(0));
 }
 
 #[test]
 fn test_bitmask_iteration() {
     let mut bitmask = Bitmask::new(5);
-    bitmask.write_bit(0, true);
-    bitmask.write_bit(3, true);
+    bitmask.write_bit(0, true).unwrap();
+    bitmask.write_bit(3, true).unwrap();
 
-    let mut i = 0;
-    for bit in bitmask {
+    for (i, bit) in bitmask.into_iter().enumerate() {
         if i == 0 || i == 3 {
             assert!(bit);
         } else {
             assert!(!bit);
         }
-        i += 1;
     }
 }
+
+#[test]
+fn test_bitmask_wider_than_32_bits() {
+    let mut bitmask = Bitmask::new(300);
+    bitmask.write_bit(31, true).unwrap();
+    bitmask.write_bit(32, true).unwrap();
+    bitmask.write_bit(299, true).unwrap();
+    assert!(bitmask.read_bit(299));
+    assert!(!bitmask.read_bit(298));
+    assert_eq!(bitmask.ones().collect::<Vec<_>>(), [31, 32, 299]);
+    assert_eq!(bitmask.count_ones(), 3);
+    bitmask.write_bit(32, false).unwrap();
+    assert_eq!(bitmask.ones().collect::<Vec<_>>(), [31, 299]);
+    assert_eq!(bitmask.into_iter().filter(|bit| *bit).count(), 2);
+}
+
+#[test]
+fn test_bitmask_out_of_range() {
+    let mut bitmask = Bitmask::full(5);
+    assert!(!bitmask.read_bit(5));
+    assert!(!bitmask.read_bit(u32::MAX));
+    assert!(bitmask.write_bit(5, true).is_err());
+    assert!(bitmask.write_bit(u32::MAX, false).is_err());
+    assert_eq!(bitmask, Bitmask::full(5));
+    assert_eq!(bitmask.into_iter().count(), 5);
+}
+
+#[test]
+fn test_bitmask_from_ones() {
+    let bitmask = Bitmask::from_ones(40, [39, 0, 8]).unwrap();
+    assert_eq!(bitmask.ones().collect::<Vec<_>>(), [0, 8, 39]);
+    assert!(Bitmask::from_ones(40, [40]).is_err());
+    assert_eq!(Bitmask::from_ones(40, []).unwrap(), Bitmask::new(40));
+}
+
+#[test]
+fn test_bitmask_set_operations() {
+    let a = Bitmask::from_ones(8, [2, 3]).unwrap();
+    let mut b = Bitmask::new(40);
+    b.write_bit(2, true).unwrap();
+    b.write_bit(1, true).unwrap();
+    b.write_bit(39, true).unwrap();
+
+    let union = a.union(&b);
+    assert_eq!(union.size(), 40);
+    assert_eq!(union.ones().collect::<Vec<_>>(), [1, 2, 3, 39]);
+    let intersection = a.intersection(&b);
+    assert_eq!(intersection.size(), 40);
+    assert_eq!(intersection.ones().collect::<Vec<_>>(), [2]);
+    let difference = a.difference(&b);
+    assert_eq!(difference.size(), 8);
+    assert_eq!(difference.ones().collect::<Vec<_>>(), [3]);
+    assert!(b.difference(&b).is_empty());
+    assert!(!a.is_empty());
+}
+
+#[test]
+fn test_bitmask_encoding() {
+    let mut bitmask = Bitmask::new(300);
+    bitmask.write_bit(0, true).unwrap();
+    bitmask.write_bit(299, true).unwrap();
+    let encoded = bincode::encode_to_vec(&bitmask, bincode::config::standard()).unwrap();
+    // The size as a varint, then one bit per input
+    assert_eq!(encoded.len(), 3 + 38);
+    let (decoded, _): (Bitmask, usize) =
+        bincode::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+    assert_eq!(decoded, bitmask);
+
+    // Bits past the size can't be set by a sender either
+    let mut past_size =
+        bincode::encode_to_vec(Bitmask::new(5), bincode::config::standard()).unwrap();
+    *past_size.last_mut().unwrap() = 0b100000;
+    assert!(
+        bincode::decode_from_slice::<Bitmask, _>(&past_size, bincode::config::standard()).is_err()
+    );
+}
+
+#[test]
+fn test_bitmask_huge_size_is_refused() {
+    let mut encoded = Vec::new();
+    encoded.extend(bincode::encode_to_vec(u32::MAX, bincode::config::standard()).unwrap());
+    let config = bincode::config::standard().with_limit::<1024>();
+    assert!(bincode::decode_from_slice::<Bitmask, _>(&encoded, config).is_err());
+}
@@ -0,0 +1,237 @@
+use std::net::{Ipv6Addr, SocketAddr};
+
+use evdev::{AbsInfo, AbsoluteAxisCode, KeyCode};
+
+use crate::{
+    capabilities::{AxisRange, DeviceCapabilities, HelloPacket},
+    datagram::{BroadcastPacket, deserialize, serialize},
+    net::bind_dual_stack,
+    server::StarboardServerBuilder,
+};
+
+use super::test_server;
+
+fn stick() -> AxisRange {
+    AxisRange {
+        minimum: -32768,
+        maximum: 32767,
+        fuzz: 16,
+        flat: 128,
+        resolution: 0,
+    }
+}
+
+fn trigger() -> AxisRange {
+    AxisRange {
+        minimum: 0,
+        maximum: 32767,
+        fuzz: 0,
+        flat: 0,
+        resolution: 0,
+    }
+}
+
+fn steam_deck() -> DeviceCapabilities {
+    DeviceCapabilities::new(
+        [KeyCode::BTN_SOUTH, KeyCode::BTN_EAST, KeyCode::BTN_TL2],
+        [
+            (AbsoluteAxisCode::ABS_X, stick()),
+            (AbsoluteAxisCode::ABS_HAT2Y, trigger()),
+        ],
+    )
+}
+
+#[test]
+fn test_hello_round_trip() {
+    let hello = HelloPacket {
+        id: 7,
+        capabilities: steam_deck(),
+        motion: None,
+        desktop: None,
+    };
+    let decoded: HelloPacket = deserialize(&serialize(&hello).unwrap()).unwrap();
+    assert_eq!(decoded.id, 7);
+    assert_eq!(decoded.capabilities, steam_deck());
+    assert_eq!(
+        decoded.capabilities.keys().collect::<Vec<_>>(),
+        [KeyCode::BTN_SOUTH, KeyCode::BTN_EAST, KeyCode::BTN_TL2]
+    );
+    assert_eq!(
+        decoded.capabilities.axes().collect::<Vec<_>>(),
+        [
+            (AbsoluteAxisCode::ABS_X, stick()),
+            (AbsoluteAxisCode::ABS_HAT2Y, trigger())
+        ]
+    );
+}
+
+#[test]
+fn test_axis_range_keeps_abs_info() {
+    let info = AbsInfo::new(1200, -32768, 32767, 16, 128, 3);
+    let range = AxisRange::from(info);
+    let rebuilt = range.to_abs_info();
+    assert_eq!(rebuilt.minimum(), -32768);
+    assert_eq!(rebuilt.maximum(), 32767);
+    assert_eq!(rebuilt.fuzz(), 16);
+    assert_eq!(rebuilt.flat(), 128);
+    assert_eq!(rebuilt.resolution(), 3);
+    // The virtual axis starts at rest rather than wherever the physical one happened to be
+    assert_eq!(rebuilt.value(), 0);
+    assert_eq!(trigger().rest(), 0);
+    let offset = AxisRange {
+        minimum: 10,
+        maximum: 20,
+        ..trigger()
+    };
+    assert_eq!(offset.rest(), 10);
+}
+
+#[test]
+fn test_capabilities_validation() {
+    assert!(steam_deck().validate().is_ok());
+    assert!(DeviceCapabilities::default().validate().is_ok());
+    let past_last_key = DeviceCapabilities::new([KeyCode(0x300)], []);
+    assert!(past_last_key.validate().is_err());
+    let past_last_axis = DeviceCapabilities::new([], [(AbsoluteAxisCode(0x40), stick())]);
+    assert!(past_last_axis.validate().is_err());
+    let repeated = DeviceCapabilities::new([KeyCode::BTN_SOUTH, KeyCode::BTN_SOUTH], []);
+    assert!(repeated.validate().is_err());
+    let inverted = AxisRange {
+        minimum: 1,
+        maximum: -1,
+        ..stick()
+    };
+    let inverted = DeviceCapabilities::new([], [(AbsoluteAxisCode::ABS_X, inverted)]);
+    assert!(inverted.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_server_refuses_invalid_hello() {
+    let server = test_server();
+    let sock = bind_dual_stack(0).unwrap();
+    let sink = bind_dual_stack(0).unwrap();
+    let source = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        sink.local_addr().unwrap().port(),
+    );
+    let presence = serialize(&BroadcastPacket::new(7, "Steam Deck").unwrap()).unwrap();
+    server
+        .handle_device_search_packet(&presence, source, &sock)
+        .await
+        .unwrap();
+
+    let hello = HelloPacket {
+        id: 7,
+        capabilities: steam_deck(),
+        motion: None,
+        desktop: None,
+    };
+    server
+        .receive_serial(&serialize(&hello).unwrap(), source, &sock)
+        .await;
+    assert_eq!(server.rejects().read().await.count(source.ip()), 0);
+
+    let hello = HelloPacket {
+        id: 7,
+        capabilities: DeviceCapabilities::new([KeyCode(0x300)], []),
+        motion: None,
+        desktop: None,
+    };
+    server
+        .receive_serial(&serialize(&hello).unwrap(), source, &sock)
+        .await;
+    assert_eq!(server.rejects().read().await.count(source.ip()), 1);
+
+    // Motion sensors are held to the same standard as the rest of the controller
+    let hello = HelloPacket {
+        id: 7,
+        capabilities: steam_deck(),
+        motion: Some(DeviceCapabilities::new([KeyCode(0x300)], [])),
+        desktop: None,
+    };
+    server
+        .receive_serial(&serialize(&hello).unwrap(), source, &sock)
+        .await;
+    assert_eq!(server.rejects().read().await.count(source.ip()), 2);
+}
+
+#[test]
+fn test_rescale_between_ranges() {
+    let byte = AxisRange {
+        minimum: 0,
+        maximum: 255,
+        ..trigger()
+    };
+    let hat = AxisRange {
+        minimum: -1,
+        maximum: 1,
+        ..trigger()
+    };
+    // Matching ranges pass values through untouched
+    assert_eq!(stick().rescale(-12345, &stick()), -12345);
+    assert_eq!(trigger().rescale(32767, &trigger()), 32767);
+    // Both ends, and the middle, land on the same points of the new range
+    assert_eq!(byte.rescale(0, &trigger()), 0);
+    assert_eq!(byte.rescale(32767, &trigger()), 255);
+    assert_eq!(byte.rescale(16384, &trigger()), 128);
+    assert_eq!(stick().rescale(0, &byte), -32768);
+    assert_eq!(stick().rescale(255, &byte), 32767);
+    assert_eq!(hat.rescale(-32768, &stick()), -1);
+    assert_eq!(hat.rescale(0, &stick()), 0);
+    assert_eq!(hat.rescale(32767, &stick()), 1);
+    assert_eq!(stick().rescale(1, &hat), 32767);
+    // Values outside of the source range are held to it
+    assert_eq!(byte.rescale(40000, &trigger()), 255);
+    assert_eq!(byte.rescale(-5, &trigger()), 0);
+    assert_eq!(stick().rescale(i32::MAX, &stick()), 32767);
+    // The full i32 range doesn't overflow
+    let full = AxisRange {
+        minimum: i32::MIN,
+        maximum: i32::MAX,
+        ..trigger()
+    };
+    assert_eq!(full.rescale(255, &byte), i32::MAX);
+    assert_eq!(byte.rescale(i32::MIN, &full), 0);
+    let positive = AxisRange {
+        minimum: 0,
+        maximum: i32::MAX,
+        ..trigger()
+    };
+    assert_eq!(positive.rescale(i32::MAX, &full), i32::MAX);
+    assert_eq!(full.rescale(0, &positive), i32::MIN);
+    // A source range with no width can't say where the axis is, so it's left at rest
+    let stuck = AxisRange {
+        minimum: 7,
+        maximum: 7,
+        ..trigger()
+    };
+    assert_eq!(stick().rescale(7, &stuck), 0);
+}
+
+#[test]
+fn test_trigger_threshold() {
+    let byte = AxisRange {
+        minimum: 0,
+        maximum: 255,
+        ..trigger()
+    };
+    assert!(!byte.is_past(0, 0.5));
+    assert!(!byte.is_past(127, 0.5));
+    assert!(byte.is_past(128, 0.5));
+    assert!(byte.is_past(255, 1.0));
+    assert!(!byte.is_past(254, 1.0));
+    // The threshold is measured from the minimum, wherever it is
+    assert!(stick().is_past(0, 0.5));
+    assert!(!stick().is_past(-1, 0.5));
+}
+
+#[test]
+fn test_trigger_threshold_is_a_percentage() {
+    let builder = || StarboardServerBuilder::new(0, 0);
+    assert!(builder().digital_triggers(None).is_ok());
+    assert!(builder().digital_triggers(Some(50.0)).is_ok());
+    assert!(builder().digital_triggers(Some(100.0)).is_ok());
+    assert!(builder().digital_triggers(Some(0.0)).is_err());
+    assert!(builder().digital_triggers(Some(150.0)).is_err());
+    assert!(builder().digital_triggers(Some(f64::NAN)).is_err());
+}
@@ -1,29 +1,95 @@
 use crate::{
     bitmask::Bitmask,
-    datagram::{deserialize, serialize},
-    input::{StarboardAxisStates, StarboardButtonStates, StarboardInputPacket},
+    datagram::{
+        BroadcastPacket, DisconnectPacket, HEADER_LEN, MessageKind, PROTOCOL_VERSION, deserialize,
+        peek_kind, serialize,
+    },
+    input::{
+        StarboardAxisStates, StarboardButtonStates, StarboardInputPacket, StarboardKeyStates,
+        StarboardTouchStates,
+    },
 };
 
 fn test_button_states() -> StarboardButtonStates {
     let mut raw = Bitmask::new(4);
-    raw.write_bit(0, true);
-    raw.write_bit(2, true);
-    raw.write_bit(3, true);
+    raw.write_bit(0, true).unwrap();
+    raw.write_bit(2, true).unwrap();
+    raw.write_bit(3, true).unwrap();
     StarboardButtonStates { raw }
 }
 
 const TEST_AXIS_STATES: StarboardAxisStates = StarboardAxisStates {
-    axes: [0, 101, -63, 112, -1, 127, 0, 0, 0, 0],
+    axes: [0, 101, -63, 112, -1, 127, 0, 0, 0, 0, 0, 0],
 };
 
-#[test]
-fn test_packet_serialization_symmetry() {
-    let packet = StarboardInputPacket {
+fn test_packet() -> StarboardInputPacket {
+    StarboardInputPacket {
         buttons: test_button_states(),
         axes: TEST_AXIS_STATES,
+        touches: StarboardTouchStates::new(),
+        keys: StarboardKeyStates::new(),
         id: 0,
-    };
+        session: 0,
+        sequence: 0,
+    }
+}
+
+#[test]
+fn test_packet_serialization_symmetry() {
+    let packet = test_packet();
 
     let raw = serialize(&packet).unwrap();
-    assert_eq!(packet, deserialize(raw).unwrap());
+    assert_eq!(packet, deserialize(&raw).unwrap());
+}
+
+#[test]
+fn test_peek_kind() {
+    let raw = serialize(&test_packet()).unwrap();
+    assert_eq!(peek_kind(&raw).unwrap(), MessageKind::Input);
+
+    let raw = serialize(&BroadcastPacket::new(0, "Test").unwrap()).unwrap();
+    assert_eq!(peek_kind(&raw).unwrap(), MessageKind::Broadcast);
+}
+
+#[test]
+fn test_disconnect_round_trip() {
+    let raw = serialize(&DisconnectPacket::new(42)).unwrap();
+    assert_eq!(peek_kind(&raw).unwrap(), MessageKind::Disconnect);
+    let packet: DisconnectPacket = deserialize(&raw).unwrap();
+    assert_eq!(*packet.id(), 42);
+}
+
+#[test]
+fn test_deserialize_rejects_wrong_kind() {
+    let raw = serialize(&BroadcastPacket::new(0, "Test").unwrap()).unwrap();
+    assert!(deserialize::<StarboardInputPacket>(&raw).is_err());
+}
+
+#[test]
+fn test_deserialize_rejects_foreign_traffic() {
+    let mut raw = serialize(&test_packet()).unwrap();
+    raw[0] = b'X';
+    assert!(deserialize::<StarboardInputPacket>(&raw).is_err());
+    assert!(deserialize::<StarboardInputPacket>(&[]).is_err());
+}
+
+#[test]
+fn test_deserialize_rejects_version_mismatch() {
+    let mut raw = serialize(&test_packet()).unwrap();
+    raw[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    let err = deserialize::<StarboardInputPacket>(&raw).unwrap_err();
+    assert!(err.to_string().contains("protocol version"));
+}
+
+#[test]
+fn test_deserialize_rejects_corruption() {
+    let mut raw = serialize(&test_packet()).unwrap();
+    raw[HEADER_LEN] ^= 0xFF;
+    assert!(deserialize::<StarboardInputPacket>(&raw).is_err());
+}
+
+#[test]
+fn test_deserialize_rejects_truncation() {
+    let raw = serialize(&test_packet()).unwrap();
+    assert!(deserialize::<StarboardInputPacket>(&raw[..raw.len() - 1]).is_err());
 }
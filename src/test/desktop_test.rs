@@ -0,0 +1,148 @@
+use evdev::{EventType, InputEvent, KeyCode, RelativeAxisCode};
+
+use crate::{
+    bitmask::Bitmask,
+    capabilities::KEY_CODES,
+    datagram::serialize,
+    desktop::DesktopTracker,
+    input::{InputFrame, StarboardDeltaPacket, StarboardInput, StarboardInputPacket},
+    supported_actions::{AXIS_COUNT, BUTTON_COUNT},
+};
+
+fn key(key: KeyCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, key.0, value)
+}
+
+fn rel(axis: RelativeAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE.0, axis.0, value)
+}
+
+fn syn() -> InputEvent {
+    InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)
+}
+
+#[test]
+fn test_tracker_batches_changes_until_syn() {
+    let mut tracker = DesktopTracker::new();
+    assert_eq!(tracker.update(&key(KeyCode::KEY_A, 1)), None);
+    assert_eq!(tracker.update(&rel(RelativeAxisCode::REL_X, 3)), None);
+    tracker.update(&rel(RelativeAxisCode::REL_X, 4));
+    tracker.update(&rel(RelativeAxisCode::REL_WHEEL, -1));
+    assert_eq!(
+        tracker.update(&syn()),
+        Some(vec![
+            StarboardInput::Key {
+                code: KeyCode::KEY_A.0,
+                value: true
+            },
+            StarboardInput::Rel { x: 7, y: 0 },
+            StarboardInput::Wheel {
+                vertical: -1,
+                horizontal: 0
+            },
+        ])
+    );
+    assert!(tracker.is_held(KeyCode::KEY_A));
+
+    // Key repeats don't change anything
+    tracker.update(&key(KeyCode::KEY_A, 2));
+    assert_eq!(tracker.update(&syn()), None);
+
+    tracker.update(&key(KeyCode::BTN_LEFT, 1));
+    tracker.update(&syn());
+    assert_eq!(
+        tracker.release_all(),
+        vec![
+            StarboardInput::Key {
+                code: KeyCode::KEY_A.0,
+                value: false
+            },
+            StarboardInput::Key {
+                code: KeyCode::BTN_LEFT.0,
+                value: false
+            },
+        ]
+    );
+    assert!(!tracker.is_held(KeyCode::KEY_A));
+}
+
+#[test]
+fn test_keyframes_only_carry_keys_when_forwarding() {
+    let buttons = Bitmask::full(BUTTON_COUNT);
+    let axes = Bitmask::full(AXIS_COUNT);
+    let is_key = |input: &StarboardInput| matches!(input, StarboardInput::Key { .. });
+
+    let keyframe = StarboardInputPacket::new(3, 1, 7);
+    assert_eq!(keyframe.keys.raw.size(), 0);
+    assert!(!keyframe.unpack(&buttons, &axes).iter().any(is_key));
+
+    // Every key is unpacked, so that one released in a lost delta is released
+    let mut keyframe = StarboardInputPacket::new(3, 1, 7);
+    keyframe
+        .pack(StarboardInput::Key {
+            code: KeyCode::KEY_Q.0,
+            value: true,
+        })
+        .unwrap();
+    let frame = InputFrame::deserialize(&serialize(&keyframe).unwrap()).unwrap();
+    let keys: Vec<StarboardInput> = frame
+        .unpack(&buttons, &axes)
+        .into_iter()
+        .filter(is_key)
+        .collect();
+    assert_eq!(keys.len(), KEY_CODES as usize);
+    assert_eq!(
+        keys.iter()
+            .filter(|input| matches!(input, StarboardInput::Key { value: true, .. }))
+            .collect::<Vec<_>>(),
+        [&StarboardInput::Key {
+            code: KeyCode::KEY_Q.0,
+            value: true
+        }]
+    );
+
+    assert!(
+        keyframe
+            .pack(StarboardInput::Key {
+                code: KEY_CODES,
+                value: true
+            })
+            .is_err()
+    );
+    assert!(keyframe.pack(StarboardInput::Rel { x: 1, y: 1 }).is_err());
+
+    // A build can't be handed a key bitmask of any other size
+    keyframe.keys.raw = Bitmask::new(8);
+    assert!(InputFrame::deserialize(&serialize(&keyframe).unwrap()).is_err());
+}
+
+#[test]
+fn test_deltas_keep_every_movement() {
+    let mut delta = StarboardDeltaPacket::new(3, 1, 8);
+    delta.pack(StarboardInput::Rel { x: 1, y: 2 });
+    delta.pack(StarboardInput::Rel { x: 3, y: 4 });
+    delta.pack(StarboardInput::Key {
+        code: KeyCode::KEY_B.0,
+        value: true,
+    });
+    delta.pack(StarboardInput::Key {
+        code: KeyCode::KEY_B.0,
+        value: false,
+    });
+    delta.pack(StarboardInput::Key {
+        code: KEY_CODES,
+        value: true,
+    });
+    let frame = InputFrame::deserialize(&serialize(&delta).unwrap()).unwrap();
+    assert_eq!(
+        frame.unpack(&Bitmask::new(BUTTON_COUNT), &Bitmask::new(AXIS_COUNT)),
+        vec![
+            StarboardInput::Rel { x: 1, y: 2 },
+            StarboardInput::Rel { x: 3, y: 4 },
+            StarboardInput::Key {
+                code: KeyCode::KEY_B.0,
+                value: false
+            },
+        ]
+    );
+}
@@ -0,0 +1,103 @@
+use std::{
+    net::{SocketAddr, SocketAddrV6},
+    time::Duration,
+};
+
+use crate::{
+    datagram::{deserialize, serialize},
+    discovery::{ServerAnnouncement, ServerList},
+    string::StarboardString,
+};
+
+fn announcement(id: u64, name: &str) -> ServerAnnouncement {
+    ServerAnnouncement {
+        id,
+        name: StarboardString::try_from(name).unwrap(),
+        serial_port: 54321,
+        device_search_port: 61000,
+    }
+}
+
+fn addr(host: &str) -> SocketAddr {
+    format!("{host}:61000").parse().unwrap()
+}
+
+#[test]
+fn test_announcement_round_trip() {
+    let raw = serialize(&announcement(1, "Lab PC")).unwrap();
+    let decoded: ServerAnnouncement = deserialize(&raw).unwrap();
+    assert_eq!(decoded.id, 1);
+    assert_eq!(decoded.name.to_string(), "Lab PC");
+    assert_eq!(decoded.serial_port, 54321);
+    assert_eq!(decoded.device_search_port, 61000);
+}
+
+#[test]
+fn test_server_list_addresses() {
+    let mut servers = ServerList::new(Duration::from_secs(15));
+    servers.update(addr("192.168.1.10"), announcement(1, "Lab PC"));
+    let server = servers.find(1).unwrap();
+    // The host comes from the address the announcement was sent from
+    assert_eq!(server.serial_addr, "192.168.1.10:54321".parse().unwrap());
+    assert_eq!(server.device_search_addr, addr("192.168.1.10"));
+    assert!(servers.find(2).is_none());
+}
+
+#[test]
+fn test_server_list_tells_same_named_servers_apart() {
+    let mut servers = ServerList::new(Duration::from_secs(15));
+    servers.update(
+        addr("192.168.1.10"),
+        announcement(1, "Starboard Virtual Gamepad"),
+    );
+    servers.update(
+        addr("192.168.1.11"),
+        announcement(2, "Starboard Virtual Gamepad"),
+    );
+    assert_eq!(servers.live().len(), 2);
+    assert_eq!(
+        servers.find(1).unwrap().device_search_addr,
+        addr("192.168.1.10")
+    );
+    assert_eq!(
+        servers.find(2).unwrap().device_search_addr,
+        addr("192.168.1.11")
+    );
+}
+
+#[test]
+fn test_server_list_sorted_and_deduplicated() {
+    let mut servers = ServerList::new(Duration::from_secs(15));
+    servers.update(addr("192.168.1.11"), announcement(3, "Zed"));
+    servers.update(addr("192.168.1.10"), announcement(4, "Alpha"));
+    servers.update(addr("192.168.1.11"), announcement(3, "Zed"));
+    let names: Vec<String> = servers
+        .live()
+        .iter()
+        .map(|server| server.name.to_string())
+        .collect();
+    assert_eq!(names, vec!["Alpha", "Zed"]);
+}
+
+#[test]
+fn test_server_list_expires_quiet_servers() {
+    let mut servers = ServerList::new(Duration::from_millis(20));
+    servers.update(addr("192.168.1.10"), announcement(1, "Lab PC"));
+    std::thread::sleep(Duration::from_millis(40));
+    assert!(servers.live().is_empty());
+    servers.update(addr("192.168.1.11"), announcement(2, "Other PC"));
+    assert_eq!(servers.live().len(), 1);
+}
+
+#[test]
+fn test_server_list_keeps_zone() {
+    let mut servers = ServerList::new(Duration::from_secs(15));
+    let link_local = SocketAddrV6::new("fe80::1".parse().unwrap(), 61000, 0, 2).into();
+    servers.update(link_local, announcement(1, "Lab PC"));
+    let server = servers.find(1).unwrap();
+    let SocketAddr::V6(serial_addr) = server.serial_addr else {
+        panic!("The server's address should still be IPv6");
+    };
+    assert_eq!(serial_addr.scope_id(), 2);
+    assert_eq!(serial_addr.port(), 54321);
+}
@@ -0,0 +1,65 @@
+use evdev::{FFEffectData, FFEffectKind, FFEnvelope, FFReplay, FFTrigger, FFWaveform};
+
+use crate::{
+    datagram::{deserialize, serialize},
+    force_feedback::{ForceFeedback, ForceFeedbackPacket, Rumble},
+};
+
+fn test_rumble() -> Rumble {
+    Rumble {
+        strong_magnitude: 0xc000,
+        weak_magnitude: 0x4000,
+        length_ms: 250,
+        delay_ms: 10,
+    }
+}
+
+#[test]
+fn test_rumble_effect_round_trip() {
+    let rumble = test_rumble();
+    assert_eq!(Rumble::from_effect(rumble.to_effect()), Some(rumble));
+}
+
+#[test]
+fn test_rumble_ignores_other_effects() {
+    let periodic = FFEffectData {
+        direction: 0,
+        trigger: FFTrigger::default(),
+        replay: FFReplay {
+            length: 100,
+            delay: 0,
+        },
+        kind: FFEffectKind::Periodic {
+            waveform: FFWaveform::Sine,
+            period: 10,
+            magnitude: 1000,
+            offset: 0,
+            phase: 0,
+            envelope: FFEnvelope {
+                attack_length: 0,
+                attack_level: 0,
+                fade_length: 0,
+                fade_level: 0,
+            },
+        },
+    };
+    assert_eq!(Rumble::from_effect(periodic), None);
+}
+
+#[test]
+fn test_force_feedback_packet_round_trip() {
+    let packet = ForceFeedbackPacket {
+        id: 42,
+        session: 7,
+        sequence: 3,
+        feedback: ForceFeedback::Play {
+            effect: 2,
+            rumble: test_rumble(),
+            count: 1,
+        },
+    };
+    let decoded: ForceFeedbackPacket = deserialize(&serialize(&packet).unwrap()).unwrap();
+    assert_eq!(decoded.id, 42);
+    assert_eq!((decoded.session, decoded.sequence), (7, 3));
+    assert_eq!(decoded.feedback, packet.feedback);
+}
@@ -0,0 +1,231 @@
+use std::net::{Ipv6Addr, SocketAddr};
+
+use evdev::KeyCode;
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+use crate::{
+    bitmask::Bitmask,
+    capabilities::{DeviceCapabilities, HelloPacket},
+    datagram::{
+        BroadcastPacket, DisconnectPacket, HEADER_LEN, MessageKind, frame, peek_kind, serialize,
+    },
+    discovery::ServerAnnouncement,
+    force_feedback::{ForceFeedback, ForceFeedbackPacket},
+    input::{InputFrame, IntoID, StarboardDeltaPacket, StarboardInput, StarboardInputPacket},
+    net::bind_dual_stack,
+    pairing::{AuthenticatedPacket, PairCommit, PairConfirm, PairNonce, PairRequest, PairReveal},
+    ping::{PingPacket, PongPacket},
+    rejects::{Port, RECENT_REJECTS, RejectLog},
+    session::{ClientSession, ServerSessions, SessionExpired},
+    string::StarboardString,
+    supported_actions::{AXIS_COUNT, BUTTON_COUNT},
+};
+
+use super::{test_key, test_server};
+
+const ROUNDS: usize = 2000;
+
+// Returns a well formed datagram of every kind of message, for the fuzzer to start from
+fn valid_datagrams() -> Vec<Vec<u8>> {
+    let id = 7;
+    let name = StarboardString::try_from("Steam Deck").unwrap();
+    let key = test_key();
+    let mut client = ClientSession::new(id, key);
+    let mut sessions = ServerSessions::new();
+    let init = client.init();
+    let accept = sessions.accept(&key, init);
+    client.accept(accept).unwrap();
+    let pong = PongPacket {
+        id,
+        nonce: 1,
+        ping_sent_at: 1,
+        client_time: 2,
+    };
+    let south = StarboardInput::Button {
+        id: KeyCode::BTN_SOUTH.into_id().unwrap(),
+        value: true,
+    };
+    let mut keyframe = StarboardInputPacket::new(id, 1, 1);
+    keyframe.pack(south).unwrap();
+    let mut delta = StarboardDeltaPacket::new(id, 1, 2);
+    delta.pack(south);
+    vec![
+        serialize(&keyframe).unwrap(),
+        serialize(&BroadcastPacket::new(id, "Steam Deck").unwrap()).unwrap(),
+        serialize(&delta).unwrap(),
+        serialize(&AuthenticatedPacket::seal(
+            id,
+            serialize(&pong).unwrap(),
+            &key,
+        ))
+        .unwrap(),
+        serialize(&PairRequest {
+            id,
+            name,
+            public_key: [1; 32],
+        })
+        .unwrap(),
+        serialize(&PairCommit {
+            server_id: 1,
+            server_name: name,
+            public_key: [2; 32],
+            commitment: [3; 32],
+        })
+        .unwrap(),
+        serialize(&PairNonce {
+            id,
+            nonce: init.nonce,
+        })
+        .unwrap(),
+        serialize(&PairReveal { nonce: init.nonce }).unwrap(),
+        serialize(&PairConfirm { id, tag: [4; 32] }).unwrap(),
+        serialize(&init).unwrap(),
+        serialize(&accept).unwrap(),
+        serialize(&SessionExpired {
+            id,
+            session: accept.session,
+        })
+        .unwrap(),
+        serialize(&client.seal(&serialize(&pong).unwrap()).unwrap().unwrap()).unwrap(),
+        serialize(&ServerAnnouncement {
+            id: 1,
+            name,
+            serial_port: 1,
+            device_search_port: 2,
+        })
+        .unwrap(),
+        serialize(&ForceFeedbackPacket {
+            id,
+            session: 1,
+            sequence: 1,
+            feedback: ForceFeedback::Gain(100),
+        })
+        .unwrap(),
+        serialize(&DisconnectPacket::new(id)).unwrap(),
+        serialize(&PingPacket {
+            id,
+            nonce: 1,
+            sent_at: 1,
+        })
+        .unwrap(),
+        serialize(&pong).unwrap(),
+        serialize(&HelloPacket {
+            id,
+            capabilities: DeviceCapabilities::new([KeyCode::BTN_SOUTH], []),
+            motion: None,
+            desktop: None,
+        })
+        .unwrap(),
+    ]
+}
+
+// Returns one of `valid` with a few bytes of its payload changed, which usually gets past the
+// framing checks and deep into the message decoders. Some are framed as the wrong kind of message,
+// and some are mangled after framing, so that the framing checks are exercised too.
+fn mutated_datagram(rng: &mut StdRng, valid: &[Vec<u8>]) -> Vec<u8> {
+    // Safety of using `unwrap()`: there is a datagram of every kind
+    let raw = valid.choose(rng).unwrap();
+    let mut payload = raw[HEADER_LEN..].to_vec();
+    for _ in 0..rng.gen_range(1..=4) {
+        let len = payload.len();
+        match rng.gen_range(0..4) {
+            0 if len > 0 => payload[rng.gen_range(0..len)] ^= 1 << rng.gen_range(0..8),
+            1 if len > 0 => payload[rng.gen_range(0..len)] = rng.r#gen(),
+            2 => payload.truncate(rng.gen_range(0..=len)),
+            _ => payload.insert(rng.gen_range(0..=len), rng.r#gen()),
+        }
+    }
+    let kind = match rng.gen_bool(0.1) {
+        true => peek_kind(valid.choose(rng).unwrap()).unwrap(),
+        false => peek_kind(raw).unwrap(),
+    };
+    let mut raw = frame(kind, &payload).unwrap();
+    if rng.gen_bool(0.1) {
+        let index = rng.gen_range(0..raw.len());
+        raw[index] = rng.r#gen();
+    }
+    raw
+}
+
+#[test]
+fn test_fuzzing_starts_from_every_kind_of_message() {
+    let kinds: Vec<MessageKind> = valid_datagrams()
+        .iter()
+        .map(|raw| peek_kind(raw).unwrap())
+        .collect();
+    let every_kind: Vec<MessageKind> = (1..)
+        .map_while(|value| MessageKind::try_from(value).ok())
+        .collect();
+    assert_eq!(kinds, every_kind);
+}
+
+#[test]
+fn test_arbitrary_bytes_never_panic_input_decoding() {
+    let mut rng = StdRng::seed_from_u64(0x5b0a4d);
+    let valid = valid_datagrams();
+    let buttons = Bitmask::full(BUTTON_COUNT);
+    let axes = Bitmask::full(AXIS_COUNT);
+    for _ in 0..ROUNDS {
+        let raw = mutated_datagram(&mut rng, &valid);
+        if let Ok(frame) = InputFrame::deserialize(&raw) {
+            frame.unpack(&buttons, &axes);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_arbitrary_bytes_never_stop_the_server() {
+    let server = test_server();
+    let sock = bind_dual_stack(0).unwrap();
+    // Anything the server sends back goes to a socket nobody reads
+    let sink = bind_dual_stack(0).unwrap();
+    let source = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        sink.local_addr().unwrap().port(),
+    );
+    let mut rng = StdRng::seed_from_u64(0xfa22);
+    let valid = valid_datagrams();
+    for _ in 0..ROUNDS {
+        let raw = mutated_datagram(&mut rng, &valid);
+        server.receive_serial(&raw, source, &sock).await;
+        server
+            .handle_device_search_packet(&raw, source, &sock)
+            .await
+            .unwrap();
+    }
+    let rejected = server.rejects().read().await.count(source.ip());
+    assert!(rejected > 0);
+    assert_eq!(
+        server.rejects().read().await.recent().count(),
+        RECENT_REJECTS
+    );
+
+    // The server still accepts well formed packets afterwards
+    let raw = serialize(&BroadcastPacket::new(7, "Steam Deck").unwrap()).unwrap();
+    server
+        .handle_device_search_packet(&raw, source, &sock)
+        .await
+        .unwrap();
+    assert_eq!(server.rejects().read().await.count(source.ip()), rejected);
+}
+
+#[test]
+fn test_reject_log_counts_by_host() {
+    let mut log = RejectLog::new();
+    let first: SocketAddr = "192.168.1.10:54321".parse().unwrap();
+    let restarted: SocketAddr = "[::ffff:192.168.1.10]:40000".parse().unwrap();
+    let other: SocketAddr = "192.168.1.11:54321".parse().unwrap();
+    log.record(Port::Serial, first, "Bad magic".to_string());
+    log.record(Port::DeviceSearch, restarted, "Bad magic".to_string());
+    log.record(Port::Serial, other, "Bad magic".to_string());
+    assert_eq!(log.count(first.ip()), 2);
+    assert_eq!(log.count(other.ip()), 1);
+    assert_eq!(log.count("192.168.1.12".parse().unwrap()), 0);
+    assert_eq!(log.recent().next().unwrap().source, other);
+
+    for _ in 0..RECENT_REJECTS * 2 {
+        log.record(Port::Serial, other, "Bad magic".to_string());
+    }
+    assert_eq!(log.recent().count(), RECENT_REJECTS);
+    assert_eq!(log.count(other.ip()), 1 + RECENT_REJECTS as u64 * 2);
+}
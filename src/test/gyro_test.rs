@@ -0,0 +1,126 @@
+use evdev::KeyCode;
+
+use crate::{
+    capabilities::AxisRange,
+    gyro::{GyroAim, GyroMode, GyroSettings, deflect},
+    input::MotionSample,
+};
+
+// Returns a sample of the Steam Deck turning at `yaw` and `pitch` degrees per second
+fn turning(yaw: f64, pitch: f64, timestamp: i32) -> MotionSample {
+    let mut values = [0; 6];
+    values[3] = (pitch * 16.0) as i32;
+    values[4] = (yaw * 16.0) as i32;
+    MotionSample { values, timestamp }
+}
+
+#[test]
+fn test_gyro_mode_cycles() {
+    let mut mode = GyroMode::default();
+    assert_eq!(mode, GyroMode::Off);
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        mode = mode.next();
+        seen.push(mode.to_string());
+    }
+    assert_eq!(seen, ["mouse", "stick", "off"]);
+}
+
+#[test]
+fn test_gyro_settings_are_validated() {
+    assert!(GyroSettings::default().validate().is_ok());
+    let settings = |sensitivity, smoothing, deadzone, ratchet| GyroSettings {
+        sensitivity,
+        smoothing,
+        deadzone,
+        ratchet,
+    };
+    assert!(
+        settings(2.5, 0.5, 1.0, Some(KeyCode::BTN_TL))
+            .validate()
+            .is_ok()
+    );
+    assert!(settings(0.0, 0.0, 0.0, None).validate().is_err());
+    assert!(settings(1.0, 1.0, 0.0, None).validate().is_err());
+    assert!(settings(1.0, 0.0, -1.0, None).validate().is_err());
+    assert!(settings(1.0, 0.0, f64::NAN, None).validate().is_err());
+    assert!(
+        settings(1.0, 0.0, 0.0, Some(KeyCode::KEY_A))
+            .validate()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_gyro_moves_the_pointer_as_far_as_it_turns() {
+    let mut aim = GyroAim::new(GyroSettings::default());
+    // Nothing moves until there's a sample to measure the time from
+    assert_eq!(aim.mouse_motion(&turning(90.0, 0.0, 0)), (0.0, 0.0));
+    // Turning left at 90 degrees per second for 10ms turns 0.9 degrees, so 9 pixels to the left
+    let (dx, dy) = aim.mouse_motion(&turning(90.0, 0.0, 10_000));
+    assert!((dx + 9.0).abs() < 1e-9);
+    assert_eq!(dy, 0.0);
+    // Tilting the top back aims up, and a gap in the readings moves nothing
+    let (_, dy) = aim.mouse_motion(&turning(0.0, 45.0, 20_000));
+    assert!(dy < 0.0);
+    assert_eq!(aim.mouse_motion(&turning(90.0, 0.0, 500_000)), (0.0, 0.0));
+}
+
+#[test]
+fn test_gyro_pushes_the_stick_with_its_speed() {
+    let settings = GyroSettings {
+        sensitivity: 2.0,
+        ..GyroSettings::default()
+    };
+    let mut aim = GyroAim::new(settings);
+    let (x, y) = aim.stick_deflection(&turning(-45.0, 0.0, 0));
+    assert!((x - 0.5).abs() < 1e-9);
+    assert_eq!(y, 0.0);
+    assert_eq!(aim.stick_deflection(&turning(-720.0, 0.0, 0)), (1.0, 0.0));
+
+    let stick = AxisRange {
+        minimum: -32768,
+        maximum: 32767,
+        fuzz: 0,
+        flat: 0,
+        resolution: 0,
+    };
+    assert_eq!(deflect(&stick, 0, 0.5), 16384);
+    assert_eq!(deflect(&stick, 20000, 0.5), 32767);
+    assert_eq!(deflect(&stick, -100, 0.0), -100);
+}
+
+#[test]
+fn test_gyro_deadzone_and_smoothing() {
+    let settings = GyroSettings {
+        smoothing: 0.5,
+        deadzone: 5.0,
+        ..GyroSettings::default()
+    };
+    let mut aim = GyroAim::new(settings);
+    assert_eq!(aim.stick_deflection(&turning(4.0, 0.0, 0)), (0.0, 0.0));
+    // Half of each new speed is taken in at a time
+    let (first, _) = aim.stick_deflection(&turning(-180.0, 0.0, 0));
+    let (second, _) = aim.stick_deflection(&turning(-180.0, 0.0, 0));
+    assert!((first - 0.5).abs() < 1e-9);
+    assert!((second - 0.75).abs() < 1e-9);
+}
+
+#[test]
+fn test_gyro_only_aims_while_the_ratchet_is_held() {
+    let settings = GyroSettings {
+        ratchet: Some(KeyCode::BTN_TL),
+        ..GyroSettings::default()
+    };
+    let mut aim = GyroAim::new(settings);
+    assert!(!aim.is_active());
+    assert_eq!(aim.stick_deflection(&turning(-90.0, 0.0, 0)), (0.0, 0.0));
+
+    assert!(!aim.press(KeyCode::BTN_TR, true));
+    assert!(aim.press(KeyCode::BTN_TL, true));
+    assert!(aim.is_active());
+    assert!(aim.stick_deflection(&turning(-90.0, 0.0, 0)).0 > 0.0);
+
+    assert!(aim.press(KeyCode::BTN_TL, false));
+    assert_eq!(aim.stick_deflection(&turning(-90.0, 0.0, 0)), (0.0, 0.0));
+}
@@ -2,23 +2,25 @@ use core::{assert_eq, convert::TryInto};
 
 use crate::{
     bitmask::Bitmask,
+    datagram::{BroadcastPacket, serialize},
     input::{
-        FromByte, IntoByte, StarboardAxisStates, StarboardButtonStates, StarboardInput,
-        StarboardInputPacket,
+        InputFrame, IntoID, StarboardAxisStates, StarboardButtonStates, StarboardDeltaPacket,
+        StarboardInput, StarboardInputPacket, StarboardKeyStates, StarboardTouchStates,
     },
+    supported_actions::{AXIS_COUNT, BUTTON_COUNT},
 };
 use evdev::{AbsoluteAxisCode, EventType, InputEvent, KeyCode};
 
 fn test_button_states() -> StarboardButtonStates {
-    let mut raw = Bitmask::new(32);
-    raw.write_bit(0, true);
-    raw.write_bit(2, true);
-    raw.write_bit(3, true);
+    let mut raw = Bitmask::new(BUTTON_COUNT);
+    raw.write_bit(0, true).unwrap();
+    raw.write_bit(2, true).unwrap();
+    raw.write_bit(3, true).unwrap();
     StarboardButtonStates { raw }
 }
 
 const TEST_AXIS_STATES: StarboardAxisStates = StarboardAxisStates {
-    axes: [0, 145, 223, 1125, 102, 255, 0, 0, 0, 0],
+    axes: [0, 145, 223, 1125, 102, 255, 0, 0, 0, 0, 0, 0],
 };
 
 #[test]
@@ -49,8 +51,8 @@ fn test_button_get_state() {
 
 #[test]
 fn test_button_get_states() {
-    let mask = Bitmask::new_from_u32(4, 0b1010);
-    let states = test_button_states().get_state_with_mask(mask);
+    let mask = Bitmask::from_ones(4, [1, 3]).unwrap();
+    let states = test_button_states().get_state_with_mask(&mask);
     assert_eq!(
         states,
         vec![
@@ -98,8 +100,8 @@ fn test_axis_get_state() {
 
 #[test]
 fn test_axis_get_states() {
-    let mask = Bitmask::new_from_u32(6, 0b100101);
-    let states = TEST_AXIS_STATES.get_state_with_mask(mask);
+    let mask = Bitmask::from_ones(6, [0, 2, 5]).unwrap();
+    let states = TEST_AXIS_STATES.get_state_with_mask(&mask);
     assert_eq!(states[0], StarboardInput::Axis { id: 0, value: 0 });
     assert_eq!(states[1], StarboardInput::Axis { id: 2, value: 223 });
     assert_eq!(states[2], StarboardInput::Axis { id: 5, value: 255 });
@@ -110,11 +112,15 @@ fn test_packet_unwrap() {
     let packet = StarboardInputPacket {
         buttons: test_button_states(),
         axes: TEST_AXIS_STATES,
+        touches: StarboardTouchStates::new(),
+        keys: StarboardKeyStates::new(),
         id: 0,
+        session: 0,
+        sequence: 0,
     };
 
-    let buttons = test_button_states().get_state_with_mask(Bitmask::MAX);
-    let axes = TEST_AXIS_STATES.get_state_with_mask(Bitmask::MAX);
+    let buttons = test_button_states().get_state_with_mask(&Bitmask::full(BUTTON_COUNT));
+    let axes = TEST_AXIS_STATES.get_state_with_mask(&Bitmask::full(AXIS_COUNT));
 
     let mut combined_inputs: Vec<StarboardInput> = Vec::new();
 
@@ -126,19 +132,25 @@ fn test_packet_unwrap() {
         combined_inputs.push(axis);
     }
 
-    assert_eq!(packet.unpack(Bitmask::MAX, Bitmask::MAX), combined_inputs);
+    // Every trackpad is unpacked, whatever the masks say
+    combined_inputs.extend(StarboardTouchStates::new().get_states());
+
+    assert_eq!(
+        packet.unpack(&Bitmask::full(BUTTON_COUNT), &Bitmask::full(AXIS_COUNT)),
+        combined_inputs
+    );
 }
 
 #[test]
-fn test_into_byte_evdev() {
-    assert_eq!(KeyCode::BTN_THUMB.into_byte().unwrap(), 1);
-    assert_eq!(KeyCode::BTN_TR2.into_byte().unwrap(), 1024);
+fn test_into_id_evdev() {
+    assert_eq!(KeyCode::BTN_THUMB.into_id().unwrap(), 0);
+    assert_eq!(KeyCode::BTN_TR2.into_id().unwrap(), 10);
 }
 
 #[test]
 #[should_panic]
-fn test_into_byte_evdev_panics() {
-    KeyCode::KEY_BRIGHTNESS_MAX.into_byte().unwrap();
+fn test_into_id_evdev_panics() {
+    KeyCode::KEY_BRIGHTNESS_MAX.into_id().unwrap();
 }
 
 #[test]
@@ -156,3 +168,124 @@ fn test_starboard_input_into_evdev_input() {
     assert_eq!(evdev_input.code(), KeyCode::BTN_THUMB.0);
     assert_eq!(evdev_input.value(), 1);
 }
+
+#[test]
+fn test_pack_released_button() {
+    let mut packet = StarboardInputPacket::new(0, 0, 0);
+    packet
+        .pack_iter([
+            StarboardInput::Button { id: 0, value: true },
+            StarboardInput::Button {
+                id: 1,
+                value: false,
+            },
+        ])
+        .unwrap();
+    assert!(packet.buttons.raw.read_bit(0));
+    assert!(!packet.buttons.raw.read_bit(1));
+}
+
+#[test]
+fn test_delta_pack_replaces_earlier_change() {
+    let mut delta = StarboardDeltaPacket::new(0, 0, 0);
+    delta.pack(StarboardInput::Axis { id: 0, value: 10 });
+    delta.pack(StarboardInput::Button { id: 0, value: true });
+    delta.pack(StarboardInput::Axis { id: 0, value: 20 });
+    assert_eq!(
+        delta.inputs,
+        vec![
+            StarboardInput::Axis { id: 0, value: 20 },
+            StarboardInput::Button { id: 0, value: true }
+        ]
+    );
+}
+
+#[test]
+fn test_delta_unpack_respects_masks() {
+    let mut delta = StarboardDeltaPacket::new(0, 0, 0);
+    delta.pack(StarboardInput::Button { id: 0, value: true });
+    delta.pack(StarboardInput::Button { id: 1, value: true });
+    delta.pack(StarboardInput::Button {
+        id: 1000,
+        value: true,
+    });
+    delta.pack(StarboardInput::Axis { id: 2, value: 5 });
+    let button_mask = Bitmask::from_ones(BUTTON_COUNT, [0]).unwrap();
+    let axis_mask = Bitmask::from_ones(AXIS_COUNT, [2]).unwrap();
+    assert_eq!(
+        delta.unpack(&button_mask, &axis_mask),
+        vec![
+            StarboardInput::Button { id: 0, value: true },
+            StarboardInput::Axis { id: 2, value: 5 }
+        ]
+    );
+}
+
+#[test]
+fn test_input_frame_deserialize() {
+    let keyframe = StarboardInputPacket::new(3, 1, 7);
+    let frame = InputFrame::deserialize(&serialize(&keyframe).unwrap()).unwrap();
+    assert_eq!(
+        frame,
+        InputFrame::Keyframe(StarboardInputPacket::new(3, 1, 7))
+    );
+
+    let mut delta = StarboardDeltaPacket::new(3, 1, 8);
+    delta.pack(StarboardInput::Axis { id: 1, value: -5 });
+    let frame = InputFrame::deserialize(&serialize(&delta).unwrap()).unwrap();
+    assert_eq!(*frame.sequence(), 8);
+    assert_eq!(frame, InputFrame::Delta(delta));
+
+    let broadcast = BroadcastPacket::new(3, "Test").unwrap();
+    assert!(InputFrame::deserialize(&serialize(&broadcast).unwrap()).is_err());
+}
+
+#[test]
+fn test_evdev_input_into_starboard_input() {
+    let event = InputEvent::new(EventType::KEY.0, KeyCode::BTN_SOUTH.0, 2);
+    assert_eq!(
+        StarboardInput::try_from(event).unwrap(),
+        StarboardInput::Button {
+            id: KeyCode::BTN_SOUTH.into_id().unwrap(),
+            value: true
+        }
+    );
+
+    let event = InputEvent::new(EventType::ABSOLUTE.0, AbsoluteAxisCode::ABS_Y.0, -12);
+    assert_eq!(
+        StarboardInput::try_from(event).unwrap(),
+        StarboardInput::Axis { id: 1, value: -12 }
+    );
+
+    let event = InputEvent::new(EventType::KEY.0, KeyCode::KEY_A.0, 1);
+    assert!(StarboardInput::try_from(event).is_err());
+}
+
+#[test]
+fn test_axis_values_keep_their_full_range() {
+    // Pads report positions far outside of an i16
+    let event = InputEvent::new(EventType::ABSOLUTE.0, AbsoluteAxisCode::ABS_X.0, 1_000_000);
+    let input = StarboardInput::try_from(event).unwrap();
+    assert_eq!(
+        input,
+        StarboardInput::Axis {
+            id: 0,
+            value: 1_000_000
+        }
+    );
+
+    let mut keyframe = StarboardInputPacket::new(3, 1, 7);
+    keyframe.pack(input).unwrap();
+    keyframe
+        .pack(StarboardInput::Axis {
+            id: 1,
+            value: i32::MIN,
+        })
+        .unwrap();
+    let frame = InputFrame::deserialize(&serialize(&keyframe).unwrap()).unwrap();
+    let InputFrame::Keyframe(decoded) = frame else {
+        panic!("Expected a keyframe");
+    };
+    assert_eq!(decoded.axes.axes[0], 1_000_000);
+    assert_eq!(decoded.axes.axes[1], i32::MIN);
+}
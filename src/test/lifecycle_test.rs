@@ -0,0 +1,154 @@
+use core::time::Duration;
+use std::net::SocketAddr;
+
+use crate::{
+    lifecycle::{ConnectionHealth, ControllerState, LifecycleThresholds},
+    server::ControllerDiagnostic,
+    string::StarboardString,
+};
+
+fn healthy() -> ConnectionHealth {
+    ConnectionHealth {
+        silent_for: Duration::from_secs(1),
+        latency_ms: 20,
+        loss_rate: 0.0,
+    }
+}
+
+#[test]
+fn test_lifecycle_degrades_on_latency_and_loss() {
+    let thresholds = LifecycleThresholds::default();
+    let slow = ConnectionHealth {
+        latency_ms: 250,
+        ..healthy()
+    };
+    let lossy = ConnectionHealth {
+        loss_rate: 20.0,
+        ..healthy()
+    };
+    let online = ControllerState::Online;
+    assert_eq!(thresholds.next_state(online, &healthy()), online);
+    assert_eq!(
+        thresholds.next_state(online, &slow),
+        ControllerState::Degraded
+    );
+    assert_eq!(
+        thresholds.next_state(online, &lossy),
+        ControllerState::Degraded
+    );
+}
+
+#[test]
+fn test_lifecycle_times_out_and_recovers() {
+    let thresholds = LifecycleThresholds::default();
+    let quiet = ConnectionHealth {
+        silent_for: thresholds.not_responding_after,
+        ..healthy()
+    };
+    let state = thresholds.next_state(ControllerState::Degraded, &quiet);
+    assert_eq!(state, ControllerState::NotResponding);
+    assert!(state.is_silent());
+    assert_eq!(
+        thresholds.next_state(state, &healthy()),
+        ControllerState::Online
+    );
+}
+
+#[test]
+fn test_lifecycle_disconnected_is_sticky() {
+    let thresholds = LifecycleThresholds::default();
+    assert_eq!(
+        thresholds.next_state(ControllerState::Disconnected, &healthy()),
+        ControllerState::Disconnected
+    );
+}
+
+#[test]
+fn test_lifecycle_evicts_long_gone_controllers() {
+    let thresholds = LifecycleThresholds::default();
+    assert!(!thresholds.should_evict(&healthy()));
+    let gone = ConnectionHealth {
+        silent_for: thresholds.evict_after,
+        ..healthy()
+    };
+    assert!(thresholds.should_evict(&gone));
+}
+
+#[test]
+fn test_diagnostic_loss_is_measured_since_last_check() {
+    let name = StarboardString::try_from("Test").unwrap();
+    let mut diagnostic = ControllerDiagnostic::new(0, name, ControllerState::Online);
+    let now = diagnostic.last_ping;
+    diagnostic.accept_sequence(1, 0);
+    diagnostic.accept_sequence(1, 2);
+    assert!(diagnostic.check_health(now).loss_rate > 0.0);
+    diagnostic.accept_sequence(1, 3);
+    assert_eq!(diagnostic.check_health(now).loss_rate, 0.0);
+}
+
+#[test]
+fn test_diagnostic_recovers_when_pinged() {
+    let name = StarboardString::try_from("Test").unwrap();
+    let mut diagnostic = ControllerDiagnostic::new(0, name, ControllerState::Online);
+    let thresholds = LifecycleThresholds::default();
+    let later = diagnostic.last_ping + thresholds.not_responding_after.as_secs() as i64;
+    let health = diagnostic.check_health(later);
+    diagnostic.update_status(&thresholds, &health);
+    assert_eq!(*diagnostic.status(), ControllerState::NotResponding);
+    diagnostic.record_ping();
+    assert_eq!(*diagnostic.status(), ControllerState::Online);
+}
+
+#[test]
+fn test_diagnostic_refuses_a_second_address() {
+    let name = StarboardString::try_from("Test").unwrap();
+    let mut diagnostic = ControllerDiagnostic::new(0, name, ControllerState::Online);
+    let first: SocketAddr = "[::1]:5000".parse().unwrap();
+    let second: SocketAddr = "[::1]:6000".parse().unwrap();
+    assert!(diagnostic.claim_serial_addr(first));
+    assert!(diagnostic.claim_serial_addr(first));
+    assert!(!diagnostic.claim_serial_addr(second));
+    assert_eq!(diagnostic.conflict(), Some(second));
+}
+
+#[test]
+fn test_diagnostic_hands_over_once_silent() {
+    let name = StarboardString::try_from("Test").unwrap();
+    let mut diagnostic = ControllerDiagnostic::new(0, name, ControllerState::Disconnected);
+    let first: SocketAddr = "[::1]:5000".parse().unwrap();
+    let second: SocketAddr = "[::1]:6000".parse().unwrap();
+    assert!(diagnostic.claim_presence_addr(first));
+    assert!(diagnostic.claim_serial_addr(first));
+    assert!(diagnostic.claim_presence_addr(second));
+    assert_eq!(diagnostic.conflict(), None);
+    // The controller came back from a new address, so its input may too
+    diagnostic.record_ping();
+    assert!(diagnostic.claim_serial_addr(second));
+}
+
+#[test]
+fn test_diagnostic_binds_input_to_the_broadcasting_host() {
+    let name = StarboardString::try_from("Test").unwrap();
+    let mut diagnostic = ControllerDiagnostic::new(0, name, ControllerState::Online);
+    let presence: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+    let spoofed: SocketAddr = "[2001:db8::2]:6000".parse().unwrap();
+    let serial: SocketAddr = "[2001:db8::1]:6000".parse().unwrap();
+    assert!(diagnostic.claim_presence_addr(presence));
+    assert!(!diagnostic.claim_serial_addr(spoofed));
+    assert!(diagnostic.claim_serial_addr(serial));
+    assert!(!diagnostic.claim_serial_addr(spoofed));
+    assert_eq!(diagnostic.refused, 2);
+}
+
+#[test]
+fn test_diagnostic_rebinds_input_after_a_handshake() {
+    let name = StarboardString::try_from("Test").unwrap();
+    let mut diagnostic = ControllerDiagnostic::new(0, name, ControllerState::Online);
+    let first: SocketAddr = "[::1]:5000".parse().unwrap();
+    let second: SocketAddr = "[::1]:6000".parse().unwrap();
+    assert!(diagnostic.claim_serial_addr(first));
+    diagnostic.record_handshake(second);
+    assert!(diagnostic.claim_serial_addr(second));
+    assert!(!diagnostic.claim_serial_addr(first));
+    assert_eq!(diagnostic.refused, 1);
+}
@@ -1,4 +1,79 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use crate::{
+    pairing::{ClientPairing, PairingKey, ServerPairing, format_pin},
+    server::{StarboardServer, StarboardServerBuilder},
+    string::StarboardString,
+};
+
 mod bitmask_test;
+mod capabilities_test;
 mod datagram_test;
+mod desktop_test;
+mod discovery_test;
 mod fixed_queue_test;
+mod force_feedback_test;
+mod fuzz_test;
+mod gyro_test;
 mod input_test;
+mod lifecycle_test;
+mod motion_test;
+mod net_test;
+mod pairing_test;
+mod ping_test;
+mod remap_test;
+mod sequence_test;
+mod session_test;
+mod storage_test;
+mod supported_actions_test;
+mod trackpad_test;
+
+// Helpers shared by several test modules
+
+// Builds a server that keeps its state in a temporary directory of its own, rather than in the data
+// directory of whoever runs the tests
+pub fn test_server() -> Arc<StarboardServer> {
+    static SERVERS: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "starboard_server_{}_{}",
+        process::id(),
+        SERVERS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    StarboardServerBuilder::new(0, 0)
+        .data_dir(dir)
+        .build("Test".to_string())
+        .unwrap()
+}
+
+// Returns a key shared by a client with ID 1 and a server, after pairing them
+pub fn test_key() -> PairingKey {
+    let addr: SocketAddr = "192.168.1.20:40000".parse().unwrap();
+    let mut server = ServerPairing::new(7, StarboardString::try_from("Test Server").unwrap());
+    server.open();
+    let mut client = ClientPairing::new(1);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+    let commit = server
+        .handle_request(addr, client.request(name), false)
+        .unwrap();
+    let reveal = server
+        .handle_nonce(addr, client.handle_commit(commit))
+        .unwrap();
+    client.handle_reveal(reveal).unwrap();
+    for digit in format_pin(client.code().unwrap()).chars() {
+        server.enter_digit(digit);
+    }
+    let paired = server
+        .handle_confirm(addr, client.confirm().unwrap())
+        .unwrap()
+        .unwrap();
+    paired.key
+}
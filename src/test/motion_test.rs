@@ -0,0 +1,65 @@
+use evdev::{AbsoluteAxisCode, EventType, InputEvent, MiscCode};
+
+use crate::{
+    datagram::serialize,
+    input::{InputFrame, MotionSample, StarboardDeltaPacket, StarboardInput, StarboardInputPacket},
+    motion::MotionTracker,
+    supported_actions::{MOTION_AXES, MOTION_AXIS_COUNT, motion_axis_by_code},
+};
+
+fn abs(axis: AbsoluteAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::ABSOLUTE.0, axis.0, value)
+}
+
+fn syn() -> InputEvent {
+    InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)
+}
+
+#[test]
+fn test_motion_axes_are_numbered_in_order() {
+    for (index, axis) in MOTION_AXES.iter().enumerate() {
+        assert_eq!(axis.id as usize, index);
+        assert_eq!(motion_axis_by_code(axis.code).unwrap().id, axis.id);
+    }
+    assert_eq!(MOTION_AXIS_COUNT, 6);
+    assert!(motion_axis_by_code(AbsoluteAxisCode::ABS_HAT0X).is_none());
+}
+
+#[test]
+fn test_tracker_completes_readings_on_syn() {
+    let mut tracker = MotionTracker::new();
+    assert_eq!(tracker.update(&abs(AbsoluteAxisCode::ABS_X, 120)), None);
+    assert_eq!(tracker.update(&abs(AbsoluteAxisCode::ABS_RZ, -7)), None);
+    let timestamp = InputEvent::new(EventType::MISC.0, MiscCode::MSC_TIMESTAMP.0, 4000);
+    assert_eq!(tracker.update(&timestamp), None);
+    assert_eq!(
+        tracker.update(&syn()),
+        Some(MotionSample {
+            values: [120, 0, 0, 0, 0, -7],
+            timestamp: 4000,
+        })
+    );
+
+    // A report that changed nothing isn't a new reading, but later readings keep earlier values
+    assert_eq!(tracker.update(&syn()), None);
+    tracker.update(&abs(AbsoluteAxisCode::ABS_Y, 3));
+    assert_eq!(
+        tracker.update(&syn()).unwrap().values,
+        [120, 3, 0, 0, 0, -7]
+    );
+}
+
+#[test]
+fn test_motion_only_travels_in_deltas() {
+    let sample = MotionSample {
+        values: [1, -2, 3, -4, 5, -6],
+        timestamp: 77,
+    };
+    let mut delta = StarboardDeltaPacket::new(3, 1, 8);
+    delta.pack(StarboardInput::Motion { sample });
+    let frame = InputFrame::deserialize(&serialize(&delta).unwrap()).unwrap();
+    assert_eq!(frame, InputFrame::Delta(delta));
+
+    let mut keyframe = StarboardInputPacket::new(3, 1, 8);
+    assert!(keyframe.pack(StarboardInput::Motion { sample }).is_err());
+}
@@ -0,0 +1,98 @@
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use crate::{
+    datagram::format_addr,
+    net::{bind_dual_stack, bind_ipv4, broadcast_addrs, resolve_addr, to_dual_stack},
+};
+
+#[test]
+fn test_to_dual_stack() {
+    let v4: SocketAddr = "192.168.1.10:61000".parse().unwrap();
+    let mapped = to_dual_stack(v4);
+    assert_eq!(mapped, "[::ffff:192.168.1.10]:61000".parse().unwrap());
+    assert_eq!(to_dual_stack(mapped), mapped);
+}
+
+#[test]
+fn test_format_addr() {
+    let mapped: SocketAddr = "[::ffff:192.168.1.10]:61000".parse().unwrap();
+    assert_eq!(format_addr(mapped), "192.168.1.10:61000");
+    let v6: SocketAddr = "[2001:db8::1]:61000".parse().unwrap();
+    assert_eq!(format_addr(v6), "[2001:db8::1]:61000");
+}
+
+#[tokio::test]
+async fn test_resolve_addr() {
+    assert_eq!(
+        resolve_addr("192.168.1.10:61000").await.unwrap(),
+        "[::ffff:192.168.1.10]:61000".parse().unwrap()
+    );
+    assert_eq!(
+        resolve_addr("[2001:db8::1]:61000").await.unwrap(),
+        "[2001:db8::1]:61000".parse().unwrap()
+    );
+    assert!(resolve_addr("192.168.1.10").await.is_err());
+}
+
+#[tokio::test]
+async fn test_resolve_addr_with_zone() {
+    let resolved = resolve_addr("[fe80::1%3]:61000").await.unwrap();
+    assert_eq!(
+        resolved,
+        SocketAddrV6::new("fe80::1".parse().unwrap(), 61000, 0, 3).into()
+    );
+    assert!(
+        resolve_addr("[fe80::1%not-an-interface]:61000")
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_dual_stack_socket() {
+    let server = bind_dual_stack(0).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let client = bind_dual_stack(0).unwrap();
+    let mut buf = [0; 8];
+
+    // IPv4 peers are reached through their IPv4-mapped address
+    let v4 = to_dual_stack(SocketAddr::new([127, 0, 0, 1].into(), port));
+    client.send_to(&[4], v4).await.unwrap();
+    let (len, from) = server.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], &[4]);
+    assert_eq!(format_addr(from), format!("127.0.0.1:{}", from.port()));
+
+    client
+        .send_to(&[6], SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port))
+        .await
+        .unwrap();
+    let (len, _) = server.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], &[6]);
+}
+
+#[tokio::test]
+async fn test_ipv4_fallback_socket() {
+    // Hosts without IPv6 use IPv4 sockets, which still reach servers with dual-stack sockets
+    let server = bind_dual_stack(0).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let client = bind_ipv4(0).unwrap();
+    let mut buf = [0; 8];
+
+    client
+        .send_to(&[4], SocketAddr::new([127, 0, 0, 1].into(), port))
+        .await
+        .unwrap();
+    let (len, from) = server.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], &[4]);
+    server.send_to(&[6], from).await.unwrap();
+    let (len, _) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], &[6]);
+}
+
+#[test]
+fn test_broadcast_addrs() {
+    for addr in broadcast_addrs(61000).unwrap() {
+        assert!(addr.is_ipv4());
+        assert_eq!(addr.port(), 61000);
+    }
+}
@@ -0,0 +1,226 @@
+use std::net::SocketAddr;
+
+use super::test_key;
+use crate::{
+    datagram::{deserialize, serialize},
+    pairing::{AuthenticatedPacket, ClientPairing, PairingStatus, ServerPairing, format_pin},
+    string::StarboardString,
+};
+
+fn test_addr() -> SocketAddr {
+    "192.168.1.20:40000".parse().unwrap()
+}
+
+fn test_server() -> ServerPairing {
+    let mut server = ServerPairing::new(7, StarboardString::try_from("Test Server").unwrap());
+    server.open();
+    server
+}
+
+// Types `code` into the server's pairing page
+fn enter_code(server: &mut ServerPairing, code: u32) {
+    for digit in format_pin(code).chars() {
+        server.enter_digit(digit);
+    }
+}
+
+#[test]
+fn test_pairing_handshake() {
+    let mut server = test_server();
+    let mut client = ClientPairing::new(42);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+
+    let commit = server
+        .handle_request(test_addr(), client.request(name), false)
+        .unwrap();
+    let nonce = client.handle_commit(commit);
+    let reveal = server.handle_nonce(test_addr(), nonce).unwrap();
+    let pin = client.handle_reveal(reveal).unwrap();
+    let PairingStatus::AwaitingPin {
+        pin: server_pin, ..
+    } = *server.status()
+    else {
+        panic!("The server should be showing a PIN");
+    };
+    assert_eq!(pin, server_pin);
+
+    // Nothing is paired until the operator enters the client's code
+    assert!(
+        server
+            .handle_confirm(test_addr(), client.confirm().unwrap())
+            .unwrap()
+            .is_none()
+    );
+    enter_code(&mut server, client.code().unwrap());
+    let paired = server
+        .handle_confirm(test_addr(), client.confirm().unwrap())
+        .unwrap()
+        .unwrap();
+    let (server_id, server_name, client_key) = client.finish(paired.confirm).unwrap();
+    assert_eq!(server_id, 7);
+    assert_eq!(paired.id, 42);
+    assert!(!paired.repairing);
+    assert_eq!(paired.key, client_key);
+    assert_eq!(
+        <StarboardString as Into<String>>::into(server_name),
+        "Test Server"
+    );
+    assert!(matches!(server.status(), PairingStatus::Paired { .. }));
+}
+
+#[test]
+fn test_pairing_rejects_tampered_nonce() {
+    let mut server = test_server();
+    let mut client = ClientPairing::new(42);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+
+    let commit = server
+        .handle_request(test_addr(), client.request(name), false)
+        .unwrap();
+    let nonce = client.handle_commit(commit);
+    let mut reveal = server.handle_nonce(test_addr(), nonce).unwrap();
+    reveal.nonce[0] ^= 1;
+    assert!(client.handle_reveal(reveal).is_err());
+}
+
+#[test]
+fn test_pairing_rejects_other_addresses() {
+    let mut server = test_server();
+    let mut client = ClientPairing::new(42);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+
+    let commit = server
+        .handle_request(test_addr(), client.request(name), false)
+        .unwrap();
+    let nonce = client.handle_commit(commit);
+    let other: SocketAddr = "192.168.1.66:40000".parse().unwrap();
+    assert!(server.handle_nonce(other, nonce).is_err());
+}
+
+#[test]
+fn test_pairing_closed() {
+    let mut server = test_server();
+    server.close();
+    let client = ClientPairing::new(42);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+    assert!(
+        server
+            .handle_request(test_addr(), client.request(name), false)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_pairing_one_handshake_at_a_time() {
+    let mut server = test_server();
+    let client = ClientPairing::new(42);
+    let intruder = ClientPairing::new(66);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+    let other: SocketAddr = "192.168.1.66:40000".parse().unwrap();
+
+    let commit = server
+        .handle_request(test_addr(), client.request(name), false)
+        .unwrap();
+    assert!(
+        server
+            .handle_request(other, intruder.request(name), false)
+            .is_err()
+    );
+    // A request sent again gets the same commitment
+    let again = server
+        .handle_request(test_addr(), client.request(name), false)
+        .unwrap();
+    assert_eq!(again.commitment, commit.commitment);
+
+    // Once the operator refuses it, someone else can pair
+    server.refuse();
+    assert!(matches!(server.status(), PairingStatus::Failed(_)));
+    assert!(
+        server
+            .handle_request(other, intruder.request(name), false)
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_pairing_keeps_the_first_nonce() {
+    let mut server = test_server();
+    let mut client = ClientPairing::new(42);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+
+    let commit = server
+        .handle_request(test_addr(), client.request(name), true)
+        .unwrap();
+    let mut nonce = client.handle_commit(commit);
+    let reveal = server.handle_nonce(test_addr(), nonce).unwrap();
+    assert_eq!(
+        server.handle_nonce(test_addr(), nonce).unwrap().nonce,
+        reveal.nonce
+    );
+    nonce.nonce[0] ^= 1;
+    assert!(server.handle_nonce(test_addr(), nonce).is_err());
+    // The operator is told that pairing replaces the controller's key
+    assert!(matches!(
+        server.status(),
+        PairingStatus::AwaitingPin {
+            repairing: true,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_pairing_needs_the_clients_code() {
+    let mut server = test_server();
+    let mut client = ClientPairing::new(42);
+    let name = StarboardString::try_from("Test Deck").unwrap();
+
+    let commit = server
+        .handle_request(test_addr(), client.request(name), false)
+        .unwrap();
+    let reveal = server
+        .handle_nonce(test_addr(), client.handle_commit(commit))
+        .unwrap();
+    client.handle_reveal(reveal).unwrap();
+    let code = client.code().unwrap();
+
+    // A wrong code isn't approved, but the operator can type it again
+    enter_code(&mut server, (code + 1) % 1_000_000);
+    assert!(matches!(
+        server.status(),
+        PairingStatus::AwaitingPin { mismatch: true, .. }
+    ));
+    assert!(
+        server
+            .handle_confirm(test_addr(), client.confirm().unwrap())
+            .unwrap()
+            .is_none()
+    );
+    enter_code(&mut server, code);
+    assert!(
+        server
+            .handle_confirm(test_addr(), client.confirm().unwrap())
+            .unwrap()
+            .is_some()
+    );
+}
+
+#[test]
+fn test_authenticated_packet_round_trip() {
+    let key = test_key();
+    let packet = AuthenticatedPacket::seal(1, vec![1, 2, 3], &key);
+    let raw = serialize(&packet).unwrap();
+    let packet: AuthenticatedPacket = deserialize(&raw).unwrap();
+    assert_eq!(packet.open(&key).unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_authenticated_packet_rejects_wrong_key_or_id() {
+    let key = test_key();
+    let other_key = test_key();
+    let packet = AuthenticatedPacket::seal(1, vec![1, 2, 3], &key);
+    assert!(packet.open(&other_key).is_err());
+
+    // Re-sealing the same payload under another ID must not verify with the original tag
+    assert!(!key.verify(2, &[1, 2, 3], &key.sign(1, &[1, 2, 3])));
+}
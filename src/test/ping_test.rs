@@ -0,0 +1,91 @@
+use crate::{
+    datagram::{deserialize, serialize},
+    lifecycle::ControllerState,
+    ping::{PingPacket, PongPacket, RoundTrip},
+    server::{ControllerDiagnostic, OUTSTANDING_PINGS},
+    string::StarboardString,
+};
+
+fn test_diagnostic() -> ControllerDiagnostic {
+    let name = StarboardString::try_from("Test").unwrap();
+    ControllerDiagnostic::new(7, name, ControllerState::Online)
+}
+
+#[test]
+fn test_round_trip_ignores_client_clock() {
+    // The client's clock is 5 seconds ahead and answers 20ms into a 40ms round trip
+    let pong = PongPacket {
+        id: 7,
+        nonce: 1,
+        ping_sent_at: 1_000,
+        client_time: 6_020,
+    };
+    let round_trip = RoundTrip::measure(&pong, 1_040);
+    assert_eq!(round_trip.rtt_ms, 40);
+    assert_eq!(round_trip.clock_offset_ms, 5_000);
+}
+
+#[test]
+fn test_round_trip_behind_clock() {
+    let pong = PongPacket {
+        id: 7,
+        nonce: 1,
+        ping_sent_at: 10_000,
+        client_time: 7_005,
+    };
+    let round_trip = RoundTrip::measure(&pong, 10_010);
+    assert_eq!(round_trip.rtt_ms, 10);
+    assert_eq!(round_trip.clock_offset_ms, -3_000);
+}
+
+#[test]
+fn test_pong_answers_ping() {
+    let ping = PingPacket {
+        id: 7,
+        nonce: 9,
+        sent_at: 123,
+    };
+    let pong = PongPacket::answer(&ping);
+    let pong: PongPacket = deserialize(&serialize(&pong).unwrap()).unwrap();
+    assert_eq!(pong.id, 7);
+    assert_eq!(pong.nonce, 9);
+    assert_eq!(pong.ping_sent_at, 123);
+}
+
+fn pong(nonce: u64, ping_sent_at: i64, client_time: i64) -> PongPacket {
+    PongPacket {
+        id: 7,
+        nonce,
+        ping_sent_at,
+        client_time,
+    }
+}
+
+#[test]
+fn test_diagnostic_records_late_pongs() {
+    let mut diagnostic = test_diagnostic();
+    let first = diagnostic.send_ping(100);
+    let second = diagnostic.send_ping(1_100);
+    // The answer to the first ping arrives after the second ping was sent, but still counts
+    assert!(diagnostic.record_pong(&pong(first, 100, 1_000), 1_900));
+    assert_eq!(diagnostic.latency.last(), Some(1_800));
+    assert!(diagnostic.record_pong(&pong(second, 1_100, 1_115), 1_130));
+    assert_eq!(diagnostic.latency.last(), Some(30));
+    assert_eq!(diagnostic.clock_offset, Some(0));
+    // A replayed pong doesn't count twice
+    assert!(!diagnostic.record_pong(&pong(second, 1_100, 1_115), 1_160));
+}
+
+#[test]
+fn test_diagnostic_refuses_unknown_pongs() {
+    let mut diagnostic = test_diagnostic();
+    let nonce = diagnostic.send_ping(100);
+    // A pong has to echo both the nonce and the send time of a ping
+    assert!(!diagnostic.record_pong(&pong(nonce.wrapping_add(1), 100, 150), 200));
+    assert!(!diagnostic.record_pong(&pong(nonce, 101, 150), 200));
+    // Only so many pings are waited on
+    for i in 1..=OUTSTANDING_PINGS as i64 {
+        diagnostic.send_ping(100 + i * 1_000);
+    }
+    assert!(!diagnostic.record_pong(&pong(nonce, 100, 150), 20_000));
+}
@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fs, process, sync::Arc};
+
+use evdev::{AbsoluteAxisCode, KeyCode};
+
+use crate::{
+    capabilities::{AxisRange, DeviceCapabilities},
+    input::{IntoID, StarboardInput},
+    remap::{Mapping, Profile, Remapper, load_profiles, next_profile},
+    supported_actions::{SUPPORTED_AXES, SUPPORTED_BUTTONS, axis_by_code},
+};
+
+// Returns the range Starboard expects `axis` in
+fn range(axis: AbsoluteAxisCode) -> AxisRange {
+    axis_by_code(axis).unwrap().range
+}
+
+fn button(key: KeyCode, value: bool) -> StarboardInput {
+    StarboardInput::Button {
+        id: key.into_id().unwrap(),
+        value,
+    }
+}
+
+fn axis(axis: AbsoluteAxisCode, value: i32) -> StarboardInput {
+    StarboardInput::Axis {
+        id: axis.into_id().unwrap(),
+        value,
+    }
+}
+
+fn remapper(text: &str) -> Remapper {
+    Remapper::new(Arc::new(Profile::parse("test", text).unwrap()))
+}
+
+#[test]
+fn test_profiles_are_parsed() {
+    let profile = Profile::parse(
+        "racing",
+        "# Jump with B\n\
+         BTN_SOUTH -> BTN_EAST\n\
+         \n\
+         ABS_Y -> ABS_RY invert  # Flight controls\n\
+         BTN_DPAD_LEFT -> ABS_X min\n\
+         BTN_TL -> ABS_HAT2Y\n\
+         ABS_HAT2X -> BTN_TR -25%\n\
+         ABS_HAT2Y -> BTN_TL2\n",
+    )
+    .unwrap();
+    assert_eq!(profile.name, "racing");
+    assert_eq!(
+        profile.mappings,
+        [
+            Mapping::Button {
+                from: KeyCode::BTN_SOUTH,
+                to: KeyCode::BTN_EAST,
+            },
+            Mapping::Axis {
+                from: AbsoluteAxisCode::ABS_Y,
+                to: AbsoluteAxisCode::ABS_RY,
+                invert: true,
+            },
+            Mapping::ButtonToAxis {
+                from: KeyCode::BTN_DPAD_LEFT,
+                to: AbsoluteAxisCode::ABS_X,
+                toward_maximum: false,
+            },
+            Mapping::ButtonToAxis {
+                from: KeyCode::BTN_TL,
+                to: AbsoluteAxisCode::ABS_HAT2Y,
+                toward_maximum: true,
+            },
+            Mapping::AxisToButton {
+                from: AbsoluteAxisCode::ABS_HAT2X,
+                to: KeyCode::BTN_TR,
+                threshold: -0.25,
+            },
+            Mapping::AxisToButton {
+                from: AbsoluteAxisCode::ABS_HAT2Y,
+                to: KeyCode::BTN_TL2,
+                threshold: 0.5,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_invalid_profiles_are_rejected() {
+    let error = |text| Profile::parse("bad", text).unwrap_err().to_string();
+    assert!(error("BTN_SOUTH -> BTN_EAST\nBTN_SOUTH BTN_EAST").starts_with("line 2:"));
+    // Only inputs Starboard supports can be mapped
+    assert!(error("KEY_A -> BTN_EAST").contains("KEY_A"));
+    assert!(error("BTN_SOUTH -> ABS_MISC").contains("ABS_MISC"));
+    assert!(error("BTN_SOUTH ->").contains("after `->`"));
+    // Options only go with the mappings they apply to
+    assert!(error("BTN_SOUTH -> BTN_EAST invert").contains("invert"));
+    assert!(error("ABS_X -> ABS_Y max").contains("max"));
+    assert!(error("ABS_X -> BTN_EAST 150%").contains("150%"));
+    assert!(error("ABS_X -> BTN_EAST 0%").contains("0%"));
+    assert!(error("ABS_X -> BTN_EAST half").contains("half"));
+    assert!(error("ABS_X -> ABS_Y invert twice").contains("twice"));
+}
+
+#[test]
+fn test_buttons_are_remapped() {
+    let mut remapper = remapper("BTN_SOUTH -> BTN_EAST\nBTN_EAST -> BTN_SOUTH");
+    let inputs = vec![
+        button(KeyCode::BTN_SOUTH, true),
+        button(KeyCode::BTN_EAST, false),
+        button(KeyCode::BTN_NORTH, true),
+        StarboardInput::Rel { x: 3, y: -2 },
+    ];
+    assert_eq!(
+        remapper.remap(inputs, range),
+        [
+            // Inputs that aren't mapped come out as themselves, before the mapped ones
+            button(KeyCode::BTN_NORTH, true),
+            StarboardInput::Rel { x: 3, y: -2 },
+            button(KeyCode::BTN_EAST, true),
+            button(KeyCode::BTN_SOUTH, false),
+        ]
+    );
+}
+
+#[test]
+fn test_axes_are_remapped() {
+    let mut remapper = remapper("ABS_Y -> ABS_Y invert\nABS_Z -> ABS_HAT2Y");
+    let mut remap = |input| remapper.remap(vec![input], range);
+    let (y, z, hat) = (
+        AbsoluteAxisCode::ABS_Y,
+        AbsoluteAxisCode::ABS_Z,
+        AbsoluteAxisCode::ABS_HAT2Y,
+    );
+    assert_eq!(remap(axis(y, -32768)), [axis(y, 32767)]);
+    assert_eq!(remap(axis(y, 1000)), [axis(y, -1001)]);
+    // Values are put in the range of the axis they're mapped to
+    assert_eq!(remap(axis(z, 255)), [axis(hat, 32767)]);
+    assert_eq!(remap(axis(z, 0)), [axis(hat, 0)]);
+}
+
+#[test]
+fn test_buttons_and_axes_are_remapped_into_each_other() {
+    let mut remapper = remapper(
+        "BTN_DPAD_LEFT -> ABS_X min\n\
+         BTN_TL -> ABS_HAT2Y\n\
+         ABS_HAT2X -> BTN_TR 25%\n\
+         ABS_RX -> BTN_WEST -50%\n\
+         ABS_RX -> BTN_EAST 50%",
+    );
+    let mut remap = |input| remapper.remap(vec![input], range);
+    let (x, hat) = (AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_HAT2Y);
+    assert_eq!(
+        remap(button(KeyCode::BTN_DPAD_LEFT, true)),
+        [axis(x, -32768)]
+    );
+    assert_eq!(remap(button(KeyCode::BTN_DPAD_LEFT, false)), [axis(x, 0)]);
+    assert_eq!(remap(button(KeyCode::BTN_TL, true)), [axis(hat, 32767)]);
+    assert_eq!(remap(button(KeyCode::BTN_TL, false)), [axis(hat, 0)]);
+
+    let trigger = AbsoluteAxisCode::ABS_HAT2X;
+    assert_eq!(remap(axis(trigger, 8000)), [button(KeyCode::BTN_TR, false)]);
+    assert_eq!(remap(axis(trigger, 8192)), [button(KeyCode::BTN_TR, true)]);
+
+    // A stick mapped both ways presses one button or the other, depending on where it's pushed
+    let mut stick = |value| remap(axis(AbsoluteAxisCode::ABS_RX, value));
+    let buttons = |west, east| {
+        [
+            button(KeyCode::BTN_WEST, west),
+            button(KeyCode::BTN_EAST, east),
+        ]
+    };
+    assert_eq!(stick(-20000), buttons(true, false));
+    assert_eq!(stick(0), buttons(false, false));
+    assert_eq!(stick(20000), buttons(false, true));
+}
+
+// Returns every button and axis the controller has, as a keyframe carries them, with `held` pressed
+// and every axis at rest
+fn keyframe(held: &[KeyCode]) -> Vec<StarboardInput> {
+    let buttons = SUPPORTED_BUTTONS
+        .keys()
+        .map(|key| button(*key, held.contains(key)));
+    let axes = SUPPORTED_AXES
+        .iter()
+        .map(|supported| axis(supported.code, supported.range.rest()));
+    buttons.chain(axes).collect()
+}
+
+// Returns what each button and axis ends up set to once `inputs` are sent in order
+fn settle(inputs: &[StarboardInput]) -> (HashMap<u32, bool>, HashMap<u32, i32>) {
+    let (mut buttons, mut axes) = (HashMap::new(), HashMap::new());
+    for input in inputs {
+        match *input {
+            StarboardInput::Button { id, value } => {
+                buttons.insert(id, value);
+            }
+            StarboardInput::Axis { id, value } => {
+                axes.insert(id, value);
+            }
+            _ => {}
+        }
+    }
+    (buttons, axes)
+}
+
+#[test]
+fn test_keyframes_are_remapped() {
+    let mut remapper = remapper(
+        "BTN_SOUTH -> BTN_EAST\n\
+         BTN_DPAD_LEFT -> ABS_X min\n\
+         BTN_DPAD_RIGHT -> ABS_X max",
+    );
+    let east = KeyCode::BTN_EAST.into_id().unwrap();
+    let south = KeyCode::BTN_SOUTH.into_id().unwrap();
+    let x = AbsoluteAxisCode::ABS_X.into_id().unwrap();
+
+    // The controller's own B and left stick don't undo what's mapped to them
+    let held = [KeyCode::BTN_SOUTH, KeyCode::BTN_DPAD_LEFT];
+    let remapped = remapper.remap(keyframe(&held), range);
+    let (buttons, axes) = settle(&remapped);
+    assert!(buttons[&east]);
+    assert!(!buttons.contains_key(&south));
+    assert_eq!(axes[&x], -32768);
+    // Each button and axis that's mapped to is only set once
+    let sets_east = |input: &&StarboardInput| *input == &button(KeyCode::BTN_EAST, true);
+    assert_eq!(remapped.iter().filter(sets_east).count(), 1);
+
+    // With both buttons mapped to an axis held, it goes to whichever end is further from rest.
+    // Letting go of one leaves it where the other one holds it.
+    let both = remapper.remap(vec![button(KeyCode::BTN_DPAD_RIGHT, true)], range);
+    assert_eq!(both, [axis(AbsoluteAxisCode::ABS_X, -32768)]);
+    let right = remapper.remap(vec![button(KeyCode::BTN_DPAD_LEFT, false)], range);
+    assert_eq!(right, [axis(AbsoluteAxisCode::ABS_X, 32767)]);
+    let (buttons, axes) = settle(&remapper.remap(keyframe(&[KeyCode::BTN_DPAD_RIGHT]), range));
+    assert!(!buttons[&east]);
+    assert_eq!(axes[&x], 32767);
+    let (_, axes) = settle(&remapper.remap(keyframe(&[]), range));
+    assert_eq!(axes[&x], 0);
+}
+
+#[test]
+fn test_profiles_extend_capabilities() {
+    let profile = Profile::parse("extend", "BTN_SOUTH -> BTN_MODE\nBTN_TL -> ABS_HAT2Y").unwrap();
+    let capabilities = DeviceCapabilities::new(
+        [KeyCode::BTN_SOUTH],
+        [(AbsoluteAxisCode::ABS_X, range(AbsoluteAxisCode::ABS_X))],
+    );
+    let extended = profile.extend(&capabilities);
+    assert!(extended.keys().any(|key| key == KeyCode::BTN_MODE));
+    assert!(extended.keys().any(|key| key == KeyCode::BTN_SOUTH));
+    assert_eq!(extended.axes().count(), 2);
+}
+
+#[test]
+fn test_profiles_are_loaded_and_cycled() {
+    let dir = std::env::temp_dir().join(format!("starboard_profiles_{}", process::id()));
+    assert_eq!(load_profiles(&dir).unwrap(), (Vec::new(), Vec::new()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("shooter.profile"), "ABS_HAT2Y -> BTN_TL2").unwrap();
+    fs::write(dir.join("racing.profile"), "BTN_SOUTH -> ABS_HAT2X").unwrap();
+    fs::write(dir.join("notes.txt"), "Not a profile").unwrap();
+    let (profiles, skipped) = load_profiles(&dir).unwrap();
+    assert!(skipped.is_empty());
+    let names: Vec<&str> = profiles
+        .iter()
+        .map(|profile| profile.name.as_str())
+        .collect();
+    assert_eq!(names, ["racing", "shooter"]);
+
+    let racing = next_profile(&profiles, None);
+    assert_eq!(racing.as_deref(), Some(&*profiles[0]));
+    let shooter = next_profile(&profiles, racing.as_ref());
+    assert_eq!(shooter.as_deref(), Some(&*profiles[1]));
+    assert!(next_profile(&profiles, shooter.as_ref()).is_none());
+
+    // A profile that doesn't parse is skipped, saying which file it's in, and the rest still load
+    fs::write(dir.join("broken.profile"), "BTN_SOUTH").unwrap();
+    let (profiles, skipped) = load_profiles(&dir).unwrap();
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].contains("broken.profile") && skipped[0].contains("line 1"));
+    fs::remove_dir_all(&dir).unwrap();
+}
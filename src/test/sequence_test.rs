@@ -0,0 +1,131 @@
+use crate::{
+    lifecycle::ControllerState,
+    sequence::{
+        RETIRED_SESSIONS, SequenceVerdict, SequenceWindow, Sequencer, SessionWindow, WINDOW_SIZE,
+    },
+    server::ControllerDiagnostic,
+    string::StarboardString,
+};
+
+fn test_diagnostic() -> ControllerDiagnostic {
+    let name = StarboardString::try_from("Test").unwrap();
+    ControllerDiagnostic::new(0, name, ControllerState::Online)
+}
+
+#[test]
+fn test_sequence_window_in_order() {
+    let mut window = SequenceWindow::new();
+    for sequence in 0..10 {
+        assert_eq!(
+            window.check(sequence),
+            SequenceVerdict::Accepted { skipped: 0 }
+        );
+    }
+    assert_eq!(window.check(9), SequenceVerdict::Duplicate);
+}
+
+#[test]
+fn test_sequence_window_duplicate() {
+    let mut window = SequenceWindow::new();
+    window.check(4);
+    window.check(5);
+    assert_eq!(window.check(5), SequenceVerdict::Duplicate);
+    assert_eq!(window.check(4), SequenceVerdict::Duplicate);
+}
+
+#[test]
+fn test_sequence_window_reordering() {
+    let mut window = SequenceWindow::new();
+    window.check(1);
+    assert_eq!(window.check(4), SequenceVerdict::Accepted { skipped: 2 });
+    assert_eq!(window.check(3), SequenceVerdict::Late);
+    assert_eq!(window.check(3), SequenceVerdict::Duplicate);
+    assert_eq!(window.check(2), SequenceVerdict::Late);
+}
+
+#[test]
+fn test_sequence_window_stale() {
+    let mut window = SequenceWindow::new();
+    window.check(0);
+    window.check(WINDOW_SIZE);
+    assert_eq!(window.check(0), SequenceVerdict::Stale);
+    assert_eq!(window.check(1), SequenceVerdict::Late);
+}
+
+#[test]
+fn test_diagnostic_counts_dropped_packets() {
+    let mut diagnostic = test_diagnostic();
+    assert!(diagnostic.accept_sequence(7, 0));
+    assert!(diagnostic.accept_sequence(7, 3));
+    assert_eq!(diagnostic.packets.lost, 2);
+    assert!(!diagnostic.accept_sequence(7, 2));
+    assert_eq!(diagnostic.packets.lost, 1);
+    assert_eq!(diagnostic.packets.stale, 1);
+    assert!(!diagnostic.accept_sequence(7, 3));
+    assert_eq!(diagnostic.packets.duplicates, 1);
+    assert_eq!(diagnostic.packets.received, 4);
+}
+
+#[test]
+fn test_diagnostic_resets_on_new_session() {
+    let mut diagnostic = test_diagnostic();
+    assert!(diagnostic.accept_sequence(1, 500));
+    assert!(!diagnostic.accept_sequence(1, 0));
+    assert!(diagnostic.accept_sequence(2, 0));
+    // Packets from before the sender restarted are refused
+    assert!(!diagnostic.accept_sequence(1, 501));
+    assert_eq!(diagnostic.packets.stale, 2);
+    // Sessions aren't ordered, so a restart may come back with a lower one
+    assert!(diagnostic.accept_sequence(0, 0));
+}
+
+#[test]
+fn test_session_window_resets_on_new_session() {
+    let mut window = SessionWindow::new();
+    assert_eq!(
+        window.check(1, 10),
+        SequenceVerdict::Accepted { skipped: 0 }
+    );
+    assert_eq!(window.check(1, 10), SequenceVerdict::Duplicate);
+    assert_eq!(window.check(2, 0), SequenceVerdict::Accepted { skipped: 0 });
+    assert_eq!(window.check(1, 11), SequenceVerdict::Stale);
+    assert_eq!(window.check(2, 1), SequenceVerdict::Accepted { skipped: 0 });
+}
+
+#[test]
+fn test_session_window_forgets_old_sessions() {
+    let mut window = SessionWindow::new();
+    for session in (0..=RETIRED_SESSIONS as u64).rev() {
+        assert_eq!(
+            window.check(session, 0),
+            SequenceVerdict::Accepted { skipped: 0 }
+        );
+    }
+    // Only so many sessions are remembered, and the oldest is forgotten first
+    assert_eq!(
+        window.check(RETIRED_SESSIONS as u64, 1),
+        SequenceVerdict::Stale
+    );
+    assert_eq!(window.check(1, 1), SequenceVerdict::Stale);
+    window.check(100, 0);
+    assert_eq!(window.check(1, 1), SequenceVerdict::Stale);
+    assert_eq!(
+        window.check(RETIRED_SESSIONS as u64, 1),
+        SequenceVerdict::Accepted { skipped: 0 }
+    );
+}
+
+#[test]
+fn test_sequencer_counts_up_within_a_session() {
+    let mut sequencer = Sequencer::new();
+    let (session, first) = sequencer.next();
+    assert_eq!(sequencer.next(), (session, first + 1));
+    assert_eq!(sequencer.next(), (session, first + 2));
+}
+
+#[test]
+fn test_sequencers_start_different_sessions() {
+    let (first, _) = Sequencer::new().next();
+    let (second, _) = Sequencer::new().next();
+    assert_ne!(second, first);
+}
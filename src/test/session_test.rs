@@ -0,0 +1,142 @@
+use crate::{
+    datagram::{HEADER_LEN, deserialize, serialize},
+    pairing::PairingKey,
+    session::{ClientSession, SealedPacket, ServerSessions},
+};
+
+use super::test_key;
+
+fn establish(key: PairingKey) -> (ClientSession, ServerSessions) {
+    let mut client = ClientSession::new(1, key);
+    let mut server = ServerSessions::new();
+    let accept = server.accept(&key, client.init());
+    client.accept(accept).unwrap();
+    // The server only takes the session up once the client sends something in it
+    let sealed = client.seal(&[]).unwrap().unwrap();
+    server.open(sealed).unwrap();
+    (client, server)
+}
+
+#[test]
+fn test_session_round_trip() {
+    let (mut client, mut server) = establish(test_key());
+    for payload in [vec![1, 2, 3], vec![4, 5, 6]] {
+        let sealed = client.seal(&payload).unwrap().unwrap();
+        let sealed: SealedPacket = deserialize(&serialize(&sealed).unwrap()).unwrap();
+        assert!(server.knows(&sealed));
+        assert_eq!(server.open(sealed).unwrap(), payload);
+    }
+}
+
+#[test]
+fn test_session_not_established() {
+    let mut client = ClientSession::new(1, test_key());
+    assert!(client.seal(&[1, 2, 3]).is_none());
+    client.init();
+    assert!(client.seal(&[1, 2, 3]).is_none());
+}
+
+#[test]
+fn test_session_rejects_replays() {
+    let (mut client, mut server) = establish(test_key());
+    let raw = serialize(&client.seal(&[1, 2, 3]).unwrap().unwrap()).unwrap();
+    assert!(server.open(deserialize(&raw).unwrap()).is_ok());
+    assert!(server.open(deserialize(&raw).unwrap()).is_err());
+}
+
+#[test]
+fn test_session_rejects_tampering() {
+    let (mut client, mut server) = establish(test_key());
+    let raw = serialize(&client.seal(&[1, 2, 3]).unwrap().unwrap()).unwrap();
+    // The frame's checksum would catch a flipped bit, so the payload is decoded directly to get
+    // the tampered packet past it
+    let mut payload = raw[HEADER_LEN..].to_vec();
+    let last = payload.len() - 1;
+    payload[last] ^= 1;
+    let (tampered, _): (SealedPacket, usize) =
+        bincode::decode_from_slice(&payload, bincode::config::standard()).unwrap();
+    assert!(server.open(tampered).is_err());
+
+    // The untouched packet still opens
+    assert!(server.open(deserialize(&raw).unwrap()).is_ok());
+}
+
+#[test]
+fn test_session_rejects_wrong_key() {
+    let key = test_key();
+    let mut client = ClientSession::new(1, test_key());
+    let mut server = ServerSessions::new();
+    // The server derives the session from a different pairing key than the client
+    let accept = server.accept(&key, client.init());
+    client.accept(accept).unwrap();
+    let sealed = client.seal(&[1, 2, 3]).unwrap().unwrap();
+    assert!(server.knows(&sealed));
+    assert!(server.open(sealed).is_err());
+}
+
+#[test]
+fn test_session_unknown_to_server() {
+    let (mut client, _) = establish(test_key());
+    // A restarted server has no sessions
+    let mut server = ServerSessions::new();
+    let sealed = client.seal(&[1, 2, 3]).unwrap().unwrap();
+    assert!(!server.knows(&sealed));
+    assert!(server.open(sealed).is_err());
+}
+
+#[test]
+fn test_session_rekey() {
+    let key = test_key();
+    let (mut client, mut server) = establish(key);
+    let old = client.seal(&[1]).unwrap().unwrap();
+
+    // Packets sealed with the previous session still open after renegotiating
+    let accept = server.accept(&key, client.init());
+    assert!(client.seal(&[2]).is_some());
+    client.accept(accept).unwrap();
+    let new = client.seal(&[3]).unwrap().unwrap();
+    assert_ne!(old.session(), new.session());
+    assert_eq!(server.open(new).unwrap(), vec![3]);
+    assert_eq!(server.open(old).unwrap(), vec![1]);
+}
+
+#[test]
+fn test_session_rejects_unrequested_accept() {
+    let key = test_key();
+    let mut client = ClientSession::new(1, key);
+    let mut server = ServerSessions::new();
+    let mut other = ClientSession::new(1, key);
+    let accept = server.accept(&key, other.init());
+    client.init();
+    assert!(client.accept(accept).is_err());
+}
+
+#[test]
+fn test_session_survives_replayed_init() {
+    let key = test_key();
+    let mut client = ClientSession::new(1, key);
+    let mut server = ServerSessions::new();
+    let first = client.init();
+    let accept = server.accept(&key, first);
+
+    // A resent request gets the same answer without offering another session
+    let resent = server.accept(&key, first);
+    assert_eq!(resent.session, accept.session);
+    assert_eq!(resent.server_nonce, accept.server_nonce);
+    client.accept(accept).unwrap();
+    let sealed = client.seal(&[1]).unwrap().unwrap();
+    assert_eq!(server.open(sealed).unwrap(), vec![1]);
+
+    // Replaying the request only offers a session nobody can use, and the client keeps its own
+    for _ in 0..8 {
+        server.accept(&key, first);
+    }
+    let sealed = client.seal(&[2]).unwrap().unwrap();
+    assert_eq!(server.open(sealed).unwrap(), vec![2]);
+
+    // The client can still move on to a new session
+    let accept = server.accept(&key, client.init());
+    client.accept(accept).unwrap();
+    let sealed = client.seal(&[3]).unwrap().unwrap();
+    assert_eq!(server.open(sealed).unwrap(), vec![3]);
+}
@@ -0,0 +1,53 @@
+use std::{fs, process};
+
+use crate::{
+    pairing::PairingKey,
+    storage::{KeyStore, id_at},
+};
+
+fn test_key(byte: u8) -> PairingKey {
+    bincode::decode_from_slice([byte; 32].as_slice(), bincode::config::standard())
+        .unwrap()
+        .0
+}
+
+#[test]
+fn test_key_store_persists() {
+    let path = std::env::temp_dir().join(format!("starboard_key_store_{}", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut store: KeyStore<u64> = KeyStore::open(path.clone()).unwrap();
+    assert!(!store.contains(&7));
+    store.insert(7, test_key(1)).unwrap();
+    store.insert(9, test_key(2)).unwrap();
+
+    let store: KeyStore<u64> = KeyStore::open(path.clone()).unwrap();
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get(&7), Some(&test_key(1)));
+    assert_eq!(store.get(&9), Some(&test_key(2)));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_key_store_only_replaces_keys_when_asked() {
+    let path = std::env::temp_dir().join(format!("starboard_key_store_replace_{}", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut store: KeyStore<u64> = KeyStore::open(path.clone()).unwrap();
+    store.insert(7, test_key(1)).unwrap();
+    assert!(store.insert(7, test_key(2)).is_err());
+    assert_eq!(store.get(&7), Some(&test_key(1)));
+    store.replace(7, test_key(2)).unwrap();
+    assert_eq!(store.get(&7), Some(&test_key(2)));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_client_id_persists() {
+    let path = std::env::temp_dir().join(format!("starboard_client_id_{}", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let id = id_at(&path).unwrap();
+    assert_eq!(id_at(&path).unwrap(), id);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+
+use evdev::{AbsoluteAxisCode, KeyCode};
+
+use crate::{
+    bitmask::Bitmask,
+    datagram::serialize,
+    input::{
+        FromID, InputFrame, IntoID, StarboardAxisStates, StarboardInput, StarboardInputPacket,
+    },
+    supported_actions::{AXIS_COUNT, BUTTON_COUNT, SUPPORTED_AXES, axis_by_code, axis_by_id},
+};
+
+#[test]
+fn test_axis_ids_are_packet_indices() {
+    // Axis states are stored by ID, so the IDs have to count up from 0 without gaps
+    let ids: Vec<u32> = SUPPORTED_AXES.iter().map(|axis| axis.id).collect();
+    let expected: Vec<u32> = (0..AXIS_COUNT).collect();
+    assert_eq!(ids, expected);
+    assert_eq!(StarboardAxisStates::new().axes.len(), AXIS_COUNT as usize);
+}
+
+#[test]
+fn test_axis_descriptors_are_unique() {
+    let codes: HashSet<AbsoluteAxisCode> = SUPPORTED_AXES.iter().map(|axis| axis.code).collect();
+    let names: HashSet<&str> = SUPPORTED_AXES.iter().map(|axis| axis.name).collect();
+    assert_eq!(codes.len(), SUPPORTED_AXES.len());
+    assert_eq!(names.len(), SUPPORTED_AXES.len());
+}
+
+#[test]
+fn test_axis_round_trips() {
+    for axis in SUPPORTED_AXES {
+        assert_eq!(axis.code.into_id().unwrap(), axis.id);
+        assert_eq!(
+            FromID::<AbsoluteAxisCode>::from_id(axis.id).unwrap(),
+            axis.code
+        );
+        assert_eq!(axis_by_code(axis.code).unwrap().id, axis.id);
+        assert_eq!(axis_by_id(axis.id).unwrap().code, axis.code);
+        assert!(axis.range.minimum < axis.range.maximum);
+    }
+    assert!(FromID::<AbsoluteAxisCode>::from_id(AXIS_COUNT).is_err());
+    assert!(AbsoluteAxisCode::ABS_MISC.into_id().is_err());
+}
+
+#[test]
+fn test_axis_bitmask_matches_table() {
+    // Each axis sets the bit of its own ID, so the bitmask reads back as the same axes
+    for axis in SUPPORTED_AXES {
+        let mask: Bitmask = [axis.code].into_iter().collect();
+        assert_eq!(mask.ones().collect::<Vec<_>>(), [axis.id]);
+    }
+    let all: Bitmask = SUPPORTED_AXES.iter().map(|axis| axis.code).collect();
+    assert!(all.into_iter().all(|state| state));
+}
+
+#[test]
+fn test_axis_inputs_reach_the_right_axis() {
+    for axis in SUPPORTED_AXES {
+        let input = StarboardInput::Axis {
+            id: axis.id,
+            value: 1,
+        };
+        let event: evdev::InputEvent = input.try_into().unwrap();
+        assert_eq!(event.code(), axis.code.0);
+    }
+}
+
+#[test]
+fn test_analog_triggers_are_forwarded() {
+    for (code, button) in [
+        (AbsoluteAxisCode::ABS_Z, KeyCode::BTN_TL2),
+        (AbsoluteAxisCode::ABS_RZ, KeyCode::BTN_TR2),
+    ] {
+        let axis = axis_by_code(code).unwrap();
+        assert_eq!(axis.button, Some(button));
+        assert_eq!(axis.range.rest(), 0);
+
+        let mut keyframe = StarboardInputPacket::new(3, 1, 7);
+        let input = StarboardInput::Axis {
+            id: code.into_id().unwrap(),
+            value: 200,
+        };
+        keyframe.pack(input).unwrap();
+        let InputFrame::Keyframe(decoded) =
+            InputFrame::deserialize(&serialize(&keyframe).unwrap()).unwrap()
+        else {
+            panic!("Expected a keyframe");
+        };
+        let enabled: Bitmask = [code].into_iter().collect();
+        let inputs = decoded.unpack(&Bitmask::new(BUTTON_COUNT), &enabled);
+        assert_eq!(inputs[..1], [input]);
+    }
+}
+
+#[test]
+fn test_trigger_buttons_are_supported() {
+    for button in SUPPORTED_AXES.iter().filter_map(|axis| axis.button) {
+        assert!(button.into_id().is_ok());
+    }
+}
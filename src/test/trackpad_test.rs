@@ -0,0 +1,118 @@
+use evdev::{AbsoluteAxisCode, EventType, InputEvent, KeyCode};
+
+use crate::{
+    bitmask::Bitmask,
+    datagram::serialize,
+    input::{InputFrame, IntoID, StarboardDeltaPacket, StarboardInput, StarboardInputPacket},
+    supported_actions::{
+        AXIS_COUNT, BUTTON_COUNT, SUPPORTED_TRACKPADS, TRACKPAD_COUNT, axis_by_code,
+        trackpad_by_axis, trackpad_by_click,
+    },
+    trackpad::{TouchTracker, TrackpadMode, touch_at, trackpads_on},
+};
+
+fn abs(axis: AbsoluteAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::ABSOLUTE.0, axis.0, value)
+}
+
+#[test]
+fn test_trackpads_are_supported_inputs() {
+    // The server falls back on the axis table for the range of a trackpad's axes
+    for (id, pad) in SUPPORTED_TRACKPADS.iter().enumerate() {
+        assert_eq!(pad.id, id as u32);
+        assert!(axis_by_code(pad.x).is_some());
+        assert!(axis_by_code(pad.y).is_some());
+        assert!(pad.click.into_id().is_ok());
+        assert_eq!(trackpad_by_axis(pad.x).unwrap().id, pad.id);
+        assert_eq!(trackpad_by_axis(pad.y).unwrap().id, pad.id);
+        assert_eq!(trackpad_by_click(pad.click).unwrap().id, pad.id);
+    }
+    assert!(trackpad_by_axis(AbsoluteAxisCode::ABS_X).is_none());
+    assert!(trackpad_by_click(KeyCode::BTN_SOUTH).is_none());
+}
+
+#[test]
+fn test_trackpads_need_both_axes() {
+    let both = [AbsoluteAxisCode::ABS_HAT0X, AbsoluteAxisCode::ABS_HAT0Y];
+    assert_eq!(trackpads_on(&both).len(), 1);
+    assert!(trackpads_on(&[AbsoluteAxisCode::ABS_HAT1X]).is_empty());
+    assert!(trackpads_on(&[AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y]).is_empty());
+}
+
+#[test]
+fn test_touch_tracker_reports_moved_trackpads() {
+    let mut tracker = TouchTracker::new(SUPPORTED_TRACKPADS.iter().collect());
+    assert!(tracker.changes().is_empty());
+
+    tracker.update(&abs(AbsoluteAxisCode::ABS_HAT1X, 1200));
+    tracker.update(&abs(AbsoluteAxisCode::ABS_HAT1Y, -300));
+    tracker.update(&abs(AbsoluteAxisCode::ABS_X, 5000));
+    assert_eq!(
+        tracker.changes(),
+        [StarboardInput::Touch {
+            id: 1,
+            state: touch_at(1200, -300)
+        }]
+    );
+    assert!(tracker.changes().is_empty());
+
+    // Only one axis moving still reports the whole contact
+    tracker.update(&abs(AbsoluteAxisCode::ABS_HAT1X, 1300));
+    let StarboardInput::Touch { state, .. } = tracker.changes()[0] else {
+        panic!("Expected a touch");
+    };
+    assert_eq!((state.touching, state.x, state.y), (true, 1300, -300));
+
+    // Lifting the finger puts the trackpad back at its centre
+    tracker.update(&abs(AbsoluteAxisCode::ABS_HAT1X, 0));
+    tracker.update(&abs(AbsoluteAxisCode::ABS_HAT1Y, 0));
+    let StarboardInput::Touch { state, .. } = tracker.changes()[0] else {
+        panic!("Expected a touch");
+    };
+    assert!(!state.touching);
+}
+
+#[test]
+fn test_touches_survive_keyframes_and_deltas() {
+    let touch = StarboardInput::Touch {
+        id: 0,
+        state: touch_at(-4000, 2500),
+    };
+    let buttons = Bitmask::new(BUTTON_COUNT);
+    let axes = Bitmask::new(AXIS_COUNT);
+
+    let mut keyframe = StarboardInputPacket::new(3, 1, 7);
+    keyframe.pack(touch).unwrap();
+    let decoded = InputFrame::deserialize(&serialize(&keyframe).unwrap()).unwrap();
+    let inputs = decoded.unpack(&buttons, &axes);
+    assert_eq!(inputs.len(), TRACKPAD_COUNT as usize);
+    assert_eq!(inputs[0], touch);
+
+    let mut delta = StarboardDeltaPacket::new(3, 1, 8);
+    delta.pack(touch);
+    delta.pack(StarboardInput::Touch {
+        id: TRACKPAD_COUNT,
+        state: touch_at(1, 1),
+    });
+    let decoded = InputFrame::deserialize(&serialize(&delta).unwrap()).unwrap();
+    assert_eq!(decoded.unpack(&buttons, &axes), [touch]);
+
+    let mut keyframe = StarboardInputPacket::new(3, 1, 9);
+    let outside = StarboardInput::Touch {
+        id: TRACKPAD_COUNT,
+        state: touch_at(1, 1),
+    };
+    assert!(keyframe.pack(outside).is_err());
+}
+
+#[test]
+fn test_trackpad_modes_cycle() {
+    let mut mode = TrackpadMode::default();
+    assert_eq!(mode, TrackpadMode::Joystick);
+    for _ in 0..3 {
+        mode = mode.next();
+    }
+    assert_eq!(mode, TrackpadMode::Joystick);
+    assert_eq!(TrackpadMode::Touchpad.next(), TrackpadMode::Mouse);
+    assert_eq!(TrackpadMode::Mouse.to_string(), "mouse");
+}
@@ -0,0 +1,392 @@
+use core::fmt::{self, Display};
+use std::collections::HashMap;
+
+use anyhow::Result;
+use evdev::{
+    AbsInfo, AbsoluteAxisCode, AttributeSet, EventType, InputEvent, KeyCode, PropType,
+    RelativeAxisCode, UinputAbsSetup, uinput::VirtualDevice,
+};
+
+use crate::{
+    capabilities::AxisRange,
+    input::{StarboardInput, TouchState},
+    supported_actions::{
+        SUPPORTED_TRACKPADS, TRACKPAD_COUNT, TrackpadDescriptor, axis_by_code, trackpad_by_id,
+    },
+};
+
+// Trackpads are forwarded as their axes and click buttons, like any other input, and also as
+// contacts. The server picks per controller whether the contacts drive a touchpad, a mouse, or
+// nothing, in which case the axes move the joystick's hats as before.
+
+// How far the pointer moves, in pixels, for each unit a finger moves across a trackpad. The Steam
+// Deck's trackpads are 65534 units across, so a swipe across one moves the pointer about 800
+// pixels.
+const MOUSE_SPEED: f64 = 1.0 / 80.0;
+
+// The resolution touchpads declare when the controller doesn't say, in units per millimetre. The
+// Steam Deck's trackpads are about 33mm across. Without one, desktops guess at the touchpad's size.
+const TOUCHPAD_RESOLUTION: i32 = 2000;
+
+// Where a controller's trackpads go on the server
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TrackpadMode {
+    #[default]
+    Joystick, // The trackpads move the joystick's hats
+    Touchpad, // Each trackpad is a multitouch touchpad
+    Mouse,    // The trackpads move the pointer, and clicking them clicks
+}
+
+impl TrackpadMode {
+    // Returns the mode after this one, for cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Joystick => Self::Touchpad,
+            Self::Touchpad => Self::Mouse,
+            Self::Mouse => Self::Joystick,
+        }
+    }
+}
+
+impl Display for TrackpadMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Joystick => write!(f, "joystick"),
+            Self::Touchpad => write!(f, "touchpad"),
+            Self::Mouse => write!(f, "mouse"),
+        }
+    }
+}
+
+// Returns the trackpads that report their contacts on `axes`
+pub fn trackpads_on(axes: &[AbsoluteAxisCode]) -> Vec<&'static TrackpadDescriptor> {
+    SUPPORTED_TRACKPADS
+        .iter()
+        .filter(|pad| axes.contains(&pad.x) && axes.contains(&pad.y))
+        .collect()
+}
+
+// Returns the contact reported at `x` and `y`. The Steam Deck reports a trackpad that isn't being
+// touched as being at its centre, which a finger never lands on exactly.
+pub fn touch_at(x: i32, y: i32) -> TouchState {
+    TouchState {
+        touching: (x, y) != (0, 0),
+        x,
+        y,
+    }
+}
+
+// Follows the contacts on a device's trackpads through the events it reports, since each contact
+// is spread across two axes
+pub struct TouchTracker {
+    pads: Vec<&'static TrackpadDescriptor>,
+    positions: [(i32, i32); TRACKPAD_COUNT as usize],
+    changed: [bool; TRACKPAD_COUNT as usize],
+}
+
+impl TouchTracker {
+    pub fn new(pads: Vec<&'static TrackpadDescriptor>) -> Self {
+        Self {
+            pads,
+            positions: [(0, 0); TRACKPAD_COUNT as usize],
+            changed: [false; TRACKPAD_COUNT as usize],
+        }
+    }
+
+    // Records `event` if it moves one of the trackpads
+    pub fn update(&mut self, event: &InputEvent) {
+        if event.event_type() != EventType::ABSOLUTE {
+            return;
+        }
+        let axis = AbsoluteAxisCode(event.code());
+        for pad in &self.pads {
+            let id = pad.id as usize;
+            if axis == pad.x {
+                self.positions[id].0 = event.value();
+            } else if axis == pad.y {
+                self.positions[id].1 = event.value();
+            } else {
+                continue;
+            }
+            self.changed[id] = true;
+        }
+    }
+
+    // Returns the contact on every trackpad that has moved since the last call
+    pub fn changes(&mut self) -> Vec<StarboardInput> {
+        let mut inputs = Vec::new();
+        for pad in &self.pads {
+            let id = pad.id as usize;
+            if std::mem::take(&mut self.changed[id]) {
+                let (x, y) = self.positions[id];
+                inputs.push(StarboardInput::Touch {
+                    id: pad.id,
+                    state: touch_at(x, y),
+                });
+            }
+        }
+        inputs
+    }
+}
+
+// Returns the range the controller reports `axis` in, or the one Starboard expects if the
+// controller hasn't said
+fn source_range(
+    sources: &HashMap<AbsoluteAxisCode, AxisRange>,
+    axis: AbsoluteAxisCode,
+) -> AxisRange {
+    match sources.get(&axis) {
+        Some(range) => *range,
+        // Safety of using `unwrap()`: every trackpad axis is in SUPPORTED_AXES
+        None => axis_by_code(axis).unwrap().range,
+    }
+}
+
+fn abs_event(axis: AbsoluteAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::ABSOLUTE.0, axis.0, value)
+}
+
+fn key_event(key: KeyCode, pressed: bool) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, key.0, pressed.into())
+}
+
+// The devices a controller's trackpads drive when they don't move the joystick's hats
+pub enum VirtualTrackpads {
+    Touchpads(Vec<VirtualTouchpad>), // One per trackpad, by ID
+    Mouse(VirtualMouse),
+}
+
+impl VirtualTrackpads {
+    // Builds the devices for `mode`, or nothing if the trackpads stay on the joystick. Each
+    // touchpad declares the range its controller reports in, as given by `sources`.
+    pub fn new(
+        mode: TrackpadMode,
+        name: &str,
+        sources: &HashMap<AbsoluteAxisCode, AxisRange>,
+    ) -> Result<Option<Self>> {
+        Ok(match mode {
+            TrackpadMode::Joystick => None,
+            TrackpadMode::Touchpad => Some(Self::Touchpads(
+                SUPPORTED_TRACKPADS
+                    .iter()
+                    .map(|pad| VirtualTouchpad::new(pad, name, sources))
+                    .collect::<Result<_>>()?,
+            )),
+            TrackpadMode::Mouse => Some(Self::Mouse(VirtualMouse::new(&format!(
+                "{name} Trackpad Mouse"
+            ))?)),
+        })
+    }
+
+    // Moves, places or lifts the finger on the id-th trackpad
+    pub fn touch(&mut self, id: u32, state: TouchState) -> Result<()> {
+        match self {
+            Self::Touchpads(touchpads) => match touchpads.get_mut(id as usize) {
+                Some(touchpad) => touchpad.touch(state),
+                None => Ok(()),
+            },
+            Self::Mouse(mouse) => mouse.touch(id, state),
+        }
+    }
+
+    // Presses or releases the click of the id-th trackpad
+    pub fn click(&mut self, id: u32, pressed: bool) -> Result<()> {
+        match self {
+            Self::Touchpads(touchpads) => match touchpads.get_mut(id as usize) {
+                Some(touchpad) => touchpad.click(pressed),
+                None => Ok(()),
+            },
+            Self::Mouse(mouse) => mouse.click(id, pressed),
+        }
+    }
+
+    // Lifts every finger and releases every click
+    pub fn neutralize(&mut self) -> Result<()> {
+        for id in 0..TRACKPAD_COUNT {
+            self.touch(id, TouchState::default())?;
+            self.click(id, false)?;
+        }
+        Ok(())
+    }
+}
+
+// A single touch touchpad for one of a controller's trackpads
+pub struct VirtualTouchpad {
+    raw: VirtualDevice,
+    x: AxisRange,
+    y: AxisRange,
+    touching: bool,
+    tracking_id: i32, // Given to the next contact, so that each one is told apart from the last
+}
+
+impl VirtualTouchpad {
+    fn new(
+        pad: &TrackpadDescriptor,
+        name: &str,
+        sources: &HashMap<AbsoluteAxisCode, AxisRange>,
+    ) -> Result<Self> {
+        let with_resolution = |range: AxisRange| match range.resolution {
+            0 => AxisRange {
+                resolution: TOUCHPAD_RESOLUTION,
+                ..range
+            },
+            _ => range,
+        };
+        let x = with_resolution(source_range(sources, pad.x));
+        let y = with_resolution(source_range(sources, pad.y));
+        let keys: AttributeSet<KeyCode> = [
+            KeyCode::BTN_TOUCH,
+            KeyCode::BTN_TOOL_FINGER,
+            KeyCode::BTN_LEFT,
+        ]
+        .into_iter()
+        .collect();
+        let properties: AttributeSet<PropType> = [PropType::POINTER, PropType::BUTTONPAD]
+            .into_iter()
+            .collect();
+        let axis = |code, range: AxisRange| UinputAbsSetup::new(code, range.to_abs_info());
+        let slots = AbsInfo::new(0, 0, 0, 0, 0, 0);
+        let tracking_ids = AbsInfo::new(0, 0, u16::MAX.into(), 0, 0, 0);
+        let name = format!("{name} {}", pad.name);
+        let raw = VirtualDevice::builder()?
+            .name(&name)
+            .with_keys(&keys)?
+            .with_properties(&properties)?
+            .with_absolute_axis(&axis(AbsoluteAxisCode::ABS_X, x))?
+            .with_absolute_axis(&axis(AbsoluteAxisCode::ABS_Y, y))?
+            .with_absolute_axis(&axis(AbsoluteAxisCode::ABS_MT_POSITION_X, x))?
+            .with_absolute_axis(&axis(AbsoluteAxisCode::ABS_MT_POSITION_Y, y))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_MT_SLOT, slots))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisCode::ABS_MT_TRACKING_ID,
+                tracking_ids,
+            ))?
+            .build()?;
+        Ok(Self {
+            raw,
+            x,
+            y,
+            touching: false,
+            tracking_id: 0,
+        })
+    }
+
+    fn touch(&mut self, state: TouchState) -> Result<()> {
+        let mut events = Vec::new();
+        if state.touching {
+            if !self.touching {
+                events.push(abs_event(
+                    AbsoluteAxisCode::ABS_MT_TRACKING_ID,
+                    self.tracking_id,
+                ));
+                events.push(key_event(KeyCode::BTN_TOUCH, true));
+                events.push(key_event(KeyCode::BTN_TOOL_FINGER, true));
+                self.tracking_id = (self.tracking_id + 1) % i32::from(u16::MAX);
+            }
+            let x = state.x.clamp(self.x.minimum, self.x.maximum);
+            // The Steam Deck reports up as positive, while touchpads count down from the top
+            let y =
+                self.y.maximum - (state.y.clamp(self.y.minimum, self.y.maximum) - self.y.minimum);
+            events.extend([
+                abs_event(AbsoluteAxisCode::ABS_MT_POSITION_X, x),
+                abs_event(AbsoluteAxisCode::ABS_MT_POSITION_Y, y),
+                abs_event(AbsoluteAxisCode::ABS_X, x),
+                abs_event(AbsoluteAxisCode::ABS_Y, y),
+            ]);
+        } else if self.touching {
+            events.extend([
+                abs_event(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1),
+                key_event(KeyCode::BTN_TOUCH, false),
+                key_event(KeyCode::BTN_TOOL_FINGER, false),
+            ]);
+        } else {
+            return Ok(());
+        }
+        self.touching = state.touching;
+        self.raw.emit(&events)?;
+        Ok(())
+    }
+
+    fn click(&mut self, pressed: bool) -> Result<()> {
+        self.raw.emit(&[key_event(KeyCode::BTN_LEFT, pressed)])?;
+        Ok(())
+    }
+}
+
+// A mouse moved by every one of a controller's trackpads, or by its gyro
+pub struct VirtualMouse {
+    raw: VirtualDevice,
+    // Where the finger on each trackpad was last, if there is one
+    last: [Option<(i32, i32)>; TRACKPAD_COUNT as usize],
+    // Movement too small to move the pointer by a whole pixel yet
+    remainder: (f64, f64),
+}
+
+impl VirtualMouse {
+    pub fn new(name: &str) -> Result<Self> {
+        let keys: AttributeSet<KeyCode> = SUPPORTED_TRACKPADS
+            .iter()
+            .map(|pad| pad.mouse_button)
+            .collect();
+        let axes: AttributeSet<RelativeAxisCode> =
+            [RelativeAxisCode::REL_X, RelativeAxisCode::REL_Y]
+                .into_iter()
+                .collect();
+        let raw = VirtualDevice::builder()?
+            .name(name)
+            .with_keys(&keys)?
+            .with_relative_axes(&axes)?
+            .build()?;
+        Ok(Self {
+            raw,
+            last: [None; TRACKPAD_COUNT as usize],
+            remainder: (0.0, 0.0),
+        })
+    }
+
+    // Moves the pointer as far as the finger on the id-th trackpad moved. Placing a finger doesn't
+    // move the pointer, so that the pointer can be moved further by lifting the finger and placing
+    // it back where it started.
+    fn touch(&mut self, id: u32, state: TouchState) -> Result<()> {
+        let Some(last) = self.last.get_mut(id as usize) else {
+            return Ok(());
+        };
+        if !state.touching {
+            *last = None;
+            return Ok(());
+        }
+        let Some((x, y)) = last.replace((state.x, state.y)) else {
+            return Ok(());
+        };
+        // Up is positive on the Steam Deck, but the pointer moves down as y increases
+        let dx = (f64::from(state.x) - f64::from(x)) * MOUSE_SPEED;
+        let dy = (f64::from(y) - f64::from(state.y)) * MOUSE_SPEED;
+        self.move_by(dx, dy)
+    }
+
+    // Moves the pointer `dx` pixels right and `dy` pixels down, carrying whatever doesn't add up
+    // to a whole pixel over to the next move
+    pub fn move_by(&mut self, dx: f64, dy: f64) -> Result<()> {
+        let dx = dx + self.remainder.0;
+        let dy = dy + self.remainder.1;
+        self.remainder = (dx.fract(), dy.fract());
+        let (dx, dy) = (dx.trunc() as i32, dy.trunc() as i32);
+        if (dx, dy) == (0, 0) {
+            return Ok(());
+        }
+        let rel =
+            |axis: RelativeAxisCode, value| InputEvent::new(EventType::RELATIVE.0, axis.0, value);
+        self.raw.emit(&[
+            rel(RelativeAxisCode::REL_X, dx),
+            rel(RelativeAxisCode::REL_Y, dy),
+        ])?;
+        Ok(())
+    }
+
+    fn click(&mut self, id: u32, pressed: bool) -> Result<()> {
+        if let Some(pad) = trackpad_by_id(id) {
+            self.raw.emit(&[key_event(pad.mouse_button, pressed)])?;
+        }
+        Ok(())
+    }
+}